@@ -1,5 +1,5 @@
 
-import init, { FlightDeck, compile_program } from "/pkg/flight_deck.js";
+import init, { FlightDeck, compile_program_with_graphs, format_program, lookup_source } from "/pkg/flight_deck.js";
 import AsyncQueue from "/async_queue.js";
 
 const statusEls = Array.from(document.querySelectorAll('[data-status-message]')).filter(Boolean);
@@ -9,12 +9,15 @@ const loadProgramBtn = document.getElementById('load-program');
 const brightnessEl = document.getElementById('brightness-slider');
 const brightnessValueEl = document.getElementById('brightness-value');
 const editorEl = document.getElementById('program-editor');
+const formatProgramBtn = document.getElementById('format-program');
 let writer = null;
 const SEND_QUEUE_KEY = "__toSendQueue__";
 const DECK_KEY = "__flightDeck__";
 const HANDLERS_BOUND_KEY = "__flightDeckHandlersBound__";
 const UI_STATE_SERIALIZE_KEY = "__serializeUiState";
 const UI_STATE_RESTORE_KEY = "__restoreUiState";
+const SOURCE_MAP_KEY = "__programSourceMap";
+const PROGRAM_PARAMS_KEY = "__applyProgramParams";
 const GLOBAL_BRIGHTNESS_FUNCTION = 4;
 const CONTROL_STATIC_PREFIX = "init_";
 const CONTROL_STATIC_BLOCK = "control_statics";
@@ -307,6 +310,10 @@ class DeckReceiveHandler {
         console.warn("error", { hasRequestId, requestId, errorCode, errorString});
     }
 
+    onProgramData(requestId, totalSize, blockNumber, block) {
+        console.log("program data", { requestId, totalSize, blockNumber, words: Array.from(block) });
+    }
+
     onUiStateBlock(requestId, totalSize, blockNumber, block) {
         if (!uiStateFetch) {
             return;
@@ -540,6 +547,49 @@ function setStatus(msg) {
     });
 }
 
+// compile_program_with_graphs throws an array of diagnostics for source problems.
+function describeCompileError(err) {
+    if (!Array.isArray(err)) {
+        return err.message || err;
+    }
+    return err.map((diagnostic) => {
+        const where = diagnostic.line === 0
+            ? ''
+            : `${diagnostic.file ? diagnostic.file + ' ' : ''}line ${diagnostic.line}: `;
+        const fix = diagnostic.fix ? ` (${diagnostic.fix})` : '';
+        const code = diagnostic.code ? ` [${diagnostic.code}]` : '';
+        return `${where}${diagnostic.message}${fix}${code}`;
+    }).join('; ');
+}
+
+// Controls declared with `.param` replace a track's hand-written ones
+// before the UI state is saved with the program.
+function applyProgramParams(descriptor) {
+    const apply = globalThis[PROGRAM_PARAMS_KEY];
+    if (typeof apply === 'function') {
+        apply(descriptor.params);
+    }
+}
+
+// Lint warnings do not stop a load, so they trail the status message.
+function describeWarnings(descriptor) {
+    const warnings = descriptor.warnings;
+    return warnings.length ? ` with warnings: ${describeCompileError(warnings)}` : '';
+}
+
+// Where a program address came from, using the source map of the loaded
+// program (kept in the UI state blob so it survives a reload).
+export function describeProgramAddress(address) {
+    const map = globalThis[SOURCE_MAP_KEY];
+    const source = map && map.length ? lookup_source(map, address) : undefined;
+    if (!source) {
+        return `word ${address}`;
+    }
+    const where = `${source.file ? source.file + ' ' : ''}line ${source.line}`;
+    const owner = source.machine ? `${source.machine}.${source.function}` : source.function;
+    return `${where} (${owner})`;
+}
+
 function setConnectionState(isConnected) {
     if (!statusPillEl) {
         return;
@@ -554,12 +604,19 @@ function buildProgramSourceFromTracks() {
     }
     const tracks = Array.from(trackList.querySelectorAll('fd-track'));
     const sources = [];
+    const graphs = [];
     const missing = [];
 
     tracks.forEach((track, index) => {
         const machineId = track.dataset.machineId || '';
         const machineAssembly = track.dataset.machineAssembly || '';
         const machineSource = track.dataset.machineSource || '';
+        const machineGraph = track.dataset.machineGraph || '';
+        // Node graph tracks compile to machines after all the assembly ones.
+        if (machineGraph && !machineAssembly) {
+            graphs.push(machineGraph);
+            return;
+        }
         const isEmpty = !machineId && !machineAssembly;
         if (isEmpty) {
             return;
@@ -588,14 +645,15 @@ function buildProgramSourceFromTracks() {
     if (missing.length) {
         return {
             source: '',
+            graphs,
             error: `Missing assembly for ${missing.join(', ')}.`,
             isEmpty: false,
         };
     }
-    if (!sources.length) {
-        return { source: '', error: 'No machines selected in tracks.', isEmpty: true };
+    if (!sources.length && !graphs.length) {
+        return { source: '', graphs, error: 'No machines selected in tracks.', isEmpty: true };
     }
-    return { source: sources.join('\n\n'), isEmpty: false };
+    return { source: sources.join('\n\n'), graphs, isEmpty: false };
 }
 
 function enableControls() { 
@@ -907,6 +965,11 @@ async function startUsbReceiveLoop(device) {
 if (!globalThis[HANDLERS_BOUND_KEY]) {
     globalThis[HANDLERS_BOUND_KEY] = true;
     connectBtn?.addEventListener('click', connect);
+    formatProgramBtn?.addEventListener('click', () => {
+        if (editorEl) {
+            editorEl.value = format_program(editorEl.value);
+        }
+    });
     loadProgramBtn?.addEventListener('click', async () => {
         let deck = globalThis[DECK_KEY];
         if (!deck) {
@@ -914,26 +977,28 @@ if (!globalThis[HANDLERS_BOUND_KEY]) {
             return;
         }
     if (!editorEl) {
-        const { source, error } = buildProgramSourceFromTracks();
+        const { source, graphs, error } = buildProgramSourceFromTracks();
         if (error) {
             setStatus(error);
             return;
         }
         const programBuffer = new Uint16Array(4096);
         try {
-            const descriptor = compile_program(source, programBuffer);
+            const descriptor = compile_program_with_graphs(source, graphs, programBuffer, true);
             console.log("program length: ", descriptor.length);
+            globalThis[SOURCE_MAP_KEY] = descriptor.source_map;
+            applyProgramParams(descriptor);
 
             const uiStateBytes = await buildCompressedUiState();
             deck.load_program(programBuffer, descriptor.length, uiStateBytes);
-            setStatus(`Loaded program (${descriptor.length} words)`);
+            setStatus(`Loaded program (${descriptor.length} words)${describeWarnings(descriptor)}`);
         } catch (err) {
             console.error('Load program error:', err);
-            setStatus('Load program failed: ' + (err.message || err));
+            setStatus('Load program failed: ' + describeCompileError(err));
         }
         return;
     }
-    const { source, error, isEmpty } = buildProgramSourceFromTracks();
+    const { source, graphs, error, isEmpty } = buildProgramSourceFromTracks();
     const programSource = source || editorEl.value;
     if (error && !isEmpty) {
         setStatus(error);
@@ -945,14 +1010,16 @@ if (!globalThis[HANDLERS_BOUND_KEY]) {
     }
     const programBuffer = new Uint16Array(4096);
     try {
-        const descriptor = compile_program(programSource, programBuffer);
+        const descriptor = compile_program_with_graphs(programSource, graphs ?? [], programBuffer, true);
         console.log("program length: ", descriptor.length);
+        globalThis[SOURCE_MAP_KEY] = descriptor.source_map;
+        applyProgramParams(descriptor);
         const uiStateBytes = await buildCompressedUiState();
         deck.load_program(programBuffer, descriptor.length, uiStateBytes);
-        setStatus(`Loaded program (${descriptor.length} words)`);
+        setStatus(`Loaded program (${descriptor.length} words)${describeWarnings(descriptor)}`);
         } catch (err) {
             console.error('Load program error:', err);
-            setStatus('Load program failed: ' + (err.message || err));
+            setStatus('Load program failed: ' + describeCompileError(err));
         }
     });
 
@@ -0,0 +1,88 @@
+//! Formats light machine assembly.
+//!
+//!     lm-fmt                  format stdin to stdout
+//!     lm-fmt FILE...          print each file formatted
+//!     lm-fmt --write FILE...  rewrite the files in place
+//!     lm-fmt --check FILE...  exit 1 if any file is not formatted
+
+use std::io::{self, Read, Write};
+use std::process::ExitCode;
+
+use light_machine::assembler::format::format_source;
+
+const USAGE: &str = "usage: lm-fmt [--write | --check] [FILE...]";
+
+#[derive(PartialEq)]
+enum Mode {
+    Print,
+    Write,
+    Check,
+}
+
+fn format(source: &str) -> String {
+    let mut formatted = String::with_capacity(source.len());
+    // Writing to a `String` cannot fail.
+    let _ = format_source(source, &mut formatted);
+    formatted
+}
+
+fn run() -> Result<bool, String> {
+    let mut mode = Mode::Print;
+    let mut files = Vec::new();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--write" if mode == Mode::Print => mode = Mode::Write,
+            "--check" if mode == Mode::Print => mode = Mode::Check,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(true);
+            }
+            _ if arg.starts_with('-') => return Err(USAGE.to_string()),
+            _ => files.push(arg),
+        }
+    }
+
+    if files.is_empty() {
+        if mode != Mode::Print {
+            return Err(USAGE.to_string());
+        }
+        let mut source = String::new();
+        io::stdin().read_to_string(&mut source).map_err(|err| format!("stdin: {err}"))?;
+        io::stdout()
+            .write_all(format(&source).as_bytes())
+            .map_err(|err| format!("stdout: {err}"))?;
+        return Ok(true);
+    }
+
+    let mut formatted_already = true;
+    for file in &files {
+        let source = std::fs::read_to_string(file).map_err(|err| format!("{file}: {err}"))?;
+        let formatted = format(&source);
+        match mode {
+            Mode::Print => io::stdout()
+                .write_all(formatted.as_bytes())
+                .map_err(|err| format!("stdout: {err}"))?,
+            Mode::Write if formatted != source => {
+                std::fs::write(file, formatted).map_err(|err| format!("{file}: {err}"))?
+            }
+            Mode::Write => {}
+            Mode::Check if formatted != source => {
+                eprintln!("{file}: not formatted");
+                formatted_already = false;
+            }
+            Mode::Check => {}
+        }
+    }
+    Ok(formatted_already)
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(message) => {
+            eprintln!("{message}");
+            ExitCode::from(2)
+        }
+    }
+}
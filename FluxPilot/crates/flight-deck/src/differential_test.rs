@@ -0,0 +1,365 @@
+// Differential tests: every program here is assembled by the firmware
+// assembler straight into a `ProgramBuilder` and by `GraphAssembler` through a
+// `ProgramGraph`, and the two images must match word for word. Programs whose
+// machine types or static data blocks repeat are left out, since the graph
+// dedupes those and the firmware assembler does not. The same seeds also
+// check that formatting a program leaves its image alone, and that the
+// optimizer leaves what it does alone.
+
+use light_machine::assembler::Assembler;
+use light_machine::builder::ProgramBuilder;
+use light_machine::{Program, ProgramWord, StackWord};
+
+use crate::graph_assembler::GraphAssembler;
+use crate::program_graph::optimize::optimize;
+
+const MACHINE_MAX: usize = 8;
+const FUNCTION_MAX: usize = 16;
+const LABEL_CAP: usize = 64;
+const DATA_CAP: usize = 64;
+const BUFFER_WORDS: usize = 1024;
+
+// Header counts both builders need up front.
+struct Counts {
+    instances: ProgramWord,
+    types: ProgramWord,
+    shared_functions: ProgramWord,
+}
+
+fn count(source: &str) -> Counts {
+    let directives = |name: &str| {
+        source
+            .lines()
+            .filter(|line| line.split_whitespace().next() == Some(name))
+            .count() as ProgramWord
+    };
+    // A shared function may be declared and defined, so count names.
+    let mut shared: Vec<&str> = source
+        .lines()
+        .filter_map(|line| {
+            let mut tokens = line.split_whitespace();
+            matches!(tokens.next(), Some(".shared_func" | ".shared_func_decl")).then(|| tokens.next()).flatten()
+        })
+        .collect();
+    shared.sort();
+    shared.dedup();
+    Counts {
+        instances: directives(".machine") + directives(".instance"),
+        types: directives(".machine"),
+        shared_functions: shared.len() as ProgramWord,
+    }
+}
+
+// The image, or a description of the first error. `None` from the graph
+// side when it merged repeated machine types.
+type Image = Result<Vec<ProgramWord>, String>;
+
+fn firmware_image(source: &str) -> Image {
+    let counts = count(source);
+    let mut buffer = vec![0; BUFFER_WORDS];
+    let builder = ProgramBuilder::<MACHINE_MAX, FUNCTION_MAX>::new(
+        &mut buffer,
+        counts.instances,
+        counts.types,
+        counts.shared_functions,
+    )
+    .map_err(|err| format!("builder: {err:?}"))?;
+    let mut asm: Assembler<MACHINE_MAX, FUNCTION_MAX, LABEL_CAP, DATA_CAP> = Assembler::new(builder);
+    for (index, line) in source.lines().enumerate() {
+        asm.add_line(line)
+            .map_err(|err| format!("line {}: {err:?}", index + 1))?;
+    }
+    let length = asm.finish().map_err(|err| format!("finish: {err:?}"))?.length;
+    buffer.truncate(length);
+    Ok(buffer)
+}
+
+fn graph_image(source: &str) -> Result<Option<Vec<ProgramWord>>, String> {
+    let counts = count(source);
+    let mut assembler = GraphAssembler::new(counts.shared_functions);
+    for (index, line) in source.lines().enumerate() {
+        assembler
+            .add_line(line)
+            .map_err(|err| format!("line {}: {err:?}", index + 1))?;
+    }
+    let graph = assembler.finish().map_err(|err| format!("finish: {err:?}"))?;
+    if graph.type_count() != counts.types {
+        return Ok(None);
+    }
+    let mut buffer = vec![0; BUFFER_WORDS];
+    let mut builder = ProgramBuilder::<MACHINE_MAX, FUNCTION_MAX>::new(
+        &mut buffer,
+        graph.instance_count(),
+        graph.type_count(),
+        graph.shared_function_count(),
+    )
+    .map_err(|err| format!("builder: {err:?}"))?;
+    // The firmware assembler writes no symbols.
+    builder.strip_symbols(true);
+    let length = graph
+        .emit_into(builder)
+        .map_err(|err| format!("emit: {err:?}"))?
+        .length;
+    buffer.truncate(length);
+    Ok(Some(buffer))
+}
+
+// What each call left on the stack, or whether it failed.
+type Calls = Vec<Result<Vec<StackWord>, ()>>;
+
+// The graph assembler's program length, and the result of calling every
+// function of every instance once, in order. `None` if it does not build.
+fn graph_calls(source: &str, optimized: bool) -> Option<(usize, Calls)> {
+    let counts = count(source);
+    let mut assembler = GraphAssembler::new(counts.shared_functions);
+    for line in source.lines() {
+        assembler.add_line(line).ok()?;
+    }
+    let mut graph = assembler.finish().ok()?;
+    if optimized {
+        optimize(&mut graph);
+    }
+    let mut buffer = vec![0; BUFFER_WORDS];
+    let builder = ProgramBuilder::<MACHINE_MAX, FUNCTION_MAX>::new(
+        &mut buffer,
+        graph.instance_count(),
+        graph.type_count(),
+        graph.shared_function_count(),
+    )
+    .ok()?;
+    let descriptor = graph.emit_into(builder).ok()?;
+    let mut memory = vec![0; 256];
+    let mut program = Program::new(&buffer[..descriptor.length], &mut memory).ok()?;
+    program.set_step_limit(Some(10_000));
+    let mut calls = Vec::new();
+    for (machine, instance) in descriptor.instances.iter().enumerate() {
+        let functions = descriptor
+            .types
+            .get(usize::from(instance.type_id))
+            .map_or(0, |machine_type| machine_type.functions.len());
+        for function in 0..functions {
+            program.stack_mut().clear();
+            let result = program.call(machine as ProgramWord, function);
+            calls.push(result.map(|()| program.stack().as_slice().to_vec()).map_err(|_| ()));
+        }
+    }
+    Some((descriptor.length, calls))
+}
+
+// Both assemblers must reject the program, or both must accept it with the
+// same image.
+fn assert_same_image(name: &str, source: &str) {
+    match (firmware_image(source), graph_image(source)) {
+        (Ok(firmware), Ok(Some(graph))) => assert_eq!(firmware, graph, "{name}: images differ"),
+        (Ok(_), Ok(None)) => {}
+        (Err(_), Err(_)) => {}
+        (firmware, graph) => panic!("{name}: firmware {firmware:?}, graph {graph:?}"),
+    }
+}
+
+// The firmware assembler's seed corpus: a 2-byte header, then source text.
+// Seeds that pull in includes are left out.
+fn fuzz_seed_programs() -> Vec<(String, String)> {
+    let corpus = concat!(env!("CARGO_MANIFEST_DIR"), "/../../fuzz/corpus/assembler");
+    let mut entries: Vec<_> = std::fs::read_dir(corpus).unwrap().map(|entry| entry.unwrap().path()).collect();
+    entries.sort();
+    entries
+        .into_iter()
+        .filter_map(|path| {
+            let bytes = std::fs::read(&path).unwrap();
+            let source = bytes.get(2..).and_then(|text| std::str::from_utf8(text).ok())?;
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            (!source.contains(".include")).then(|| (name, source.to_string()))
+        })
+        .collect()
+}
+
+#[test]
+fn fuzz_seed_programs_assemble_identically() {
+    let seeds = fuzz_seed_programs();
+    assert!(!seeds.is_empty());
+    for (name, source) in &seeds {
+        assert_same_image(name, source);
+    }
+}
+
+// Formatting only moves whitespace and case, so the formatted program must
+// assemble to the same image, or fail just like the original.
+#[test]
+fn formatting_keeps_the_image() {
+    for (name, source) in fuzz_seed_programs() {
+        let formatted = crate::format_program(&source);
+        assert_eq!(crate::format_program(&formatted), formatted, "{name}: not idempotent");
+        match (firmware_image(&source), firmware_image(&formatted)) {
+            (Ok(original), Ok(reformatted)) => assert_eq!(original, reformatted, "{name}: images differ"),
+            (Err(_), Err(_)) => {}
+            (original, reformatted) => panic!("{name}: original {original:?}, formatted {reformatted:?}"),
+        }
+    }
+}
+
+// The optimized program is never longer, and every call that succeeds
+// either way leaves the same stack.
+#[test]
+fn optimizing_keeps_behavior() {
+    for (name, source) in fuzz_seed_programs() {
+        let (Some((length, calls)), Some((optimized_length, optimized_calls))) =
+            (graph_calls(&source, false), graph_calls(&source, true))
+        else {
+            continue;
+        };
+        assert!(optimized_length <= length, "{name}: optimizing grew the program");
+        for (call, optimized_call) in calls.iter().zip(&optimized_calls) {
+            if let (Ok(stack), Ok(optimized_stack)) = (call, optimized_call) {
+                assert_eq!(stack, optimized_stack, "{name}: results differ");
+            }
+        }
+    }
+}
+
+#[test]
+fn data_between_functions_keeps_source_order() {
+    assert_same_image(
+        "data between functions",
+        "
+.shared_data palette
+warm: .word #ff8000
+.end
+.machine strip locals 2 functions 3
+.local level 0
+.func_decl later index 2
+.data table
+first: 1
+.word 'A', -1, 0b101
+.end
+.func init index 1
+PUSH first + 1
+LOAD_STATIC
+PUSH warm
+LOAD_STATIC
+CALL later
+EXIT
+.end
+.data more
+7
+.end
+.func later
+PUSH #102030
+LSTORE level
+JUMP done
+done:
+EXIT
+.end
+.func get index 0
+LLOAD level
+RET 1
+.end
+.end
+",
+    );
+}
+
+#[test]
+fn calls_instances_and_shared_functions_match() {
+    assert_same_image(
+        "calls and instances",
+        "
+.shared total 0
+.shared_func_decl blend index 1 args 2 returns 1
+.shared_func clamp index 0
+SLOAD 0
+RET 1
+.end
+.machine pixel locals 2 functions 2
+.local red 0
+.local green 1
+.func_decl mix index 1 args 1 returns 1
+.func init index 0
+LLOAD red
+CALL mix 1
+LLOAD green
+PUSH 7
+CALL_SHARED blend 2
+CALL_SHARED clamp
+GSTORE total
+EXIT
+.end
+.func mix
+.frame value 0
+SLOAD value
+RET 1
+.end
+.end
+.instance second of pixel red = 3, green = 4
+.instance third of pixel
+.shared_func blend
+SLOAD 0
+RET 1
+.end
+",
+    );
+}
+
+#[test]
+fn macros_consts_and_control_blocks_match() {
+    assert_same_image(
+        "macros and control",
+        "
+.const LIMIT 4 * 2
+.macro bump slot, by
+    LLOAD slot
+    PUSH by
+    ADD
+    LSTORE slot
+.endm
+.machine counter locals 2 functions 1
+.local count 0
+.local step 1
+.func tick index 0
+    .for step 0, LIMIT
+        bump count, 1
+    .endfor
+    LLOAD count
+    .if gt LIMIT - 1
+        PUSH 0
+        LSTORE count
+    .else
+        bump count, 'a' - 'a'
+    .endif
+    .while
+        LLOAD count
+    .do lt 3
+        bump count, 1
+    .endwhile
+    exit
+.end
+.end
+",
+    );
+}
+
+#[test]
+fn params_match() {
+    let source = "
+.machine strip locals 5 functions 2
+.local level 0
+.local red 1
+.local green 2
+.local blue 3
+.local on 4
+.param level range min 10 max 1000 step 10 default 500 func set_level
+.param tint color default #468bc0 local red
+.param on toggle default 1 local on
+.func init index 0
+EXIT
+.end
+.func set_level index 1
+LSTORE level
+EXIT
+.end
+.end
+.instance second of strip
+";
+    assert!(firmware_image(source).is_ok());
+    assert_same_image("params", source);
+}
@@ -1,19 +1,45 @@
 use std::collections::HashMap;
 
+use light_machine::assembler::expression::{self, parse_word, Value};
+use light_machine::assembler::control::ControlStack;
+use light_machine::assembler::diagnostic::{self, Diagnostic};
+use light_machine::assembler::include::{self, SourceLocation, SourceResolver};
+use light_machine::assembler::macros::{MacroLine, MacroLineBuffer, MacroTable, MAX_MACRO_DEPTH};
+use light_machine::assembler::signature::{FunctionHeader, Signature};
+use light_machine::assembler::syntax::{
+    parse_line,
+    DataWord,
+    Directive,
+    InstanceDirective,
+    Instruction,
+    Line,
+    Mnemonic,
+    Operand,
+    OperandKind,
+    ParamDirective,
+    ParamTargetName,
+};
 use light_machine::assembler::{AssemblerError, AssemblerErrorKind};
+use light_machine::metadata::ParamTarget;
 use light_machine::{Ops, ProgramWord};
 
 use crate::program_graph::{
     FunctionRef,
+    FunctionSource,
+    MachineTypeId,
     ProgramGraph,
     ProgramGraphBuilder,
     SharedStaticId,
     StaticId,
+    TypeItem,
+    TypeParam,
     WordRef,
 };
+use crate::symbols::{SymbolKind, SymbolRecorder, SymbolTable};
 
-const MAX_TOKENS: usize = 6;
 const NAME_CAP: usize = 32;
+pub(crate) const MACRO_TEXT_CAP: usize = 8192;
+pub(crate) const MACRO_COUNT_CAP: usize = 32;
 
 #[derive(Clone)]
 struct Label {
@@ -24,12 +50,20 @@ struct Label {
 struct Fixup {
     name: String,
     at: usize,
+    offset: i64,
 }
 
 struct FuncEntry {
     name: String,
     index: ProgramWord,
     defined: bool,
+    signature: Signature,
+}
+
+struct MachineEntry {
+    name: String,
+    type_id: MachineTypeId,
+    locals: Vec<Label>,
 }
 
 struct StaticLabelRef {
@@ -49,14 +83,25 @@ enum BlockKind {
 
 struct FunctionAssembly {
     words: Vec<WordRef>,
+    source: FunctionSource,
 }
 
 enum OperandRef {
     Literal(ProgramWord),
-    Label(String),
+    LabelOffset(ProgramWord),
+    Label(String, i64),
     Static(StaticLabelRef),
 }
 
+// Label bases seen by expressions. Code labels are relative to the function
+// start and statics to their block until the graph is emitted.
+#[derive(PartialEq)]
+enum Symbol {
+    Code,
+    Static { id: usize, shared: bool },
+    Forward(String),
+}
+
 pub struct GraphAssembler {
     graph: ProgramGraphBuilder,
     block: BlockKind,
@@ -68,6 +113,11 @@ pub struct GraphAssembler {
     globals: Vec<Label>,
     shared_globals: Vec<Label>,
     stack_slots: Vec<Label>,
+    consts: Vec<Label>,
+    machines: Vec<MachineEntry>,
+    instance_count: ProgramWord,
+    macros: Box<MacroTable<MACRO_TEXT_CAP, MACRO_COUNT_CAP>>,
+    control: ControlStack,
     data: Vec<ProgramWord>,
     cursor: ProgramWord,
     function_count: ProgramWord,
@@ -77,12 +127,20 @@ pub struct GraphAssembler {
     globals_size: ProgramWord,
     shared_globals_size: ProgramWord,
     shared_globals_locked: bool,
-    current_machine_statics: Vec<StaticId>,
-    current_functions: Vec<FunctionRef>,
+    current_items: Vec<TypeItem>,
     current_function: Option<FunctionAssembly>,
     current_function_index: Option<ProgramWord>,
     current_shared_function_index: Option<ProgramWord>,
+    current_sources: Vec<(ProgramWord, FunctionSource)>,
+    // The machine's `.param`s, with the function each function target
+    // names until the machine ends.
+    current_params: Vec<(TypeParam, Option<String>)>,
+    machine_name: String,
+    source_file: Option<String>,
+    source_line: u32,
     line_number: u32,
+    signature: Signature,
+    symbols: Option<SymbolRecorder>,
 }
 
 impl GraphAssembler {
@@ -98,6 +156,11 @@ impl GraphAssembler {
             globals: Vec::new(),
             shared_globals: Vec::new(),
             stack_slots: Vec::new(),
+            consts: Vec::new(),
+            machines: Vec::new(),
+            instance_count: 0,
+            macros: Box::default(),
+            control: ControlStack::new(),
             data: Vec::new(),
             cursor: 0,
             function_count: 0,
@@ -107,61 +170,161 @@ impl GraphAssembler {
             globals_size: 0,
             shared_globals_size: 0,
             shared_globals_locked: false,
-            current_machine_statics: Vec::new(),
-            current_functions: Vec::new(),
+            current_items: Vec::new(),
             current_function: None,
             current_function_index: None,
             current_shared_function_index: None,
+            current_sources: Vec::new(),
+            current_params: Vec::new(),
+            machine_name: String::new(),
+            source_file: None,
+            source_line: 0,
             line_number: 0,
+            signature: Signature::default(),
+            symbols: None,
         }
     }
 
+    /// Also records the program's symbols, for [`Self::symbols`].
+    pub fn with_symbols(mut self) -> Self {
+        self.symbols = Some(SymbolRecorder::default());
+        self
+    }
+
+    /// Every symbol defined so far and its uses, if recording was turned on
+    /// with [`Self::with_symbols`]. Lines that failed to assemble still
+    /// contribute what they define and use.
+    pub fn symbols(&self) -> Option<SymbolTable> {
+        self.symbols.as_ref().map(SymbolRecorder::table)
+    }
+
     pub fn add_line(&mut self, line: &str) -> Result<(), AssemblerError> {
+        self.add_line_from(None, line)
+    }
+
+    /// Like [`Self::add_line`] for a line read from `location`, which the
+    /// source map records instead of the running line count.
+    pub fn add_line_at(&mut self, location: SourceLocation<'_>, line: &str) -> Result<(), AssemblerError> {
+        self.add_line_from(Some(location), line)
+    }
+
+    fn add_line_from(&mut self, location: Option<SourceLocation<'_>>, line: &str) -> Result<(), AssemblerError> {
         self.line_number = self
             .line_number
             .checked_add(1)
             .ok_or(AssemblerError::Kind(AssemblerErrorKind::LineNumberOverflow))?;
         let line_number = self.line_number;
-        let line = strip_comment(line).trim();
-        if line.is_empty() {
-            return Ok(());
-        }
-
-        let mut tokens: Vec<&str> = Vec::new();
-        for token in line.split_whitespace() {
-            if tokens.len() >= MAX_TOKENS {
-                return Err(AssemblerError::Kind(AssemblerErrorKind::TooManyTokens).with_line(line_number));
+        match location {
+            Some(location) => {
+                if self.source_file.as_deref() != location.file {
+                    self.source_file = location.file.map(str::to_string);
+                }
+                self.source_line = location.line;
+            }
+            None => {
+                self.source_file = None;
+                self.source_line = line_number;
             }
-            tokens.push(token);
         }
-        if tokens.is_empty() {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::EmptyLine).with_line(line_number));
+        if let Some(symbols) = self.symbols.as_mut() {
+            symbols.start_line(self.source_file.as_deref(), self.source_line, line);
         }
+        self.expand_line(line, 0).map_err(|err| err.with_line(line_number))
+    }
 
-        let first = *tokens
-            .first()
-            .ok_or_else(|| AssemblerError::Kind(AssemblerErrorKind::EmptyLine))?;
-
-        if tokens.len() == 1 && first.ends_with(':') {
-            return self.add_label(first).map_err(|err| err.with_line(line_number));
+    fn expand_line(&mut self, line: &str, depth: usize) -> Result<(), AssemblerError> {
+        let mut call = match self.macros.classify(line)? {
+            MacroLine::Plain => return self.lower_line(line),
+            MacroLine::Recorded => return Ok(()),
+            MacroLine::Call(call) => call,
+        };
+        if depth >= MAX_MACRO_DEPTH {
+            return Err(AssemblerError::Kind(AssemblerErrorKind::MacroTooDeep));
+        }
+        let mut expanded = MacroLineBuffer::new();
+        while self.macros.expand_next(&mut call, &mut expanded)? {
+            self.expand_line(&expanded, depth + 1)?;
         }
+        Ok(())
+    }
 
-        if matches!(self.block, BlockKind::Data | BlockKind::SharedData) && first != ".end" {
-            return self.handle_data_line(&tokens).map_err(|err| err.with_line(line_number));
+    fn lower_line(&mut self, line: &str) -> Result<(), AssemblerError> {
+        let in_function = matches!(self.block, BlockKind::Function | BlockKind::SharedFunction);
+        let stack_slots = &self.stack_slots;
+        let lowered = self
+            .control
+            .lower(line, in_function, |name| stack_slots.iter().any(|slot| slot.name == name))?;
+        let Some(lowered) = lowered else {
+            return self.assemble_line(line);
+        };
+        for line in &lowered {
+            self.assemble_line(line)?;
         }
+        Ok(())
+    }
 
-        if first.starts_with('.') {
-            return self
-                .handle_directive(&tokens)
-                .map_err(|err| err.with_line(line_number));
+    fn assemble_line(&mut self, line: &str) -> Result<(), AssemblerError> {
+        let line_number = self.line_number;
+        let in_data = matches!(self.block, BlockKind::Data | BlockKind::SharedData);
+        let start = self.function_len();
+        let result = parse_line(line, in_data).and_then(|line| match line {
+            Line::Empty => Ok(()),
+            Line::Label(name) => self.add_label(name),
+            Line::Directive(directive) => self.handle_directive(directive),
+            Line::Data(word) => self.handle_data_line(word),
+            Line::Instruction(instruction) => self.handle_instruction(instruction),
+        });
+        if let Some(mut symbols) = self.symbols.take() {
+            if let Ok(parsed) = parse_line(line, in_data) {
+                symbols.record(&parsed, |kind, name| self.symbol_value(kind, name));
+            }
+            self.symbols = Some(symbols);
         }
+        result.map_err(|err| err.with_line(line_number))?;
+        let end = self.function_len();
+        if let Some(function) = self.current_function.as_mut() {
+            function
+                .source
+                .add_words(start, end, self.source_file.as_deref(), self.source_line);
+        }
+        Ok(())
+    }
+
+    fn function_len(&self) -> ProgramWord {
+        self.current_function
+            .as_ref()
+            .map_or(0, |function| function.words.len() as ProgramWord)
+    }
+
+    /// Assembles a whole source, expanding `.include` through `resolver`.
+    pub fn add_source<R: SourceResolver>(&mut self, source: &str, resolver: &R) -> Result<(), AssemblerError> {
+        include::for_each_line(source, resolver, |location, line| self.add_line_at(location, line))
+    }
+
+    /// Like [`Self::add_source`], but carries on after recoverable errors and
+    /// passes every problem to `report`. Returns `false` if it had to stop.
+    pub fn add_source_reporting<R: SourceResolver>(
+        &mut self,
+        source: &str,
+        resolver: &R,
+        report: impl FnMut(Diagnostic),
+    ) -> bool {
+        diagnostic::for_each_line_reporting(
+            source,
+            resolver,
+            |location, line| self.add_line_at(location, line),
+            report,
+        )
+    }
 
-        self.handle_instruction(&tokens)
-            .map_err(|err| err.with_line(line_number))
+    /// The graph being assembled, for machines that do not come from
+    /// assembly text. Machines added here follow those assembled so far.
+    pub fn graph_mut(&mut self) -> &mut ProgramGraphBuilder {
+        &mut self.graph
     }
 
     pub fn finish(self) -> Result<ProgramGraph, AssemblerError> {
-        if !matches!(self.block, BlockKind::None) {
+        if !matches!(self.block, BlockKind::None) || self.macros.is_recording() {
             return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
         }
         for entry in &self.shared_funcs {
@@ -172,52 +335,125 @@ impl GraphAssembler {
         Ok(self.graph.build())
     }
 
-    fn handle_directive(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
-        let Some(token) = tokens.first() else {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
+    fn handle_directive(&mut self, directive: Directive<'_>) -> Result<(), AssemblerError> {
+        match directive {
+            Directive::Machine {
+                name,
+                locals,
+                functions,
+            } => self.start_machine(name, locals, functions),
+            Directive::Instance(instance) => self.add_instance(instance),
+            Directive::Func(header) => self.start_function(header),
+            Directive::FuncDecl(header) => self.declare_function(header),
+            Directive::SharedFunc(header) => self.start_shared_function(header),
+            Directive::SharedFuncDecl(header) => self.declare_shared_function(header),
+            Directive::Local { name, index } => self.declare_local(name, index),
+            Directive::Shared { name, index } => self.declare_shared(name, index),
+            Directive::Frame { name, offset } => self.declare_stack_slot(name, offset),
+            Directive::Data { .. } => self.start_data(),
+            Directive::SharedData { .. } => self.start_shared_data(),
+            Directive::Const { name, expr } => self.define_const(name, expr),
+            Directive::Param(param) => self.declare_param(param),
+            Directive::End => self.end_block(),
+        }
+    }
+
+    fn declare_param(&mut self, param: ParamDirective<'_>) -> Result<(), AssemblerError> {
+        if !matches!(self.block, BlockKind::Machine) {
+            return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
+        }
+        let name = to_name(param.name)?;
+        if self.current_params.iter().any(|(entry, _)| entry.name == name) {
+            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidParam));
+        }
+        let (target, function) = match param.target {
+            ParamTargetName::Local(local) => {
+                let local = self
+                    .resolve_local_operand(local)?
+                    .ok_or(AssemblerError::Kind(AssemblerErrorKind::UnknownLabel))?;
+                let last = local
+                    .checked_add(param.kind.value_count())
+                    .ok_or(AssemblerError::Kind(AssemblerErrorKind::GlobalIndexOutOfRange))?;
+                if last > self.globals_size {
+                    return Err(AssemblerError::Kind(AssemblerErrorKind::GlobalIndexOutOfRange));
+                }
+                (ParamTarget::Local(local), None)
+            }
+            ParamTargetName::Function(function) => (ParamTarget::Function(0), Some(to_name(function)?)),
         };
-        match *token {
-            ".machine" => self.start_machine(tokens),
-            ".func" => self.start_function(tokens),
-            ".func_decl" => self.declare_function(tokens),
-            ".shared_func" => self.start_shared_function(tokens),
-            ".shared_func_decl" => self.declare_shared_function(tokens),
-            ".local" => self.declare_local(tokens),
-            ".shared" => self.declare_shared(tokens),
-            ".frame" => self.declare_stack_slot(tokens),
-            ".data" => self.start_data(tokens),
-            ".shared_data" => self.start_shared_data(tokens),
-            ".end" => self.end_block(),
-            _ => Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective)),
-        }
-    }
-
-    fn handle_instruction(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+        let info = param.info(0, target, |text| self.evaluate(text, false)?.to_word())?;
+        self.current_params.push((TypeParam { name, info }, function));
+        Ok(())
+    }
+
+    fn handle_instruction(&mut self, instruction: Instruction<'_>) -> Result<(), AssemblerError> {
         match self.block {
-            BlockKind::Function | BlockKind::SharedFunction => self.handle_function_instruction(tokens),
-            BlockKind::Data | BlockKind::SharedData => self.handle_data_line(tokens),
+            BlockKind::Function | BlockKind::SharedFunction => self.handle_function_instruction(instruction),
             _ => Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedInstruction)),
         }
     }
 
-    fn start_machine(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+    fn define_const(&mut self, name: &str, expr: &str) -> Result<(), AssemblerError> {
+        let name = to_name(name)?;
+        if self.consts.iter().any(|entry| entry.name == name) {
+            return Err(AssemblerError::Kind(AssemblerErrorKind::DuplicateConst));
+        }
+        let offset = self.evaluate(expr, false)?.to_word()?;
+        self.consts.push(Label { name, offset });
+        Ok(())
+    }
+
+    fn add_instance(&mut self, instance: InstanceDirective<'_>) -> Result<(), AssemblerError> {
         if !matches!(self.block, BlockKind::None) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
         }
-        let locals_token = tokens.get(2).copied();
-        let functions_token = tokens.get(4).copied();
-        if tokens.len() != 6
-            || !matches!(locals_token, Some("locals") | Some("globals"))
-            || functions_token != Some("functions")
-        {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        }
-        let globals_size = parse_word(tokens.get(3).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
-        let function_count = parse_word(tokens.get(5).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
+        let name = to_name(instance.name)?;
+        if self.consts.iter().any(|entry| entry.name == name) {
+            return Err(AssemblerError::Kind(AssemblerErrorKind::DuplicateConst));
+        }
+        let entry = self
+            .machines
+            .iter()
+            .find(|entry| entry.name == instance.machine)
+            .ok_or(AssemblerError::Kind(AssemblerErrorKind::UnknownMachine))?;
+        let mut initial_locals = Vec::new();
+        for assignment in instance.assignments() {
+            let (local, expr) = assignment?;
+            let local = entry
+                .locals
+                .iter()
+                .find(|label| label.name == local)
+                .ok_or(AssemblerError::Kind(AssemblerErrorKind::UnknownLabel))?;
+            let value = self.evaluate(expr, false)?.to_word()?;
+            initial_locals.push((local.offset, value));
+        }
+        self.graph
+            .add_named_machine_instance(entry.type_id, name.clone(), initial_locals);
+        self.consts.push(Label {
+            name,
+            offset: self.instance_count,
+        });
+        self.next_instance()
+    }
+
+    fn next_instance(&mut self) -> Result<(), AssemblerError> {
+        self.instance_count = self
+            .instance_count
+            .checked_add(1)
+            .ok_or(AssemblerError::Kind(AssemblerErrorKind::MaxLabelsExceeded))?;
+        Ok(())
+    }
+
+    fn start_machine(
+        &mut self,
+        name: &str,
+        globals_size: ProgramWord,
+        function_count: ProgramWord,
+    ) -> Result<(), AssemblerError> {
+        if !matches!(self.block, BlockKind::None) {
+            return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
+        }
+        self.machine_name = to_name(name)?;
         self.labels.clear();
         self.static_labels.retain(|_, label| label.shared);
         self.fixups.clear();
@@ -227,8 +463,9 @@ impl GraphAssembler {
         self.next_function_index = 0;
         self.funcs.clear();
         self.globals_size = globals_size;
-        self.current_machine_statics.clear();
-        self.current_functions.clear();
+        self.current_items.clear();
+        self.current_sources.clear();
+        self.current_params.clear();
         self.current_function_index = None;
         self.current_shared_function_index = None;
         if !self.shared_globals_locked {
@@ -239,23 +476,13 @@ impl GraphAssembler {
         Ok(())
     }
 
-    fn start_function(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+    fn start_function(&mut self, header: FunctionHeader<'_>) -> Result<(), AssemblerError> {
         if !matches!(self.block, BlockKind::Machine) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
         }
-        if tokens.len() != 2 && tokens.len() != 4 {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        }
-        let name = to_name(tokens.get(1).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
-        let index = if tokens.len() == 4 {
-            if tokens.get(2).copied() != Some("index") {
-                return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-            }
-            parse_word(tokens.get(3).ok_or(AssemblerError::Kind(
-                AssemblerErrorKind::InvalidDirective,
-            ))?)?
+        let name = to_name(header.name)?;
+        let index = if let Some(index) = header.index {
+            index
         } else if let Some(entry) = self.funcs.iter().find(|entry| entry.name == name) {
             entry.index
         } else {
@@ -265,35 +492,28 @@ impl GraphAssembler {
         if index >= self.function_count {
             return Err(AssemblerError::Kind(AssemblerErrorKind::FunctionIndexOutOfRange));
         }
-        self.mark_function_defined(&name, index)?;
+        self.signature = self.mark_function_defined(&name, index, header.signature)?;
 
         self.labels.clear();
         self.fixups.clear();
         self.stack_slots.clear();
         self.cursor = 0;
-        self.current_function = Some(FunctionAssembly { words: Vec::new() });
+        self.current_function = Some(FunctionAssembly {
+            words: Vec::new(),
+            source: FunctionSource::new(Some(self.machine_name.clone()), name),
+        });
         self.current_function_index = Some(index);
         self.block = BlockKind::Function;
         Ok(())
     }
 
-    fn start_shared_function(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+    fn start_shared_function(&mut self, header: FunctionHeader<'_>) -> Result<(), AssemblerError> {
         if !matches!(self.block, BlockKind::None) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
         }
-        if tokens.len() != 2 && tokens.len() != 4 {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        }
-        let name = to_name(tokens.get(1).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
-        let index = if tokens.len() == 4 {
-            if tokens.get(2).copied() != Some("index") {
-                return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-            }
-            parse_word(tokens.get(3).ok_or(AssemblerError::Kind(
-                AssemblerErrorKind::InvalidDirective,
-            ))?)?
+        let name = to_name(header.name)?;
+        let index = if let Some(index) = header.index {
+            index
         } else if let Some(entry) = self.shared_funcs.iter().find(|entry| entry.name == name) {
             entry.index
         } else {
@@ -303,7 +523,7 @@ impl GraphAssembler {
         if index >= self.shared_function_count {
             return Err(AssemblerError::Kind(AssemblerErrorKind::FunctionIndexOutOfRange));
         }
-        self.mark_shared_function_defined(&name, index)?;
+        self.signature = self.mark_shared_function_defined(&name, index, header.signature)?;
 
         self.labels.clear();
         self.fixups.clear();
@@ -313,31 +533,23 @@ impl GraphAssembler {
             self.graph.set_shared_globals_size(self.shared_globals_size);
             self.shared_globals_locked = true;
         }
-        self.current_function = Some(FunctionAssembly { words: Vec::new() });
+        self.current_function = Some(FunctionAssembly {
+            words: Vec::new(),
+            source: FunctionSource::new(None, name),
+        });
         self.current_shared_function_index = Some(index);
         self.block = BlockKind::SharedFunction;
         Ok(())
     }
 
-    fn declare_function(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+    fn declare_function(&mut self, header: FunctionHeader<'_>) -> Result<(), AssemblerError> {
         if !matches!(self.block, BlockKind::Machine) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
         }
-        if tokens.len() != 2 && tokens.len() != 4 {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        }
-        let name = to_name(tokens.get(1).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
-        let index = if tokens.len() == 4 {
-            if tokens.get(2).copied() != Some("index") {
-                return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-            }
-            parse_word(tokens.get(3).ok_or(AssemblerError::Kind(
-                AssemblerErrorKind::InvalidDirective,
-            ))?)?
-        } else {
-            self.next_free_function_index()?
+        let name = to_name(header.name)?;
+        let index = match header.index {
+            Some(index) => index,
+            None => self.next_free_function_index()?,
         };
 
         if index >= self.function_count {
@@ -350,29 +562,19 @@ impl GraphAssembler {
             name,
             index,
             defined: false,
+            signature: header.signature,
         });
         Ok(())
     }
 
-    fn declare_shared_function(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+    fn declare_shared_function(&mut self, header: FunctionHeader<'_>) -> Result<(), AssemblerError> {
         if !matches!(self.block, BlockKind::None) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
         }
-        if tokens.len() != 2 && tokens.len() != 4 {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        }
-        let name = to_name(tokens.get(1).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
-        let index = if tokens.len() == 4 {
-            if tokens.get(2).copied() != Some("index") {
-                return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-            }
-            parse_word(tokens.get(3).ok_or(AssemblerError::Kind(
-                AssemblerErrorKind::InvalidDirective,
-            ))?)?
-        } else {
-            self.next_free_shared_function_index()?
+        let name = to_name(header.name)?;
+        let index = match header.index {
+            Some(index) => index,
+            None => self.next_free_shared_function_index()?,
         };
 
         if index >= self.shared_function_count {
@@ -385,29 +587,22 @@ impl GraphAssembler {
             name,
             index,
             defined: false,
+            signature: header.signature,
         });
         Ok(())
     }
 
-    fn declare_local(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+    fn declare_local(&mut self, name: &str, index: ProgramWord) -> Result<(), AssemblerError> {
         if !matches!(self.block, BlockKind::Machine) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
         }
-        if tokens.len() != 3 {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        }
-        let name = to_name(tokens.get(1).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
+        let name = to_name(name)?;
         if self.globals.iter().any(|entry| entry.name == name) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::DuplicateGlobal));
         }
         if self.shared_globals.iter().any(|entry| entry.name == name) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::DuplicateGlobal));
         }
-        let index = parse_word(tokens.get(2).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
         if index >= self.globals_size {
             return Err(AssemblerError::Kind(AssemblerErrorKind::GlobalIndexOutOfRange));
         }
@@ -415,22 +610,14 @@ impl GraphAssembler {
         Ok(())
     }
 
-    fn declare_shared(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+    fn declare_shared(&mut self, name: &str, index: ProgramWord) -> Result<(), AssemblerError> {
         if !matches!(self.block, BlockKind::None) || self.shared_globals_locked {
             return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
         }
-        if tokens.len() != 3 {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        }
-        let name = to_name(tokens.get(1).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
+        let name = to_name(name)?;
         if self.shared_globals.iter().any(|entry| entry.name == name) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::DuplicateGlobal));
         }
-        let index = parse_word(tokens.get(2).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
         let next_size = index
             .checked_add(1)
             .ok_or(AssemblerError::Kind(AssemblerErrorKind::GlobalIndexOutOfRange))?;
@@ -441,33 +628,22 @@ impl GraphAssembler {
         Ok(())
     }
 
-    fn declare_stack_slot(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+    fn declare_stack_slot(&mut self, name: &str, offset: ProgramWord) -> Result<(), AssemblerError> {
         if !matches!(self.block, BlockKind::Function) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
         }
-        if tokens.len() != 3 {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        }
-        let name = to_name(tokens.get(1).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
+        let name = to_name(name)?;
         if self.stack_slots.iter().any(|entry| entry.name == name) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::DuplicateStackSlot));
         }
-        let offset = parse_word(tokens.get(2).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
         self.stack_slots.push(Label { name, offset });
         Ok(())
     }
 
-    fn start_data(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+    fn start_data(&mut self) -> Result<(), AssemblerError> {
         if !matches!(self.block, BlockKind::Machine) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
         }
-        if tokens.len() != 2 {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        }
         self.labels.clear();
         self.data.clear();
         self.cursor = 0;
@@ -475,13 +651,10 @@ impl GraphAssembler {
         Ok(())
     }
 
-    fn start_shared_data(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+    fn start_shared_data(&mut self) -> Result<(), AssemblerError> {
         if !matches!(self.block, BlockKind::None) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
         }
-        if tokens.len() != 2 {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        }
         self.labels.clear();
         self.data.clear();
         self.cursor = 0;
@@ -496,36 +669,37 @@ impl GraphAssembler {
                     .current_function
                     .take()
                     .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingFunction))?;
-                self.resolve_fixups(&mut function)?;
+                let resolved = self.resolve_fixups(&mut function)?;
                 let function_id = self.graph.add_function(function.words);
                 let index = self
                     .current_function_index
                     .take()
                     .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingFunction))?;
-                self.current_functions.push(FunctionRef {
+                self.current_items.push(TypeItem::Function(FunctionRef {
                     index,
                     function_id,
-                });
+                }));
+                self.current_sources.push((index, function.source));
                 self.block = BlockKind::Machine;
-                Ok(())
+                resolved
             }
             BlockKind::SharedFunction => {
                 let mut function = self
                     .current_function
                     .take()
                     .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingFunction))?;
-                self.resolve_fixups(&mut function)?;
+                let resolved = self.resolve_fixups(&mut function)?;
                 let index = self
                     .current_shared_function_index
                     .take()
                     .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingFunction))?;
                 self.graph
-                    .add_shared_function(index, function.words)
+                    .add_shared_function_with_source(index, function.words, function.source)
                     .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::Builder(
                         light_machine::builder::MachineBuilderError::FunctionCoutExceeded,
                     )))?;
                 self.block = BlockKind::None;
-                Ok(())
+                resolved
             }
             BlockKind::Data => {
                 let static_id = self.graph.add_static(&self.data);
@@ -542,7 +716,7 @@ impl GraphAssembler {
                         },
                     );
                 }
-                self.current_machine_statics.push(static_id);
+                self.current_items.push(TypeItem::Static(static_id));
                 self.data.clear();
                 self.labels.clear();
                 self.block = BlockKind::Machine;
@@ -574,219 +748,146 @@ impl GraphAssembler {
                         return Err(AssemblerError::Kind(AssemblerErrorKind::FunctionNotDeclared));
                     }
                 }
-                let mut functions = self.current_functions.clone();
-                functions.sort_by_key(|func| func.index);
-                let type_id = self.graph.add_machine_type(
-                    functions,
-                    self.current_machine_statics.clone(),
+                let mut params = Vec::new();
+                for (mut param, function) in std::mem::take(&mut self.current_params) {
+                    if let Some(function) = function {
+                        let entry = self
+                            .funcs
+                            .iter()
+                            .find(|entry| entry.name == function)
+                            .ok_or(AssemblerError::Kind(AssemblerErrorKind::UnknownLabel))?;
+                        param.info.target = ParamTarget::Function(entry.index);
+                    }
+                    params.push(param);
+                }
+                let type_id = self.graph.add_machine_type_with_params(
+                    std::mem::take(&mut self.current_items),
                     self.globals_size,
                     self.function_count,
+                    std::mem::take(&mut self.current_sources),
+                    params,
                 );
-                self.graph.add_machine_instance(type_id);
+                let locals = self
+                    .globals
+                    .iter()
+                    .map(|label| (label.offset, label.name.clone()))
+                    .collect();
+                self.graph
+                    .name_machine_type(type_id, self.machine_name.clone(), locals);
+                self.graph
+                    .add_named_machine_instance(type_id, self.machine_name.clone(), Vec::new());
                 self.block = BlockKind::None;
-                Ok(())
+                self.machines.push(MachineEntry {
+                    name: self.machine_name.clone(),
+                    type_id,
+                    locals: self.globals.clone(),
+                });
+                self.next_instance()
             }
             BlockKind::None => Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective)),
         }
     }
 
-    fn handle_data_line(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
-        let first = tokens.first().copied().ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?;
-        if tokens.len() == 1 && first.ends_with(':') {
-            return self.add_label(first);
-        }
-        let value = if tokens.len() == 2 && first == ".word" {
-            parse_word(tokens.get(1).ok_or(AssemblerError::Kind(
-                AssemblerErrorKind::InvalidDirective,
-            ))?)?
-        } else if tokens.len() == 1 {
-            parse_word(first)?
-        } else {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        };
-        self.data.push(value);
-        self.cursor = self
-            .cursor
-            .checked_add(1)
-            .ok_or(AssemblerError::Kind(AssemblerErrorKind::CursorOverflow))?;
-        Ok(())
-    }
-
-    fn handle_function_instruction(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
-        let mnemonic = tokens.first().copied().ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidInstruction,
-        ))?;
-        match mnemonic {
-            "LOAD_STATIC" | "load_static" => self.emit_stack_target(tokens, Ops::LoadStatic),
-            "JUMP" | "jump" => self.emit_stack_target(tokens, Ops::Jump),
-            "CALL" | "call" => self.emit_stack_target(tokens, Ops::Call),
-            "CALL_SHARED" | "call_shared" => self.emit_shared_stack_target(tokens, Ops::CallShared),
-            "BRLT" | "brlt" => self.emit_stack_target(tokens, Ops::BranchLessThan),
-            "BRLTE" | "brlte" => self.emit_stack_target(tokens, Ops::BranchLessThanEq),
-            "BRGT" | "brgt" => self.emit_stack_target(tokens, Ops::BranchGreaterThan),
-            "BRGTE" | "brgte" => self.emit_stack_target(tokens, Ops::BranchGreaterThanEq),
-            "BREQ" | "breq" => self.emit_stack_target(tokens, Ops::BranchEqual),
-            _ => self.emit_simple_op(tokens),
-        }
-    }
-
-    fn emit_simple_op(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
-        let mnemonic = tokens.first().copied().ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidInstruction,
-        ))?;
-        let operand_token = tokens.get(1).copied();
-        let expects_operand = matches!(
-            mnemonic,
-            "PUSH"
-                | "push"
-                | "LLOAD"
-                | "lload"
-                | "LSTORE"
-                | "lstore"
-                | "GLOAD"
-                | "gload"
-                | "GSTORE"
-                | "gstore"
-                | "SLOAD"
-                | "sload"
-                | "SSTORE"
-                | "sstore"
-                | "RET"
-                | "ret"
-        );
-
-        let operand = if expects_operand {
-            let token = operand_token.ok_or(AssemblerError::Kind(
-                AssemblerErrorKind::InvalidInstruction,
-            ))?;
-            if matches!(mnemonic, "SLOAD" | "sload" | "SSTORE" | "sstore") {
-                OperandRef::Literal(
-                    self.resolve_stack_operand(token)?
-                        .ok_or(AssemblerError::Kind(AssemblerErrorKind::InvalidInstruction))?,
-                )
-            } else if matches!(mnemonic, "LLOAD" | "lload" | "LSTORE" | "lstore") {
-                OperandRef::Literal(
-                    self.resolve_local_operand(token)?
-                        .ok_or(AssemblerError::Kind(AssemblerErrorKind::InvalidInstruction))?,
-                )
-            } else if matches!(mnemonic, "GLOAD" | "gload" | "GSTORE" | "gstore") {
-                OperandRef::Literal(
-                    self.resolve_shared_global_operand(token)?
-                        .ok_or(AssemblerError::Kind(AssemblerErrorKind::InvalidInstruction))?,
-                )
-            } else {
-                self.resolve_operand(token)?
-            }
-        } else {
-            OperandRef::Literal(0)
+    fn handle_data_line(&mut self, word: DataWord<'_>) -> Result<(), AssemblerError> {
+        let words = match word {
+            DataWord::Color(color) => color.to_vec(),
+            DataWord::Expr(text) => vec![self.evaluate(text, false)?.to_word()?],
         };
-
-        let opcode = match mnemonic {
-            "PUSH" | "push" => Ops::Push,
-            "POP" | "pop" => Ops::Pop,
-            "LLOAD" | "lload" => Ops::LocalLoad,
-            "LSTORE" | "lstore" => Ops::LocalStore,
-            "GLOAD" | "gload" => Ops::GlobalLoad,
-            "GSTORE" | "gstore" => Ops::GlobalStore,
-            "SLOAD" | "sload" => Ops::StackLoad,
-            "SSTORE" | "sstore" => Ops::StackStore,
-            "DUP" | "dup" => Ops::Dup,
-            "SWAP" | "swap" => Ops::Swap,
-            "RET" | "ret" => Ops::Return,
-            "EXIT" | "exit" => Ops::Exit,
-            "AND" | "and" => Ops::And,
-            "OR" | "or" => Ops::Or,
-            "XOR" | "xor" => Ops::Xor,
-            "NOT" | "not" => Ops::Not,
-            "BAND" | "band" => Ops::BitwiseAnd,
-            "BOR" | "bor" => Ops::BitwiseOr,
-            "BXOR" | "bxor" => Ops::BitwiseXor,
-            "BNOT" | "bnot" => Ops::BitwiseNot,
-            "ADD" | "add" => Ops::Add,
-            "SUB" | "sub" => Ops::Subtract,
-            "MUL" | "mul" => Ops::Multiply,
-            "DIV" | "div" => Ops::Divide,
-            "MOD" | "mod" => Ops::Mod,
-            _ => return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidInstruction)),
-        };
-
-        if expects_operand {
-            self.cursor = self
-                .cursor
-                .checked_add(2)
-                .ok_or(AssemblerError::Kind(AssemblerErrorKind::CursorOverflow))?;
-            self.push_word(WordRef::Literal(opcode.into()))?;
-            self.push_operand(operand)?;
-        } else {
+        for word in words {
+            self.data.push(word);
             self.cursor = self
                 .cursor
                 .checked_add(1)
                 .ok_or(AssemblerError::Kind(AssemblerErrorKind::CursorOverflow))?;
-            self.push_word(WordRef::Literal(opcode.into()))?;
         }
         Ok(())
     }
 
-    fn emit_stack_target(&mut self, tokens: &[&str], opcode: Ops) -> Result<(), AssemblerError> {
-        match tokens.len() {
-            1 => {
-                self.cursor = self
-                    .cursor
-                    .checked_add(1)
-                    .ok_or(AssemblerError::Kind(AssemblerErrorKind::CursorOverflow))?;
-                self.push_word(WordRef::Literal(opcode.into()))?;
-                Ok(())
+    fn handle_function_instruction(&mut self, instruction: Instruction<'_>) -> Result<(), AssemblerError> {
+        let Instruction { mnemonic, operand } = instruction;
+        let opcode = mnemonic.opcode();
+        let text = match operand {
+            Operand::None => return self.emit_words(&[opcode.into()]),
+            Operand::Call { name, argc } => return self.emit_call(mnemonic, name, argc),
+            Operand::Color(color) => {
+                let [red, green, blue] = color;
+                let push = Ops::Push.into();
+                return self.emit_words(&[push, red, push, green, push, blue]);
+            }
+            Operand::Expr(text) => text,
+        };
+        let invalid = || AssemblerError::Kind(AssemblerErrorKind::InvalidInstruction);
+        let operand = match mnemonic.operand_kind() {
+            OperandKind::StackSlot => OperandRef::Literal(self.resolve_stack_operand(text)?.ok_or_else(invalid)?),
+            OperandKind::Local => OperandRef::Literal(self.resolve_local_operand(text)?.ok_or_else(invalid)?),
+            OperandKind::SharedGlobal => {
+                OperandRef::Literal(self.resolve_shared_global_operand(text)?.ok_or_else(invalid)?)
             }
-            2 => {
-                let operand_token = tokens.get(1).copied().ok_or(AssemblerError::Kind(
-                    AssemblerErrorKind::InvalidInstruction,
-                ))?;
-                let operand = self.resolve_operand(operand_token)?;
+            OperandKind::SharedTarget => {
+                OperandRef::Literal(self.resolve_shared_function_operand(text)?.ok_or_else(invalid)?)
+            }
+            _ => self.resolve_operand(text)?,
+        };
+        match mnemonic.operand_kind() {
+            // A stack target is pushed ahead of the op that pops it.
+            OperandKind::Target | OperandKind::SharedTarget => {
                 self.cursor = self
                     .cursor
                     .checked_add(3)
                     .ok_or(AssemblerError::Kind(AssemblerErrorKind::CursorOverflow))?;
                 self.push_word(WordRef::Literal(Ops::Push.into()))?;
                 self.push_operand(operand)?;
-                self.push_word(WordRef::Literal(opcode.into()))?;
-                Ok(())
-            }
-            _ => Err(AssemblerError::Kind(AssemblerErrorKind::InvalidInstruction)),
-        }
-    }
-
-    fn emit_shared_stack_target(&mut self, tokens: &[&str], opcode: Ops) -> Result<(), AssemblerError> {
-        match tokens.len() {
-            1 => {
-                self.cursor = self
-                    .cursor
-                    .checked_add(1)
-                    .ok_or(AssemblerError::Kind(AssemblerErrorKind::CursorOverflow))?;
-                self.push_word(WordRef::Literal(opcode.into()))?;
-                Ok(())
+                self.push_word(WordRef::Literal(opcode.into()))
             }
-            2 => {
-                let operand_token = tokens.get(1).copied().ok_or(AssemblerError::Kind(
-                    AssemblerErrorKind::InvalidInstruction,
-                ))?;
-                let operand = self
-                    .resolve_shared_function_operand(operand_token)?
-                    .ok_or(AssemblerError::Kind(AssemblerErrorKind::InvalidInstruction))?;
+            _ => {
+                if mnemonic == Mnemonic::Return
+                    && let OperandRef::Literal(count) = operand
+                {
+                    self.signature.check_returns(count)?;
+                }
                 self.cursor = self
                     .cursor
-                    .checked_add(3)
+                    .checked_add(2)
                     .ok_or(AssemblerError::Kind(AssemblerErrorKind::CursorOverflow))?;
-                self.push_word(WordRef::Literal(Ops::Push.into()))?;
-                self.push_word(WordRef::Literal(operand))?;
                 self.push_word(WordRef::Literal(opcode.into()))?;
-                Ok(())
+                self.push_operand(operand)
             }
-            _ => Err(AssemblerError::Kind(AssemblerErrorKind::InvalidInstruction)),
         }
     }
 
+    // `CALL <name> <argc>`: pushes the argument count and the function index
+    // the VM expects on top of the arguments.
+    fn emit_call(&mut self, mnemonic: Mnemonic, name: &str, argc: &str) -> Result<(), AssemblerError> {
+        let functions = if mnemonic == Mnemonic::CallShared {
+            &self.shared_funcs
+        } else {
+            &self.funcs
+        };
+        let entry = functions
+            .iter()
+            .find(|entry| entry.name == name)
+            .ok_or(AssemblerError::Kind(AssemblerErrorKind::UnknownLabel))?;
+        let (index, signature) = (entry.index, entry.signature);
+        let argc = self
+            .immediate(argc)?
+            .ok_or(AssemblerError::Kind(AssemblerErrorKind::InvalidInstruction))?;
+        signature.check_args(argc)?;
+        self.emit_words(&[Ops::Push.into(), argc, Ops::Push.into(), index, mnemonic.opcode().into()])
+    }
+
+    fn emit_words(&mut self, words: &[ProgramWord]) -> Result<(), AssemblerError> {
+        let width = ProgramWord::try_from(words.len())
+            .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::CursorOverflow))?;
+        self.cursor = self
+            .cursor
+            .checked_add(width)
+            .ok_or(AssemblerError::Kind(AssemblerErrorKind::CursorOverflow))?;
+        for word in words {
+            self.push_word(WordRef::Literal(*word))?;
+        }
+        Ok(())
+    }
+
     fn push_word(&mut self, word: WordRef) -> Result<(), AssemblerError> {
         let function = self
             .current_function
@@ -806,35 +907,37 @@ impl GraphAssembler {
                     self.push_word(WordRef::Static(StaticId::new(label.id), label.offset))
                 }
             }
-            OperandRef::Label(name) => {
+            OperandRef::LabelOffset(offset) => self.push_word(WordRef::LabelOffset(offset)),
+            OperandRef::Label(name, offset) => {
                 let function = self
                     .current_function
                     .as_ref()
                     .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingFunction))?;
                 let at = function.words.len();
-                self.fixups.push(Fixup { name, at });
+                self.fixups.push(Fixup { name, at, offset });
                 self.push_word(WordRef::LabelOffset(0))
             }
         }
     }
 
-    fn resolve_fixups(&mut self, function: &mut FunctionAssembly) -> Result<(), AssemblerError> {
+    // Labels that were never defined are left unpatched and returned as the
+    // inner error, so the block can still be closed and assembly carry on.
+    fn resolve_fixups(&mut self, function: &mut FunctionAssembly) -> Result<Result<(), AssemblerError>, AssemblerError> {
+        let mut resolved = Ok(());
         while let Some(fixup) = self.fixups.pop() {
-            let label = self
-                .labels
-                .iter()
-                .find(|label| label.name == fixup.name)
-                .ok_or(AssemblerError::Kind(AssemblerErrorKind::UnknownLabel))?;
+            let Some(label) = self.labels.iter().find(|label| label.name == fixup.name) else {
+                resolved = Err(AssemblerError::Kind(AssemblerErrorKind::UnknownLabel));
+                continue;
+            };
             let Some(slot) = function.words.get_mut(fixup.at) else {
                 return Err(AssemblerError::Kind(AssemblerErrorKind::UnknownLabel));
             };
-            *slot = WordRef::LabelOffset(label.offset);
+            *slot = WordRef::LabelOffset(expression::relocate(label.offset, fixup.offset)?);
         }
-        Ok(())
+        Ok(resolved)
     }
 
-    fn add_label(&mut self, token: &str) -> Result<(), AssemblerError> {
-        let name = token.trim_end_matches(':');
+    fn add_label(&mut self, name: &str) -> Result<(), AssemblerError> {
         let name = to_name(name)?;
         if self.labels.iter().any(|label| label.name == name) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::DuplicateLabel));
@@ -878,7 +981,13 @@ impl GraphAssembler {
         Ok(index)
     }
 
-    fn mark_function_defined(&mut self, name: &str, index: ProgramWord) -> Result<(), AssemblerError> {
+    // Returns the function's signature, merged with any earlier declaration.
+    fn mark_function_defined(
+        &mut self,
+        name: &str,
+        index: ProgramWord,
+        signature: Signature,
+    ) -> Result<Signature, AssemblerError> {
         if let Some(entry) = self.funcs.iter_mut().find(|entry| entry.name == name) {
             if entry.defined {
                 return Err(AssemblerError::Kind(AssemblerErrorKind::FunctionAlreadyDefined));
@@ -887,21 +996,24 @@ impl GraphAssembler {
             if entry.index != index {
                 return Err(AssemblerError::Kind(AssemblerErrorKind::FunctionIndexDuplicate));
             }
-            return Ok(());
+            entry.signature = entry.signature.merge(signature)?;
+            return Ok(entry.signature);
         }
         self.funcs.push(FuncEntry {
             name: name.to_string(),
             index,
             defined: true,
+            signature,
         });
-        Ok(())
+        Ok(signature)
     }
 
     fn mark_shared_function_defined(
         &mut self,
         name: &str,
         index: ProgramWord,
-    ) -> Result<(), AssemblerError> {
+        signature: Signature,
+    ) -> Result<Signature, AssemblerError> {
         if let Some(entry) = self.shared_funcs.iter_mut().find(|entry| entry.name == name) {
             if entry.defined {
                 return Err(AssemblerError::Kind(AssemblerErrorKind::FunctionAlreadyDefined));
@@ -910,23 +1022,38 @@ impl GraphAssembler {
             if entry.index != index {
                 return Err(AssemblerError::Kind(AssemblerErrorKind::FunctionIndexDuplicate));
             }
-            return Ok(());
+            entry.signature = entry.signature.merge(signature)?;
+            return Ok(entry.signature);
         }
         self.shared_funcs.push(FuncEntry {
             name: name.to_string(),
             index,
             defined: true,
+            signature,
         });
-        Ok(())
+        Ok(signature)
     }
 
     fn resolve_operand(&mut self, token: &str) -> Result<OperandRef, AssemblerError> {
-        if let Ok(value) = parse_word(token) {
+        if expression::is_expression(token) {
+            let value = self.evaluate(token, true)?;
+            return match value.base {
+                None => Ok(OperandRef::Literal(expression::to_literal_word(value.offset)?)),
+                Some(Symbol::Code) => Ok(OperandRef::LabelOffset(expression::to_word(value.offset)?)),
+                Some(Symbol::Static { id, shared }) => Ok(OperandRef::Static(StaticLabelRef {
+                    id,
+                    offset: expression::to_word(value.offset)?,
+                    shared,
+                })),
+                Some(Symbol::Forward(name)) => Ok(OperandRef::Label(name, value.offset)),
+            };
+        }
+        if let Some(value) = self.immediate(token)? {
             return Ok(OperandRef::Literal(value));
         }
         let name = to_name(token)?;
         if let Some(label) = self.labels.iter().find(|label| label.name == name) {
-            return Ok(OperandRef::Literal(label.offset));
+            return Ok(OperandRef::LabelOffset(label.offset));
         }
         if let Some(label) = self.static_labels.get(&name) {
             return Ok(OperandRef::Static(StaticLabelRef {
@@ -945,13 +1072,78 @@ impl GraphAssembler {
             return Ok(OperandRef::Literal(entry.offset));
         }
         if matches!(self.block, BlockKind::Function | BlockKind::SharedFunction) {
-            return Ok(OperandRef::Label(name));
+            return Ok(OperandRef::Label(name, 0));
         }
         Err(AssemblerError::Kind(AssemblerErrorKind::UnknownLabel))
     }
 
+    fn immediate(&self, token: &str) -> Result<Option<ProgramWord>, AssemblerError> {
+        // Names cannot start with a digit or a quote, so a bad number is not a
+        // forward label.
+        if token.starts_with(|c: char| c.is_ascii_digit() || c == '\'') && !expression::is_expression(token) {
+            return parse_word(token).map(Some);
+        }
+        if let Some(entry) = self.consts.iter().find(|entry| entry.name == token) {
+            return Ok(Some(entry.offset));
+        }
+        if expression::is_expression(token) {
+            return self.evaluate(token, false)?.to_word().map(Some);
+        }
+        Ok(None)
+    }
+
+    fn evaluate(&self, text: &str, forward: bool) -> Result<Value<Symbol>, AssemblerError> {
+        expression::evaluate(text, |name| self.lookup_symbol(name, forward))
+    }
+
+    fn lookup_symbol(&self, name: &str, forward: bool) -> Result<Value<Symbol>, AssemblerError> {
+        if let Some(entry) = self.consts.iter().find(|entry| entry.name == name) {
+            return Ok(Value::literal(i64::from(entry.offset)));
+        }
+        let in_function = matches!(self.block, BlockKind::Function | BlockKind::SharedFunction);
+        if in_function && let Some(label) = self.labels.iter().find(|label| label.name == name) {
+            return Ok(Value::symbol(Symbol::Code, i64::from(label.offset)));
+        }
+        if let Some(label) = self.static_labels.get(name) {
+            let symbol = Symbol::Static {
+                id: label.id,
+                shared: label.shared,
+            };
+            return Ok(Value::symbol(symbol, i64::from(label.offset)));
+        }
+        if let Some(entry) = self.funcs.iter().find(|entry| entry.name == name) {
+            return Ok(Value::literal(i64::from(entry.index)));
+        }
+        if let Some(entry) = self.globals.iter().find(|entry| entry.name == name) {
+            return Ok(Value::literal(i64::from(entry.offset)));
+        }
+        if let Some(entry) = self.shared_globals.iter().find(|entry| entry.name == name) {
+            return Ok(Value::literal(i64::from(entry.offset)));
+        }
+        if forward && in_function {
+            return Ok(Value::symbol(Symbol::Forward(to_name(name)?), 0));
+        }
+        Err(AssemblerError::Kind(AssemblerErrorKind::UnknownLabel))
+    }
+
+    // What the assembler has recorded for a name it was just given.
+    fn symbol_value(&self, kind: SymbolKind, name: &str) -> Option<ProgramWord> {
+        let offset = |labels: &[Label]| labels.iter().find(|label| label.name == name).map(|label| label.offset);
+        let index = |funcs: &[FuncEntry]| funcs.iter().find(|entry| entry.name == name).map(|entry| entry.index);
+        match kind {
+            SymbolKind::Function => index(&self.funcs),
+            SymbolKind::SharedFunction => index(&self.shared_funcs),
+            SymbolKind::Local => offset(&self.globals),
+            SymbolKind::Shared => offset(&self.shared_globals),
+            SymbolKind::Frame => offset(&self.stack_slots),
+            SymbolKind::Label => offset(&self.labels),
+            SymbolKind::Const | SymbolKind::Instance => offset(&self.consts),
+            SymbolKind::Machine | SymbolKind::Data => None,
+        }
+    }
+
     fn resolve_shared_function_operand(&mut self, token: &str) -> Result<Option<ProgramWord>, AssemblerError> {
-        if let Ok(value) = parse_word(token) {
+        if let Some(value) = self.immediate(token)? {
             return Ok(Some(value));
         }
         let name = to_name(token)?;
@@ -962,7 +1154,7 @@ impl GraphAssembler {
     }
 
     fn resolve_stack_operand(&mut self, token: &str) -> Result<Option<ProgramWord>, AssemblerError> {
-        if let Ok(value) = parse_word(token) {
+        if let Some(value) = self.immediate(token)? {
             return Ok(Some(value));
         }
         let name = to_name(token)?;
@@ -976,7 +1168,7 @@ impl GraphAssembler {
     }
 
     fn resolve_local_operand(&mut self, token: &str) -> Result<Option<ProgramWord>, AssemblerError> {
-        if let Ok(value) = parse_word(token) {
+        if let Some(value) = self.immediate(token)? {
             if matches!(self.block, BlockKind::SharedFunction) {
                 return Ok(Some(value));
             }
@@ -993,7 +1185,7 @@ impl GraphAssembler {
     }
 
     fn resolve_shared_global_operand(&mut self, token: &str) -> Result<Option<ProgramWord>, AssemblerError> {
-        if let Ok(value) = parse_word(token) {
+        if let Some(value) = self.immediate(token)? {
             if value >= self.shared_globals_size {
                 return Err(AssemblerError::Kind(AssemblerErrorKind::GlobalIndexOutOfRange));
             }
@@ -1014,30 +1206,12 @@ trait AssemblerErrorExt {
 impl AssemblerErrorExt for AssemblerError {
     fn with_line(self, line: u32) -> AssemblerError {
         match self {
-            AssemblerError::WithLine { .. } => self,
+            AssemblerError::WithLine { .. } | AssemblerError::InFile { .. } => self,
             AssemblerError::Kind(kind) => AssemblerError::WithLine { line, kind },
         }
     }
 }
 
-fn parse_word(token: &str) -> Result<ProgramWord, AssemblerError> {
-    if let Some(hex) = token.strip_prefix("0x") {
-        u16::from_str_radix(hex, 16)
-            .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::InvalidNumber))
-    } else {
-        token
-            .parse::<u16>()
-            .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::InvalidNumber))
-    }
-}
-
-fn strip_comment(line: &str) -> &str {
-    match line.split(';').next() {
-        Some(part) => part,
-        None => line,
-    }
-}
-
 fn to_name(name: &str) -> Result<String, AssemblerError> {
     if name.len() > NAME_CAP {
         return Err(AssemblerError::Kind(AssemblerErrorKind::NameTooLong));
@@ -1049,6 +1223,7 @@ fn to_name(name: &str) -> Result<String, AssemblerError> {
 mod test {
     use super::*;
     use light_machine::builder::ProgramBuilder;
+    use light_machine::metadata;
 
     fn compile_graph(source: &str) -> Result<ProgramGraph, AssemblerError> {
         let shared_function_count = source
@@ -1062,6 +1237,24 @@ mod test {
         assembler.finish()
     }
 
+    fn run_graph(source: &str) -> Vec<u32> {
+        let graph = compile_graph(source).unwrap();
+        let mut buffer = [0u16; 256];
+        let builder = ProgramBuilder::<2, 2>::new(
+            &mut buffer,
+            graph.instance_count(),
+            graph.type_count(),
+            graph.shared_function_count(),
+        )
+        .unwrap();
+        let descriptor = graph.emit_into(builder).unwrap();
+        let mut memory = vec![0u32; 64];
+        let mut program =
+            light_machine::Program::new(&buffer[..descriptor.length], memory.as_mut_slice()).unwrap();
+        program.call(0, 0).unwrap();
+        program.stack().as_slice().to_vec()
+    }
+
     #[test]
     fn graph_assembler_dedupes_identical_types() {
         let source = r#"
@@ -1158,4 +1351,587 @@ mod test {
         .unwrap();
         let _descriptor = graph.emit_into(builder).unwrap();
     }
+
+    #[test]
+    fn graph_assembler_resolves_backward_labels() {
+        let source = r#"
+            .machine alpha locals 0 functions 1
+            .func init index 0
+            PUSH 3
+            top:
+            PUSH 1
+            SUB
+            DUP
+            PUSH 0
+            BRGT top
+            EXIT
+            .end
+            .end
+        "#;
+        assert_eq!(run_graph(source), vec![0]);
+    }
+
+    #[test]
+    fn graph_assembler_call_sugar_checks_signatures() {
+        let source = r#"
+            .shared_func double args 1 returns 1
+            SLOAD 0
+            PUSH 2
+            MUL
+            RET 1
+            .end
+            .machine alpha locals 0 functions 2
+            .func_decl add index 1 args 2 returns 1
+            .func init index 0
+            PUSH 3
+            PUSH 4
+            CALL add 2
+            CALL_SHARED double 1
+            EXIT
+            .end
+            .func add
+            .frame a 0
+            .frame b 1
+            SLOAD a
+            SLOAD b
+            ADD
+            RET 1
+            .end
+            .end
+        "#;
+        assert_eq!(run_graph(source), vec![14]);
+
+        let Err(err) = compile_graph(&source.replace("CALL add 2", "CALL add 3")) else {
+            panic!("expected an error");
+        };
+        assert!(matches!(err.error_kind(), AssemblerErrorKind::ArgumentCountMismatch));
+        let Err(err) = compile_graph(&source.replace("ADD\n            RET 1", "ADD\n            RET 0")) else {
+            panic!("expected an error");
+        };
+        assert!(matches!(err.error_kind(), AssemblerErrorKind::ReturnCountMismatch));
+    }
+
+    #[test]
+    fn graph_assembler_accepts_color_binary_negative_and_char_literals() {
+        let source = r#"
+            .const NEG -2
+            .machine alpha locals 0 functions 1
+            .data palette
+            palette:
+            #102030
+            .word 'z'
+            .end
+            .func init index 0
+            PUSH #ff8000
+            PUSH 0b1010
+            PUSH -1
+            PUSH NEG
+            PUSH ';' ; a comment
+            LOAD_STATIC palette + 1
+            LOAD_STATIC palette + 3
+            EXIT
+            .end
+            .end
+        "#;
+        assert_eq!(
+            run_graph(source),
+            vec![0xFF, 0x80, 0x00, 0b1010, 0xFFFF, 0xFFFE, 59, 0x20, 122]
+        );
+    }
+
+    #[test]
+    fn graph_assembler_evaluates_const_expressions() {
+        let source = r#"
+            .const BASE 10
+            .const STEP (BASE + 2) * 3
+            .machine alpha locals 0 functions 1
+            .func init index 0
+            PUSH STEP
+            PUSH 0xF0 | 0x0F & (1 << 4)
+            PUSH BASE / 3 - 1
+            EXIT
+            .end
+            .end
+        "#;
+        assert_eq!(run_graph(source), vec![36, 0xF0, 2]);
+    }
+
+    #[test]
+    fn graph_assembler_applies_label_offsets() {
+        let source = r#"
+            .const LED_COUNT 8
+            .shared_data table
+            first:
+            .word LED_COUNT * 2
+            LED_COUNT - 1
+            .end
+
+            .machine alpha locals 0 functions 1
+            .func init index 0
+            JUMP skip + 2
+            PUSH 99
+            skip:
+            PUSH 1
+            LOAD_STATIC first + 1
+            LOAD_STATIC first
+            EXIT
+            .end
+            .end
+        "#;
+        assert_eq!(run_graph(source), vec![7, 16]);
+    }
+
+    #[test]
+    fn graph_assembler_rejects_const_label_address() {
+        let source = r#"
+            .machine alpha locals 0 functions 1
+            .func init index 0
+            here:
+            .const WHERE here
+        "#;
+        let Err(err) = compile_graph(source) else {
+            panic!("expected an error");
+        };
+        assert!(matches!(err.error_kind(), AssemblerErrorKind::InvalidExpression));
+    }
+
+    #[test]
+    fn graph_assembler_expands_includes() {
+        let mut library = crate::source_resolver::MapResolver::new();
+        library.insert("leds.fpa", ".const LEDS 30\n.include \"helpers.fpa\"\n");
+        library.insert("helpers.fpa", ".shared_func last index 0\nPUSH LEDS - 1\nRET 1\n.end\n");
+        let source = r#"
+            .include "leds.fpa"
+            .machine alpha locals 0 functions 1
+            .func init index 0
+            PUSH 0
+            CALL_SHARED last
+            EXIT
+            .end
+            .end
+        "#;
+        let mut assembler = GraphAssembler::new(1);
+        assembler.add_source(source, &library).unwrap();
+        let graph = assembler.finish().unwrap();
+        assert_eq!(graph.shared_function_count(), 1);
+
+        let mut assembler = GraphAssembler::new(1);
+        let err = assembler
+            .add_source(".include \"leds.fpa\"\n.include \"helpers.fpa\"", &library)
+            .unwrap_err();
+        assert_eq!(err.file_name(), Some("helpers.fpa"));
+        assert_eq!(err.line_number(), Some(1));
+        assert!(matches!(err.error_kind(), AssemblerErrorKind::FunctionAlreadyDefined));
+    }
+
+    #[test]
+    fn graph_assembler_expands_macros_with_local_labels() {
+        let source = r#"
+            .macro count_down from
+            PUSH from
+            loop:
+            PUSH 1
+            SUB
+            DUP
+            PUSH 0
+            BRGT loop
+            .endm
+            .machine alpha locals 0 functions 1
+            .func init index 0
+            count_down 3
+            count_down 2 * 2
+            EXIT
+            .end
+            .end
+        "#;
+        assert_eq!(run_graph(source), vec![0, 0]);
+    }
+
+    #[test]
+    fn graph_assembler_lowers_control_blocks() {
+        let source = r#"
+            .machine alpha locals 1 functions 1
+            .local i 0
+            .func init index 0
+            .for i 0, 3
+            LLOAD i
+            .if eq 1
+            PUSH 10
+            .else
+            PUSH 20
+            .endif
+            .endfor
+            EXIT
+            .end
+            .end
+        "#;
+        assert_eq!(run_graph(source), vec![20, 10, 20]);
+    }
+
+    #[test]
+    fn graph_assembler_reports_every_recoverable_error() {
+        let source = "\
+.machine alpha locals 1 functions 1
+.func init index 0
+PUSH 0x10000
+LLOAD 3
+JUMP nowhere
+EXIT
+.end
+.end
+";
+        let mut assembler = GraphAssembler::new(0);
+        let mut found = Vec::new();
+        let finished = assembler.add_source_reporting(source, &include::NoIncludes, |diagnostic| {
+            found.push((diagnostic.line, diagnostic.start, diagnostic.end, diagnostic.message()));
+        });
+        assert!(finished);
+        assert_eq!(
+            found,
+            vec![
+                (3, 5, 12, "invalid number"),
+                (4, 6, 7, "global index out of range"),
+                (7, 0, 4, "unknown label"),
+            ]
+        );
+        assert!(assembler.finish().is_ok());
+    }
+
+    #[test]
+    fn graph_assembler_maps_faults_back_to_source() {
+        let mut library = crate::source_resolver::MapResolver::new();
+        library.insert("helpers.fpa", ".shared_func last index 0\nPUSH 29\nRET 1\n.end\n");
+        let source = "\
+.include \"helpers.fpa\"
+.machine alpha locals 0 functions 1
+.func init index 0
+PUSH 0
+CALL_SHARED last
+EXIT
+.end
+.end
+.machine beta locals 0 functions 1
+.func init index 0
+POP
+EXIT
+.end
+.end
+";
+        let mut assembler = GraphAssembler::new(1);
+        assembler.add_source(source, &library).unwrap();
+        let graph = assembler.finish().unwrap();
+        let mut buffer = [0u16; 256];
+        let builder = ProgramBuilder::<2, 2>::new(
+            &mut buffer,
+            graph.instance_count(),
+            graph.type_count(),
+            graph.shared_function_count(),
+        )
+        .unwrap();
+        let (descriptor, bytes) = graph.emit_with_source_map(builder).unwrap();
+        let map = light_machine::assembler::source_map::SourceMap::new(&bytes).unwrap();
+        let lines: Vec<_> = map
+            .ranges()
+            .map(|range| (range.file, range.line, range.machine, range.function))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                (Some("helpers.fpa"), 2, None, Some("last")),
+                (Some("helpers.fpa"), 3, None, Some("last")),
+                (None, 4, Some("alpha"), Some("init")),
+                (None, 5, Some("alpha"), Some("init")),
+                (None, 6, Some("alpha"), Some("init")),
+                (None, 11, Some("beta"), Some("init")),
+                (None, 12, Some("beta"), Some("init")),
+            ]
+        );
+
+        let mut memory = vec![0u32; 64];
+        let mut program =
+            light_machine::Program::new(&buffer[..descriptor.length], memory.as_mut_slice()).unwrap();
+        assert!(program.call(1, 0).is_err());
+        let fault = map.lookup(program.last_pc()).unwrap();
+        assert_eq!((fault.line, fault.machine), (11, Some("beta")));
+    }
+
+    #[test]
+    fn graph_assembler_instances_share_a_type_with_their_own_locals() {
+        let source = r#"
+            .machine comet locals 3 functions 3
+            .local red 0
+            .local green 1
+            .local blue 2
+            .func init index 0
+            EXIT
+            .end
+            .func start_frame index 1
+            POP
+            EXIT
+            .end
+            .func get_color index 2
+            LLOAD red
+            LLOAD green
+            LLOAD blue
+            EXIT
+            .end
+            .end
+            .instance green_comet of comet green = 0xFF
+            .instance purple_comet of comet red = 0x80, blue = 0x80
+        "#;
+        let graph = compile_graph(source).unwrap();
+        assert_eq!(graph.instance_count(), 3);
+        assert_eq!(graph.type_count(), 1);
+
+        let mut buffer = [0u16; 256];
+        let builder = ProgramBuilder::<3, 3>::new(
+            &mut buffer,
+            graph.instance_count(),
+            graph.type_count(),
+            graph.shared_function_count(),
+        )
+        .unwrap();
+        let descriptor = graph.emit_into(builder).unwrap();
+        let mut memory = vec![0u32; 64];
+        let mut program =
+            light_machine::Program::new(&buffer[..descriptor.length], memory.as_mut_slice()).unwrap();
+        let mut colors = Vec::new();
+        for index in 0..3 {
+            program.init_machine(index).unwrap();
+            for _ in 0..3 {
+                program.stack_mut().push(0).unwrap();
+            }
+            colors.push(program.get_led_color(index, 0).unwrap());
+            program.stack_mut().clear();
+        }
+        assert_eq!(colors, [(0, 0, 0), (0, 0xFF, 0), (0x80, 0, 0x80)]);
+    }
+
+    #[test]
+    fn graph_assembler_records_symbols_and_references() {
+        let source = "\
+.const LEVEL 3
+.shared_func blend index 0
+    EXIT
+.end
+.machine main locals 2 functions 2
+    .local red 0
+    .func_decl get index 1
+    .data palette
+    colors:
+        .word LEVEL
+    .end
+    .func init index 0
+        .frame tmp 0
+        PUSH colors
+        LSTORE red
+        SLOAD tmp
+        JUMP done
+        CALL get 0
+        CALL_SHARED blend
+    done:
+        EXIT
+    .end
+    .func get index 1
+        LLOAD red
+        EXIT
+    .end
+.end
+.instance other of main red = LEVEL
+.machine broken locals 1 functions 1
+    .func init index 0
+        LLOAD red
+        BOGUS
+    .end
+.end
+";
+        let mut assembler = GraphAssembler::new(1).with_symbols();
+        for line in source.lines() {
+            let _ = assembler.add_line(line);
+        }
+        let table = assembler.symbols().unwrap();
+        let find = |name: &str, kind: SymbolKind| {
+            table
+                .symbols()
+                .iter()
+                .find(|symbol| symbol.name == name && symbol.kind == kind)
+                .unwrap()
+        };
+        let at = |symbol: &crate::symbols::SymbolInfo| {
+            let mut lines: Vec<_> = symbol
+                .references
+                .iter()
+                .map(|location| (location.line, location.start, location.end))
+                .collect();
+            lines.sort();
+            ((symbol.definition.line, symbol.definition.start, symbol.definition.end), lines)
+        };
+
+        let level = find("LEVEL", SymbolKind::Const);
+        assert_eq!(level.value, Some(3));
+        assert_eq!(at(level), ((1, 7, 12), vec![(10, 14, 19), (28, 30, 35)]));
+
+        // The body is the definition; the declaration and the call are uses.
+        let get = find("get", SymbolKind::Function);
+        assert_eq!(get.value, Some(1));
+        assert_eq!(get.machine.as_deref(), Some("main"));
+        assert_eq!(at(get), ((23, 10, 13), vec![(7, 15, 18), (18, 13, 16)]));
+
+        let red = find("red", SymbolKind::Local);
+        assert_eq!(red.value, Some(0));
+        assert_eq!(at(red), ((6, 11, 14), vec![(15, 15, 18), (24, 14, 17), (28, 24, 27)]));
+
+        let done = find("done", SymbolKind::Label);
+        assert_eq!(done.function.as_deref(), Some("init"));
+        assert_eq!(at(done), ((20, 4, 8), vec![(17, 13, 17)]));
+
+        assert_eq!(at(find("colors", SymbolKind::Label)).1, vec![(14, 13, 19)]);
+        assert_eq!(at(find("tmp", SymbolKind::Frame)).1, vec![(16, 14, 17)]);
+        assert_eq!(at(find("blend", SymbolKind::SharedFunction)).1, vec![(19, 20, 25)]);
+        assert_eq!(at(find("main", SymbolKind::Machine)).1, vec![(28, 19, 23)]);
+        assert_eq!(find("palette", SymbolKind::Data).machine.as_deref(), Some("main"));
+        assert_eq!(find("other", SymbolKind::Instance).value, Some(1));
+
+        // `broken` has no `red`, and the bad line did not stop recording.
+        let broken = find("init", SymbolKind::Function);
+        assert_eq!(broken.machine.as_deref(), Some("main"));
+        assert!(table.symbols().iter().any(|symbol| symbol.machine.as_deref() == Some("broken")
+            && symbol.kind == SymbolKind::Function));
+
+        let hovered = table.at(None, 24, 15).unwrap();
+        assert_eq!((hovered.name.as_str(), hovered.kind), ("red", SymbolKind::Local));
+    }
+
+    #[test]
+    fn graph_assembler_symbols_point_macro_arguments_at_the_call() {
+        let source = "\
+.macro load_twice slot
+    LLOAD slot
+    LLOAD slot
+.endm
+.machine main locals 1 functions 1
+    .local level 0
+    .func init index 0
+        load_twice level
+    loop:
+        JUMP loop
+    .end
+.end
+";
+        let mut assembler = GraphAssembler::new(0).with_symbols();
+        for line in source.lines() {
+            assembler.add_line(line).unwrap();
+        }
+        let table = assembler.symbols().unwrap();
+        let level = table.symbols().iter().find(|symbol| symbol.name == "level").unwrap();
+        let uses: Vec<_> = level.references.iter().map(|at| (at.line, at.start, at.end)).collect();
+        assert_eq!(uses, [(8, 19, 24), (8, 19, 24)]);
+        assert!(table.symbols().iter().all(|symbol| !symbol.name.starts_with("__")));
+        assert!(GraphAssembler::new(0).symbols().is_none());
+    }
+
+    #[test]
+    fn graph_assembler_keeps_types_with_different_params_apart() {
+        let source = r#"
+            .machine alpha locals 1 functions 2
+            .local level 0
+            .param level range max 100 default 50 func set_level
+            .func init index 0
+            EXIT
+            .end
+            .func set_level index 1
+            LSTORE level
+            EXIT
+            .end
+            .end
+
+            .machine beta locals 1 functions 2
+            .local level 0
+            .func init index 0
+            EXIT
+            .end
+            .func set_level index 1
+            LSTORE level
+            EXIT
+            .end
+            .end
+        "#;
+        let graph = compile_graph(source).unwrap();
+        assert_eq!(graph.type_count(), 2);
+        let mut buffer = [0u16; 256];
+        let builder = ProgramBuilder::<2, 2>::new(
+            &mut buffer,
+            graph.instance_count(),
+            graph.type_count(),
+            graph.shared_function_count(),
+        )
+        .unwrap();
+        let length = graph.emit_into(builder).unwrap().length;
+        let params: Vec<_> = light_machine::metadata::params(&buffer[..length])
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(params.len(), 1);
+        assert!(params[0].name.eq_str("level"));
+        assert_eq!(params[0].info.type_id, 0);
+        assert_eq!(params[0].info.target, ParamTarget::Function(1));
+        assert_eq!((params[0].info.max, params[0].info.default[0]), (100, 50));
+    }
+
+    #[test]
+    fn graph_assembler_emits_symbols() {
+        let source = "\
+.shared_func dim index 0
+    RET 1
+.end
+.machine main locals 1 functions 2
+    .local level 0
+    .func init index 0
+        EXIT
+    .end
+    .func set_level index 1
+        LSTORE level
+        EXIT
+    .end
+.end
+";
+        let graph = compile_graph(source).unwrap();
+        let mut buffer = [0u16; 256];
+        let builder = ProgramBuilder::<2, 2>::new(
+            &mut buffer,
+            graph.instance_count(),
+            graph.type_count(),
+            graph.shared_function_count(),
+        )
+        .unwrap();
+        let length = graph.emit_into(builder).unwrap().length;
+        let image = &buffer[..length];
+        let find = |kind, owner, index| {
+            metadata::symbols(image)
+                .unwrap()
+                .find(kind, owner, index)
+        };
+        assert!(find(metadata::SymbolKind::Type, 0, 0).unwrap().eq_str("main"));
+        assert!(find(metadata::SymbolKind::Instance, 0, 0).unwrap().eq_str("main"));
+        assert!(find(metadata::SymbolKind::Function, 0, 1).unwrap().eq_str("set_level"));
+        assert!(find(metadata::SymbolKind::Local, 0, 0).unwrap().eq_str("level"));
+        assert!(find(metadata::SymbolKind::SharedFunction, 0, 0).unwrap().eq_str("dim"));
+        assert!(find(metadata::SymbolKind::Function, 0, 2).is_none());
+    }
+
+    #[test]
+    fn graph_assembler_rejects_params_outside_a_machine() {
+        let source = "\
+.machine main locals 1 functions 1
+    .func init index 0
+        .param level range local 0
+        EXIT
+    .end
+.end
+";
+        let Err(err) = compile_graph(source) else {
+            panic!("a .param inside a function should not assemble");
+        };
+        assert!(matches!(err.error_kind(), AssemblerErrorKind::UnexpectedDirective));
+    }
 }
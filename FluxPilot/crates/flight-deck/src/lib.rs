@@ -1,24 +1,43 @@
 use wasm_bindgen::prelude::*;
 
-use console_error_panic_hook;
 use pliot::protocol::{Controler, ErrorType, FunctionId, MessageType, Protocol};
 
 use light_machine::{
+    MachineError,
     ProgramDescriptor,
     ProgramWord,
     StackWord,
-    assembler::{AssemblerError, AssemblerErrorKind},
+    assembler::{
+        diagnostic::{self, Diagnostic},
+        format,
+        include::{self, SourceLocation, SourceResolver},
+        source_map::{SourceMap, SourceRange},
+    },
     builder::*,
+    metadata::{self, PackedName, Param, ParamKind, ParamTarget, SymbolKind},
 };
 use postcard::{to_vec_cobs, from_bytes_cobs};
 
 use heapless::Vec;
 use std::vec::Vec as StdVec;
 
-mod graph_assembler;
-mod program_graph;
+pub mod graph_assembler;
+pub mod node_graph;
+mod prescan;
+pub mod program_graph;
+pub mod source_resolver;
+pub mod stdlib;
+pub mod symbols;
+
+#[cfg(test)]
+mod differential_test;
 
 use graph_assembler::GraphAssembler;
+use prescan::SharedDefinitions;
+use node_graph::NodeGraph;
+use program_graph::lint::{self, Allowances, Lint, Warning};
+use source_resolver::MapResolver;
+use symbols::{SymbolInfo, SymbolLocation};
 
 const MAX_ARGS: usize = 10;
 const MAX_RESULT: usize = 3;
@@ -33,6 +52,7 @@ const I2C_DEFAULT_LABEL_PREFIX: &str = "i2c_default_";
 const I2C_INIT_SHARED_FUNCTION_NAME: &str = "init_program";
 const I2C_INIT_SHARED_FUNCTION_INDEX: u16 = 0;
 const I2C_SHARED_GLOBAL_ANCHOR: &str = "__i2c_map_last__";
+const I2C_PRELUDE_NAME: &str = "i2c_prelude";
 
 type ProtocolType = Protocol<MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE>;
 
@@ -95,6 +115,15 @@ extern "C" {
         block: &[u8],
     );
 
+    #[wasm_bindgen(method, js_name = onProgramData)]
+    pub fn on_program_data(
+        this: &ReceiveHandler,
+        request_id: u64,
+        total_size: u32,
+        block_number: u32,
+        block: &[ProgramWord],
+    );
+
     #[wasm_bindgen(method, js_name = onI2cDevices)]
     pub fn on_i2c_devices(
         this: &ReceiveHandler,
@@ -115,11 +144,17 @@ pub struct FlightDeck {
 pub struct ProgramDescriptorJs {
     length: usize,
     machine_function_counts: StdVec<u32>,
+    source_map: StdVec<u8>,
+    warnings: StdVec<DiagnosticJs>,
+    params: StdVec<ParamJs>,
 }
 
 impl ProgramDescriptorJs {
     fn from_descriptor<const MACHINE_COUNT: usize, const FUNCTION_COUNT: usize>(
         descriptor: ProgramDescriptor<MACHINE_COUNT, FUNCTION_COUNT>,
+        source_map: StdVec<u8>,
+        warnings: StdVec<DiagnosticJs>,
+        params: StdVec<ParamJs>,
     ) -> Self {
         let machine_function_counts = descriptor
             .instances
@@ -135,6 +170,9 @@ impl ProgramDescriptorJs {
         Self {
             length: descriptor.length,
             machine_function_counts,
+            source_map,
+            warnings,
+            params,
         }
     }
 }
@@ -160,6 +198,483 @@ impl ProgramDescriptorJs {
             .copied()
             .ok_or_else(|| JsValue::from_str("machine index out of range"))
     }
+
+    /// Serialized map from program words back to source lines; pass it to
+    /// `lookup_source`. Empty for programs that were not compiled from source.
+    #[wasm_bindgen(getter)]
+    pub fn source_map(&self) -> StdVec<u8> {
+        self.source_map.clone()
+    }
+
+    /// Lint warnings for the compiled source. They do not stop the program
+    /// from being built.
+    #[wasm_bindgen(getter)]
+    pub fn warnings(&self) -> StdVec<DiagnosticJs> {
+        self.warnings.clone()
+    }
+
+    /// The controls the program declares with `.param`, one set per machine
+    /// instance.
+    #[wasm_bindgen(getter)]
+    pub fn params(&self) -> StdVec<ParamJs> {
+        self.params.clone()
+    }
+}
+
+/// A control declared with `.param`, for one machine instance. `kind` is
+/// `range`, `toggle` or `color`; `target` is `local` or `function`.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct ParamJs {
+    machine: u32,
+    name: String,
+    kind: &'static str,
+    target: &'static str,
+    target_index: ProgramWord,
+    min: ProgramWord,
+    max: ProgramWord,
+    step: ProgramWord,
+    default: StdVec<ProgramWord>,
+}
+
+impl ParamJs {
+    fn new(machine: u32, param: &Param<'_>) -> Self {
+        let info = param.info;
+        let (target, target_index) = match info.target {
+            ParamTarget::Local(index) => ("local", index),
+            ParamTarget::Function(index) => ("function", index),
+        };
+        let value_count = usize::from(info.kind.value_count());
+        Self {
+            machine,
+            name: unpack_name(&param.name),
+            kind: match info.kind {
+                ParamKind::Range => "range",
+                ParamKind::Toggle => "toggle",
+                ParamKind::Color => "color",
+            },
+            target,
+            target_index,
+            min: info.min,
+            max: info.max,
+            step: info.step,
+            default: info.default.iter().take(value_count).copied().collect(),
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl ParamJs {
+    /// Index of the machine instance the control drives.
+    #[wasm_bindgen(getter)]
+    pub fn machine(&self) -> u32 {
+        self.machine
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn kind(&self) -> String {
+        self.kind.to_string()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn target(&self) -> String {
+        self.target.to_string()
+    }
+
+    /// The local, or the first of three for a color, or the function index.
+    #[wasm_bindgen(getter)]
+    pub fn target_index(&self) -> ProgramWord {
+        self.target_index
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn min(&self) -> ProgramWord {
+        self.min
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn max(&self) -> ProgramWord {
+        self.max
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn step(&self) -> ProgramWord {
+        self.step
+    }
+
+    /// The starting value, or a color's red, green and blue.
+    #[wasm_bindgen(getter)]
+    pub fn default_value(&self) -> StdVec<ProgramWord> {
+        self.default.clone()
+    }
+}
+
+/// A name from a program image's symbols section. `kind` is `type`,
+/// `instance`, `function`, `local` or `shared_function`.
+#[wasm_bindgen]
+pub struct ImageSymbolJs {
+    kind: &'static str,
+    owner: ProgramWord,
+    index: ProgramWord,
+    name: String,
+}
+
+#[wasm_bindgen]
+impl ImageSymbolJs {
+    #[wasm_bindgen(getter)]
+    pub fn kind(&self) -> String {
+        self.kind.to_string()
+    }
+
+    /// The machine type of an instance, function or local.
+    #[wasm_bindgen(getter)]
+    pub fn owner(&self) -> ProgramWord {
+        self.owner
+    }
+
+    /// Type id, machine index, function, local or shared function index.
+    #[wasm_bindgen(getter)]
+    pub fn index(&self) -> ProgramWord {
+        self.index
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+/// The names a program image carries, such as one read back from a device.
+/// Empty for an image built with symbols stripped.
+#[wasm_bindgen]
+pub fn program_symbols(image: &[u16]) -> Result<StdVec<ImageSymbolJs>, JsValue> {
+    let symbols = metadata::symbols(image).map_err(|err| JsValue::from_str(&err.to_string()))?;
+    symbols
+        .map(|symbol| {
+            let symbol = symbol.map_err(|err| JsValue::from_str(&err.to_string()))?;
+            Ok(ImageSymbolJs {
+                kind: match symbol.kind {
+                    SymbolKind::Type => "type",
+                    SymbolKind::Instance => "instance",
+                    SymbolKind::Function => "function",
+                    SymbolKind::Local => "local",
+                    SymbolKind::SharedFunction => "shared_function",
+                },
+                owner: symbol.owner,
+                index: symbol.index,
+                name: unpack_name(&symbol.name),
+            })
+        })
+        .collect()
+}
+
+fn unpack_name(name: &PackedName<'_>) -> String {
+    String::from_utf8_lossy(&name.bytes().collect::<StdVec<u8>>()).into_owned()
+}
+
+/// The params of `image`, repeated for every instance of the machine type
+/// that declares them.
+fn image_params(image: &[ProgramWord]) -> Result<StdVec<ParamJs>, MachineError> {
+    let params = metadata::params(image)?.collect::<Result<StdVec<_>, _>>()?;
+    if params.is_empty() {
+        return Ok(StdVec::new());
+    }
+    let machine_count = image
+        .get(light_machine::MACHINE_COUNT_OFFSET)
+        .copied()
+        .ok_or(MachineError::OutOfBoudsStaticRead(light_machine::MACHINE_COUNT_OFFSET))?;
+    let mut expanded = StdVec::new();
+    for machine in 0..machine_count {
+        let type_id = metadata::instance_type(image, machine)?;
+        expanded.extend(
+            params
+                .iter()
+                .filter(|param| param.info.type_id == type_id)
+                .map(|param| ParamJs::new(u32::from(machine), param)),
+        );
+    }
+    Ok(expanded)
+}
+
+/// The source line a program address was assembled from.
+#[wasm_bindgen]
+pub struct SourceRangeJs {
+    start: u32,
+    end: u32,
+    file: Option<String>,
+    line: u32,
+    machine: Option<String>,
+    function: Option<String>,
+}
+
+impl From<SourceRange<'_>> for SourceRangeJs {
+    fn from(range: SourceRange<'_>) -> Self {
+        Self {
+            start: u32::from(range.start),
+            end: u32::from(range.end),
+            file: range.file.map(str::to_string),
+            line: range.line,
+            machine: range.machine.map(str::to_string),
+            function: range.function.map(str::to_string),
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl SourceRangeJs {
+    #[wasm_bindgen(getter)]
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn end(&self) -> u32 {
+        self.end
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn file(&self) -> Option<String> {
+        self.file.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn machine(&self) -> Option<String> {
+        self.machine.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn function(&self) -> Option<String> {
+        self.function.clone()
+    }
+}
+
+/// A problem found while compiling, for the editor to underline.
+/// `start..end` are 0-based character columns in `line`; an empty span
+/// covers the whole line, and `line` is 0 when no line is to blame.
+/// `code` names the lint behind a warning.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct DiagnosticJs {
+    file: Option<String>,
+    line: u32,
+    start: u32,
+    end: u32,
+    severity: &'static str,
+    message: String,
+    fix: Option<String>,
+    code: Option<&'static str>,
+}
+
+impl DiagnosticJs {
+    fn error(message: &str) -> Self {
+        Self {
+            file: None,
+            line: 0,
+            start: 0,
+            end: 0,
+            severity: diagnostic::Severity::Error.as_str(),
+            message: message.to_string(),
+            fix: None,
+            code: None,
+        }
+    }
+}
+
+impl From<Diagnostic> for DiagnosticJs {
+    fn from(diagnostic: Diagnostic) -> Self {
+        Self {
+            file: diagnostic.file.as_ref().map(|file| file.to_string()),
+            line: diagnostic.line,
+            start: diagnostic.start,
+            end: diagnostic.end,
+            severity: diagnostic.severity.as_str(),
+            message: diagnostic.message().to_string(),
+            fix: diagnostic.fix().map(str::to_string),
+            code: None,
+        }
+    }
+}
+
+impl From<Warning> for DiagnosticJs {
+    fn from(warning: Warning) -> Self {
+        Self {
+            file: warning.file,
+            line: warning.line,
+            start: warning.start,
+            end: warning.end,
+            severity: diagnostic::Severity::Warning.as_str(),
+            message: warning.message,
+            fix: None,
+            code: Some(warning.lint.name()),
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl DiagnosticJs {
+    #[wasm_bindgen(getter)]
+    pub fn file(&self) -> Option<String> {
+        self.file.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn end(&self) -> u32 {
+        self.end
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn severity(&self) -> String {
+        self.severity.to_string()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn message(&self) -> String {
+        self.message.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn fix(&self) -> Option<String> {
+        self.fix.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn code(&self) -> Option<String> {
+        self.code.map(str::to_string)
+    }
+}
+
+/// Where a symbol is defined or used. Columns as in `DiagnosticJs`.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct SymbolLocationJs {
+    file: Option<String>,
+    line: u32,
+    start: u32,
+    end: u32,
+}
+
+impl From<&SymbolLocation> for SymbolLocationJs {
+    fn from(location: &SymbolLocation) -> Self {
+        Self {
+            file: location.file.clone(),
+            line: location.line,
+            start: location.start,
+            end: location.end,
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl SymbolLocationJs {
+    #[wasm_bindgen(getter)]
+    pub fn file(&self) -> Option<String> {
+        self.file.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn end(&self) -> u32 {
+        self.end
+    }
+}
+
+/// A name defined in a program, for completion, hover and rename. `kind` is
+/// one of `machine`, `instance`, `function`, `shared_function`, `local`,
+/// `shared`, `frame`, `label`, `data` or `const`.
+#[wasm_bindgen]
+pub struct SymbolJs {
+    name: String,
+    kind: &'static str,
+    machine: Option<String>,
+    function: Option<String>,
+    value: Option<ProgramWord>,
+    definition: SymbolLocationJs,
+    references: StdVec<SymbolLocationJs>,
+}
+
+impl From<&SymbolInfo> for SymbolJs {
+    fn from(symbol: &SymbolInfo) -> Self {
+        Self {
+            name: symbol.name.clone(),
+            kind: symbol.kind.as_str(),
+            machine: symbol.machine.clone(),
+            function: symbol.function.clone(),
+            value: symbol.value,
+            definition: SymbolLocationJs::from(&symbol.definition),
+            references: symbol.references.iter().map(SymbolLocationJs::from).collect(),
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl SymbolJs {
+    #[wasm_bindgen(getter)]
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn kind(&self) -> String {
+        self.kind.to_string()
+    }
+
+    /// The machine the symbol belongs to; `None` for top-level names.
+    #[wasm_bindgen(getter)]
+    pub fn machine(&self) -> Option<String> {
+        self.machine.clone()
+    }
+
+    /// The function a label or `.frame` slot belongs to.
+    #[wasm_bindgen(getter)]
+    pub fn function(&self) -> Option<String> {
+        self.function.clone()
+    }
+
+    /// Function index, local or shared index, frame offset, label offset or
+    /// constant value, when known.
+    #[wasm_bindgen(getter)]
+    pub fn value(&self) -> Option<ProgramWord> {
+        self.value
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn definition(&self) -> SymbolLocationJs {
+        self.definition.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn references(&self) -> StdVec<SymbolLocationJs> {
+        self.references.clone()
+    }
 }
 
 impl Default for FlightDeck {
@@ -263,6 +778,19 @@ impl FlightDeck {
                     block.as_slice(),
                 );
             }
+            Protocol::ProgramData {
+                request_id,
+                total_size,
+                block_number,
+                block,
+            } => {
+                handler.on_program_data(
+                    request_id.value(),
+                    total_size,
+                    block_number,
+                    block.as_slice(),
+                );
+            }
             Protocol::I2cDevices {
                 request_id,
                 total_count,
@@ -311,6 +839,18 @@ impl FlightDeck {
         Ok(request_id)
     }
 
+    /// Asks the device for block `block_number` of the program image it
+    /// holds. Blocks arrive through `onProgramData`; pass the joined image
+    /// to `program_params` or `program_symbols`.
+    pub fn read_program_block(&mut self, block_number: u32) -> Result<Option<u64>, FlightDeckError> {
+        let message = self.controler.read_program(block_number);
+        let message_buf = to_vec_cobs::<ProtocolType, 512>(&message)
+            .map_err(|_| FlightDeckError::CouldNotEncode)?;
+        send(message_buf.as_slice());
+        let request_id = message.get_request_id().map(|id| id.value());
+        Ok(request_id)
+    }
+
     pub fn get_i2c_devices(&mut self, offset: u32) -> Result<Option<u64>, FlightDeckError> {
         let message = self.controler.get_i2c_devices(offset);
         let message_buf = to_vec_cobs::<ProtocolType, 512>(&message)
@@ -394,27 +934,213 @@ const fn message_type_name(message_type: &MessageType) -> &'static str {
         MessageType::UiStateBlock => "UiStateBlock",
         MessageType::ReadUiState => "ReadUiState",
         MessageType::FinishProgram => "FinishProgram",
+        MessageType::ReadProgram => "ReadProgram",
+        MessageType::ProgramData => "ProgramData",
     }
 }
 
 #[wasm_bindgen]
 pub fn get_test_program(buffer: &mut [u16]) -> Result<ProgramDescriptorJs, JsValue> {
     let descriptor = build_test_program(buffer)?;
-    Ok(ProgramDescriptorJs::from_descriptor(descriptor))
+    Ok(ProgramDescriptorJs::from_descriptor(
+        descriptor,
+        StdVec::new(),
+        StdVec::new(),
+        StdVec::new(),
+    ))
+}
+
+/// The controls declared in a program image, such as one read back from a
+/// device. Same as `ProgramDescriptorJs.params`.
+#[wasm_bindgen]
+pub fn program_params(image: &[u16]) -> Result<StdVec<ParamJs>, JsValue> {
+    image_params(image).map_err(|err| JsValue::from_str(&err.to_string()))
+}
+
+/// Sources that `.include "<name>"` can refer to when compiling in the browser.
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct SourceLibrary {
+    sources: MapResolver,
+}
+
+#[wasm_bindgen]
+impl SourceLibrary {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> SourceLibrary {
+        SourceLibrary::default()
+    }
+
+    pub fn add(&mut self, name: &str, source: &str) {
+        self.sources.insert(name, source);
+    }
+}
+
+/// Compiles `source` into `buffer`. With `optimize` the program goes
+/// through the peephole optimizer in `program_graph::optimize` first. On
+/// failure the error is an array of `DiagnosticJs`, one per problem found.
+#[wasm_bindgen]
+pub fn compile_program(source: &str, buffer: &mut [u16], optimize: bool) -> Result<ProgramDescriptorJs, JsValue> {
+    compile_with_resolver(source, &[], &MapResolver::new(), buffer, optimize).map_err(JsValue::from)
+}
+
+/// Like [`compile_program`], with node graphs patched in the UI. Each of
+/// `graphs` is a graph's JSON and becomes a machine after the machines in
+/// `source`, in order.
+#[wasm_bindgen]
+pub fn compile_program_with_graphs(
+    source: &str,
+    graphs: StdVec<String>,
+    buffer: &mut [u16],
+    optimize: bool,
+) -> Result<ProgramDescriptorJs, JsValue> {
+    let graphs = graphs
+        .iter()
+        .enumerate()
+        .map(|(index, json)| {
+            NodeGraph::from_json(json)
+                .map_err(|err| DiagnosticJs::error(&format!("node graph {}: {err}", index + 1)))
+        })
+        .collect::<Result<StdVec<_>, _>>()
+        .map_err(|err| JsValue::from(vec![err]))?;
+    compile_with_resolver(source, &graphs, &MapResolver::new(), buffer, optimize).map_err(JsValue::from)
+}
+
+#[wasm_bindgen]
+pub fn compile_program_with_library(
+    source: &str,
+    library: &SourceLibrary,
+    buffer: &mut [u16],
+    optimize: bool,
+) -> Result<ProgramDescriptorJs, JsValue> {
+    compile_with_resolver(source, &[], &library.sources, buffer, optimize).map_err(JsValue::from)
+}
+
+/// Where the word at `address` came from, given a program's `source_map`.
+/// `None` for addresses outside any function or a map that cannot be read.
+#[wasm_bindgen]
+pub fn lookup_source(source_map: &[u8], address: u32) -> Option<SourceRangeJs> {
+    let address = usize::try_from(address).ok()?;
+    SourceMap::new(source_map)?.lookup(address).map(SourceRangeJs::from)
+}
+
+/// Every problem in `source`, without keeping the compiled program. When
+/// the program compiles these are its lint warnings.
+#[wasm_bindgen]
+pub fn check_program(source: &str, library: &SourceLibrary) -> StdVec<DiagnosticJs> {
+    let mut buffer = vec![0u16; usize::from(ProgramWord::MAX) + 1];
+    match compile_with_resolver(source, &[], &library.sources, &mut buffer, false) {
+        Ok(descriptor) => descriptor.warnings,
+        Err(diagnostics) => diagnostics,
+    }
 }
 
+/// `source` in canonical layout. See `light_machine::assembler::format`.
 #[wasm_bindgen]
-pub fn compile_program(source: &str, buffer: &mut [u16]) -> Result<ProgramDescriptorJs, JsValue> {
-    let injected_source = inject_i2c_init_program(source)?;
-    console_log(injected_source.as_str());
-    let shared_function_count = count_shared_functions(&injected_source)?;
-    let mut assembler = GraphAssembler::new(shared_function_count);
-    for line in injected_source.lines() {
-        assembler.add_line(line).map_err(assembler_error_to_js)?;
-    }
-    let graph = assembler.finish().map_err(assembler_error_to_js)?;
+pub fn format_program(source: &str) -> String {
+    let mut formatted = String::with_capacity(source.len());
+    // Writing to a `String` cannot fail.
+    let _ = format::format_source(source, &mut formatted);
+    formatted
+}
+
+/// Every symbol in `source` with its definition and references. Lines that
+/// do not assemble still contribute what they define and use, so this works
+/// on a program that is being edited.
+#[wasm_bindgen]
+pub fn symbol_table(source: &str, library: &SourceLibrary) -> StdVec<SymbolJs> {
+    // Shared function indices are checked against the real count when
+    // compiling; here any index will do.
+    let mut assembler = GraphAssembler::new(ProgramWord::MAX).with_symbols();
+    let _ = include::for_each_line(source, &library.sources, |location, text| {
+        let _ = assembler.add_line_at(location, text);
+        Ok(())
+    });
+    assembler
+        .symbols()
+        .map(|table| table.symbols().iter().map(SymbolJs::from).collect())
+        .unwrap_or_default()
+}
+
+/// One line of the expanded program and where it came from.
+struct SourceLine {
+    file: Option<String>,
+    line: u32,
+    text: String,
+}
+
+impl SourceLine {
+    fn location(&self) -> SourceLocation<'_> {
+        SourceLocation {
+            file: self.file.as_deref(),
+            line: self.line,
+        }
+    }
+}
+
+/// The compiler behind `compile_program`, for hosts outside the browser:
+/// `resolver` supplies `.include`d files and `graphs` become machines after
+/// those in `source`. On failure the error lists every problem found.
+pub fn compile_with_resolver<R: SourceResolver>(
+    source: &str,
+    graphs: &[NodeGraph],
+    resolver: &R,
+    buffer: &mut [u16],
+    optimize: bool,
+) -> Result<ProgramDescriptorJs, StdVec<DiagnosticJs>> {
+    // Expand includes first so the prescans below see included shared functions.
+    let mut lines: StdVec<SourceLine> = StdVec::new();
+    include::for_each_line(source, resolver, |location, text| {
+        lines.push(SourceLine {
+            file: location.file.map(str::to_string),
+            line: location.line,
+            text: text.to_string(),
+        });
+        Ok(())
+    })
+    .map_err(|err| vec![Diagnostic::from_error(err, "").into()])?;
+    inject_i2c_init_program(&mut lines).map_err(|err| vec![err])?;
+    link_stdlib(&mut lines).map_err(|err| vec![err])?;
+    let expanded_source = join_lines(&lines);
+    #[cfg(target_arch = "wasm32")]
+    console_log(expanded_source.as_str());
+    let shared_function_count = count_shared_functions(&expanded_source).map_err(|err| vec![err])?;
+    let mut assembler = GraphAssembler::new(shared_function_count).with_symbols();
+    let mut allowances = Allowances::new();
+    let mut diagnostics: StdVec<DiagnosticJs> = StdVec::new();
+    for line in &lines {
+        allowances.add_line(line.file.as_deref(), line.line, &line.text);
+        let Err(err) = assembler.add_line_at(line.location(), &line.text) else {
+            continue;
+        };
+        let recoverable = diagnostic::is_recoverable(err.error_kind(), &line.text);
+        diagnostics.push(Diagnostic::from_error(err.at_location(line.location()), &line.text).into());
+        if !recoverable {
+            return Err(diagnostics);
+        }
+    }
+    let symbols = assembler.symbols().unwrap_or_default();
+    for graph in graphs {
+        if let Err(err) = graph.lower_into(assembler.graph_mut()) {
+            diagnostics.push(DiagnosticJs::error(&format!("node graph `{}`: {err}", graph.name)));
+        }
+    }
+    // Still finish after recoverable errors so unclosed blocks get reported.
+    let mut graph = match assembler.finish() {
+        Ok(graph) if diagnostics.is_empty() => graph,
+        Ok(_) => return Err(diagnostics),
+        Err(err) => {
+            diagnostics.push(Diagnostic::from_error(err, "").into());
+            return Err(diagnostics);
+        }
+    };
     if graph.instance_count() == 0 {
-        return Err(JsValue::from_str("no .machine directive found"));
+        return Err(vec![DiagnosticJs::error("no .machine directive found")]);
+    }
+    let findings = lint::lint(&graph, &symbols);
+    // Lint first, so the warnings describe the code as written.
+    if optimize {
+        program_graph::optimize::optimize(&mut graph);
     }
     let builder = ProgramBuilder::<ASM_MACHINE_MAX, ASM_FUNCTION_MAX>::new(
         buffer,
@@ -422,11 +1148,46 @@ pub fn compile_program(source: &str, buffer: &mut [u16]) -> Result<ProgramDescri
         graph.type_count(),
         graph.shared_function_count(),
     )
-    .map_err(|_| JsValue::from_str("program buffer too small for machine count"))?;
-    let descriptor = graph
-        .emit_into(builder)
-        .map_err(|_| JsValue::from_str("program builder error"))?;
-    Ok(ProgramDescriptorJs::from_descriptor(descriptor))
+    .map_err(|_| vec![DiagnosticJs::error("program buffer too small for machine count")])?;
+    let (descriptor, source_map) = match graph.emit_with_source_map(builder) {
+        Ok(emitted) => emitted,
+        Err(err) => {
+            // A machine missing a function fails the build; its lint knows
+            // where the machine is, so it stands in for the builder's error.
+            let (undefined, other): (StdVec<Warning>, StdVec<Warning>) = findings
+                .into_iter()
+                .partition(|warning| warning.lint == Lint::UndefinedFunction);
+            let mut diagnostics: StdVec<DiagnosticJs> = undefined
+                .into_iter()
+                .map(|warning| DiagnosticJs {
+                    severity: diagnostic::Severity::Error.as_str(),
+                    ..DiagnosticJs::from(warning)
+                })
+                .collect();
+            if diagnostics.is_empty() {
+                diagnostics.push(Diagnostic::from_error(err.into(), "").into());
+            }
+            diagnostics.extend(
+                other
+                    .into_iter()
+                    .filter(|warning| !allowances.allows(warning))
+                    .map(DiagnosticJs::from),
+            );
+            return Err(diagnostics);
+        }
+    };
+    let warnings = findings
+        .into_iter()
+        .filter(|warning| !allowances.allows(warning))
+        .map(DiagnosticJs::from)
+        .collect();
+    let params = buffer
+        .get(..descriptor.length)
+        .ok_or_else(|| vec![DiagnosticJs::error("program buffer too small")])
+        .and_then(|image| {
+            image_params(image).map_err(|err| vec![DiagnosticJs::error(&format!("params: {err}"))])
+        })?;
+    Ok(ProgramDescriptorJs::from_descriptor(descriptor, source_map, warnings, params))
 }
 
 fn build_test_program(buffer: &mut [u16]) -> Result<ProgramDescriptor<1, 2>, JsValue> {
@@ -500,109 +1261,62 @@ fn build_test_program(buffer: &mut [u16]) -> Result<ProgramDescriptor<1, 2>, JsV
     let program_builder =
         machine.finish().map_err(|_| JsValue::from_str("could not finish machine"))?;
 
-    let descriptor = program_builder.finish_program();
+    let descriptor = program_builder
+        .finish_program()
+        .map_err(|_| JsValue::from_str("could not finish program"))?;
 
     Ok(descriptor)
 }
 
-fn count_shared_functions(source: &str) -> Result<u16, JsValue> {
-    let mut max_index: u16 = 0;
-    let mut has_shared = false;
-    let mut next_auto: u16 = 0;
-    for line in source.lines() {
-        let line = line.split(';').next().unwrap_or("").trim();
-        if !line.starts_with(".shared_func") && !line.starts_with(".shared_func_decl") {
-            continue;
-        }
-        has_shared = true;
-        let mut tokens = line.split_whitespace();
-        let _ = tokens.next(); // directive
-        let _ = tokens.next(); // name
-        if tokens.next() == Some("index") {
-            let token = tokens
-                .next()
-                .ok_or_else(|| JsValue::from_str("invalid shared function index"))?;
-            let index: u16 = token
-                .parse()
-                .map_err(|_| JsValue::from_str("invalid shared function index"))?;
-            if index > max_index {
-                max_index = index;
-            }
-        } else {
-            if next_auto > max_index {
-                max_index = next_auto;
-            }
-            next_auto = next_auto
-                .checked_add(1)
-                .ok_or_else(|| JsValue::from_str("shared function count overflow"))?;
-        }
-    }
-    if !has_shared {
+fn count_shared_functions(source: &str) -> Result<u16, DiagnosticJs> {
+    let Some(max_index) = SharedDefinitions::scan(source).indices().into_iter().max() else {
         return Ok(SHARED_FUNCTION_RESERVED_COUNT);
-    }
+    };
     let count = max_index
         .checked_add(1)
-        .ok_or_else(|| JsValue::from_str("shared function count overflow"))?;
+        .ok_or_else(|| DiagnosticJs::error("shared function count overflow"))?;
     Ok(count.max(SHARED_FUNCTION_RESERVED_COUNT))
 }
 
-fn inject_i2c_init_program(source: &str) -> Result<String, JsValue> {
+fn join_lines(lines: &[SourceLine]) -> String {
+    let mut out = String::new();
+    for line in lines {
+        out.push_str(&line.text);
+        out.push('\n');
+    }
+    out
+}
+
+fn inject_i2c_init_program(lines: &mut StdVec<SourceLine>) -> Result<(), DiagnosticJs> {
     if I2C_MAPPING_GLOBALS_WORDS == 0 {
-        return Ok(source.to_string());
+        return Ok(());
     }
-    if shared_function_index_defined(source, I2C_INIT_SHARED_FUNCTION_INDEX)? {
-        return Ok(source.to_string());
+    let source = join_lines(lines);
+    if SharedDefinitions::scan(&source).indices().contains(&I2C_INIT_SHARED_FUNCTION_INDEX) {
+        return Ok(());
     }
-    let has_defaults = has_shared_data_block(source, I2C_DEFAULTS_BLOCK);
+    let has_defaults = has_shared_data_block(&source, I2C_DEFAULTS_BLOCK);
     let injection = build_i2c_injection(has_defaults)?;
-    Ok(insert_program_prelude(source, &injection))
+    insert_program_prelude(lines, I2C_PRELUDE_NAME, &injection);
+    Ok(())
 }
 
-fn shared_function_index_defined(source: &str, target_index: u16) -> Result<bool, JsValue> {
-    use std::collections::HashSet;
-    let mut used: HashSet<u16> = HashSet::new();
-    let mut next_auto: u16 = 0;
-    let mut has_shared = false;
-    for line in source.lines() {
-        let line = line.split(';').next().unwrap_or("").trim();
-        if !line.starts_with(".shared_func") && !line.starts_with(".shared_func_decl") {
-            continue;
-        }
-        has_shared = true;
-        let mut tokens = line.split_whitespace();
-        let _ = tokens.next(); // directive
-        let _ = tokens.next(); // name
-        let mut index: Option<u16> = None;
-        if tokens.next() == Some("index") {
-            let token = tokens
-                .next()
-                .ok_or_else(|| JsValue::from_str("invalid shared function index"))?;
-            index = Some(
-                token
-                    .parse()
-                    .map_err(|_| JsValue::from_str("invalid shared function index"))?,
-            );
-        }
-        let assigned = if let Some(value) = index {
-            value
-        } else {
-            while used.contains(&next_auto) {
-                next_auto = next_auto
-                    .checked_add(1)
-                    .ok_or_else(|| JsValue::from_str("shared function count overflow"))?;
-            }
-            let value = next_auto;
-            next_auto = next_auto
-                .checked_add(1)
-                .ok_or_else(|| JsValue::from_str("shared function count overflow"))?;
-            value
-        };
-        used.insert(assigned);
-        if assigned == target_index {
-            return Ok(true);
-        }
+/// Links the standard library routines the program calls. They go above
+/// the reserved I2C indexes and every index the program uses, so the
+/// program's own shared functions keep their numbers.
+fn link_stdlib(lines: &mut StdVec<SourceLine>) -> Result<(), DiagnosticJs> {
+    let source = join_lines(lines);
+    let first_index = SharedDefinitions::scan(&source)
+        .indices()
+        .into_iter()
+        .max()
+        .map_or(0, |index| index.saturating_add(1))
+        .max(SHARED_FUNCTION_RESERVED_COUNT);
+    let library = stdlib::link(&source, first_index);
+    if !library.is_empty() {
+        insert_program_prelude(lines, stdlib::FILE_NAME, &library);
     }
-    Ok(has_shared && used.contains(&target_index))
+    Ok(())
 }
 
 fn has_shared_data_block(source: &str, block_name: &str) -> bool {
@@ -620,11 +1334,11 @@ fn has_shared_data_block(source: &str, block_name: &str) -> bool {
     false
 }
 
-fn build_i2c_injection(has_defaults: bool) -> Result<String, JsValue> {
+fn build_i2c_injection(has_defaults: bool) -> Result<String, DiagnosticJs> {
     let mut lines: StdVec<String> = StdVec::new();
     let last_index = I2C_MAPPING_GLOBALS_WORDS
         .checked_sub(1)
-        .ok_or_else(|| JsValue::from_str("invalid i2c mapping size"))?;
+        .ok_or_else(|| DiagnosticJs::error("invalid i2c mapping size"))?;
     lines.push(format!(".shared {} {}", I2C_SHARED_GLOBAL_ANCHOR, last_index));
     if !has_defaults {
         lines.push(format!(".shared_data {}", I2C_DEFAULTS_BLOCK));
@@ -650,11 +1364,10 @@ fn build_i2c_injection(has_defaults: bool) -> Result<String, JsValue> {
     Ok(lines.join("\n"))
 }
 
-fn insert_program_prelude(source: &str, injection: &str) -> String {
-    let mut lines: StdVec<&str> = source.lines().collect();
+fn insert_program_prelude(lines: &mut StdVec<SourceLine>, file: &str, injection: &str) {
     let mut insert_at = 0usize;
     for (idx, line) in lines.iter().enumerate() {
-        let trimmed = line.split(';').next().unwrap_or("").trim();
+        let trimmed = line.text.split(';').next().unwrap_or("").trim();
         if trimmed.is_empty() {
             continue;
         }
@@ -664,52 +1377,11 @@ fn insert_program_prelude(source: &str, injection: &str) -> String {
         }
         break;
     }
-    if insert_at >= lines.len() {
-        return format!("{}\n{}", source.trim_end(), injection);
-    }
-    let mut out = String::new();
-    for (idx, line) in lines.iter().enumerate() {
-        if idx == insert_at {
-            out.push_str(injection);
-            out.push('\n');
-        }
-        out.push_str(line);
-        out.push('\n');
-    }
-    out
-}
-
-fn assembler_error_to_js(err: AssemblerError) -> JsValue {
-    let kind = match err.error_kind() {
-        AssemblerErrorKind::EmptyLine => "empty line",
-        AssemblerErrorKind::TooManyTokens => "too many tokens",
-        AssemblerErrorKind::InvalidDirective => "invalid directive",
-        AssemblerErrorKind::InvalidInstruction => "invalid instruction",
-        AssemblerErrorKind::InvalidNumber => "invalid number",
-        AssemblerErrorKind::NameTooLong => "name too long",
-        AssemblerErrorKind::DuplicateLabel => "duplicate label",
-        AssemblerErrorKind::DuplicateGlobal => "duplicate global",
-        AssemblerErrorKind::DuplicateStackSlot => "duplicate stack slot",
-        AssemblerErrorKind::GlobalIndexOutOfRange => "global index out of range",
-        AssemblerErrorKind::MaxLabelsExceeded => "max labels exceeded",
-        AssemblerErrorKind::UnknownLabel => "unknown label",
-        AssemblerErrorKind::MissingMachine => "missing machine",
-        AssemblerErrorKind::MissingFunction => "missing function",
-        AssemblerErrorKind::MissingProgram => "missing program",
-        AssemblerErrorKind::UnexpectedDirective => "unexpected directive",
-        AssemblerErrorKind::UnexpectedInstruction => "unexpected instruction",
-        AssemblerErrorKind::FunctionAlreadyDefined => "function already defined",
-        AssemblerErrorKind::FunctionNotDeclared => "function not declared",
-        AssemblerErrorKind::FunctionIndexOutOfRange => "function index out of range",
-        AssemblerErrorKind::FunctionIndexDuplicate => "function index duplicate",
-        AssemblerErrorKind::MaxFunctionsExceeded => "max functions exceeded",
-        AssemblerErrorKind::LineNumberOverflow => "line number overflow",
-        AssemblerErrorKind::CursorOverflow => "cursor overflow",
-        AssemblerErrorKind::DataTooLarge => "data too large",
-        AssemblerErrorKind::Builder(_) => "builder error",
-    };
-    match err.line_number() {
-        Some(line) => JsValue::from_str(&format!("line {}: {}", line, kind)),
-        None => JsValue::from_str(kind),
-    }
+    // The prelude gets its own name so errors in it are not blamed on the user's lines.
+    let prelude = injection.lines().enumerate().map(|(idx, text)| SourceLine {
+        file: Some(file.to_string()),
+        line: (idx + 1) as u32,
+        text: text.to_string(),
+    });
+    lines.splice(insert_at..insert_at, prelude);
 }
@@ -0,0 +1,778 @@
+// Node graphs: no-code patches of oscillators, color generators, math and
+// blend nodes wired into an output, as the UI edits them.
+//
+// A graph is plain data that serializes to JSON, so the UI keeps it in its
+// state blob and hands it back to be compiled. Each graph lowers to one
+// machine in a `ProgramGraph`: `start_frame` remembers the tick, and
+// `get_color` evaluates the nodes the output depends on, once each, for
+// every LED. Node values are integers, mostly levels from 0 to 255; colors
+// are three of them.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use thiserror_no_std::Error;
+
+use crate::program_graph::{ProgramGraph, ProgramGraphBuilder};
+
+mod lower;
+
+/// The format version this code reads and writes.
+pub const NODE_GRAPH_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeGraph {
+    pub version: u32,
+    /// Names the machine, for the source map.
+    pub name: String,
+    pub nodes: Vec<Node>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Node {
+    /// Unique within the graph; inputs refer to nodes by id.
+    pub id: u32,
+    /// Where the UI draws the node. Kept for the UI and otherwise unused.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<[i32; 2]>,
+    #[serde(flatten)]
+    pub kind: NodeKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum NodeKind {
+    /// The frame number the host passed to `start_frame`.
+    Tick,
+    /// The LED being colored.
+    Index,
+    /// The LED's color from the machine before this one.
+    Below,
+    Constant { value: u32 },
+    /// A color from three levels.
+    Color { r: Input, g: Input, b: Input },
+    /// A fully saturated color around the color wheel, `hue` 0 to 255.
+    Hue { hue: Input },
+    /// A level from 0 to 255 that repeats every `period` frames, shifted by
+    /// `phase` frames.
+    Oscillator {
+        shape: Shape,
+        period: Input,
+        phase: Input,
+    },
+    Math { op: MathOp, a: Input, b: Input },
+    /// `b` laid over `a` with `mode`, at `amount` from 0 (all `a`) to 255.
+    Blend {
+        mode: BlendMode,
+        a: Input,
+        b: Input,
+        amount: Input,
+    },
+    /// `color` scaled by `level` out of 255.
+    Dim { color: Input, level: Input },
+    /// The color the machine gives each LED. A graph has exactly one.
+    Output { color: Input },
+}
+
+/// Where an input's value comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Input {
+    Node(u32),
+    Value(u32),
+    Color([u8; 3]),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Shape {
+    Saw,
+    Triangle,
+    Square,
+    Sine,
+}
+
+/// Integer math. Dividing by zero gives 0 rather than stopping the machine;
+/// `scale` is `a * b / 255`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MathOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Min,
+    Max,
+    Scale,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode {
+    /// `b` itself, so `amount` fades from `a` to `b`.
+    Mix,
+    /// `a + b`, at most 255.
+    Add,
+    /// `a * b / 255`.
+    Multiply,
+    /// The brighter of `a` and `b`.
+    Lighten,
+    /// `a - b`, at least 0.
+    Subtract,
+}
+
+/// What a port carries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortType {
+    Value,
+    Color,
+}
+
+impl core::fmt::Display for PortType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            PortType::Value => "a value",
+            PortType::Color => "a color",
+        })
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum NodeGraphError {
+    #[error("the node graph could not be read: {0}")]
+    Format(String),
+    #[error("node graph version {0} is not supported")]
+    Version(u32),
+    #[error("node {0} is defined more than once")]
+    DuplicateNode(u32),
+    #[error("input `{input}` of node {node} uses node {target}, which does not exist")]
+    UnknownNode {
+        node: u32,
+        input: &'static str,
+        target: u32,
+    },
+    #[error("input `{input}` of node {node} needs {expected}")]
+    WrongType {
+        node: u32,
+        input: &'static str,
+        expected: PortType,
+    },
+    #[error("node {0} depends on its own output")]
+    Cycle(u32),
+    #[error("the graph has no output node")]
+    MissingOutput,
+    #[error("node {0} is a second output node")]
+    ExtraOutput(u32),
+    #[error("the graph is too large for one machine")]
+    TooLarge,
+    /// Lowering broke one of its own rules, which is a bug here rather than
+    /// in the graph.
+    #[error("internal error lowering node {node}: {message}")]
+    Internal { node: u32, message: &'static str },
+}
+
+impl NodeGraphError {
+    /// The node the error is about, if there is one.
+    pub fn node(&self) -> Option<u32> {
+        match self {
+            NodeGraphError::DuplicateNode(node)
+            | NodeGraphError::UnknownNode { node, .. }
+            | NodeGraphError::WrongType { node, .. }
+            | NodeGraphError::Cycle(node)
+            | NodeGraphError::ExtraOutput(node)
+            | NodeGraphError::Internal { node, .. } => Some(*node),
+            NodeGraphError::Format(_)
+            | NodeGraphError::Version(_)
+            | NodeGraphError::MissingOutput
+            | NodeGraphError::TooLarge => None,
+        }
+    }
+}
+
+impl NodeKind {
+    /// What the node produces; `None` for the output.
+    pub fn output(&self) -> Option<PortType> {
+        match self {
+            NodeKind::Tick
+            | NodeKind::Index
+            | NodeKind::Constant { .. }
+            | NodeKind::Oscillator { .. }
+            | NodeKind::Math { .. } => Some(PortType::Value),
+            NodeKind::Below
+            | NodeKind::Color { .. }
+            | NodeKind::Hue { .. }
+            | NodeKind::Blend { .. }
+            | NodeKind::Dim { .. } => Some(PortType::Color),
+            NodeKind::Output { .. } => None,
+        }
+    }
+
+    /// Each input's name, value and the type it needs.
+    pub fn inputs(&self) -> Vec<(&'static str, Input, PortType)> {
+        use PortType::{Color, Value};
+        match self {
+            NodeKind::Tick | NodeKind::Index | NodeKind::Below | NodeKind::Constant { .. } => Vec::new(),
+            NodeKind::Color { r, g, b } => vec![("r", *r, Value), ("g", *g, Value), ("b", *b, Value)],
+            NodeKind::Hue { hue } => vec![("hue", *hue, Value)],
+            NodeKind::Oscillator { period, phase, .. } => {
+                vec![("period", *period, Value), ("phase", *phase, Value)]
+            }
+            NodeKind::Math { a, b, .. } => vec![("a", *a, Value), ("b", *b, Value)],
+            NodeKind::Blend { a, b, amount, .. } => {
+                vec![("a", *a, Color), ("b", *b, Color), ("amount", *amount, Value)]
+            }
+            NodeKind::Dim { color, level } => vec![("color", *color, Color), ("level", *level, Value)],
+            NodeKind::Output { color } => vec![("color", *color, Color)],
+        }
+    }
+}
+
+impl NodeGraph {
+    pub fn from_json(json: &str) -> Result<NodeGraph, NodeGraphError> {
+        let graph: NodeGraph =
+            serde_json::from_str(json).map_err(|err| NodeGraphError::Format(err.to_string()))?;
+        if graph.version != NODE_GRAPH_VERSION {
+            return Err(NodeGraphError::Version(graph.version));
+        }
+        Ok(graph)
+    }
+
+    pub fn to_json(&self) -> String {
+        // A graph is plain data; serializing it cannot fail.
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// The nodes the output depends on, each after its inputs, ending with
+    /// the output. Checks that every input is wired to a node that exists
+    /// and has the right type, and that nothing depends on itself.
+    pub fn evaluation_order(&self) -> Result<Vec<&Node>, NodeGraphError> {
+        let mut nodes: HashMap<u32, &Node> = HashMap::new();
+        let mut output = None;
+        for node in &self.nodes {
+            if nodes.insert(node.id, node).is_some() {
+                return Err(NodeGraphError::DuplicateNode(node.id));
+            }
+            if matches!(node.kind, NodeKind::Output { .. }) {
+                if output.is_some() {
+                    return Err(NodeGraphError::ExtraOutput(node.id));
+                }
+                output = Some(node);
+            }
+        }
+        for node in &self.nodes {
+            for (input, value, expected) in node.kind.inputs() {
+                let found = match value {
+                    Input::Value(_) => PortType::Value,
+                    Input::Color(_) => PortType::Color,
+                    Input::Node(target) => nodes
+                        .get(&target)
+                        .ok_or(NodeGraphError::UnknownNode {
+                            node: node.id,
+                            input,
+                            target,
+                        })?
+                        .kind
+                        .output()
+                        .ok_or(NodeGraphError::WrongType {
+                            node: node.id,
+                            input,
+                            expected,
+                        })?,
+                };
+                if found != expected {
+                    return Err(NodeGraphError::WrongType {
+                        node: node.id,
+                        input,
+                        expected,
+                    });
+                }
+            }
+        }
+        let output = output.ok_or(NodeGraphError::MissingOutput)?;
+        visit(output, &nodes)
+    }
+
+    /// Adds this graph's machine type and one instance of it to `builder`.
+    pub fn lower_into(&self, builder: &mut ProgramGraphBuilder) -> Result<(), NodeGraphError> {
+        let order = self.evaluation_order()?;
+        lower::add_machine(self, &order, builder)
+    }
+}
+
+// Every node `root` depends on, each after its inputs, ending with `root`.
+// The path is kept on the heap, so a long chain from the UI cannot overflow
+// the stack.
+fn visit<'g>(root: &'g Node, nodes: &HashMap<u32, &'g Node>) -> Result<Vec<&'g Node>, NodeGraphError> {
+    let mut order = Vec::new();
+    let mut done = HashSet::new();
+    let mut visiting = HashSet::from([root.id]);
+    // Each node on the path, with its inputs still to visit.
+    let mut path = vec![(root, root.kind.inputs().into_iter())];
+    while let Some((node, inputs)) = path.last_mut() {
+        let node = *node;
+        let next = inputs.find_map(|(_, input, _)| match input {
+            Input::Node(target) => nodes.get(&target).copied(),
+            Input::Value(_) | Input::Color(_) => None,
+        });
+        match next {
+            Some(target) if done.contains(&target.id) => {}
+            Some(target) => {
+                if !visiting.insert(target.id) {
+                    return Err(NodeGraphError::Cycle(target.id));
+                }
+                path.push((target, target.kind.inputs().into_iter()));
+            }
+            None => {
+                visiting.remove(&node.id);
+                done.insert(node.id);
+                order.push(node);
+                path.pop();
+            }
+        }
+    }
+    Ok(order)
+}
+
+/// A program of just these graphs, one machine each in order.
+pub fn compile(graphs: &[NodeGraph]) -> Result<ProgramGraph, NodeGraphError> {
+    let mut builder = ProgramGraphBuilder::new(lower::SHARED_FUNCTION_COUNT);
+    for graph in graphs {
+        graph.lower_into(&mut builder)?;
+    }
+    Ok(builder.build())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::program_graph::lint;
+    use crate::program_graph::optimize::optimize;
+    use crate::symbols::SymbolTable;
+    use light_machine::builder::ProgramBuilder;
+    use light_machine::{Program, ProgramWord};
+
+    fn graph(nodes: Vec<(u32, NodeKind)>) -> NodeGraph {
+        NodeGraph {
+            version: NODE_GRAPH_VERSION,
+            name: "patch".to_string(),
+            nodes: nodes
+                .into_iter()
+                .map(|(id, kind)| Node {
+                    id,
+                    position: None,
+                    kind,
+                })
+                .collect(),
+        }
+    }
+
+    fn image(graphs: &[NodeGraph], optimized: bool) -> (Vec<ProgramWord>, ProgramWord) {
+        let mut program = compile(graphs).unwrap();
+        if optimized {
+            optimize(&mut program);
+        }
+        let mut buffer = vec![0; 4096];
+        let builder = ProgramBuilder::<8, 8>::new(
+            &mut buffer,
+            program.instance_count(),
+            program.type_count(),
+            program.shared_function_count(),
+        )
+        .unwrap();
+        let descriptor = program.emit_into(builder).unwrap();
+        buffer.truncate(descriptor.length);
+        (buffer, program.instance_count())
+    }
+
+    // Each tick's LED colors, drawn the way pliot draws them, checking that
+    // the optimizer does not change them.
+    fn render(graphs: &[NodeGraph], ticks: u32, leds: u16) -> Vec<Vec<(u8, u8, u8)>> {
+        let plain = render_image(&image(graphs, false), ticks, leds);
+        let optimized = render_image(&image(graphs, true), ticks, leds);
+        assert_eq!(plain, optimized);
+        plain
+    }
+
+    fn render_image(
+        (image, machines): &(Vec<ProgramWord>, ProgramWord),
+        ticks: u32,
+        leds: u16,
+    ) -> Vec<Vec<(u8, u8, u8)>> {
+        let mut memory = vec![0; 64];
+        let mut program = Program::new(image, &mut memory).unwrap();
+        program.set_step_limit(Some(10_000));
+        program.call_shared(0).unwrap();
+        let mut frames = Vec::new();
+        for tick in 0..ticks {
+            for machine in 0..*machines {
+                program.stack_mut().clear();
+                program.start_frame(machine, tick).unwrap();
+            }
+            let mut frame = Vec::new();
+            for led in 0..leds {
+                let mut color = (0, 0, 0);
+                for machine in 0..*machines {
+                    let stack = program.stack_mut();
+                    stack.clear();
+                    for component in [color.0, color.1, color.2] {
+                        stack.push(component.into()).unwrap();
+                    }
+                    color = program.get_led_color(machine, led).unwrap();
+                }
+                frame.push(color);
+            }
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn json_round_trips() {
+        let json = r#"{"version":1,"name":"pulse","nodes":[
+            {"id":1,"position":[40,80],"kind":"oscillator","shape":"sine","period":{"value":60},"phase":{"value":0}},
+            {"id":2,"kind":"dim","color":{"color":[255,64,0]},"level":{"node":1}},
+            {"id":3,"kind":"blend","mode":"lighten","a":{"node":4},"b":{"node":2},"amount":{"value":255}},
+            {"id":4,"kind":"below"},
+            {"id":5,"kind":"output","color":{"node":3}}
+        ]}"#;
+        let graph = NodeGraph::from_json(json).unwrap();
+        assert_eq!(graph.nodes[0].position, Some([40, 80]));
+        assert_eq!(
+            graph.nodes[1].kind,
+            NodeKind::Dim {
+                color: Input::Color([255, 64, 0]),
+                level: Input::Node(1),
+            }
+        );
+        assert_eq!(NodeGraph::from_json(&graph.to_json()).unwrap(), graph);
+        let order: Vec<u32> = graph.evaluation_order().unwrap().iter().map(|node| node.id).collect();
+        assert_eq!(order, vec![4, 1, 2, 3, 5]);
+    }
+
+    #[test]
+    fn hue_follows_the_tick_and_index() {
+        let rainbow = graph(vec![
+            (1, NodeKind::Tick),
+            (2, NodeKind::Index),
+            (
+                3,
+                NodeKind::Math {
+                    op: MathOp::Multiply,
+                    a: Input::Node(2),
+                    b: Input::Value(40),
+                },
+            ),
+            (
+                4,
+                NodeKind::Math {
+                    op: MathOp::Add,
+                    a: Input::Node(1),
+                    b: Input::Node(3),
+                },
+            ),
+            (5, NodeKind::Hue { hue: Input::Node(4) }),
+            (6, NodeKind::Output { color: Input::Node(5) }),
+        ]);
+        let wheel = |hue: u32| {
+            let hue = hue % 256;
+            let rem = (hue % 86 * 3) as u8;
+            match hue / 86 {
+                0 => (255 - rem, rem, 0),
+                1 => (0, 255 - rem, rem),
+                _ => (rem, 0, 255 - rem),
+            }
+        };
+        let frames = render(&[rainbow], 3, 8);
+        for (tick, frame) in frames.iter().enumerate() {
+            for (led, color) in frame.iter().enumerate() {
+                assert_eq!(*color, wheel(tick as u32 + led as u32 * 40), "tick {tick} led {led}");
+            }
+        }
+    }
+
+    #[test]
+    fn oscillators_repeat_every_period() {
+        let shapes = [Shape::Saw, Shape::Square, Shape::Triangle, Shape::Sine];
+        let graphs: Vec<NodeGraph> = shapes
+            .iter()
+            .map(|shape| {
+                graph(vec![
+                    (
+                        1,
+                        NodeKind::Oscillator {
+                            shape: *shape,
+                            period: Input::Value(16),
+                            phase: Input::Value(4),
+                        },
+                    ),
+                    (
+                        2,
+                        NodeKind::Color {
+                            r: Input::Node(1),
+                            g: Input::Value(0),
+                            b: Input::Value(0),
+                        },
+                    ),
+                    (3, NodeKind::Output { color: Input::Node(2) }),
+                ])
+            })
+            .collect();
+        let levels: Vec<Vec<u8>> = graphs
+            .iter()
+            .map(|graph| render(std::slice::from_ref(graph), 32, 1).iter().map(|frame| frame[0].0).collect())
+            .collect();
+        let saw: Vec<u8> = (0..32).map(|tick| ((tick + 4) % 16 * 16) as u8).collect();
+        assert_eq!(levels[0], saw);
+        for (tick, level) in saw.iter().enumerate() {
+            assert_eq!(levels[1][tick], if *level < 128 { 255 } else { 0 });
+            let triangle = if *level < 128 { *level * 2 } else { (255 - *level) * 2 };
+            assert_eq!(levels[2][tick], triangle);
+        }
+        // A sine rises from the middle to the top, falls through the middle
+        // to the bottom, and comes back.
+        assert_eq!(&levels[3][12..20], &[128, 184, 224, 248, 255, 248, 224, 184]);
+        assert_eq!(&levels[3][20..28], &[127, 71, 31, 7, 0, 7, 31, 71]);
+    }
+
+    #[test]
+    fn math_blend_and_dim() {
+        let value = |op, a, b| NodeKind::Math {
+            op,
+            a: Input::Value(a),
+            b: Input::Value(b),
+        };
+        let patch = graph(vec![
+            (1, value(MathOp::Divide, 200, 0)),
+            (2, value(MathOp::Modulo, 7, 3)),
+            (3, value(MathOp::Min, 9, 4)),
+            (4, value(MathOp::Scale, 200, 128)),
+            (
+                5,
+                NodeKind::Color {
+                    r: Input::Node(1),
+                    g: Input::Node(2),
+                    b: Input::Node(3),
+                },
+            ),
+            (
+                6,
+                NodeKind::Blend {
+                    mode: BlendMode::Add,
+                    a: Input::Node(5),
+                    b: Input::Color([100, 250, 255]),
+                    amount: Input::Value(1000),
+                },
+            ),
+            (
+                7,
+                NodeKind::Dim {
+                    color: Input::Node(6),
+                    level: Input::Node(4),
+                },
+            ),
+            (
+                8,
+                NodeKind::Blend {
+                    mode: BlendMode::Mix,
+                    a: Input::Node(7),
+                    b: Input::Color([255, 0, 255]),
+                    amount: Input::Value(51),
+                },
+            ),
+            (9, NodeKind::Output { color: Input::Node(8) }),
+        ]);
+        // The color is (0, 1, 4); adding (100, 250, 255) gives
+        // (100, 251, 255), capped; dimming by 100/255 gives (39, 98, 100); a
+        // fifth of the way to (255, 0, 255) gives (82, 78, 131).
+        assert_eq!(render(&[patch], 1, 1), vec![vec![(82, 78, 131)]]);
+    }
+
+    #[test]
+    fn graphs_layer_over_each_other() {
+        let base = graph(vec![
+            (1, NodeKind::Constant { value: 300 }),
+            (
+                2,
+                NodeKind::Color {
+                    r: Input::Node(1),
+                    g: Input::Value(20),
+                    b: Input::Value(0),
+                },
+            ),
+            (3, NodeKind::Output { color: Input::Node(2) }),
+        ]);
+        let top = graph(vec![
+            (1, NodeKind::Below),
+            (
+                2,
+                NodeKind::Blend {
+                    mode: BlendMode::Subtract,
+                    a: Input::Node(1),
+                    b: Input::Color([5, 30, 0]),
+                    amount: Input::Value(255),
+                },
+            ),
+            (3, NodeKind::Output { color: Input::Node(2) }),
+        ]);
+        // The output clamps 300 to 255.
+        assert_eq!(render(&[base, top], 1, 2), vec![vec![(250, 0, 0); 2]]);
+    }
+
+    #[test]
+    fn identical_graphs_share_code_and_lint_clean() {
+        let solid = graph(vec![
+            (1, NodeKind::Tick),
+            (
+                2,
+                NodeKind::Oscillator {
+                    shape: Shape::Triangle,
+                    period: Input::Node(1),
+                    phase: Input::Value(0),
+                },
+            ),
+            (
+                3,
+                NodeKind::Math {
+                    op: MathOp::Max,
+                    a: Input::Node(2),
+                    b: Input::Value(9),
+                },
+            ),
+            (4, NodeKind::Hue { hue: Input::Node(3) }),
+            (5, NodeKind::Output { color: Input::Node(4) }),
+        ]);
+        let program = compile(&[solid.clone(), solid]).unwrap();
+        assert_eq!(program.instance_count(), 2);
+        assert_eq!(program.type_count(), 1);
+        assert!(lint::lint(&program, &SymbolTable::default()).is_empty());
+    }
+
+    #[test]
+    fn invalid_graphs_are_rejected() {
+        let output = |color| NodeKind::Output { color };
+        let error = |nodes| graph(nodes).evaluation_order().unwrap_err();
+        assert_eq!(
+            error(vec![(1, NodeKind::Tick), (1, NodeKind::Index)]),
+            NodeGraphError::DuplicateNode(1)
+        );
+        assert_eq!(error(vec![(1, NodeKind::Tick)]), NodeGraphError::MissingOutput);
+        assert_eq!(
+            error(vec![(1, output(Input::Node(2)))]),
+            NodeGraphError::UnknownNode {
+                node: 1,
+                input: "color",
+                target: 2,
+            }
+        );
+        assert_eq!(
+            error(vec![(1, NodeKind::Tick), (2, output(Input::Node(1)))]),
+            NodeGraphError::WrongType {
+                node: 2,
+                input: "color",
+                expected: PortType::Color,
+            }
+        );
+        assert_eq!(
+            error(vec![
+                (1, output(Input::Color([0; 3]))),
+                (2, output(Input::Color([0; 3])))
+            ]),
+            NodeGraphError::ExtraOutput(2)
+        );
+        let err = error(vec![
+            (
+                1,
+                NodeKind::Math {
+                    op: MathOp::Add,
+                    a: Input::Node(2),
+                    b: Input::Value(1),
+                },
+            ),
+            (
+                2,
+                NodeKind::Math {
+                    op: MathOp::Add,
+                    a: Input::Node(1),
+                    b: Input::Value(1),
+                },
+            ),
+            (3, NodeKind::Hue { hue: Input::Node(1) }),
+            (4, output(Input::Node(3))),
+        ]);
+        assert_eq!(err, NodeGraphError::Cycle(1));
+        assert_eq!(err.node(), Some(1));
+        assert_eq!(
+            NodeGraph::from_json(r#"{"version":2,"name":"x","nodes":[]}"#),
+            Err(NodeGraphError::Version(2))
+        );
+        assert!(matches!(
+            NodeGraph::from_json(r#"{"version":1,"name":"x","nodes":[{"id":1,"kind":"wobble"}]}"#),
+            Err(NodeGraphError::Format(_))
+        ));
+    }
+
+    #[test]
+    fn lowering_out_of_order_is_an_error() {
+        let patch = graph(vec![
+            (1, NodeKind::Tick),
+            (2, NodeKind::Output { color: Input::Node(3) }),
+            (3, NodeKind::Below),
+        ]);
+        // The output before the node it reads, as evaluation_order never
+        // returns it.
+        let order: Vec<&Node> = patch.nodes.iter().collect();
+        let mut builder = ProgramGraphBuilder::new(lower::SHARED_FUNCTION_COUNT);
+        let err = lower::add_machine(&patch, &order, &mut builder).unwrap_err();
+        assert!(matches!(err, NodeGraphError::Internal { node: 2, .. }), "{err:?}");
+    }
+
+    #[test]
+    fn graphs_too_large_for_a_machine_are_rejected() {
+        // Each oscillator takes its period from the one before; together
+        // they are more words than a function can address.
+        let mut nodes: Vec<(u32, NodeKind)> = (1..=1000)
+            .map(|id| {
+                let period = if id == 1 { Input::Value(60) } else { Input::Node(id - 1) };
+                let oscillator = NodeKind::Oscillator {
+                    shape: Shape::Sine,
+                    period,
+                    phase: Input::Value(0),
+                };
+                (id, oscillator)
+            })
+            .collect();
+        nodes.push((
+            1001,
+            NodeKind::Color {
+                r: Input::Node(1000),
+                g: Input::Value(0),
+                b: Input::Value(0),
+            },
+        ));
+        nodes.push((1002, NodeKind::Output { color: Input::Node(1001) }));
+        assert_eq!(compile(&[graph(nodes)]).err(), Some(NodeGraphError::TooLarge));
+    }
+
+    #[test]
+    fn long_chains_do_not_overflow_the_stack() {
+        let mut nodes: Vec<(u32, NodeKind)> = (1..=200_000)
+            .map(|id| {
+                let a = if id == 1 { Input::Value(0) } else { Input::Node(id - 1) };
+                let add = NodeKind::Math {
+                    op: MathOp::Add,
+                    a,
+                    b: Input::Value(1),
+                };
+                (id, add)
+            })
+            .collect();
+        nodes.push((0, NodeKind::Hue { hue: Input::Node(200_000) }));
+        nodes.push((200_001, NodeKind::Output { color: Input::Node(0) }));
+        let chain = graph(nodes);
+        assert_eq!(chain.evaluation_order().unwrap().len(), 200_002);
+        // Every value stays on the VM stack, which a chain this long
+        // outgrows.
+        assert_eq!(compile(&[chain]).err(), Some(NodeGraphError::TooLarge));
+    }
+}
@@ -0,0 +1,539 @@
+// Lowers a checked node graph to a machine.
+//
+// The machine keeps the tick in local 0. `get_color` starts with the host's
+// `[r, g, b, index]` in stack slots 0 to 3 and evaluates the nodes in order,
+// each leaving its value (one slot, or three for a color) in the slots
+// above the ones before it, so later nodes read their inputs with `SLOAD`.
+// Inputs have no side effects, so a node reads the same input as often as
+// it needs to instead of juggling copies. Most nodes are straight-line
+// code: a 0/1 flag times one alternative plus the other stands in for a
+// branch.
+
+use std::collections::HashMap;
+
+use light_machine::{Ops, ProgramWord};
+
+use super::{BlendMode, Input, MathOp, Node, NodeGraph, NodeGraphError, NodeKind, Shape};
+use crate::program_graph::{FunctionRef, FunctionSource, ProgramGraphBuilder, TypeItem, WordRef};
+
+// pliot calls shared function 0 when a program loads. A program made only
+// of graphs has nothing to put there, so the graph fills it with `EXIT`.
+pub(super) const SHARED_FUNCTION_COUNT: ProgramWord = 1;
+
+const INIT_INDEX: ProgramWord = 0;
+const START_FRAME_INDEX: ProgramWord = 1;
+const GET_COLOR_INDEX: ProgramWord = 2;
+const FUNCTION_COUNT: ProgramWord = 3;
+
+const TICK_LOCAL: ProgramWord = 0;
+const LOCAL_COUNT: ProgramWord = 1;
+
+const BELOW_SLOT: ProgramWord = 0;
+const INDEX_SLOT: ProgramWord = 3;
+const HOST_SLOTS: ProgramWord = 4;
+// More stack slots than any one node uses above its own, so slot
+// arithmetic cannot overflow while the depth stays under the limit.
+const NODE_SLOTS: ProgramWord = 64;
+
+pub(super) fn add_machine(
+    graph: &NodeGraph,
+    order: &[&Node],
+    builder: &mut ProgramGraphBuilder,
+) -> Result<(), NodeGraphError> {
+    let mut items = Vec::new();
+    let mut sources = Vec::new();
+    let functions = [
+        (INIT_INDEX, "init", vec![WordRef::Literal(Ops::Exit.into())], None),
+        (
+            START_FRAME_INDEX,
+            "start_frame",
+            vec![
+                WordRef::Literal(Ops::LocalStore.into()),
+                WordRef::Literal(TICK_LOCAL),
+                WordRef::Literal(Ops::Exit.into()),
+            ],
+            None,
+        ),
+        {
+            let (words, nodes) = get_color(order)?;
+            (GET_COLOR_INDEX, "get_color", words, Some(nodes))
+        },
+    ];
+    for (index, name, words, nodes) in functions {
+        let mut source = FunctionSource::new(Some(graph.name.clone()), name.to_string());
+        for (offset, node) in nodes.into_iter().flatten().enumerate() {
+            let offset = ProgramWord::try_from(offset).map_err(|_| NodeGraphError::TooLarge)?;
+            source.add_words(offset, offset.saturating_add(1), None, node);
+        }
+        let function_id = builder.add_function(words);
+        items.push(TypeItem::Function(FunctionRef { index, function_id }));
+        sources.push((index, source));
+    }
+    let type_id = builder.add_machine_type_with_sources(items, LOCAL_COUNT, FUNCTION_COUNT, sources);
+    builder.name_machine_type(type_id, graph.name.clone(), Vec::new());
+    builder.add_named_machine_instance(type_id, graph.name.clone(), Vec::new());
+    Ok(())
+}
+
+// `get_color`'s words and the node each came from.
+fn get_color(order: &[&Node]) -> Result<(Vec<WordRef>, Vec<u32>), NodeGraphError> {
+    let mut emitter = Emitter {
+        words: Vec::new(),
+        nodes: Vec::new(),
+        labels: Vec::new(),
+        fixups: Vec::new(),
+        slots: HashMap::new(),
+        depth: HOST_SLOTS,
+        node: 0,
+        error: None,
+    };
+    for node in order {
+        if emitter.depth > ProgramWord::MAX - NODE_SLOTS {
+            return Err(NodeGraphError::TooLarge);
+        }
+        emitter.node = node.id;
+        let slot = emitter.depth;
+        emitter.node_kind(&node.kind);
+        emitter.slots.insert(node.id, slot);
+    }
+    for (at, label) in std::mem::take(&mut emitter.fixups) {
+        let Some(offset) = emitter.labels.get(label).copied().flatten() else {
+            let node = emitter.nodes.get(at).copied().unwrap_or_default();
+            emitter.fail(NodeGraphError::Internal {
+                node,
+                message: "a jump target was never placed",
+            });
+            continue;
+        };
+        if let Some(word) = emitter.words.get_mut(at) {
+            *word = WordRef::LabelOffset(offset);
+        }
+    }
+    match emitter.error {
+        Some(error) => Err(error),
+        None => Ok((emitter.words, emitter.nodes)),
+    }
+}
+
+struct Emitter {
+    words: Vec<WordRef>,
+    nodes: Vec<u32>,
+    labels: Vec<Option<ProgramWord>>,
+    fixups: Vec<(usize, usize)>,
+    // The first slot of each evaluated node's value.
+    slots: HashMap<u32, ProgramWord>,
+    depth: ProgramWord,
+    node: u32,
+    // The first error, if any; lowering carries on so the words stay
+    // consistent.
+    error: Option<NodeGraphError>,
+}
+
+impl Emitter {
+    fn node_kind(&mut self, kind: &NodeKind) {
+        match kind {
+            NodeKind::Tick => self.op_with(Ops::LocalLoad, TICK_LOCAL, 0, 1),
+            NodeKind::Index => self.load(INDEX_SLOT),
+            NodeKind::Below => {
+                for component in 0..3 {
+                    self.load(BELOW_SLOT + component);
+                }
+            }
+            NodeKind::Constant { value } => self.push(*value),
+            NodeKind::Color { r, g, b } => {
+                for input in [r, g, b] {
+                    self.input(*input, 0);
+                }
+            }
+            NodeKind::Hue { hue } => self.hue(*hue),
+            NodeKind::Oscillator { shape, period, phase } => self.oscillator(*shape, *period, *phase),
+            NodeKind::Math { op, a, b } => self.math(*op, *a, *b),
+            NodeKind::Blend { mode, a, b, amount } => self.blend(*mode, *a, *b, *amount),
+            NodeKind::Dim { color, level } => {
+                for component in 0..3 {
+                    self.input(*color, component);
+                    self.input(*level, 0);
+                    self.binary_op(Ops::Multiply);
+                    self.push(255);
+                    self.binary_op(Ops::Divide);
+                }
+            }
+            NodeKind::Output { color } => {
+                // The host rejects components above 255, so clamp them here
+                // rather than stop the machine.
+                for component in 0..3 {
+                    self.input(*color, component);
+                    self.push(255);
+                    self.pick(Ops::BranchLessThan);
+                }
+                self.settle(0, 3);
+                self.op(Ops::Exit);
+            }
+        }
+    }
+
+    // `hue` around the wheel: red to green, green to blue, blue to red, in
+    // three regions of 86 steps.
+    fn hue(&mut self, hue: Input) {
+        let base = self.depth;
+        // rem: how far into the region, 0 to 255.
+        self.input(hue, 0);
+        self.push(256);
+        self.binary_op(Ops::Mod);
+        self.push(86);
+        self.binary_op(Ops::Mod);
+        self.push(3);
+        self.binary_op(Ops::Multiply);
+        // region: 0, 1 or 2.
+        self.input(hue, 0);
+        self.push(256);
+        self.binary_op(Ops::Mod);
+        self.push(86);
+        self.binary_op(Ops::Divide);
+        // 255 - rem.
+        self.push(255);
+        self.load(base);
+        self.binary_op(Ops::Subtract);
+        // A flag for each region.
+        for region in 0..3 {
+            self.load(base + 1);
+            self.push(region);
+            self.binary_op(Ops::Subtract);
+            self.op(Ops::Not);
+        }
+        let (rem, fall, in_region) = (base, base + 2, [base + 3, base + 4, base + 5]);
+        // Each component rises in one region and falls in the next.
+        for (falls, rises) in [(0, 2), (1, 0), (2, 1)] {
+            self.load(in_region[falls]);
+            self.load(fall);
+            self.binary_op(Ops::Multiply);
+            self.load(in_region[rises]);
+            self.load(rem);
+            self.binary_op(Ops::Multiply);
+            self.binary_op(Ops::Add);
+        }
+        self.settle(base, 3);
+    }
+
+    fn oscillator(&mut self, shape: Shape, period: Input, phase: Input) {
+        let base = self.depth;
+        // p: where the frame falls in the period, scaled to 0..256.
+        self.op_with(Ops::LocalLoad, TICK_LOCAL, 0, 1);
+        self.input(phase, 0);
+        self.binary_op(Ops::Add);
+        self.nonzero(period);
+        self.binary_op(Ops::Mod);
+        self.push(256);
+        self.binary_op(Ops::Multiply);
+        self.nonzero(period);
+        self.binary_op(Ops::Divide);
+        match shape {
+            Shape::Saw => {}
+            Shape::Square => {
+                // 255 in the first half, 0 in the second.
+                self.load(base);
+                self.push(128);
+                self.binary_op(Ops::Divide);
+                self.op(Ops::Not);
+                self.push(255);
+                self.binary_op(Ops::Multiply);
+                self.settle(base, 1);
+            }
+            Shape::Triangle => {
+                // 2p, plus (510 - 4p) in the second half.
+                self.load(base);
+                self.push(128);
+                self.binary_op(Ops::Divide);
+                self.push(510);
+                self.load(base);
+                self.push(4);
+                self.binary_op(Ops::Multiply);
+                self.binary_op(Ops::Subtract);
+                self.binary_op(Ops::Multiply);
+                self.load(base);
+                self.push(2);
+                self.binary_op(Ops::Multiply);
+                self.binary_op(Ops::Add);
+                self.settle(base, 1);
+            }
+            Shape::Sine => {
+                // A parabola over each half: c = h * (128 - h) / 32 with h
+                // the position in the half, capped at 127, rising above
+                // 128 in the first half and falling below 127 in the
+                // second.
+                self.load(base);
+                self.push(128);
+                self.binary_op(Ops::Mod);
+                self.push(128);
+                self.load(base);
+                self.push(128);
+                self.binary_op(Ops::Mod);
+                self.binary_op(Ops::Subtract);
+                self.binary_op(Ops::Multiply);
+                self.push(32);
+                self.binary_op(Ops::Divide);
+                self.load(base + 1);
+                self.push(128);
+                self.binary_op(Ops::Divide);
+                self.binary_op(Ops::Subtract);
+                // 128 + c - (1 + 2c) in the second half.
+                self.push(128);
+                self.load(base + 1);
+                self.binary_op(Ops::Add);
+                self.load(base);
+                self.push(128);
+                self.binary_op(Ops::Divide);
+                self.push(1);
+                self.load(base + 1);
+                self.push(2);
+                self.binary_op(Ops::Multiply);
+                self.binary_op(Ops::Add);
+                self.binary_op(Ops::Multiply);
+                self.binary_op(Ops::Subtract);
+                self.settle(base, 1);
+            }
+        }
+    }
+
+    fn math(&mut self, op: MathOp, a: Input, b: Input) {
+        self.input(a, 0);
+        match op {
+            MathOp::Add | MathOp::Subtract | MathOp::Multiply => {
+                self.input(b, 0);
+                self.binary_op(match op {
+                    MathOp::Add => Ops::Add,
+                    MathOp::Subtract => Ops::Subtract,
+                    _ => Ops::Multiply,
+                });
+            }
+            MathOp::Divide | MathOp::Modulo => {
+                // Divide by 1 instead of 0, then multiply by 0.
+                self.nonzero(b);
+                self.binary_op(if op == MathOp::Divide { Ops::Divide } else { Ops::Mod });
+                self.input(b, 0);
+                self.op(Ops::Not);
+                self.op(Ops::Not);
+                self.binary_op(Ops::Multiply);
+            }
+            MathOp::Min | MathOp::Max => {
+                self.input(b, 0);
+                self.pick(if op == MathOp::Min {
+                    Ops::BranchLessThan
+                } else {
+                    Ops::BranchGreaterThan
+                });
+            }
+            MathOp::Scale => {
+                self.input(b, 0);
+                self.binary_op(Ops::Multiply);
+                self.push(255);
+                self.binary_op(Ops::Divide);
+            }
+        }
+    }
+
+    // Each component is `(a * (255 - amount) + mixed * amount) / 255`.
+    fn blend(&mut self, mode: BlendMode, a: Input, b: Input, amount: Input) {
+        let base = self.depth;
+        self.input(amount, 0);
+        self.push(255);
+        self.pick(Ops::BranchLessThan);
+        for component in 0..3 {
+            self.input(a, component);
+            self.push(255);
+            self.load(base);
+            self.binary_op(Ops::Subtract);
+            self.binary_op(Ops::Multiply);
+            match mode {
+                BlendMode::Mix => self.input(b, component),
+                BlendMode::Add => {
+                    self.input(a, component);
+                    self.input(b, component);
+                    self.binary_op(Ops::Add);
+                    self.push(255);
+                    self.pick(Ops::BranchLessThan);
+                }
+                BlendMode::Multiply => {
+                    self.input(a, component);
+                    self.input(b, component);
+                    self.binary_op(Ops::Multiply);
+                    self.push(255);
+                    self.binary_op(Ops::Divide);
+                }
+                BlendMode::Lighten => {
+                    self.input(a, component);
+                    self.input(b, component);
+                    self.pick(Ops::BranchGreaterThan);
+                }
+                BlendMode::Subtract => {
+                    // a - min(a, b)
+                    self.input(a, component);
+                    self.input(a, component);
+                    self.input(b, component);
+                    self.pick(Ops::BranchLessThan);
+                    self.binary_op(Ops::Subtract);
+                }
+            }
+            self.load(base);
+            self.binary_op(Ops::Multiply);
+            self.binary_op(Ops::Add);
+            self.push(255);
+            self.binary_op(Ops::Divide);
+        }
+        self.settle(base, 3);
+    }
+
+    // Pushes one value of `input`: itself, or `component` of a color.
+    fn input(&mut self, input: Input, component: ProgramWord) {
+        match input {
+            Input::Node(node) => match self.slots.get(&node).copied() {
+                Some(slot) => self.load(slot + component),
+                None => {
+                    self.fail(NodeGraphError::Internal {
+                        node: self.node,
+                        message: "an input was not evaluated first",
+                    });
+                    self.push(0);
+                }
+            },
+            Input::Value(value) => self.push(value),
+            Input::Color(color) => {
+                let value = color.get(usize::from(component)).copied().unwrap_or_default();
+                self.push(value.into());
+            }
+        }
+    }
+
+    // Pushes `input`, or 1 when it is 0.
+    fn nonzero(&mut self, input: Input) {
+        self.input(input, 0);
+        self.input(input, 0);
+        self.op(Ops::Not);
+        self.binary_op(Ops::Add);
+    }
+
+    // Replaces the top two values with the deeper one when `branch` takes
+    // it over the top one, and with the top one otherwise.
+    fn pick(&mut self, branch: Ops) {
+        let base = self.depth.saturating_sub(2);
+        let keep = self.new_label();
+        let end = self.new_label();
+        self.load(base);
+        self.load(base + 1);
+        self.branch(branch, keep);
+        self.store(base);
+        self.jump(end);
+        self.place(keep);
+        self.depth = base + 2;
+        self.pop(1);
+        self.place(end);
+    }
+
+    // Moves the top `count` values down to `slot` and drops everything
+    // above them.
+    fn settle(&mut self, slot: ProgramWord, count: ProgramWord) {
+        let from = self.depth.saturating_sub(count);
+        if from != slot {
+            for offset in 0..count {
+                self.load(from + offset);
+                self.store(slot + offset);
+            }
+        }
+        self.pop(self.depth.saturating_sub(slot + count));
+    }
+
+    fn fail(&mut self, error: NodeGraphError) {
+        self.error.get_or_insert(error);
+    }
+
+    fn word(&mut self, word: WordRef) {
+        self.words.push(word);
+        self.nodes.push(self.node);
+    }
+
+    fn op(&mut self, op: Ops) {
+        self.word(WordRef::Literal(op.into()));
+    }
+
+    // `op` with an operand word, popping `pops` values and pushing `pushes`.
+    fn op_with(&mut self, op: Ops, operand: ProgramWord, pops: ProgramWord, pushes: ProgramWord) {
+        self.op(op);
+        self.word(WordRef::Literal(operand));
+        self.depth = self.depth.saturating_sub(pops).saturating_add(pushes);
+    }
+
+    // An op that pops two values and pushes one.
+    fn binary_op(&mut self, op: Ops) {
+        self.op(op);
+        self.depth = self.depth.saturating_sub(1);
+    }
+
+    fn push(&mut self, value: u32) {
+        let high = (value >> 16) as ProgramWord;
+        let low = value as ProgramWord;
+        if high == 0 {
+            self.op_with(Ops::Push, low, 0, 1);
+            return;
+        }
+        // PUSH takes one program word, so build wider values from halves.
+        self.op_with(Ops::Push, high, 0, 1);
+        for _ in 0..2 {
+            self.op_with(Ops::Push, 0x100, 0, 1);
+            self.binary_op(Ops::Multiply);
+        }
+        if low != 0 {
+            self.op_with(Ops::Push, low, 0, 1);
+            self.binary_op(Ops::Add);
+        }
+    }
+
+    fn pop(&mut self, count: ProgramWord) {
+        for _ in 0..count {
+            self.op(Ops::Pop);
+        }
+        self.depth = self.depth.saturating_sub(count);
+    }
+
+    fn load(&mut self, slot: ProgramWord) {
+        self.op_with(Ops::StackLoad, slot, 0, 1);
+    }
+
+    fn store(&mut self, slot: ProgramWord) {
+        self.op_with(Ops::StackStore, slot, 1, 0);
+    }
+
+    fn new_label(&mut self) -> usize {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+
+    fn place(&mut self, label: usize) {
+        let Ok(offset) = ProgramWord::try_from(self.words.len()) else {
+            self.fail(NodeGraphError::TooLarge);
+            return;
+        };
+        if let Some(slot) = self.labels.get_mut(label) {
+            *slot = Some(offset);
+        }
+    }
+
+    fn push_label(&mut self, label: usize) {
+        self.op(Ops::Push);
+        self.fixups.push((self.words.len(), label));
+        self.word(WordRef::LabelOffset(0));
+        self.depth = self.depth.saturating_add(1);
+    }
+
+    // A branch pops the two values it compares and the target.
+    fn branch(&mut self, op: Ops, label: usize) {
+        self.push_label(label);
+        self.op(op);
+        self.depth = self.depth.saturating_sub(3);
+    }
+
+    fn jump(&mut self, label: usize) {
+        self.push_label(label);
+        self.op(Ops::Jump);
+        self.depth = self.depth.saturating_sub(1);
+    }
+}
@@ -0,0 +1,156 @@
+// The shared functions and shared data a source defines, found before it is
+// assembled: the shared function count has to be known up front, and the
+// I2C prelude and the standard library must not take indexes or names the
+// program already uses.
+//
+// Lines go through the same macro expansion and `parse_line` as in
+// `GraphAssembler`, and indexes are assigned the way it assigns them, so the
+// scan agrees with the assembler. Lines the assembler will reject are
+// skipped here; it reports them with their location.
+
+use std::collections::HashSet;
+
+use light_machine::ProgramWord;
+use light_machine::assembler::macros::{MacroLine, MacroLineBuffer, MacroTable, MAX_MACRO_DEPTH};
+use light_machine::assembler::syntax::{Directive, Line, parse_line};
+
+use crate::graph_assembler::{MACRO_COUNT_CAP, MACRO_TEXT_CAP};
+
+#[derive(Default)]
+pub(crate) struct SharedDefinitions {
+    /// Each shared function's name and index, declared or defined.
+    pub functions: Vec<(String, ProgramWord)>,
+    pub data: Vec<String>,
+    next_index: ProgramWord,
+    in_data: bool,
+}
+
+impl SharedDefinitions {
+    pub fn scan(source: &str) -> Self {
+        let mut definitions = Self::default();
+        let mut macros: Box<MacroTable<MACRO_TEXT_CAP, MACRO_COUNT_CAP>> = Box::default();
+        for line in source.lines() {
+            definitions.expand(&mut macros, line, 0);
+        }
+        definitions
+    }
+
+    pub fn indices(&self) -> HashSet<ProgramWord> {
+        self.functions.iter().map(|(_, index)| *index).collect()
+    }
+
+    /// Whether `name` is a shared function or shared data block.
+    pub fn defines(&self, name: &str) -> bool {
+        self.functions.iter().any(|(function, _)| function == name) || self.data.iter().any(|data| data == name)
+    }
+
+    fn expand(&mut self, macros: &mut MacroTable<MACRO_TEXT_CAP, MACRO_COUNT_CAP>, line: &str, depth: usize) {
+        let mut call = match macros.classify(line) {
+            Ok(MacroLine::Plain) => return self.add_line(line),
+            Ok(MacroLine::Call(call)) if depth < MAX_MACRO_DEPTH => call,
+            _ => return,
+        };
+        let mut expanded = MacroLineBuffer::new();
+        while let Ok(true) = macros.expand_next(&mut call, &mut expanded) {
+            self.expand(macros, &expanded, depth + 1);
+        }
+    }
+
+    fn add_line(&mut self, line: &str) {
+        let Ok(Line::Directive(directive)) = parse_line(line, self.in_data) else {
+            return;
+        };
+        match directive {
+            Directive::Data { .. } => self.in_data = true,
+            Directive::SharedData { name } => {
+                self.in_data = true;
+                self.data.push(name.to_string());
+            }
+            Directive::End => self.in_data = false,
+            Directive::SharedFuncDecl(header) => {
+                let index = match header.index {
+                    Some(index) => Some(index),
+                    None => self.next_free_index(),
+                };
+                if let Some(index) = index
+                    && !self.functions.iter().any(|(_, taken)| *taken == index)
+                {
+                    self.functions.push((header.name.to_string(), index));
+                }
+            }
+            Directive::SharedFunc(header) => {
+                if self.functions.iter().any(|(name, _)| name == header.name) {
+                    return;
+                }
+                let index = match header.index {
+                    Some(index) => Some(index),
+                    None => self.next_free_index(),
+                };
+                if let Some(index) = index {
+                    self.functions.push((header.name.to_string(), index));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // The lowest index from the last one handed out that nothing has taken.
+    fn next_free_index(&mut self) -> Option<ProgramWord> {
+        while self.functions.iter().any(|(_, index)| *index == self.next_index) {
+            self.next_index = self.next_index.checked_add(1)?;
+        }
+        let index = self.next_index;
+        self.next_index = self.next_index.checked_add(1)?;
+        Some(index)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn indexes_are_assigned_as_the_assembler_assigns_them() {
+        let definitions = SharedDefinitions::scan(
+            "
+.shared_func_decl blend index 0b11
+.shared_func first
+    RET 0
+.end
+.shared_func blend
+    RET 0
+.end
+.shared_func_decl second
+",
+        );
+        assert_eq!(
+            definitions.functions,
+            vec![("blend".to_string(), 3), ("first".to_string(), 0), ("second".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn macros_and_data_blocks_are_followed() {
+        let definitions = SharedDefinitions::scan(
+            "
+.macro helper name, at
+.shared_func name index at
+    RET 0
+.end
+.endm
+helper fade, 'A'
+.shared_data table
+.word 1
+.end
+.data notes
+.shared_func not_a_function
+.end
+.shared_func broken index LIMIT
+",
+        );
+        assert_eq!(definitions.functions, vec![("fade".to_string(), 65)]);
+        assert!(definitions.defines("table"));
+        assert!(!definitions.defines("notes"));
+        assert!(!definitions.defines("broken"));
+    }
+}
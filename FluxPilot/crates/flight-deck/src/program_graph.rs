@@ -1,8 +1,13 @@
 use std::collections::HashMap;
 
+use light_machine::assembler::source_map::{SourceMapSink, SourceMapWriter};
 use light_machine::builder::{FunctionIndex, MachineBuilderError, Op, ProgramBuilder};
+use light_machine::metadata::{ParamInfo, ParamSpec, SymbolKind, SymbolSpec, SYMBOL_NAME_MAX};
 use light_machine::{ProgramDescriptor, ProgramWord};
 
+pub mod lint;
+pub mod optimize;
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub struct StaticId(usize);
 
@@ -60,6 +65,65 @@ pub struct FunctionNode {
     words: Vec<WordRef>,
 }
 
+/// Words `start..end` of a function, relative to its first word, and the
+/// line they were assembled from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LineSpan {
+    pub start: ProgramWord,
+    pub end: ProgramWord,
+    pub file: Option<String>,
+    pub line: u32,
+}
+
+/// Where a function's words came from. Kept next to the graph rather than in
+/// it, so identical functions still dedupe.
+#[derive(Clone, Debug, Default)]
+pub struct FunctionSource {
+    pub machine: Option<String>,
+    pub name: String,
+    pub lines: Vec<LineSpan>,
+}
+
+impl FunctionSource {
+    pub fn new(machine: Option<String>, name: String) -> Self {
+        Self {
+            machine,
+            name,
+            lines: Vec::new(),
+        }
+    }
+
+    /// Records words `start..end` for `line`, merging with the previous span
+    /// when it is the same line.
+    pub fn add_words(&mut self, start: ProgramWord, end: ProgramWord, file: Option<&str>, line: u32) {
+        if end <= start {
+            return;
+        }
+        if let Some(last) = self.lines.last_mut()
+            && last.end == start
+            && last.line == line
+            && last.file.as_deref() == file
+        {
+            last.end = end;
+            return;
+        }
+        self.lines.push(LineSpan {
+            start,
+            end,
+            file: file.map(str::to_string),
+            line,
+        });
+    }
+}
+
+/// A `.param` of a machine type. `info.type_id` is filled in when the type
+/// is emitted.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TypeParam {
+    pub name: String,
+    pub info: ParamInfo,
+}
+
 #[derive(Clone, Debug)]
 struct StaticDataNode {
     words: Vec<ProgramWord>,
@@ -71,18 +135,40 @@ pub struct FunctionRef {
     pub function_id: FunctionId,
 }
 
+/// A static or function of a machine type, listed in the order the type
+/// writes them after its function table.
+#[derive(Clone, Debug)]
+pub enum TypeItem {
+    Static(StaticId),
+    Function(FunctionRef),
+}
+
+// Top-level entries in the order they were added, which is the order they
+// are emitted in.
+#[derive(Clone, Copy, Debug)]
+enum ProgramItem {
+    SharedStatic(SharedStaticId),
+    SharedFunction(ProgramWord),
+    Instance(usize),
+}
+
 #[derive(Clone, Debug)]
 struct MachineTypeNode {
-    functions: Vec<FunctionRef>,
-    #[allow(dead_code)]
-    statics: Vec<StaticId>,
+    items: Vec<TypeItem>,
     globals_size: ProgramWord,
     function_count: ProgramWord,
+    sources: Vec<(ProgramWord, FunctionSource)>,
+    params: Vec<TypeParam>,
+    // For the symbols section; like `sources`, not part of the key.
+    name: Option<String>,
+    locals: Vec<(ProgramWord, String)>,
 }
 
 #[derive(Clone, Debug)]
 struct MachineInstanceNode {
     type_id: MachineTypeId,
+    initial_locals: Vec<(ProgramWord, ProgramWord)>,
+    name: Option<String>,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
@@ -91,6 +177,9 @@ struct MachineTypeKey {
     statics: Vec<StaticId>,
     globals_size: ProgramWord,
     function_count: ProgramWord,
+    // Types that differ only in their controls stay apart, so each keeps
+    // its own.
+    params: Vec<TypeParam>,
 }
 
 struct NodeInterner<K, V> {
@@ -128,7 +217,9 @@ pub struct ProgramGraphBuilder {
     types: NodeInterner<MachineTypeKey, MachineTypeNode>,
     instances: Vec<MachineInstanceNode>,
     shared_functions: HashMap<ProgramWord, FunctionNode>,
+    shared_sources: HashMap<ProgramWord, FunctionSource>,
     shared_function_count: ProgramWord,
+    layout: Vec<ProgramItem>,
 }
 
 impl ProgramGraphBuilder {
@@ -141,7 +232,9 @@ impl ProgramGraphBuilder {
             types: NodeInterner::new(),
             instances: Vec::new(),
             shared_functions: HashMap::new(),
+            shared_sources: HashMap::new(),
             shared_function_count,
+            layout: Vec::new(),
         }
     }
 
@@ -160,10 +253,14 @@ impl ProgramGraphBuilder {
 
     pub fn add_shared_static(&mut self, data: &[ProgramWord]) -> SharedStaticId {
         let key = data.to_vec();
+        let known = self.shared_static_data.nodes.len();
         let id = self.shared_static_data.intern(
             key.clone(),
             StaticDataNode { words: key },
         );
+        if id == known {
+            self.layout.push(ProgramItem::SharedStatic(SharedStaticId(id)));
+        }
         SharedStaticId(id)
     }
 
@@ -186,6 +283,18 @@ impl ProgramGraphBuilder {
             return Err(MachineBuilderError::FunctionCoutExceeded);
         }
         self.shared_functions.insert(index, FunctionNode { words });
+        self.layout.push(ProgramItem::SharedFunction(index));
+        Ok(())
+    }
+
+    pub fn add_shared_function_with_source(
+        &mut self,
+        index: ProgramWord,
+        words: Vec<WordRef>,
+        source: FunctionSource,
+    ) -> Result<(), MachineBuilderError> {
+        self.add_shared_function(index, words)?;
+        self.shared_sources.insert(index, source);
         Ok(())
     }
 
@@ -196,29 +305,120 @@ impl ProgramGraphBuilder {
         globals_size: ProgramWord,
         function_count: ProgramWord,
     ) -> MachineTypeId {
+        let mut items: Vec<TypeItem> = statics.into_iter().map(TypeItem::Static).collect();
+        items.extend(functions.into_iter().map(TypeItem::Function));
+        self.add_machine_type_with_sources(items, globals_size, function_count, Vec::new())
+    }
+
+    /// Like [`Self::add_machine_type`], with the statics and functions in the
+    /// order they are written and the source of each function by index. A
+    /// type that dedupes into an earlier one keeps the earlier layout and
+    /// sources, so its words map to the first machine that defined them.
+    pub fn add_machine_type_with_sources(
+        &mut self,
+        items: Vec<TypeItem>,
+        globals_size: ProgramWord,
+        function_count: ProgramWord,
+        sources: Vec<(ProgramWord, FunctionSource)>,
+    ) -> MachineTypeId {
+        self.add_machine_type_with_params(items, globals_size, function_count, sources, Vec::new())
+    }
+
+    /// Like [`Self::add_machine_type_with_sources`] for a type with
+    /// `.param` controls, which every instance of the type shares.
+    pub fn add_machine_type_with_params(
+        &mut self,
+        items: Vec<TypeItem>,
+        globals_size: ProgramWord,
+        function_count: ProgramWord,
+        sources: Vec<(ProgramWord, FunctionSource)>,
+        params: Vec<TypeParam>,
+    ) -> MachineTypeId {
+        let mut functions = Vec::new();
+        let mut statics = Vec::new();
+        for item in &items {
+            match item {
+                TypeItem::Static(id) => statics.push(*id),
+                TypeItem::Function(func) => functions.push((func.index, func.function_id)),
+            }
+        }
+        functions.sort_by_key(|(index, _)| *index);
         let key = MachineTypeKey {
-            functions: functions
-                .iter()
-                .map(|func| (func.index, func.function_id))
-                .collect(),
-            statics: statics.clone(),
+            functions,
+            statics,
             globals_size,
             function_count,
+            params: params.clone(),
         };
         let id = self.types.intern(
             key,
             MachineTypeNode {
-                functions,
-                statics,
+                items,
                 globals_size,
                 function_count,
+                sources,
+                params,
+                name: None,
+                locals: Vec::new(),
             },
         );
         MachineTypeId(id)
     }
 
+    /// Names a type and its locals in the program's symbols. A type that
+    /// deduped into an earlier one keeps the earlier names.
+    pub fn name_machine_type(
+        &mut self,
+        type_id: MachineTypeId,
+        name: String,
+        locals: Vec<(ProgramWord, String)>,
+    ) {
+        let Some(node) = self.types.nodes.get_mut(type_id.index()) else {
+            return;
+        };
+        if node.name.is_none() {
+            node.name = Some(name);
+            node.locals = locals;
+        }
+    }
+
     pub fn add_machine_instance(&mut self, type_id: MachineTypeId) {
-        self.instances.push(MachineInstanceNode { type_id });
+        self.add_machine_instance_with_locals(type_id, Vec::new());
+    }
+
+    /// Adds an instance whose locals start as `(local, value)` pairs when the
+    /// VM initializes it.
+    pub fn add_machine_instance_with_locals(
+        &mut self,
+        type_id: MachineTypeId,
+        initial_locals: Vec<(ProgramWord, ProgramWord)>,
+    ) {
+        self.push_instance(type_id, initial_locals, None);
+    }
+
+    /// Like [`Self::add_machine_instance_with_locals`], with the name the
+    /// program's symbols give the instance.
+    pub fn add_named_machine_instance(
+        &mut self,
+        type_id: MachineTypeId,
+        name: String,
+        initial_locals: Vec<(ProgramWord, ProgramWord)>,
+    ) {
+        self.push_instance(type_id, initial_locals, Some(name));
+    }
+
+    fn push_instance(
+        &mut self,
+        type_id: MachineTypeId,
+        initial_locals: Vec<(ProgramWord, ProgramWord)>,
+        name: Option<String>,
+    ) {
+        self.layout.push(ProgramItem::Instance(self.instances.len()));
+        self.instances.push(MachineInstanceNode {
+            type_id,
+            initial_locals,
+            name,
+        });
     }
 
     pub fn build(self) -> ProgramGraph {
@@ -230,7 +430,9 @@ impl ProgramGraphBuilder {
             types: self.types.nodes,
             instances: self.instances,
             shared_functions: self.shared_functions,
+            shared_sources: self.shared_sources,
             shared_function_count: self.shared_function_count,
+            layout: self.layout,
         }
     }
 }
@@ -243,9 +445,23 @@ pub struct ProgramGraph {
     types: Vec<MachineTypeNode>,
     instances: Vec<MachineInstanceNode>,
     shared_functions: HashMap<ProgramWord, FunctionNode>,
+    shared_sources: HashMap<ProgramWord, FunctionSource>,
     shared_function_count: ProgramWord,
+    layout: Vec<ProgramItem>,
+}
+
+// `SourceMapSink` for a growable buffer; the graph is only built with std.
+struct SourceMapBytes(Vec<u8>);
+
+impl SourceMapSink for SourceMapBytes {
+    fn push_byte(&mut self, byte: u8) -> bool {
+        self.0.push(byte);
+        true
+    }
 }
 
+type GraphSourceMap = SourceMapWriter<SourceMapBytes>;
+
 impl ProgramGraph {
     pub fn instance_count(&self) -> ProgramWord {
         self.instances.len() as ProgramWord
@@ -261,75 +477,163 @@ impl ProgramGraph {
 
     pub fn emit_into<const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize>(
         &self,
-        mut builder: ProgramBuilder<'_, MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>,
+        builder: ProgramBuilder<'_, MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>,
     ) -> Result<ProgramDescriptor<MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>, MachineBuilderError> {
-        builder.set_shared_globals_size(self.shared_globals_size)?;
-
-        let mut shared_static_addresses: Vec<ProgramWord> =
-            Vec::with_capacity(self.shared_static_data.len());
-        for node in &self.shared_static_data {
-            let index = builder.add_shared_static(&node.words)?;
-            shared_static_addresses.push(index.to_word());
-        }
+        self.emit(builder, None)
+    }
 
-        let mut static_addresses: Vec<ProgramWord> = Vec::with_capacity(self.static_data.len());
-        for node in &self.static_data {
-            let index = builder.add_shared_static(&node.words)?;
-            static_addresses.push(index.to_word());
-        }
+    /// Like [`Self::emit_into`], and also returns the serialized source map
+    /// of the emitted program.
+    pub fn emit_with_source_map<const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize>(
+        &self,
+        builder: ProgramBuilder<'_, MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>,
+    ) -> Result<(ProgramDescriptor<MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>, Vec<u8>), MachineBuilderError> {
+        let mut map = SourceMapWriter::new(SourceMapBytes(Vec::new()));
+        let descriptor = self.emit(builder, Some(&mut map))?;
+        let bytes = map.finish().map_err(|_| MachineBuilderError::BufferTooSmall)?.0;
+        Ok((descriptor, bytes))
+    }
 
-        let mut program = builder;
-        for index in 0..self.shared_function_count {
-            let shared_function =
-                program.new_shared_function_at_index(FunctionIndex::new(index))?;
-            if let Some(function) = self.shared_functions.get(&index) {
-                let shared_function = emit_shared_function(
-                    shared_function,
-                    function,
-                    &static_addresses,
-                    &shared_static_addresses,
-                )?;
-                let (_index, next_program) = shared_function.finish()?;
-                program = next_program;
-            } else {
-                let mut shared_function = shared_function;
-                shared_function.add_op(Op::Exit)?;
-                let (_index, next_program) = shared_function.finish()?;
-                program = next_program;
-            }
-        }
+    fn emit<const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize>(
+        &self,
+        mut builder: ProgramBuilder<'_, MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>,
+        mut map: Option<&mut GraphSourceMap>,
+    ) -> Result<ProgramDescriptor<MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>, MachineBuilderError> {
+        builder.set_shared_globals_size(self.shared_globals_size)?;
 
+        // Statics get their address when they are written; a deduped static
+        // keeps the first one.
+        let mut shared_static_addresses: Vec<Option<ProgramWord>> =
+            vec![None; self.shared_static_data.len()];
+        let mut static_addresses: Vec<Option<ProgramWord>> = vec![None; self.static_data.len()];
+        let mut emitted_shared: Vec<bool> = vec![false; self.shared_function_count as usize];
         let mut emitted_type_ids: Vec<Option<ProgramWord>> = vec![None; self.types.len()];
         let mut next_type_id: ProgramWord = 0;
-        for instance in &self.instances {
+        let mut params: Vec<ParamSpec<'_>> = Vec::new();
+        let mut symbols: Vec<SymbolSpec<'_>> = Vec::new();
+        let mut next_instance: ProgramWord = 0;
+
+        let mut program = builder;
+        for item in &self.layout {
+            let instance = match *item {
+                ProgramItem::SharedStatic(id) => {
+                    let (Some(slot), Some(node)) = (
+                        shared_static_addresses.get_mut(id.index()),
+                        self.shared_static_data.get(id.index()),
+                    ) else {
+                        return Err(MachineBuilderError::BufferTooSmall);
+                    };
+                    *slot = Some(program.add_shared_static(&node.words)?.to_word());
+                    continue;
+                }
+                ProgramItem::SharedFunction(index) => {
+                    let Some(function) = self.shared_functions.get(&index) else {
+                        continue;
+                    };
+                    let shared_function =
+                        program.new_shared_function_at_index(FunctionIndex::new(index))?;
+                    if let Some(map) = map.as_deref_mut() {
+                        map_function(map, shared_function.function_start(), self.shared_sources.get(&index))?;
+                    }
+                    let shared_function = emit_shared_function(
+                        shared_function,
+                        function,
+                        &static_addresses,
+                        &shared_static_addresses,
+                    )?;
+                    let (_index, next_program) = shared_function.finish()?;
+                    program = next_program;
+                    if let Some(emitted) = emitted_shared.get_mut(index as usize) {
+                        *emitted = true;
+                    }
+                    continue;
+                }
+                ProgramItem::Instance(instance) => instance,
+            };
+            let Some(instance) = self.instances.get(instance) else {
+                continue;
+            };
             let type_id = instance.type_id.index();
             let Some(type_node) = self.types.get(type_id) else {
                 continue;
             };
+            let instance_number = next_instance;
+            next_instance = next_instance
+                .checked_add(1)
+                .ok_or(MachineBuilderError::MachineCountOverflowsWord(usize::from(next_instance)))?;
             if let Some(existing_id) = emitted_type_ids[type_id] {
-                program.add_instance(existing_id)?;
+                program.add_instance_with_locals(existing_id, &instance.initial_locals)?;
+                if let Some(name) = &instance.name {
+                    symbols.push(SymbolSpec {
+                        kind: SymbolKind::Instance,
+                        owner: existing_id,
+                        index: instance_number,
+                        name,
+                    });
+                }
                 continue;
             }
 
-            let mut machine = program.new_machine(type_node.function_count, type_node.globals_size)?;
-            let mut functions = type_node.functions.clone();
-            functions.sort_by_key(|func| func.index);
-            for func in functions {
+            let mut machine = program.new_machine_with_locals(
+                type_node.function_count,
+                type_node.globals_size,
+                &instance.initial_locals,
+            )?;
+            for item in &type_node.items {
+                let func = match item {
+                    TypeItem::Static(static_id) => {
+                        let Some(slot) = static_addresses.get_mut(static_id.index()) else {
+                            return Err(MachineBuilderError::BufferTooSmall);
+                        };
+                        if slot.is_some() {
+                            continue;
+                        }
+                        let Some(node) = self.static_data.get(static_id.index()) else {
+                            return Err(MachineBuilderError::BufferTooSmall);
+                        };
+                        *slot = Some(machine.add_static(&node.words)?.to_word());
+                        continue;
+                    }
+                    TypeItem::Function(func) => func,
+                };
                 let Some(node) = self.functions.get(func.function_id.index()) else {
                     continue;
                 };
                 let function_builder = machine.new_function_at_index(FunctionIndex::new(func.index))?;
-                let (index, next_machine) = emit_function(
+                if let Some(map) = map.as_deref_mut() {
+                    let source = type_node
+                        .sources
+                        .iter()
+                        .find(|(index, _)| *index == func.index)
+                        .map(|(_, source)| source);
+                    map_function(map, function_builder.function_start(), source)?;
+                }
+                let (_index, next_machine) = emit_function(
                     function_builder,
                     node,
                     &static_addresses,
                     &shared_static_addresses,
                 )?;
-                let _ = index;
                 machine = next_machine;
             }
 
             let program_builder = machine.finish()?;
+            params.extend(type_node.params.iter().map(|param| ParamSpec {
+                name: &param.name,
+                info: ParamInfo {
+                    type_id: next_type_id,
+                    ..param.info
+                },
+            }));
+            type_symbols(&mut symbols, type_node, next_type_id);
+            if let Some(name) = &instance.name {
+                symbols.push(SymbolSpec {
+                    kind: SymbolKind::Instance,
+                    owner: next_type_id,
+                    index: instance_number,
+                    name,
+                });
+            }
             emitted_type_ids[type_id] = Some(next_type_id);
             next_type_id = next_type_id
                 .checked_add(1)
@@ -339,8 +643,155 @@ impl ProgramGraph {
             program = program_builder;
         }
 
-        Ok(program.finish_program())
+        // Declared but never defined shared functions just exit.
+        for (index, emitted) in emitted_shared.iter().enumerate() {
+            if *emitted {
+                continue;
+            }
+            let mut shared_function =
+                program.new_shared_function_at_index(FunctionIndex::new(index as ProgramWord))?;
+            shared_function.add_op(Op::Exit)?;
+            let (_index, next_program) = shared_function.finish()?;
+            program = next_program;
+        }
+
+        let mut shared: Vec<_> = self.shared_sources.iter().collect();
+        shared.sort_by_key(|(index, _)| **index);
+        symbols.extend(shared.into_iter().map(|(index, source)| SymbolSpec {
+            kind: SymbolKind::SharedFunction,
+            owner: 0,
+            index: *index,
+            name: &source.name,
+        }));
+
+        // Names are only debugging help; one too long to store is dropped
+        // rather than failing the build.
+        symbols.retain(|symbol| symbol.name.len() <= SYMBOL_NAME_MAX);
+
+        program.add_params(&params)?;
+        program.add_symbols(&symbols)?;
+        program.finish_program()
+    }
+}
+
+// Symbols for a type emitted as `type_id`: its name, functions and locals.
+fn type_symbols<'g>(symbols: &mut Vec<SymbolSpec<'g>>, type_node: &'g MachineTypeNode, type_id: ProgramWord) {
+    if let Some(name) = &type_node.name {
+        symbols.push(SymbolSpec {
+            kind: SymbolKind::Type,
+            owner: 0,
+            index: type_id,
+            name,
+        });
+    }
+    let mut functions: Vec<_> = type_node.sources.iter().collect();
+    functions.sort_by_key(|(index, _)| *index);
+    symbols.extend(functions.into_iter().map(|(index, source)| SymbolSpec {
+        kind: SymbolKind::Function,
+        owner: type_id,
+        index: *index,
+        name: &source.name,
+    }));
+    symbols.extend(type_node.locals.iter().map(|(index, name)| SymbolSpec {
+        kind: SymbolKind::Local,
+        owner: type_id,
+        index: *index,
+        name,
+    }));
+}
+
+fn resolve_word(
+    word: &WordRef,
+    function_start: ProgramWord,
+    static_addresses: &[Option<ProgramWord>],
+    shared_static_addresses: &[Option<ProgramWord>],
+) -> Result<ProgramWord, MachineBuilderError> {
+    match *word {
+        WordRef::Literal(value) => Ok(value),
+        WordRef::LabelOffset(offset) => function_start
+            .checked_add(offset)
+            .ok_or(MachineBuilderError::TooLarge(offset as usize)),
+        WordRef::Static(id, offset) => static_addresses
+            .get(id.index())
+            .copied()
+            .flatten()
+            .ok_or(MachineBuilderError::BufferTooSmall)
+            .and_then(|base| {
+                base.checked_add(offset)
+                    .ok_or(MachineBuilderError::TooLarge(offset as usize))
+            }),
+        WordRef::SharedStatic(id, offset) => shared_static_addresses
+            .get(id.index())
+            .copied()
+            .flatten()
+            .ok_or(MachineBuilderError::BufferTooSmall)
+            .and_then(|base| {
+                base.checked_add(offset)
+                    .ok_or(MachineBuilderError::TooLarge(offset as usize))
+            }),
+    }
+}
+
+// Source map entries for a function that starts at `function_start`.
+fn map_function(
+    map: &mut GraphSourceMap,
+    function_start: ProgramWord,
+    source: Option<&FunctionSource>,
+) -> Result<(), MachineBuilderError> {
+    let Some(source) = source else {
+        return Ok(());
+    };
+    let absolute = |offset: ProgramWord| {
+        function_start
+            .checked_add(offset)
+            .ok_or(MachineBuilderError::TooLarge(offset as usize))
+    };
+    let map_err = |_| MachineBuilderError::BufferTooSmall;
+    map.set_function(source.machine.as_deref(), &source.name)
+        .map_err(map_err)?;
+    for span in &source.lines {
+        map.set_file(span.file.as_deref()).map_err(map_err)?;
+        map.add_words(absolute(span.start)?, absolute(span.end)?, span.line)
+            .map_err(map_err)?;
     }
+    Ok(())
+}
+
+fn emit_function<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize>(
+    mut function: light_machine::builder::FunctionBuilder<'a, MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>,
+    node: &FunctionNode,
+    static_addresses: &[Option<ProgramWord>],
+    shared_static_addresses: &[Option<ProgramWord>],
+) -> Result<
+    (
+        light_machine::builder::FunctionIndex,
+        light_machine::builder::MachineBuilder<'a, MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>,
+    ),
+    MachineBuilderError,
+> {
+    let function_start = function.function_start();
+    for word in &node.words {
+        let resolved = resolve_word(word, function_start, static_addresses, shared_static_addresses)?;
+        function.add_raw_word(resolved)?;
+    }
+    function.finish()
+}
+
+fn emit_shared_function<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize>(
+    mut function: light_machine::builder::SharedFunctionBuilder<'a, MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>,
+    node: &FunctionNode,
+    static_addresses: &[Option<ProgramWord>],
+    shared_static_addresses: &[Option<ProgramWord>],
+) -> Result<
+    light_machine::builder::SharedFunctionBuilder<'a, MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>,
+    MachineBuilderError,
+> {
+    let function_start = function.function_start();
+    for word in &node.words {
+        let resolved = resolve_word(word, function_start, static_addresses, shared_static_addresses)?;
+        function.add_raw_word(resolved)?;
+    }
+    Ok(function)
 }
 
 #[cfg(test)]
@@ -372,6 +823,26 @@ mod test {
         assert_eq!(descriptor.instances.len(), 2);
     }
 
+    #[test]
+    fn emit_rejects_undefined_function_slots() {
+        let mut builder = ProgramGraphBuilder::new(0);
+        let function_id = builder.add_function(vec![WordRef::Literal(Ops::Exit.into())]);
+        let functions = vec![FunctionRef {
+            index: 1,
+            function_id,
+        }];
+        let type_id = builder.add_machine_type(functions, Vec::new(), 0, 2);
+        builder.add_machine_instance(type_id);
+
+        let graph = builder.build();
+        let mut buffer = [0u16; 128];
+        let program_builder = ProgramBuilder::<4, 4>::new(&mut buffer, 1, 1, 0).unwrap();
+        assert!(matches!(
+            graph.emit_into(program_builder),
+            Err(MachineBuilderError::UndefinedFunction { type_id: 0, index: 0 })
+        ));
+    }
+
     #[test]
     fn dedupes_identical_functions_across_types() {
         let mut builder = ProgramGraphBuilder::new(0);
@@ -559,69 +1030,3 @@ mod test {
         assert_eq!(descriptor.instances.len(), 1);
     }
 }
-fn resolve_word(
-    word: &WordRef,
-    function_start: ProgramWord,
-    static_addresses: &[ProgramWord],
-    shared_static_addresses: &[ProgramWord],
-) -> Result<ProgramWord, MachineBuilderError> {
-    match *word {
-        WordRef::Literal(value) => Ok(value),
-        WordRef::LabelOffset(offset) => function_start
-            .checked_add(offset)
-            .ok_or(MachineBuilderError::TooLarge(offset as usize)),
-        WordRef::Static(id, offset) => static_addresses
-            .get(id.index())
-            .copied()
-            .ok_or(MachineBuilderError::BufferTooSmall)
-            .and_then(|base| {
-                base.checked_add(offset)
-                    .ok_or(MachineBuilderError::TooLarge(offset as usize))
-            }),
-        WordRef::SharedStatic(id, offset) => shared_static_addresses
-            .get(id.index())
-            .copied()
-            .ok_or(MachineBuilderError::BufferTooSmall)
-            .and_then(|base| {
-                base.checked_add(offset)
-                    .ok_or(MachineBuilderError::TooLarge(offset as usize))
-            }),
-    }
-}
-
-fn emit_function<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize>(
-    mut function: light_machine::builder::FunctionBuilder<'a, MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>,
-    node: &FunctionNode,
-    static_addresses: &[ProgramWord],
-    shared_static_addresses: &[ProgramWord],
-) -> Result<
-    (
-        light_machine::builder::FunctionIndex,
-        light_machine::builder::MachineBuilder<'a, MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>,
-    ),
-    MachineBuilderError,
-> {
-    let function_start = function.function_start();
-    for word in &node.words {
-        let resolved = resolve_word(word, function_start, static_addresses, shared_static_addresses)?;
-        function.add_raw_word(resolved)?;
-    }
-    function.finish()
-}
-
-fn emit_shared_function<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize>(
-    mut function: light_machine::builder::SharedFunctionBuilder<'a, MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>,
-    node: &FunctionNode,
-    static_addresses: &[ProgramWord],
-    shared_static_addresses: &[ProgramWord],
-) -> Result<
-    light_machine::builder::SharedFunctionBuilder<'a, MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>,
-    MachineBuilderError,
-> {
-    let function_start = function.function_start();
-    for word in &node.words {
-        let resolved = resolve_word(word, function_start, static_addresses, shared_static_addresses)?;
-        function.add_raw_word(resolved)?;
-    }
-    Ok(function)
-}
@@ -0,0 +1,578 @@
+// Lints: mistakes that assemble cleanly but misbehave on the device.
+//
+// The pass reads the finished `ProgramGraph`, decoding each function's words
+// back into instructions, and uses the assembler's `SymbolTable` for names
+// and locations the graph does not keep. Jump and call targets are only
+// known when they are pushed as a literal right before the op that uses
+// them, which is how the assemblers emit them; anything else makes the
+// affected checks assume the worst and stay quiet.
+//
+// Each lint has a name. A `; lint: allow <name>, ...` comment silences the
+// named lints on its own line or, on a line by itself, on the next line.
+// `Allowances::allow` silences a lint everywhere.
+
+use std::collections::{BTreeSet, HashSet};
+
+use light_machine::{Ops, ProgramWord};
+
+use super::{FunctionSource, MachineTypeNode, ProgramGraph, TypeItem, WordRef};
+use crate::symbols::{SymbolKind, SymbolTable};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Lint {
+    /// A function slot counted by `.machine ... functions <n>` that nothing
+    /// defines. `finish_program` rejects such a program; the lint says
+    /// which machine is at fault.
+    UndefinedFunction,
+    /// A `.local` that no instruction reads or writes.
+    UnusedLocal,
+    /// Instructions no path reaches, such as code after `EXIT`.
+    UnreachableCode,
+    /// A shared function reads or writes a local past the end of a machine
+    /// that calls it.
+    LocalOutOfRange,
+    /// `RET` in a function that is never reached through `CALL` or
+    /// `CALL_SHARED`. Called from the host there is no frame to return to.
+    ReturnWithoutFrame,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 5] = [
+        Lint::UndefinedFunction,
+        Lint::UnusedLocal,
+        Lint::UnreachableCode,
+        Lint::LocalOutOfRange,
+        Lint::ReturnWithoutFrame,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::UndefinedFunction => "undefined-function",
+            Lint::UnusedLocal => "unused-local",
+            Lint::UnreachableCode => "unreachable-code",
+            Lint::LocalOutOfRange => "local-out-of-range",
+            Lint::ReturnWithoutFrame => "ret-without-frame",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Lint> {
+        Lint::ALL.into_iter().find(|lint| lint.name() == name)
+    }
+}
+
+/// One finding. `start..end` are character columns in `line` as in a
+/// `Diagnostic`; `line` is 0 when the source is not known.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Warning {
+    pub lint: Lint,
+    pub message: String,
+    pub file: Option<String>,
+    pub line: u32,
+    pub start: u32,
+    pub end: u32,
+}
+
+/// Lints that have been switched off, everywhere or on given lines.
+#[derive(Default)]
+pub struct Allowances {
+    everywhere: HashSet<Lint>,
+    lines: HashSet<(Option<String>, u32, Lint)>,
+    pending: Vec<Lint>,
+}
+
+impl Allowances {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allow(&mut self, lint: Lint) {
+        self.everywhere.insert(lint);
+    }
+
+    /// Reads the `; lint: allow` comments of one source line. Feed lines in
+    /// order, since a comment on its own applies to the line after it.
+    pub fn add_line(&mut self, file: Option<&str>, line: u32, text: &str) {
+        let code = light_machine::assembler::syntax::strip_comment(text);
+        let comment = text.get(code.len()..).unwrap_or_default();
+        let allowed = comment
+            .trim_start_matches(';')
+            .trim()
+            .strip_prefix("lint:")
+            .and_then(|rest| rest.trim_start().strip_prefix("allow"))
+            .map(|names| {
+                names
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter_map(Lint::from_name)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        if code.trim().is_empty() {
+            self.pending.extend(allowed);
+            return;
+        }
+        for lint in std::mem::take(&mut self.pending).into_iter().chain(allowed) {
+            self.lines.insert((file.map(str::to_string), line, lint));
+        }
+    }
+
+    pub fn allows(&self, warning: &Warning) -> bool {
+        self.everywhere.contains(&warning.lint)
+            || self
+                .lines
+                .contains(&(warning.file.clone(), warning.line, warning.lint))
+    }
+}
+
+/// Every lint finding in `graph`, in program order. `symbols` should come
+/// from the assembler that built the graph.
+pub fn lint(graph: &ProgramGraph, symbols: &SymbolTable) -> Vec<Warning> {
+    let mut linter = Linter {
+        graph,
+        symbols,
+        warnings: Vec::new(),
+    };
+    for node in &graph.types {
+        linter.check_type(node);
+    }
+    linter.check_shared_functions();
+    linter.warnings
+}
+
+// One decoded instruction: its word offset, op and operand word.
+struct Instruction<'w> {
+    at: usize,
+    op: Ops,
+    operand: Option<&'w WordRef>,
+}
+
+// Decodes `words` up to the first word that is not a valid op.
+fn decode(words: &[WordRef]) -> Vec<Instruction<'_>> {
+    let mut instructions = Vec::new();
+    let mut at = 0;
+    while let Some(WordRef::Literal(word)) = words.get(at) {
+        let Ok(op) = Ops::try_from(*word) else {
+            break;
+        };
+        let operand = if op.has_operand() { words.get(at + 1) } else { None };
+        instructions.push(Instruction { at, op, operand });
+        at += if op.has_operand() { 2 } else { 1 };
+    }
+    instructions
+}
+
+fn is_branch(op: Ops) -> bool {
+    matches!(
+        op,
+        Ops::Jump
+            | Ops::BranchLessThan
+            | Ops::BranchLessThanEq
+            | Ops::BranchGreaterThan
+            | Ops::BranchGreaterThanEq
+            | Ops::BranchEqual
+    )
+}
+
+// The word pushed right before instruction `index`.
+fn pushed<'w>(instructions: &[Instruction<'w>], index: usize) -> Option<&'w WordRef> {
+    let previous = instructions.get(index.checked_sub(1)?)?;
+    (previous.op == Ops::Push).then_some(previous.operand).flatten()
+}
+
+// The function indices `op` calls, and whether some call's target could not
+// be worked out.
+fn call_targets(instructions: &[Instruction<'_>], op: Ops) -> (BTreeSet<ProgramWord>, bool) {
+    let mut targets = BTreeSet::new();
+    let mut unknown = false;
+    for (index, instruction) in instructions.iter().enumerate() {
+        if instruction.op != op {
+            continue;
+        }
+        match pushed(instructions, index) {
+            Some(WordRef::Literal(target)) => {
+                targets.insert(*target);
+            }
+            _ => unknown = true,
+        }
+    }
+    (targets, unknown)
+}
+
+// Instruction indices no path from the start reaches. Code labels pushed
+// for anything but a jump or branch right after are taken as entry points,
+// since they may be jumped to later.
+fn unreachable(instructions: &[Instruction<'_>]) -> Vec<usize> {
+    let index_at = |at: ProgramWord| instructions.iter().position(|instruction| instruction.at == usize::from(at));
+    let mut roots = vec![0];
+    for (index, instruction) in instructions.iter().enumerate() {
+        if let (Ops::Push, Some(WordRef::LabelOffset(target))) = (instruction.op, instruction.operand) {
+            let consumed = instructions.get(index + 1).is_some_and(|next| is_branch(next.op));
+            if !consumed && let Some(root) = index_at(*target) {
+                roots.push(root);
+            }
+        }
+    }
+    let mut reached = vec![false; instructions.len()];
+    while let Some(index) = roots.pop() {
+        let Some(seen) = reached.get_mut(index) else {
+            continue;
+        };
+        if *seen {
+            continue;
+        }
+        *seen = true;
+        let Some(instruction) = instructions.get(index) else {
+            continue;
+        };
+        if is_branch(instruction.op)
+            && let Some(WordRef::LabelOffset(target)) = pushed(instructions, index)
+            && let Some(target) = index_at(*target)
+        {
+            roots.push(target);
+        }
+        if !matches!(instruction.op, Ops::Jump | Ops::Exit | Ops::Return) {
+            roots.push(index + 1);
+        }
+    }
+    (0..instructions.len()).filter(|index| !reached[*index]).collect()
+}
+
+struct Linter<'g> {
+    graph: &'g ProgramGraph,
+    symbols: &'g SymbolTable,
+    warnings: Vec<Warning>,
+}
+
+impl Linter<'_> {
+    fn warn(&mut self, lint: Lint, message: String, source: Option<&FunctionSource>, at: usize) {
+        let span = source.and_then(|source| {
+            source
+                .lines
+                .iter()
+                .find(|span| usize::from(span.start) <= at && at < usize::from(span.end))
+        });
+        self.warnings.push(Warning {
+            lint,
+            message,
+            file: span.and_then(|span| span.file.clone()),
+            line: span.map_or(0, |span| span.line),
+            start: 0,
+            end: 0,
+        });
+    }
+
+    // The shared functions `words` can reach, directly or through other
+    // shared functions, and whether some call could not be followed.
+    fn shared_closure<'w>(&self, roots: impl Iterator<Item = &'w [WordRef]>) -> (BTreeSet<ProgramWord>, bool) {
+        let mut reached = BTreeSet::new();
+        let mut unknown = false;
+        let mut pending: Vec<ProgramWord> = Vec::new();
+        for words in roots {
+            let (targets, blind) = call_targets(&decode(words), Ops::CallShared);
+            unknown |= blind;
+            pending.extend(targets);
+        }
+        while let Some(index) = pending.pop() {
+            if !reached.insert(index) {
+                continue;
+            }
+            if let Some(node) = self.graph.shared_functions.get(&index) {
+                let (targets, blind) = call_targets(&decode(&node.words), Ops::CallShared);
+                unknown |= blind;
+                pending.extend(targets);
+            }
+        }
+        (reached, unknown)
+    }
+
+    fn check_type(&mut self, node: &MachineTypeNode) {
+        let graph = self.graph;
+        let machine = node
+            .sources
+            .iter()
+            .find_map(|(_, source)| source.machine.clone())
+            .unwrap_or_else(|| "?".to_string());
+        let functions: Vec<(ProgramWord, &[WordRef])> = node
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                TypeItem::Function(func) => graph
+                    .functions
+                    .get(func.function_id.index())
+                    .map(|function| (func.index, function.words.as_slice())),
+                TypeItem::Static(_) => None,
+            })
+            .collect();
+        let source_of = |index: ProgramWord| {
+            node.sources
+                .iter()
+                .find(|(source_index, _)| *source_index == index)
+                .map(|(_, source)| source)
+        };
+
+        for slot in 0..node.function_count {
+            if !functions.iter().any(|(index, _)| *index == slot) {
+                let message = format!("function {slot} of machine `{machine}` is never defined");
+                self.warn_at_symbol(Lint::UndefinedFunction, message, SymbolKind::Machine, &machine, None);
+            }
+        }
+
+        let (shared, blind_shared) = self.shared_closure(functions.iter().map(|(_, words)| *words));
+        let shared_words: Vec<(ProgramWord, &[WordRef])> = shared
+            .iter()
+            .filter_map(|index| graph.shared_functions.get(index).map(|node| (*index, node.words.as_slice())))
+            .collect();
+
+        // Every local the machine's code, or shared code it calls, touches.
+        let mut accessed = BTreeSet::new();
+        for (_, words) in functions.iter().chain(&shared_words) {
+            for instruction in decode(words) {
+                if let (Ops::LocalLoad | Ops::LocalStore, Some(WordRef::Literal(local))) =
+                    (instruction.op, instruction.operand)
+                {
+                    accessed.insert(*local);
+                }
+            }
+        }
+        let unused: Vec<(String, ProgramWord)> = self
+            .symbols
+            .symbols()
+            .iter()
+            .filter(|symbol| {
+                symbol.kind == SymbolKind::Local
+                    && symbol.machine.as_deref() == Some(machine.as_str())
+                    && symbol.references.is_empty()
+                    && !symbol.value.is_some_and(|local| accessed.contains(&local))
+            })
+            .map(|symbol| (symbol.name.clone(), symbol.value.unwrap_or_default()))
+            .collect();
+        for (name, _) in unused {
+            let message = format!("local `{name}` of machine `{machine}` is never used");
+            self.warn_at_symbol(Lint::UnusedLocal, message, SymbolKind::Local, &name, Some(&machine));
+        }
+
+        for (index, words) in &functions {
+            self.check_reachability(words, source_of(*index));
+        }
+
+        for (index, words) in &shared_words {
+            let source = graph.shared_sources.get(index);
+            for instruction in decode(words) {
+                if let (Ops::LocalLoad | Ops::LocalStore, Some(WordRef::Literal(local))) =
+                    (instruction.op, instruction.operand)
+                    && *local >= node.globals_size
+                {
+                    let message = format!(
+                        "local {local} is past the {} locals of machine `{machine}`, which calls this function",
+                        node.globals_size
+                    );
+                    self.warn(Lint::LocalOutOfRange, message, source, instruction.at);
+                }
+            }
+        }
+
+        // Machine functions are entered from the host unless a CALL in the
+        // machine, or in shared code it runs, targets them.
+        let mut called = BTreeSet::new();
+        let mut blind = blind_shared;
+        for (_, words) in functions.iter().chain(&shared_words) {
+            let (targets, unknown) = call_targets(&decode(words), Ops::Call);
+            called.extend(targets);
+            blind |= unknown;
+        }
+        if blind {
+            return;
+        }
+        for (index, words) in &functions {
+            if called.contains(index) {
+                continue;
+            }
+            if let Some(ret) = decode(words).iter().find(|instruction| instruction.op == Ops::Return) {
+                let message = format!("`RET` in a function of machine `{machine}` that is never CALLed; use `EXIT`");
+                self.warn(Lint::ReturnWithoutFrame, message, source_of(*index), ret.at);
+            }
+        }
+    }
+
+    fn check_shared_functions(&mut self) {
+        let graph = self.graph;
+        let mut indices: Vec<&ProgramWord> = graph.shared_functions.keys().collect();
+        indices.sort();
+        for index in &indices {
+            let (Some(node), source) = (graph.shared_functions.get(index), graph.shared_sources.get(index)) else {
+                continue;
+            };
+            self.check_reachability(&node.words, source);
+        }
+
+        let mut called = BTreeSet::new();
+        let all_words = graph
+            .functions
+            .iter()
+            .map(|function| &function.words)
+            .chain(graph.shared_functions.values().map(|function| &function.words));
+        for words in all_words {
+            let (targets, unknown) = call_targets(&decode(words), Ops::CallShared);
+            if unknown {
+                return;
+            }
+            called.extend(targets);
+        }
+        for index in indices {
+            let (Some(node), source) = (graph.shared_functions.get(index), graph.shared_sources.get(index)) else {
+                continue;
+            };
+            if called.contains(index) {
+                continue;
+            }
+            if let Some(ret) = decode(&node.words).iter().find(|instruction| instruction.op == Ops::Return) {
+                let name = source.map_or("?", |source| source.name.as_str());
+                let message = format!("`RET` in shared function `{name}`, which is never CALL_SHAREDed; use `EXIT`");
+                self.warn(Lint::ReturnWithoutFrame, message, source, ret.at);
+            }
+        }
+    }
+
+    // One warning per run of unreachable instructions, at its first line.
+    fn check_reachability(&mut self, words: &[WordRef], source: Option<&FunctionSource>) {
+        let instructions = decode(words);
+        let mut previous = None;
+        for index in unreachable(&instructions) {
+            let starts_run = previous.is_none_or(|previous: usize| previous + 1 != index);
+            previous = Some(index);
+            let Some(instruction) = instructions.get(index).filter(|_| starts_run) else {
+                continue;
+            };
+            let name = source.map_or("?", |source| source.name.as_str());
+            let message = format!("unreachable code in `{name}`");
+            self.warn(Lint::UnreachableCode, message, source, instruction.at);
+        }
+    }
+
+    fn warn_at_symbol(&mut self, lint: Lint, message: String, kind: SymbolKind, name: &str, machine: Option<&str>) {
+        let symbol = self.symbols.symbols().iter().find(|symbol| {
+            symbol.kind == kind && symbol.name == name && symbol.machine.as_deref() == machine
+        });
+        let location = symbol.map(|symbol| &symbol.definition);
+        self.warnings.push(Warning {
+            lint,
+            message,
+            file: location.and_then(|location| location.file.clone()),
+            line: location.map_or(0, |location| location.line),
+            start: location.map_or(0, |location| location.start),
+            end: location.map_or(0, |location| location.end),
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph_assembler::GraphAssembler;
+
+    // Lints `source`, dropping what its `; lint: allow` comments silence,
+    // and returns each warning's name and line.
+    fn lint_source(source: &str) -> Vec<(&'static str, u32)> {
+        let shared_function_count = source
+            .lines()
+            .filter(|line| line.trim_start().starts_with(".shared_func"))
+            .count() as ProgramWord;
+        let mut assembler = GraphAssembler::new(shared_function_count).with_symbols();
+        let mut allowances = Allowances::new();
+        for (number, line) in source.lines().enumerate() {
+            allowances.add_line(None, number as u32 + 1, line);
+            assembler.add_line(line).unwrap();
+        }
+        let symbols = assembler.symbols().unwrap();
+        let graph = assembler.finish().unwrap();
+        lint(&graph, &symbols)
+            .into_iter()
+            .filter(|warning| !allowances.allows(warning))
+            .map(|warning| (warning.lint.name(), warning.line))
+            .collect()
+    }
+
+    #[test]
+    fn clean_program_has_no_warnings() {
+        let source = "\
+.shared_func bump index 0
+    LLOAD 0
+    PUSH 1
+    ADD
+    LSTORE 0
+    RET 0
+.end
+.machine main locals 1 functions 2
+    .local count 0
+    .func init index 0
+        PUSH 0
+        LSTORE count
+        EXIT
+    .end
+    .func step index 1
+        PUSH 0
+        PUSH 0
+        CALL_SHARED
+    loop:
+        LLOAD count
+        PUSH 3
+        BRGTE done
+        JUMP loop
+    done:
+        EXIT
+    .end
+.end
+";
+        assert_eq!(lint_source(source), vec![]);
+    }
+
+    #[test]
+    fn reports_each_lint_at_its_line() {
+        let source = "\
+.shared_func peek index 0
+    LLOAD 4
+    EXIT
+.end
+.machine main locals 2 functions 3
+    .local spare 1
+    .func init index 0
+        LLOAD 0
+        POP
+        PUSH 0
+        CALL_SHARED
+        EXIT
+        PUSH 1
+        POP
+    .end
+    .func step index 1
+        RET 0
+    .end
+.end
+";
+        assert_eq!(
+            lint_source(source),
+            vec![
+                ("undefined-function", 5),
+                ("unused-local", 6),
+                ("unreachable-code", 13),
+                ("local-out-of-range", 2),
+                ("ret-without-frame", 17),
+            ]
+        );
+    }
+
+    #[test]
+    fn allow_comments_silence_their_line() {
+        let source = "\
+.machine main locals 2 functions 1
+    .local spare 1 ; lint: allow unused-local
+    .func init index 0
+        EXIT
+        ; lint: allow unreachable-code
+        PUSH 1
+        POP
+    .end
+.end
+";
+        assert_eq!(lint_source(source), vec![]);
+    }
+}
@@ -0,0 +1,521 @@
+// Peephole optimization: shrinks function bodies before they are emitted.
+//
+// Each function's words are decoded into instructions and rewritten until
+// nothing changes:
+//
+// - `PUSH a; PUSH b; <op>` and `PUSH a; <op>` fold into one `PUSH` when the
+//   result fits in a program word. Division by zero is left for the VM to
+//   report.
+// - `PUSH x; POP` and `DUP; POP` go away.
+// - Code after `EXIT`, `JUMP` or `RET` goes away up to the next label.
+// - A jump or branch to `PUSH label; JUMP` goes straight to `label`, and a
+//   `JUMP` to the very next instruction goes away.
+//
+// Labels are the `LabelOffset` operands of the function itself, and are the
+// only way into the middle of it, so a rewrite never spans a label. A label
+// on removed code moves to the next instruction that is kept, which is where
+// running the removed code would have ended up. Functions that do not decode
+// cleanly, or have a label that is not on an instruction, are left alone.
+
+use std::collections::HashSet;
+
+use light_machine::{Ops, ProgramWord, StackWord};
+
+use super::{FunctionNode, FunctionSource, ProgramGraph, TypeItem, WordRef};
+
+/// Optimizes every function in `graph` in place, moving labels and source
+/// map lines along with the code.
+pub fn optimize(graph: &mut ProgramGraph) {
+    let maps: Vec<Option<Vec<ProgramWord>>> = graph.functions.iter_mut().map(optimize_node).collect();
+    for node in &mut graph.types {
+        for (index, source) in &mut node.sources {
+            let map = node.items.iter().find_map(|item| match item {
+                TypeItem::Function(func) if func.index == *index => maps.get(func.function_id.index()),
+                _ => None,
+            });
+            if let Some(Some(map)) = map {
+                remap_source(source, map);
+            }
+        }
+    }
+    for (index, function) in &mut graph.shared_functions {
+        if let Some(map) = optimize_node(function)
+            && let Some(source) = graph.shared_sources.get_mut(index)
+        {
+            remap_source(source, &map);
+        }
+    }
+}
+
+// Rewrites `node` and returns where each of its old word offsets, and the
+// end, moved to. `None` when nothing changed.
+fn optimize_node(node: &mut FunctionNode) -> Option<Vec<ProgramWord>> {
+    let mut code = decode(&node.words)?;
+    let labels_on_instructions = code.iter().all(|instruction| match instruction.operand {
+        Some(WordRef::LabelOffset(target)) => {
+            usize::from(target) == node.words.len() || code.iter().any(|other| other.at == usize::from(target))
+        }
+        _ => true,
+    });
+    if !labels_on_instructions {
+        return None;
+    }
+    let mut changed = false;
+    while rewrite(&mut code) {
+        changed = true;
+    }
+    if !changed {
+        return None;
+    }
+
+    // Old offsets between two kept instructions move to the later one.
+    let mut map = Vec::with_capacity(node.words.len() + 1);
+    let mut next_at = 0;
+    for instruction in &code {
+        while map.len() <= instruction.at {
+            map.push(next_at as ProgramWord);
+        }
+        next_at += instruction.len();
+    }
+    map.resize(node.words.len() + 1, next_at as ProgramWord);
+
+    let mut words = Vec::with_capacity(next_at);
+    for instruction in code {
+        words.push(WordRef::Literal(instruction.op.into()));
+        match instruction.operand {
+            Some(WordRef::LabelOffset(target)) => {
+                let moved = map.get(usize::from(target)).copied().unwrap_or(target);
+                words.push(WordRef::LabelOffset(moved));
+            }
+            Some(operand) => words.push(operand),
+            None => {}
+        }
+    }
+    node.words = words;
+    Some(map)
+}
+
+fn remap_source(source: &mut FunctionSource, map: &[ProgramWord]) {
+    let moved = |offset: ProgramWord| map.get(usize::from(offset)).copied().unwrap_or(offset);
+    for span in std::mem::take(&mut source.lines) {
+        source.add_words(moved(span.start), moved(span.end), span.file.as_deref(), span.line);
+    }
+}
+
+#[derive(Clone)]
+struct Instruction {
+    // Word offset in the function as it was before optimizing.
+    at: usize,
+    op: Ops,
+    operand: Option<WordRef>,
+}
+
+impl Instruction {
+    fn len(&self) -> usize {
+        if self.operand.is_some() { 2 } else { 1 }
+    }
+
+    fn label(&self) -> Option<ProgramWord> {
+        match (self.op, &self.operand) {
+            (Ops::Push, Some(WordRef::LabelOffset(target))) => Some(*target),
+            _ => None,
+        }
+    }
+}
+
+// Every instruction in `words`, or `None` if some word is not one.
+fn decode(words: &[WordRef]) -> Option<Vec<Instruction>> {
+    let mut instructions = Vec::new();
+    let mut at = 0;
+    while let Some(word) = words.get(at) {
+        let WordRef::Literal(word) = word else {
+            return None;
+        };
+        let op = Ops::try_from(*word).ok()?;
+        let operand = if op.has_operand() {
+            Some(words.get(at + 1)?.clone())
+        } else {
+            None
+        };
+        let instruction = Instruction { at, op, operand };
+        at += instruction.len();
+        instructions.push(instruction);
+    }
+    Some(instructions)
+}
+
+fn is_branch(op: Ops) -> bool {
+    matches!(
+        op,
+        Ops::Jump
+            | Ops::BranchLessThan
+            | Ops::BranchLessThanEq
+            | Ops::BranchGreaterThan
+            | Ops::BranchGreaterThanEq
+            | Ops::BranchEqual
+    )
+}
+
+// The instruction a label at old offset `target` now lands on; `code.len()`
+// for the end of the function.
+fn resolve(code: &[Instruction], target: ProgramWord) -> usize {
+    code.partition_point(|instruction| instruction.at < usize::from(target))
+}
+
+// One pass over `code`. True if anything changed.
+fn rewrite(code: &mut Vec<Instruction>) -> bool {
+    let mut changed = thread_branches(code);
+    let labeled: HashSet<usize> = code
+        .iter()
+        .filter_map(|instruction| match instruction.operand {
+            Some(WordRef::LabelOffset(target)) => Some(resolve(code, target)),
+            _ => None,
+        })
+        .collect();
+
+    let mut kept = Vec::with_capacity(code.len());
+    let mut index = 0;
+    while let Some(instruction) = code.get(index) {
+        if let Some((replacement, consumed)) = peephole(code, index)
+            && (1..consumed).all(|offset| !labeled.contains(&(index + offset)))
+        {
+            kept.extend(replacement);
+            index += consumed;
+            changed = true;
+            continue;
+        }
+        kept.push(instruction.clone());
+        index += 1;
+        if matches!(instruction.op, Ops::Exit | Ops::Jump | Ops::Return) {
+            while index < code.len() && !labeled.contains(&index) {
+                index += 1;
+                changed = true;
+            }
+        }
+    }
+    *code = kept;
+    changed
+}
+
+// Points jumps and branches whose target is `PUSH label; JUMP` at `label`,
+// unless the jumps loop back on themselves.
+fn thread_branches(code: &mut [Instruction]) -> bool {
+    let forward = |target: ProgramWord| {
+        let landing = resolve(code, target);
+        let next = code.get(landing)?.label()?;
+        (code.get(landing + 1)?.op == Ops::Jump).then_some(next)
+    };
+    let mut updates = Vec::new();
+    for index in 1..code.len() {
+        let (Some(push), Some(branch)) = (code.get(index - 1), code.get(index)) else {
+            continue;
+        };
+        let Some(start) = push.label().filter(|_| is_branch(branch.op)) else {
+            continue;
+        };
+        let mut seen = vec![resolve(code, start)];
+        let mut target = start;
+        let mut looped = false;
+        while let Some(next) = forward(target) {
+            let landing = resolve(code, next);
+            if seen.contains(&landing) {
+                looped = true;
+                break;
+            }
+            seen.push(landing);
+            target = next;
+        }
+        if !looped && target != start {
+            updates.push((index - 1, target));
+        }
+    }
+    let changed = !updates.is_empty();
+    for (index, target) in updates {
+        if let Some(push) = code.get_mut(index) {
+            push.operand = Some(WordRef::LabelOffset(target));
+        }
+    }
+    changed
+}
+
+// A shorter sequence for the instructions starting at `index`, and how many
+// of them it replaces.
+fn peephole(code: &[Instruction], index: usize) -> Option<(Vec<Instruction>, usize)> {
+    let first = code.get(index)?;
+    let second = code.get(index + 1)?;
+    let literal = |instruction: &Instruction| match (instruction.op, &instruction.operand) {
+        (Ops::Push, Some(WordRef::Literal(value))) => Some(StackWord::from(*value)),
+        _ => None,
+    };
+    let push = |value: StackWord| {
+        let value = ProgramWord::try_from(value).ok()?;
+        Some(vec![Instruction {
+            at: first.at,
+            op: Ops::Push,
+            operand: Some(WordRef::Literal(value)),
+        }])
+    };
+
+    if let (Some(lhs), Some(rhs), Some(third)) = (literal(first), literal(second), code.get(index + 2))
+        && let Some(result) = fold_binary(third.op, lhs, rhs)
+    {
+        return Some((push(result)?, 3));
+    }
+    if let Some(value) = literal(first)
+        && let Some(result) = fold_unary(second.op, value)
+    {
+        return Some((push(result)?, 2));
+    }
+    match (first.op, second.op) {
+        (Ops::Push | Ops::Dup, Ops::Pop) => Some((Vec::new(), 2)),
+        (Ops::Push, Ops::Jump) if first.label().is_some_and(|target| resolve(code, target) == index + 2) => {
+            Some((Vec::new(), 2))
+        }
+        _ => None,
+    }
+}
+
+// What the VM leaves on the stack for `lhs rhs <op>`.
+fn fold_binary(op: Ops, lhs: StackWord, rhs: StackWord) -> Option<StackWord> {
+    Some(match op {
+        Ops::Add => lhs.wrapping_add(rhs),
+        Ops::Subtract => lhs.wrapping_sub(rhs),
+        Ops::Multiply => lhs.wrapping_mul(rhs),
+        Ops::Divide => lhs.checked_div(rhs)?,
+        Ops::Mod => lhs.checked_rem(rhs)?,
+        Ops::BitwiseAnd => lhs & rhs,
+        Ops::BitwiseOr => lhs | rhs,
+        Ops::BitwiseXor => lhs ^ rhs,
+        Ops::And => StackWord::from(lhs != 0 && rhs != 0),
+        Ops::Or => StackWord::from(lhs != 0 || rhs != 0),
+        Ops::Xor => StackWord::from((lhs != 0) ^ (rhs != 0)),
+        _ => return None,
+    })
+}
+
+fn fold_unary(op: Ops, value: StackWord) -> Option<StackWord> {
+    match op {
+        Ops::Not => Some(StackWord::from(value == 0)),
+        Ops::BitwiseNot => Some(!value),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph_assembler::GraphAssembler;
+    use light_machine::assembler::source_map::SourceMap;
+    use light_machine::builder::ProgramBuilder;
+    use light_machine::Program;
+
+    struct Run {
+        length: usize,
+        source_map: Vec<u8>,
+        // The stack after each call, or the error it stopped on.
+        results: Vec<Result<Vec<StackWord>, String>>,
+    }
+
+    // Calls functions `0..functions` of machine 0 in turn, on one memory.
+    fn run(source: &str, optimized: bool, functions: usize) -> Run {
+        let shared_function_count = source
+            .lines()
+            .filter(|line| line.trim_start().starts_with(".shared_func"))
+            .count() as ProgramWord;
+        let mut assembler = GraphAssembler::new(shared_function_count);
+        for line in source.lines() {
+            assembler.add_line(line).unwrap();
+        }
+        let mut graph = assembler.finish().unwrap();
+        if optimized {
+            optimize(&mut graph);
+        }
+        let mut buffer = [0u16; 512];
+        let mut builder = ProgramBuilder::<2, 4>::new(
+            &mut buffer,
+            graph.instance_count(),
+            graph.type_count(),
+            graph.shared_function_count(),
+        )
+        .unwrap();
+        // Without symbols the code is the end of the image.
+        builder.strip_symbols(true);
+        let (descriptor, source_map) = graph.emit_with_source_map(builder).unwrap();
+        let mut memory = vec![0u32; 64];
+        let mut program = Program::new(&buffer[..descriptor.length], memory.as_mut_slice()).unwrap();
+        program.set_step_limit(Some(1000));
+        let mut results = Vec::new();
+        for function in 0..functions {
+            program.stack_mut().clear();
+            results.push(
+                program
+                    .call(0, function)
+                    .map(|()| program.stack().as_slice().to_vec())
+                    .map_err(|err| format!("{err:?}")),
+            );
+        }
+        Run {
+            length: descriptor.length,
+            source_map,
+            results,
+        }
+    }
+
+    // Runs `source` as written and optimized, and returns how many words
+    // the optimizer saved.
+    fn assert_same_behavior(source: &str, functions: usize) -> usize {
+        let plain = run(source, false, functions);
+        let optimized = run(source, true, functions);
+        assert_eq!(plain.results, optimized.results);
+        plain.length - optimized.length
+    }
+
+    #[test]
+    fn folds_constants_and_drops_stack_noise() {
+        let source = "\
+.machine main locals 2 functions 1
+    .func init index 0
+        PUSH 2
+        PUSH 3
+        ADD
+        PUSH 4
+        MUL
+        DUP
+        POP
+        PUSH 9
+        POP
+        PUSH 7
+        NOT
+        PUSH 0x00F0
+        PUSH 0x003C
+        AND
+        LSTORE 1
+        LLOAD 1
+        EXIT
+    .end
+.end
+";
+        // Two chained folds, two removed pairs, `NOT` and the logical `AND`.
+        assert_eq!(assert_same_behavior(source, 1), 3 + 3 + 2 + 3 + 1 + 3);
+        let optimized = run(source, true, 1);
+        assert_eq!(optimized.results, vec![Ok(vec![20, 0, 1])]);
+    }
+
+    #[test]
+    fn leaves_folds_the_vm_would_reject() {
+        let source = "\
+.machine main locals 1 functions 2
+    .func under index 0
+        PUSH 1
+        PUSH 2
+        SUB
+        BNOT
+        EXIT
+    .end
+    .func divide index 1
+        PUSH 1
+        PUSH 0
+        DIV
+        EXIT
+    .end
+.end
+";
+        assert_eq!(assert_same_behavior(source, 2), 0);
+    }
+
+    #[test]
+    fn drops_dead_code_and_threads_jumps() {
+        let source = "\
+.machine main locals 1 functions 1
+    .func init index 0
+        PUSH 0
+        LSTORE 0
+    top:
+        LLOAD 0
+        PUSH 5
+        BRGTE out
+        LLOAD 0
+        PUSH 1
+        ADD
+        LSTORE 0
+        JUMP step
+        PUSH 99
+        LSTORE 0
+    step:
+        JUMP top
+    out:
+        JUMP done
+    done:
+        LLOAD 0
+        EXIT
+        PUSH 1
+        POP
+    .end
+.end
+";
+        // The dead `PUSH 99; LSTORE 0` and `PUSH 1; POP`, the `JUMP` to
+        // the next line, and `step` once nothing jumps to it.
+        assert_eq!(assert_same_behavior(source, 1), 4 + 3 + 3 + 3);
+        let optimized = run(source, true, 1);
+        assert_eq!(optimized.results, vec![Ok(vec![5])]);
+    }
+
+    #[test]
+    fn keeps_labels_inside_sequences() {
+        // `mid` lands inside the fold and `skip` inside the `DUP; POP`.
+        let source = "\
+.machine main locals 1 functions 2
+    .func add index 0
+        PUSH 1
+        LLOAD 0
+        PUSH 0
+        BREQ mid
+        PUSH 3
+    mid:
+        PUSH 4
+        ADD
+        EXIT
+    .end
+    .func keep index 1
+        PUSH 6
+        LLOAD 0
+        PUSH 0
+        BREQ skip
+        DUP
+    skip:
+        POP
+        EXIT
+    .end
+.end
+";
+        assert_eq!(assert_same_behavior(source, 2), 0);
+    }
+
+    #[test]
+    fn source_map_follows_the_code() {
+        let source = "\
+.machine main locals 1 functions 1
+    .func init index 0
+        PUSH 2
+        PUSH 3
+        ADD
+        LSTORE 0
+        EXIT
+    .end
+.end
+";
+        let plain = run(source, false, 1);
+        let optimized = run(source, true, 1);
+        let lines = |run: &Run| {
+            let map = SourceMap::new(&run.source_map).unwrap();
+            let mut lines: Vec<u32> = map.ranges().map(|range| range.line).collect();
+            lines.dedup();
+            lines
+        };
+        assert_eq!(lines(&plain), vec![3, 4, 5, 6, 7]);
+        assert_eq!(lines(&optimized), vec![3, 6, 7]);
+        let map = SourceMap::new(&optimized.source_map).unwrap();
+        let exit = optimized.length - 1;
+        assert_eq!(map.lookup(exit).map(|range| range.line), Some(7));
+    }
+}
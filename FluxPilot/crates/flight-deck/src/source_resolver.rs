@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+
+use light_machine::assembler::include::SourceResolver;
+
+/// Include sources held in memory, keyed by the name used in `.include`.
+#[derive(Default)]
+pub struct MapResolver {
+    sources: HashMap<String, String>,
+}
+
+impl MapResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, name: &str, source: &str) {
+        self.sources.insert(name.to_string(), source.to_string());
+    }
+}
+
+impl SourceResolver for MapResolver {
+    fn with_source<R>(&self, name: &str, read: impl FnOnce(&str) -> R) -> Option<R> {
+        self.sources.get(name).map(|source| read(source))
+    }
+}
+
+/// Include sources read from disk, relative to `root`.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct FsResolver {
+    root: std::path::PathBuf,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FsResolver {
+    pub fn new(root: impl Into<std::path::PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl SourceResolver for FsResolver {
+    fn with_source<R>(&self, name: &str, read: impl FnOnce(&str) -> R) -> Option<R> {
+        let source = std::fs::read_to_string(self.root.join(name)).ok()?;
+        Some(read(&source))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fs_resolver_reads_relative_to_root() {
+        let root = std::env::temp_dir().join(format!("flight-deck-include-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("lib.fpa"), ".const LEDS 30\n").unwrap();
+        let resolver = FsResolver::new(&root);
+        assert_eq!(
+            resolver.with_source("lib.fpa", |text| text.to_string()),
+            Some(".const LEDS 30\n".to_string())
+        );
+        assert_eq!(resolver.with_source("missing.fpa", |_| ()), None);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
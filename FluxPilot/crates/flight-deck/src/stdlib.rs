@@ -0,0 +1,302 @@
+// The standard library: shared functions for waves, color and easing,
+// written in assembly and linked into the programs that use them.
+//
+// The library is one source, `stdlib/v1.fpa`, of `.shared_func` and
+// `.shared_data` blocks. `link` picks the blocks a program names, and the
+// blocks those name in turn, and gives each function an explicit index so
+// the library never moves a program's own shared functions.
+
+use std::collections::HashSet;
+
+use light_machine::ProgramWord;
+
+use crate::prescan::SharedDefinitions;
+
+/// The library version. Within a version routines keep their names,
+/// arguments and results; changing any of them means a new version.
+pub const VERSION: u32 = 1;
+
+/// The file linked routines are reported in, by diagnostics and the source
+/// map.
+pub const FILE_NAME: &str = "std";
+
+const SOURCE: &str = include_str!("stdlib/v1.fpa");
+
+/// The whole library, for editors to show.
+pub fn source() -> &'static str {
+    SOURCE
+}
+
+/// The names of the library's shared functions, in source order.
+pub fn routines() -> Vec<&'static str> {
+    blocks()
+        .into_iter()
+        .filter(|block| block.function)
+        .map(|block| block.name)
+        .collect()
+}
+
+/// Assembly for every library block `program` uses and does not define
+/// itself, with the blocks those use, in library order. Shared functions
+/// are numbered from `first_index` up. Empty when `program` uses none.
+pub fn link(program: &str, first_index: ProgramWord) -> String {
+    let blocks = blocks();
+    let defined = SharedDefinitions::scan(program);
+    let mut wanted: Vec<&str> = program.lines().flat_map(|line| words(code(line))).collect();
+    let mut linked = HashSet::new();
+    while let Some(name) = wanted.pop() {
+        if defined.defines(name) || linked.contains(name) {
+            continue;
+        }
+        let Some(block) = blocks.iter().find(|block| block.name == name) else {
+            continue;
+        };
+        linked.insert(block.name);
+        wanted.extend(block.lines.iter().skip(1).flat_map(|line| words(code(line))));
+    }
+
+    let mut out = String::new();
+    let mut index = first_index;
+    for block in blocks.iter().filter(|block| linked.contains(block.name)) {
+        for comment in &block.comments {
+            out.push_str(comment);
+            out.push('\n');
+        }
+        for (number, line) in block.lines.iter().enumerate() {
+            if number == 0 && block.function {
+                // The index has to follow the name, as the assembler reads it.
+                let rest = line.trim_start_matches(".shared_func").trim_start();
+                let rest = rest.strip_prefix(block.name).unwrap_or(rest);
+                out.push_str(&format!(".shared_func {} index {index}{rest}", block.name));
+                index = index.saturating_add(1);
+            } else {
+                out.push_str(line);
+            }
+            out.push('\n');
+        }
+    }
+    out
+}
+
+struct Block {
+    name: &'static str,
+    function: bool,
+    comments: Vec<&'static str>,
+    // From the header through `.end`.
+    lines: Vec<&'static str>,
+}
+
+fn blocks() -> Vec<Block> {
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut comments = Vec::new();
+    let mut open = false;
+    for line in SOURCE.lines() {
+        let text = code(line).trim();
+        if open {
+            if let Some(block) = blocks.last_mut() {
+                block.lines.push(line);
+            }
+            open = text != ".end";
+            continue;
+        }
+        let mut tokens = text.split_whitespace();
+        match (tokens.next(), tokens.next()) {
+            (Some(directive @ (".shared_func" | ".shared_data")), Some(name)) => {
+                blocks.push(Block {
+                    name,
+                    function: directive == ".shared_func",
+                    comments: core::mem::take(&mut comments),
+                    lines: vec![line],
+                });
+                open = true;
+            }
+            // A blank line ends the comment a block would take as its own.
+            (None, _) if line.trim().is_empty() => comments.clear(),
+            _ => comments.push(line),
+        }
+    }
+    blocks
+}
+
+fn code(line: &str) -> &str {
+    line.split(';').next().unwrap_or("")
+}
+
+fn words(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graph_assembler::GraphAssembler;
+    use crate::program_graph::lint;
+    use crate::symbols::SymbolTable;
+    use light_machine::builder::ProgramBuilder;
+    use light_machine::{Program, StackWord};
+
+    const FIRST_INDEX: ProgramWord = 4;
+
+    // A program, linked against the library, whose function 0 runs `body`
+    // on whatever the caller left on the stack.
+    struct Harness {
+        image: Vec<ProgramWord>,
+    }
+
+    impl Harness {
+        fn new(routine: &str, args: usize) -> Self {
+            Self::with_body("", &format!("CALL_SHARED {routine} {args}"))
+        }
+
+        fn with_body(data: &str, body: &str) -> Self {
+            let program =
+                format!("{data}\n.machine test locals 0 functions 1\n.func call index 0\n{body}\nEXIT\n.end\n.end\n");
+            let source = format!("{}{program}", link(&program, FIRST_INDEX));
+            let mut assembler = GraphAssembler::new(32);
+            for line in source.lines() {
+                assembler.add_line(line).unwrap_or_else(|err| panic!("{line}: {err:?}"));
+            }
+            let graph = assembler.finish().unwrap();
+            assert!(lint::lint(&graph, &SymbolTable::default()).is_empty());
+            let mut image = vec![0; 2048];
+            let builder = ProgramBuilder::<2, 2>::new(
+                &mut image,
+                graph.instance_count(),
+                graph.type_count(),
+                graph.shared_function_count(),
+            )
+            .unwrap();
+            let descriptor = graph.emit_into(builder).unwrap();
+            image.truncate(descriptor.length);
+            Self { image }
+        }
+
+        fn call(&self, args: &[StackWord]) -> Vec<StackWord> {
+            let mut memory = vec![0; 64];
+            let mut program = Program::new(&self.image, &mut memory).unwrap();
+            program.set_step_limit(Some(1000));
+            for arg in args {
+                program.stack_mut().push(*arg).unwrap();
+            }
+            program.call(0, 0).unwrap();
+            program.stack().as_slice().to_vec()
+        }
+    }
+
+    fn scale8(a: u32, b: u32) -> u32 {
+        a * b / 255
+    }
+
+    // Checks a one-argument routine against `expected` for every level.
+    fn check_levels(routine: &str, expected: impl Fn(u32) -> u32) {
+        let harness = Harness::new(routine, 1);
+        for x in 0..256 {
+            assert_eq!(harness.call(&[x]), vec![expected(x)], "{routine}({x})");
+        }
+    }
+
+    #[test]
+    fn waves() {
+        check_levels("std_triangle8", |x| if x < 128 { 2 * x } else { 2 * (255 - x) });
+        let sine = |x: u32| (128.0 + 127.0 * (f64::from(x) * core::f64::consts::PI / 128.0).sin()).round();
+        let sin8 = Harness::new("std_sin8", 1);
+        let cos8 = Harness::new("std_cos8", 1);
+        for x in 0..512 {
+            let level = f64::from(sin8.call(&[x])[0]);
+            assert!((level - sine(x)).abs() <= 1.0, "std_sin8({x}) = {level}");
+            assert_eq!(cos8.call(&[x]), sin8.call(&[x + 64]));
+        }
+        assert_eq!(sin8.call(&[64]), vec![255]);
+        assert_eq!(sin8.call(&[192]), vec![0]);
+    }
+
+    #[test]
+    fn gamma_and_easing() {
+        check_levels("std_gamma8", |x| (3 * scale8(x, x) + scale8(scale8(x, x), x)) / 4);
+        check_levels("std_ease_in8", |x| scale8(x, x));
+        check_levels("std_ease_out8", |x| 255 - scale8(255 - x, 255 - x));
+        check_levels("std_ease_in_out8", |x| {
+            if x < 128 {
+                2 * scale8(x, x)
+            } else {
+                255 - 2 * scale8(255 - x, 255 - x)
+            }
+        });
+        let gamma = Harness::new("std_gamma8", 1);
+        let exact = 255.0 * (128.0f64 / 255.0).powf(2.2);
+        assert!((f64::from(gamma.call(&[128])[0]) - exact).abs() < 2.0);
+    }
+
+    #[test]
+    fn hsv_matches_the_usual_conversion() {
+        let harness = Harness::new("std_hsv", 3);
+        for (h, s, v) in [(0, 255, 255), (43, 255, 255), (128, 255, 200), (200, 128, 255), (255, 0, 90)] {
+            let rise = h % 43 * 6;
+            let low = scale8(v, 255 - s);
+            let falling = scale8(v, 255 - scale8(s, rise));
+            let rising = scale8(v, 255 - scale8(s, 255 - rise));
+            let expected = match h / 43 {
+                0 => [v, rising, low],
+                1 => [falling, v, low],
+                2 => [low, v, rising],
+                3 => [low, falling, v],
+                4 => [rising, low, v],
+                _ => [v, low, falling],
+            };
+            assert_eq!(harness.call(&[h, s, v]), expected.to_vec(), "hsv({h}, {s}, {v})");
+        }
+        assert_eq!(harness.call(&[0, 255, 255]), vec![255, 0, 0]);
+        assert_eq!(harness.call(&[86, 255, 255]), vec![0, 255, 0]);
+        assert_eq!(harness.call(&[300, 0, 90]), vec![90, 90, 90]);
+    }
+
+    #[test]
+    fn palette_blends_between_entries() {
+        let data = ".shared_data colors\nfire:\n    #000000\n    #ff0000\n    #ffff00\n    #ffffff\n.end";
+        // The caller passes the count and position; the body adds the table.
+        let harness = Harness::with_body(
+            data,
+            "PUSH fire\nSLOAD 0\nSLOAD 1\nCALL_SHARED std_palette 3",
+        );
+        let color = |count, position| harness.call(&[count, position])[2..].to_vec();
+        assert_eq!(color(4, 0), vec![0, 0, 0]);
+        assert_eq!(color(4, 64), vec![255, 0, 0]);
+        assert_eq!(color(4, 96), vec![255, 128, 0]);
+        assert_eq!(color(4, 192), vec![255, 255, 255]);
+        // The last entry blends back into the first.
+        assert_eq!(color(4, 224), vec![127, 127, 127]);
+        assert_eq!(color(2, 128), vec![255, 0, 0]);
+        assert_eq!(color(0, 128), vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn wrap_distance_goes_the_short_way() {
+        let harness = Harness::new("std_wrap_distance", 3);
+        assert_eq!(harness.call(&[2, 58, 60]), vec![4]);
+        assert_eq!(harness.call(&[58, 2, 60]), vec![4]);
+        assert_eq!(harness.call(&[10, 40, 60]), vec![30]);
+        assert_eq!(harness.call(&[75, 10, 60]), vec![5]);
+        assert_eq!(harness.call(&[5, 5, 60]), vec![0]);
+        assert_eq!(harness.call(&[5, 9, 0]), vec![0]);
+    }
+
+    #[test]
+    fn links_only_what_is_used() {
+        let program = ".machine m locals 0 functions 1\n.func init index 0\n    PUSH 1\n    CALL_SHARED std_cos8 1\n    EXIT\n.end\n.end\n";
+        let linked = link(program, 9);
+        let headers: Vec<&str> = linked.lines().filter(|line| line.starts_with(".shared")).collect();
+        assert_eq!(
+            headers,
+            vec![
+                ".shared_data std_sine_quarter",
+                ".shared_func std_sin8 index 9 args 1 returns 1",
+                ".shared_func std_cos8 index 10 args 1 returns 1",
+            ]
+        );
+        let own = format!(".shared_func std_sin8 index 4\n    RET 1\n.end\n{program}");
+        assert!(!link(&own, 9).contains("std_sin8 index"));
+        assert_eq!(link("; std_hsv in a comment\n", 9), "");
+        assert_eq!(routines().first(), Some(&"std_scale8"));
+    }
+}
@@ -0,0 +1,423 @@
+// Symbols for editor tooling: every name a program defines, where, and
+// every place it is used.
+//
+// `GraphAssembler::with_symbols` feeds each parsed line to a
+// `SymbolRecorder`, including lines the assembler goes on to reject, so a
+// half-written program still gets completion. Definitions are taken as they
+// appear; uses are kept by name and scope and resolved once the whole
+// source has been seen, following the assembler's lookup order, so forward
+// references resolve too. Names the assembler generates for macro labels and
+// control blocks (`__...`) are left out.
+
+use light_machine::assembler::expression::{self, Value};
+use light_machine::assembler::syntax::{
+    strip_comment,
+    DataWord,
+    Directive,
+    Line,
+    Operand,
+    OperandKind,
+    ParamTargetName,
+};
+use light_machine::ProgramWord;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymbolKind {
+    Machine,
+    Instance,
+    Function,
+    SharedFunction,
+    Local,
+    Shared,
+    Frame,
+    Label,
+    Data,
+    Const,
+}
+
+impl SymbolKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SymbolKind::Machine => "machine",
+            SymbolKind::Instance => "instance",
+            SymbolKind::Function => "function",
+            SymbolKind::SharedFunction => "shared_function",
+            SymbolKind::Local => "local",
+            SymbolKind::Shared => "shared",
+            SymbolKind::Frame => "frame",
+            SymbolKind::Label => "label",
+            SymbolKind::Data => "data",
+            SymbolKind::Const => "const",
+        }
+    }
+}
+
+/// A span of source text. `start..end` are 0-based character columns in
+/// `line`; an empty span covers the whole line, for names that came out of
+/// a macro expansion.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SymbolLocation {
+    pub file: Option<String>,
+    pub line: u32,
+    pub start: u32,
+    pub end: u32,
+}
+
+impl SymbolLocation {
+    fn contains(&self, file: Option<&str>, line: u32, column: u32) -> bool {
+        self.file.as_deref() == file && self.line == line && (self.start..self.end).contains(&column)
+    }
+}
+
+/// One definition and its uses. `machine` and `function` give the scope it
+/// was defined in: a code label or `.frame` slot has both, a machine's
+/// functions, locals and data labels only `machine`, and top-level names
+/// neither. `value` is the index, offset or constant the name stands for,
+/// where the assembler got that far.
+#[derive(Clone, Debug)]
+pub struct SymbolInfo {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub machine: Option<String>,
+    pub function: Option<String>,
+    pub value: Option<ProgramWord>,
+    pub definition: SymbolLocation,
+    pub references: Vec<SymbolLocation>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    symbols: Vec<SymbolInfo>,
+}
+
+impl SymbolTable {
+    /// Every symbol, in the order they were defined.
+    pub fn symbols(&self) -> &[SymbolInfo] {
+        &self.symbols
+    }
+
+    /// The symbol defined or used at a position, for hover, go to
+    /// definition and rename.
+    pub fn at(&self, file: Option<&str>, line: u32, column: u32) -> Option<&SymbolInfo> {
+        self.symbols.iter().find(|symbol| {
+            symbol.definition.contains(file, line, column)
+                || symbol
+                    .references
+                    .iter()
+                    .any(|reference| reference.contains(file, line, column))
+        })
+    }
+}
+
+/// What a use may refer to.
+#[derive(Clone, Copy, PartialEq)]
+enum Usage {
+    /// An expression: a constant, label, function index, local or shared
+    /// global.
+    Value,
+    /// A `.frame` slot, or else a value.
+    Frame,
+    Local,
+    Shared,
+    Function,
+    SharedFunction,
+    Machine,
+}
+
+struct Use {
+    name: String,
+    usage: Usage,
+    machine: Option<String>,
+    function: Option<String>,
+    location: SymbolLocation,
+}
+
+#[derive(Default)]
+pub(crate) struct SymbolRecorder {
+    symbols: Vec<SymbolInfo>,
+    uses: Vec<Use>,
+    // The source line being assembled, and where its text lives so names
+    // borrowed from it can be told apart from names in a macro expansion.
+    file: Option<String>,
+    line: u32,
+    text: String,
+    text_at: std::ops::Range<usize>,
+    machine: Option<String>,
+    function: Option<String>,
+    in_data: bool,
+}
+
+impl SymbolRecorder {
+    pub(crate) fn start_line(&mut self, file: Option<&str>, line: u32, text: &str) {
+        if self.file.as_deref() != file {
+            self.file = file.map(str::to_string);
+        }
+        self.line = line;
+        self.text.clear();
+        self.text.push_str(text);
+        let start = text.as_ptr() as usize;
+        self.text_at = start..start + text.len();
+    }
+
+    /// Records the names in one parsed line. `value` looks up what the
+    /// assembler made of a definition once it has handled the line.
+    pub(crate) fn record(&mut self, line: &Line<'_>, value: impl Fn(SymbolKind, &str) -> Option<ProgramWord>) {
+        let define = |recorder: &mut Self, kind: SymbolKind, name: &str, declaration: bool| {
+            let value = value(kind, name);
+            recorder.define(kind, name, value, declaration);
+        };
+        match line {
+            Line::Empty => {}
+            Line::Label(name) => define(self, SymbolKind::Label, name, false),
+            Line::Data(DataWord::Expr(expr)) => self.use_expression(expr),
+            Line::Data(DataWord::Color(_)) => {}
+            Line::Instruction(instruction) => match instruction.operand {
+                Operand::None | Operand::Color(_) => {}
+                Operand::Call { name, argc } => {
+                    let usage = match instruction.mnemonic.operand_kind() {
+                        OperandKind::SharedTarget => Usage::SharedFunction,
+                        _ => Usage::Function,
+                    };
+                    self.use_name(name, usage);
+                    self.use_expression(argc);
+                }
+                Operand::Expr(text) => {
+                    let usage = match instruction.mnemonic.operand_kind() {
+                        OperandKind::StackSlot => Usage::Frame,
+                        OperandKind::Local => Usage::Local,
+                        OperandKind::SharedGlobal => Usage::Shared,
+                        OperandKind::SharedTarget => Usage::SharedFunction,
+                        _ => Usage::Value,
+                    };
+                    self.use_names(text, usage);
+                }
+            },
+            Line::Directive(directive) => match directive {
+                Directive::Machine { name, .. } => {
+                    define(self, SymbolKind::Machine, name, false);
+                    self.machine = Some(name.to_string());
+                }
+                Directive::Instance(instance) => {
+                    define(self, SymbolKind::Instance, instance.name, false);
+                    self.use_name(instance.machine, Usage::Machine);
+                    // Assignments name the locals of the instantiated machine.
+                    for (local, expr) in instance.assignments().flatten() {
+                        let machine = self.machine.replace(instance.machine.to_string());
+                        self.use_name(local, Usage::Local);
+                        self.machine = machine;
+                        self.use_expression(expr);
+                    }
+                }
+                Directive::Func(header) => {
+                    define(self, SymbolKind::Function, header.name, false);
+                    self.function = Some(header.name.to_string());
+                }
+                Directive::FuncDecl(header) => define(self, SymbolKind::Function, header.name, true),
+                Directive::SharedFunc(header) => {
+                    define(self, SymbolKind::SharedFunction, header.name, false);
+                    self.function = Some(header.name.to_string());
+                }
+                Directive::SharedFuncDecl(header) => define(self, SymbolKind::SharedFunction, header.name, true),
+                Directive::Local { name, .. } => define(self, SymbolKind::Local, name, false),
+                Directive::Shared { name, .. } => define(self, SymbolKind::Shared, name, false),
+                Directive::Frame { name, .. } => define(self, SymbolKind::Frame, name, false),
+                Directive::Data { name } | Directive::SharedData { name } => {
+                    define(self, SymbolKind::Data, name, false);
+                    self.in_data = true;
+                }
+                Directive::Const { name, expr } => {
+                    define(self, SymbolKind::Const, name, false);
+                    self.use_expression(expr);
+                }
+                Directive::Param(param) => {
+                    match param.target {
+                        ParamTargetName::Local(local) => self.use_name(local, Usage::Local),
+                        ParamTargetName::Function(function) => self.use_name(function, Usage::Function),
+                    }
+                    let values = [param.min, param.max, param.step, param.default];
+                    for expr in values.into_iter().flatten().filter(|expr| !expr.starts_with('#')) {
+                        self.use_expression(expr);
+                    }
+                }
+                Directive::End => {
+                    if self.function.take().is_none() && !std::mem::take(&mut self.in_data) {
+                        self.machine = None;
+                    }
+                }
+            },
+        }
+    }
+
+    /// Resolves every recorded use and returns the table.
+    pub(crate) fn table(&self) -> SymbolTable {
+        let mut symbols = self.symbols.clone();
+        for usage in &self.uses {
+            if let Some(symbol) = resolve(&symbols, usage).and_then(|index| symbols.get_mut(index)) {
+                symbol.references.push(usage.location.clone());
+            }
+        }
+        SymbolTable { symbols }
+    }
+
+    // A `.func_decl` stands in as the definition until the body turns up,
+    // and then becomes a reference. Anything else defined twice is an
+    // assembler error; the repeat is kept as a reference so rename still
+    // finds it.
+    fn define(&mut self, kind: SymbolKind, name: &str, value: Option<ProgramWord>, declaration: bool) {
+        if is_generated(name) {
+            return;
+        }
+        let location = self.location(name);
+        let (machine, function) = self.scope(kind);
+        let existing = self.symbols.iter_mut().find(|symbol| {
+            symbol.kind == kind && symbol.name == name && symbol.machine == machine && symbol.function == function
+        });
+        if let Some(symbol) = existing {
+            if symbol.value.is_none() {
+                symbol.value = value;
+            }
+            if declaration {
+                symbol.references.push(location);
+            } else {
+                let declared = std::mem::replace(&mut symbol.definition, location);
+                symbol.references.push(declared);
+            }
+            return;
+        }
+        self.symbols.push(SymbolInfo {
+            name: name.to_string(),
+            kind,
+            machine,
+            function,
+            value,
+            definition: location,
+            references: Vec::new(),
+        });
+    }
+
+    // Code labels and frame slots belong to their function; data labels,
+    // like a machine's other names, to the machine. Names outside any
+    // machine are top level.
+    fn scope(&self, kind: SymbolKind) -> (Option<String>, Option<String>) {
+        match kind {
+            SymbolKind::Machine | SymbolKind::Instance | SymbolKind::Const | SymbolKind::Shared
+            | SymbolKind::SharedFunction => (None, None),
+            SymbolKind::Label | SymbolKind::Frame if !self.in_data => (self.machine.clone(), self.function.clone()),
+            _ => (self.machine.clone(), None),
+        }
+    }
+
+    fn use_name(&mut self, name: &str, usage: Usage) {
+        if is_generated(name) {
+            return;
+        }
+        self.uses.push(Use {
+            name: name.to_string(),
+            usage,
+            machine: self.machine.clone(),
+            function: self.function.clone(),
+            location: self.location(name),
+        });
+    }
+
+    fn use_expression(&mut self, text: &str) {
+        self.use_names(text, Usage::Value);
+    }
+
+    // Only names used on their own get `usage`; inside a larger expression
+    // they are values.
+    fn use_names(&mut self, text: &str, usage: Usage) {
+        let usage = if expression::is_expression(text) { Usage::Value } else { usage };
+        let mut names = Vec::new();
+        // Every name is worth 1, which cannot fail any operator; a syntax
+        // error stops the scan, keeping the names before it.
+        let _ = expression::evaluate::<(), _>(text, |name| {
+            names.push((name.to_string(), self.location(name)));
+            Ok(Value::literal(1))
+        });
+        for (name, location) in names {
+            if is_generated(&name) {
+                continue;
+            }
+            self.uses.push(Use {
+                name,
+                usage,
+                machine: self.machine.clone(),
+                function: self.function.clone(),
+                location,
+            });
+        }
+    }
+
+    fn location(&self, name: &str) -> SymbolLocation {
+        let at = name.as_ptr() as usize;
+        let offset = if self.text_at.contains(&at) {
+            Some(at - self.text_at.start)
+        } else {
+            find_identifier(strip_comment(&self.text), name)
+        };
+        let (start, end) = offset
+            .and_then(|offset| self.text.get(..offset))
+            .map(|before| {
+                let start = before.chars().count() as u32;
+                (start, start + name.chars().count() as u32)
+            })
+            .unwrap_or((0, 0));
+        SymbolLocation {
+            file: self.file.clone(),
+            line: self.line,
+            start,
+            end,
+        }
+    }
+}
+
+fn is_generated(name: &str) -> bool {
+    name.starts_with("__")
+}
+
+// The byte offset of `name` as a whole word in `text`.
+fn find_identifier(text: &str, name: &str) -> Option<usize> {
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    text.match_indices(name).map(|(offset, _)| offset).find(|&offset| {
+        let before = text[..offset].chars().next_back();
+        let after = text[offset + name.len()..].chars().next();
+        !before.is_some_and(is_word) && !after.is_some_and(is_word)
+    })
+}
+
+// The symbol `usage` refers to, looked up the way the assembler resolves
+// operands: constants first, then names in the narrowest scope.
+fn resolve(symbols: &[SymbolInfo], usage: &Use) -> Option<usize> {
+    let find = |kind: SymbolKind, machine: Option<&str>, function: Option<&str>| {
+        symbols.iter().position(|symbol| {
+            symbol.kind == kind
+                && symbol.name == usage.name
+                && symbol.machine.as_deref() == machine
+                && symbol.function.as_deref() == function
+        })
+    };
+    let machine = usage.machine.as_deref();
+    let function = usage.function.as_deref();
+    let constant = || find(SymbolKind::Const, None, None).or_else(|| find(SymbolKind::Instance, None, None));
+    let value = || {
+        constant()
+            .or_else(|| find(SymbolKind::Label, machine, function))
+            .or_else(|| find(SymbolKind::Label, machine, None))
+            .or_else(|| find(SymbolKind::Label, None, None))
+            .or_else(|| find(SymbolKind::Function, machine, None))
+            .or_else(|| find(SymbolKind::Local, machine, None))
+            .or_else(|| find(SymbolKind::Shared, None, None))
+    };
+    match usage.usage {
+        Usage::Value => value(),
+        Usage::Frame => constant()
+            .or_else(|| find(SymbolKind::Frame, machine, function))
+            .or_else(value),
+        Usage::Local => constant().or_else(|| find(SymbolKind::Local, machine, None)),
+        Usage::Shared => constant().or_else(|| find(SymbolKind::Shared, None, None)),
+        Usage::SharedFunction => constant().or_else(|| find(SymbolKind::SharedFunction, None, None)),
+        Usage::Function => find(SymbolKind::Function, machine, None),
+        Usage::Machine => find(SymbolKind::Machine, None, None),
+    }
+}
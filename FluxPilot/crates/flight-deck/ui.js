@@ -117,9 +117,74 @@ function sendControlCall(element, control, args) {
   callMachineFunction(machineIndex, functionId, sanitized);
 }
 
+function paramLabel(name) {
+  return name
+    .split(/[_-]+/)
+    .filter(Boolean)
+    .map((word) => word[0].toUpperCase() + word.slice(1))
+    .join(" ");
+}
+
+function paramColor(values) {
+  const hex = values
+    .map((value) => Math.max(0, Math.min(255, value)).toString(16).padStart(2, "0"))
+    .join("");
+  return `#${hex}`;
+}
+
+// Controls for one track from the `.param`s its machine declares. Locals
+// for the control statics come from the hand-written control calling the
+// same function, if there is one.
+function controlsFromParams(params, previous) {
+  return params.map((param) => {
+    const functionId =
+      param.target === "function" ? param.target_index : undefined;
+    const match = previous.find(
+      (control) =>
+        functionId !== undefined && Number(control.functionId) === functionId
+    );
+    const defaults = Array.from(param.default_value);
+    const isColor = param.kind === "color";
+    return new MachineControlDescriptor({
+      id: param.name,
+      label: paramLabel(param.name),
+      functionId,
+      type: isColor ? "color_picker" : "range",
+      min: param.min,
+      max: param.max,
+      step: param.step,
+      defaultValue: isColor ? paramColor(defaults) : defaults[0] ?? param.min,
+      locals: match?.locals ?? [],
+    });
+  });
+}
+
+// Replace each track's controls with the ones its compiled program declares,
+// keeping the values of controls that are still there. Tracks whose machine
+// has no `.param`s keep their hand-written controls.
+export function applyProgramParams(params) {
+  const trackList = document.getElementById("track-list");
+  if (!trackList || !params || !params.length) {
+    return;
+  }
+  const tracks = Array.from(trackList.querySelectorAll("fd-track")).filter(
+    (item) => Boolean(item.dataset.machineId || item.dataset.machineAssembly)
+  );
+  tracks.forEach((track, index) => {
+    const own = params.filter((param) => param.machine === index);
+    const trackMachine = track.querySelector("fd-track-machine");
+    if (!own.length || !trackMachine) {
+      return;
+    }
+    const values = snapshotControlValues(trackMachine);
+    trackMachine.controls = controlsFromParams(own, trackMachine.controls);
+    applyControlValues(track, values);
+  });
+}
+
 const UI_STATE_VERSION = 1;
 
-function buildTrackElement({ name, meta, machineId, assembly, controls, source }) {
+function buildTrackElement({ name, meta, machineId, assembly, graph, controls, source }) {
   const track = document.createElement("fd-track");
   const machine = document.createElement("fd-track-machine");
   machine.setAttribute("name", name || "Machine");
@@ -134,6 +199,9 @@ function buildTrackElement({ name, meta, machineId, assembly, controls, source }
   if (assembly) {
     track.dataset.machineAssembly = assembly;
   }
+  if (graph) {
+    track.dataset.machineGraph = graph;
+  }
   track.appendChild(machine);
   return track;
 }
@@ -187,6 +255,25 @@ function applyControlValues(track, values) {
   });
 }
 
+// The program's source map rides along in the UI state as base64.
+function encodeSourceMap(bytes) {
+  if (!(bytes instanceof Uint8Array) || !bytes.length) {
+    return "";
+  }
+  return btoa(String.fromCharCode(...bytes));
+}
+
+function decodeSourceMap(text) {
+  if (typeof text !== "string" || !text) {
+    return new Uint8Array();
+  }
+  try {
+    return Uint8Array.from(atob(text), (ch) => ch.charCodeAt(0));
+  } catch (err) {
+    return new Uint8Array();
+  }
+}
+
 export function serializeUiState() {
   const trackList = document.getElementById("track-list");
   if (!trackList) {
@@ -201,6 +288,7 @@ export function serializeUiState() {
         machineId: track.dataset.machineId ?? "",
         source: track.dataset.machineSource ?? "",
         assembly: track.dataset.machineAssembly ?? "",
+        graph: track.dataset.machineGraph ?? "",
         controls: Array.isArray(trackMachine?.controls)
           ? trackMachine.controls
           : [],
@@ -211,6 +299,7 @@ export function serializeUiState() {
   const state = {
     version: UI_STATE_VERSION,
     tracks,
+    sourceMap: encodeSourceMap(globalThis.__programSourceMap),
   };
   const json = JSON.stringify(state);
   return new TextEncoder().encode(json);
@@ -240,6 +329,7 @@ export function restoreUiState(bytes, { replace = true } = {}) {
     return false;
   }
   const tracks = Array.isArray(payload.tracks) ? payload.tracks : [];
+  globalThis.__programSourceMap = decodeSourceMap(payload.sourceMap);
   const machineRegistry = new Map(
     DEFAULT_MACHINE_RACK.map((machine) => [machine.id, machine])
   );
@@ -265,6 +355,7 @@ export function restoreUiState(bytes, { replace = true } = {}) {
       meta,
       machineId,
       assembly,
+      graph: trackState.graph || "",
       controls,
       source: trackState.source || "",
     });
@@ -277,6 +368,7 @@ export function restoreUiState(bytes, { replace = true } = {}) {
 if (typeof globalThis !== "undefined") {
   globalThis.__serializeUiState = serializeUiState;
   globalThis.__restoreUiState = restoreUiState;
+  globalThis.__applyProgramParams = applyProgramParams;
 }
 export const CRAWLER_MACHINE = `
 .machine main locals 5 functions 6
@@ -398,6 +490,11 @@ export const SIMPLE_CRAWLER_MACHINE = `
     .local led_count 5
     .local current_index 6
 
+    .param speed range min 10 max 1000 step 10 default 1000 func set_speed
+    .param brightness range min 10 max 100 default 30 func set_brightness
+    .param led_count range min 1 max 1024 default 25 func set_led_count
+    .param color color default #468bc0 func set_rgb
+
     .func init index 0
       LOAD_STATIC init_red
       LSTORE red
@@ -499,6 +596,7 @@ export const PULSE_MACHINE = `
     .local frame_blue 5
     .local frame_green  6
 
+    .param color color default #468bc0 func set_rgb
 
     .func init index 0
       LOAD_STATIC init_red
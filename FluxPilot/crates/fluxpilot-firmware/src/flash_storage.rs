@@ -53,16 +53,16 @@ impl<F: NorFlash> FlashStorage<F> {
             .checked_sub(flash_base)
             .ok_or(StorageError::new(StorageErrorKind::InvalidHeader))?;
         let storage_offset = u32::try_from(storage_offset).map_err(|_| StorageError::new(StorageErrorKind::InvalidHeader))?;
-        if storage_offset as usize % F::READ_SIZE != 0
-            || storage_offset as usize % F::WRITE_SIZE != 0
-            || storage_offset as usize % F::ERASE_SIZE != 0
+        if !(storage_offset as usize).is_multiple_of(F::READ_SIZE)
+            || !(storage_offset as usize).is_multiple_of(F::WRITE_SIZE)
+            || !(storage_offset as usize).is_multiple_of(F::ERASE_SIZE)
         {
             return Err(StorageError::new(StorageErrorKind::UnalignedWrite));
         }
-        if WORD_SIZE_BYTES % F::WRITE_SIZE != 0 {
+        if !WORD_SIZE_BYTES.is_multiple_of(F::WRITE_SIZE) {
             return Err(StorageError::new(StorageErrorKind::UnalignedWrite));
         }
-        if HEADER_SIZE_BYTES % F::WRITE_SIZE != 0 || HEADER_SIZE_BYTES % F::READ_SIZE != 0 {
+        if !HEADER_SIZE_BYTES.is_multiple_of(F::WRITE_SIZE) || !HEADER_SIZE_BYTES.is_multiple_of(F::READ_SIZE) {
             return Err(StorageError::new(StorageErrorKind::UnalignedWrite));
         }
         let storage_end_offset = storage_offset
@@ -268,11 +268,11 @@ impl<F: NorFlash> FlashStorage<F> {
         if self.active_slot == 0 { 1 } else { 0 }
     }
 
-    fn slice_program_words<'a>(
-        &'a self,
+    fn slice_program_words(
+        &self,
         start: usize,
         program_words: usize,
-    ) -> Option<&'a [ProgramWord]> {
+    ) -> Option<&[ProgramWord]> {
         let bytes_len = program_words.checked_mul(WORD_SIZE_BYTES)?;
         let end = start.checked_add(bytes_len)?;
         if start < self.storage_start || end > self.storage_end {
@@ -285,7 +285,7 @@ impl<F: NorFlash> FlashStorage<F> {
         Some(unsafe { core::slice::from_raw_parts(start as *const ProgramWord, program_words) })
     }
 
-    fn slice_bytes<'a>(&'a self, start: usize, len: usize) -> Option<&'a [u8]> {
+    fn slice_bytes(&self, start: usize, len: usize) -> Option<&[u8]> {
         let end = start.checked_add(len)?;
         if start < self.storage_start || end > self.storage_end {
             return None;
@@ -294,11 +294,11 @@ impl<F: NorFlash> FlashStorage<F> {
         Some(unsafe { core::slice::from_raw_parts(start as *const u8, len) })
     }
 
-    fn program_slice_for_slot<'a>(
-        &'a self,
+    fn program_slice_for_slot(
+        &self,
         slot: usize,
         program_words: usize,
-    ) -> Option<&'a [ProgramWord]> {
+    ) -> Option<&[ProgramWord]> {
         let (start, end) = self.program_bounds(slot)?;
         let max_len = end
             .checked_sub(start)
@@ -360,12 +360,12 @@ impl<F: NorFlash> FlashStorage<F> {
             .ok_or(StorageError::new(StorageErrorKind::ProgramTooLarge))
     }
 
-    fn ui_state_slice_for_slot<'a>(
-        &'a self,
+    fn ui_state_slice_for_slot(
+        &self,
         slot: usize,
         program_words: usize,
         ui_state_len: usize,
-    ) -> Option<&'a [u8]> {
+    ) -> Option<&[u8]> {
         let ui_start = self.ui_state_start(slot, program_words).ok()?;
         let slot_end = self.slot_end_addr(slot).ok()?;
         let ui_end = ui_start.checked_add(ui_state_len)?;
@@ -391,7 +391,7 @@ impl<F: NorFlash> FlashStorage<F> {
         crc32_bytes(ui_state) == expected_crc
     }
 
-    fn program_slice<'a>(&'a self) -> &'a [ProgramWord] {
+    fn program_slice(&self) -> &[ProgramWord] {
         let Some((start, end)) = self.program_bounds(self.active_slot) else {
             return &[];
         };
@@ -506,7 +506,7 @@ impl<F: NorFlash> FlashStorage<F> {
     }
 
     fn flash_program_words(&mut self, start: u32, program: &[ProgramWord]) -> Result<(), StorageError> {
-        if start as usize % F::WRITE_SIZE != 0 {
+        if !(start as usize).is_multiple_of(F::WRITE_SIZE) {
             return Err(StorageError::new(StorageErrorKind::UnalignedWrite));
         }
         let byte_len = program
@@ -535,7 +535,7 @@ impl<F: NorFlash> FlashStorage<F> {
         bytes: &[u8],
         allow_pad: bool,
     ) -> Result<(), StorageError> {
-        if start as usize % F::WRITE_SIZE != 0 {
+        if !(start as usize).is_multiple_of(F::WRITE_SIZE) {
             return Err(StorageError::new(StorageErrorKind::UnalignedWrite));
         }
         if F::WRITE_SIZE > MAX_WRITE_BUFFER {
@@ -703,7 +703,7 @@ impl<F: NorFlash> Storage for FlashStorage<F> {
             )
             .ok_or(StorageError::new(StorageErrorKind::UiStateTooLarge))?;
         let is_last = end_byte == loader.ui_state_len;
-        if !is_last && block.len() % F::WRITE_SIZE != 0 {
+        if !is_last && !block.len().is_multiple_of(F::WRITE_SIZE) {
             return Err(StorageError::new(StorageErrorKind::UnalignedWrite));
         }
         self.flash_program_bytes(offset, block, is_last)?;
@@ -757,6 +757,13 @@ impl<F: NorFlash> Storage for FlashStorage<F> {
         Program::new(program, memory).map_err(StorageError::invalid_program)
     }
 
+    fn get_program_len(&mut self, program_number: ProgramNumber) -> Result<u32, StorageError> {
+        if program_number.value() != 0 {
+            return Err(StorageError::new(StorageErrorKind::UnknownProgram));
+        }
+        Ok(self.program_words as u32)
+    }
+
     fn get_ui_state_len(&mut self, program_number: ProgramNumber) -> Result<u32, StorageError> {
         if program_number.value() != 0 {
             return Err(StorageError::new(StorageErrorKind::UnknownProgram));
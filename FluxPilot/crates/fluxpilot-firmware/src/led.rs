@@ -68,7 +68,7 @@ pub async fn led_loop<
             }
         }
 
-        let _ = writer.write(data.clone());
+        let _ = writer.write(*data);
 
         let wait_duration = match Duration::from_millis(FRAME_TARGET_MS)
             .checked_sub(start_time.elapsed())
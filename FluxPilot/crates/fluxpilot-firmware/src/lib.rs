@@ -1,4 +1,6 @@
 #![no_std]
+// See pliot for why `StorageError` results are allowed to be large.
+#![allow(clippy::result_large_err)]
 
 pub mod program;
 pub mod led;
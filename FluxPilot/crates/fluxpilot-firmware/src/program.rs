@@ -1,5 +1,7 @@
 use light_machine::builder::{FunctionIndex, MachineBuilderError, Op, ProgramBuilder};
 
+/// Builds the fallback program at the start of `buffer` and returns its
+/// length in words, which `MemStorage::with_program` takes.
 #[link_section = ".coldtext"]
 #[inline(never)]
 pub fn default_program(buffer: &mut [u16]) -> Result<usize, MachineBuilderError> {
@@ -54,7 +56,7 @@ pub fn default_program(buffer: &mut [u16]) -> Result<usize, MachineBuilderError>
     let (_function_index, machine) = function.finish()?;
 
     let program_builder = machine.finish()?;
-    let descriptor = program_builder.finish_program();
+    let descriptor = program_builder.finish_program()?;
 
     Ok(descriptor.length)
 }
@@ -72,17 +72,9 @@ where
                 let wrote = {
                     let mut guard = shared.lock().await;
                     let PliotShared { pliot } = &mut *guard;
-                    let result = pliot.process_message(
-                        frame.as_mut_slice(),
-                        out_buf.as_mut_slice(),
-                    );
-
-                    match result {
-                        Ok(wrote) => wrote,
-                        Err(_) => {
-                            0
-                        }
-                    }
+                    pliot
+                        .process_message(frame.as_mut_slice(), out_buf.as_mut_slice())
+                        .unwrap_or_default()
                 };
                 frame.clear();
 
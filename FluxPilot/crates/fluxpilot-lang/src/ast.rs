@@ -0,0 +1,187 @@
+// The parsed program. Every node keeps the span it was parsed from so the
+// checker can report against the source.
+
+use crate::Span;
+
+#[derive(Clone, Debug)]
+pub struct Name {
+    pub text: String,
+    pub span: Span,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TypeName {
+    Int,
+    Bool,
+    Color,
+}
+
+/// Machines and instances in source order, which is the order of their
+/// machine indexes.
+#[derive(Clone, Debug)]
+pub struct Program {
+    pub items: Vec<Item>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Item {
+    Machine(Machine),
+    Instance(Instance),
+}
+
+#[derive(Clone, Debug)]
+pub struct Machine {
+    pub name: Name,
+    pub vars: Vec<Var>,
+    pub functions: Vec<Function>,
+}
+
+/// `var name[: type] = literal;`, one per machine local (three for a color).
+#[derive(Clone, Debug)]
+pub struct Var {
+    pub name: Name,
+    pub ty: Option<(TypeName, Span)>,
+    pub value: Expr,
+}
+
+/// `instance name of machine { var = literal, ... }`.
+#[derive(Clone, Debug)]
+pub struct Instance {
+    pub name: Name,
+    pub machine: Name,
+    pub values: Vec<(Name, Expr)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Param {
+    pub name: Name,
+    pub ty: TypeName,
+}
+
+#[derive(Clone, Debug)]
+pub struct Function {
+    pub name: Name,
+    pub params: Vec<Param>,
+    pub returns: Option<(TypeName, Span)>,
+    pub body: Block,
+}
+
+#[derive(Clone, Debug)]
+pub struct Block {
+    pub statements: Vec<Statement>,
+    /// The final expression without a `;`, which is the block's value.
+    pub tail: Option<Box<Expr>>,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug)]
+pub enum Statement {
+    Let {
+        name: Name,
+        ty: Option<(TypeName, Span)>,
+        value: Expr,
+    },
+    /// `name = value`; `op` is set for `+=` and friends.
+    Assign {
+        name: Name,
+        op: Option<BinaryOp>,
+        value: Expr,
+    },
+    For {
+        var: Name,
+        from: Expr,
+        to: Expr,
+        body: Block,
+    },
+    Return {
+        value: Option<Expr>,
+        span: Span,
+    },
+    Expr(Expr),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Mod,
+    BitAnd,
+    BitOr,
+    BitXor,
+    And,
+    Or,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl BinaryOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Subtract => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Mod => "%",
+            BinaryOp::BitAnd => "&",
+            BinaryOp::BitOr => "|",
+            BinaryOp::BitXor => "^",
+            BinaryOp::And => "&&",
+            BinaryOp::Or => "||",
+            BinaryOp::Equal => "==",
+            BinaryOp::NotEqual => "!=",
+            BinaryOp::Less => "<",
+            BinaryOp::LessEqual => "<=",
+            BinaryOp::Greater => ">",
+            BinaryOp::GreaterEqual => ">=",
+        }
+    }
+
+    pub fn is_comparison(self) -> bool {
+        matches!(
+            self,
+            BinaryOp::Equal
+                | BinaryOp::NotEqual
+                | BinaryOp::Less
+                | BinaryOp::LessEqual
+                | BinaryOp::Greater
+                | BinaryOp::GreaterEqual
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+}
+
+#[derive(Clone, Debug)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug)]
+pub enum ExprKind {
+    Int(u32),
+    Bool(bool),
+    Color(u8, u8, u8),
+    Name(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(Name, Vec<Expr>),
+    /// `.r`, `.g` or `.b` of a color.
+    Field(Box<Expr>, Name),
+    If {
+        condition: Box<Expr>,
+        then: Block,
+        otherwise: Option<Box<Expr>>,
+    },
+    Block(Block),
+}
@@ -0,0 +1,1135 @@
+// Type checks the program and lowers it to a `ProgramGraph`.
+//
+// Each machine becomes a machine type and an instance. Its `var`s are
+// locals, laid out in declaration order, and their starting values become
+// the instance's initial locals. `init`, `start_frame` and `get_color` keep
+// the indexes the host calls them by; a machine that leaves one out gets a
+// body that does nothing. Other functions follow in declaration order and
+// are reached with `CALL`.
+//
+// Parameters and `let`s live on the stack and are read and written with
+// `SLOAD`/`SSTORE` relative to the frame pointer. A `let` is the value its
+// initializer left on the stack, so the generator tracks the stack depth at
+// every point and frees a block's `let`s when the block ends. The host
+// calls the entry points without a frame, with the frame pointer at 0, so
+// they end in `EXIT` instead of `RET`; `get_color` first moves its color
+// into the three slots it was given and drops everything above them.
+
+use std::collections::{HashMap, HashSet};
+
+use flight_deck::program_graph::{
+    FunctionRef, FunctionSource, MachineTypeId, ProgramGraph, ProgramGraphBuilder, TypeItem,
+    WordRef,
+};
+use light_machine::{Ops, ProgramWord};
+
+use crate::ast::{
+    BinaryOp, Block, Expr, ExprKind, Function, Instance, Item, Machine, Name, Program, Statement,
+    TypeName, UnaryOp,
+};
+use crate::{Diagnostic, Span};
+
+const INIT_INDEX: ProgramWord = 0;
+const START_FRAME_INDEX: ProgramWord = 1;
+const GET_COLOR_INDEX: ProgramWord = 2;
+const ENTRY_POINT_COUNT: ProgramWord = 3;
+
+// pliot calls shared function 0 when a program loads. The language has no
+// shared functions, so the graph fills it with `EXIT`.
+const SHARED_FUNCTION_COUNT: ProgramWord = 1;
+
+const BUILTINS: [&str; 3] = ["rgb", "min", "max"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Type {
+    Int,
+    Bool,
+    Color,
+    /// What statements and blocks without a value have.
+    Unit,
+    /// A block that always returns before it ends.
+    Never,
+    /// An expression that already has a diagnostic; checked against
+    /// anything without another one.
+    Error,
+}
+
+impl Type {
+    fn size(self) -> ProgramWord {
+        match self {
+            Type::Int | Type::Bool => 1,
+            Type::Color => 3,
+            Type::Unit | Type::Never | Type::Error => 0,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Type::Int => "int",
+            Type::Bool => "bool",
+            Type::Color => "color",
+            Type::Unit => "no value",
+            Type::Never => "never",
+            Type::Error => "error",
+        }
+    }
+
+    /// Whether a value of this type is acceptable where `expected` is.
+    fn fits(self, expected: Type) -> bool {
+        self == expected || matches!(self, Type::Never | Type::Error) || expected == Type::Error
+    }
+}
+
+impl From<TypeName> for Type {
+    fn from(name: TypeName) -> Self {
+        match name {
+            TypeName::Int => Type::Int,
+            TypeName::Bool => Type::Bool,
+            TypeName::Color => Type::Color,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Entry {
+    Init,
+    StartFrame,
+    GetColor,
+}
+
+impl Entry {
+    fn from_name(name: &str) -> Option<Entry> {
+        match name {
+            "init" => Some(Entry::Init),
+            "start_frame" => Some(Entry::StartFrame),
+            "get_color" => Some(Entry::GetColor),
+            _ => None,
+        }
+    }
+
+    fn index(self) -> ProgramWord {
+        match self {
+            Entry::Init => INIT_INDEX,
+            Entry::StartFrame => START_FRAME_INDEX,
+            Entry::GetColor => GET_COLOR_INDEX,
+        }
+    }
+
+    fn params(self) -> &'static [Type] {
+        match self {
+            Entry::Init => &[],
+            Entry::StartFrame => &[Type::Int],
+            Entry::GetColor => &[Type::Color, Type::Int],
+        }
+    }
+
+    fn returns(self) -> Type {
+        match self {
+            Entry::GetColor => Type::Color,
+            Entry::Init | Entry::StartFrame => Type::Unit,
+        }
+    }
+
+    fn declaration(self) -> &'static str {
+        match self {
+            Entry::Init => "fn init()",
+            Entry::StartFrame => "fn start_frame(tick: int)",
+            Entry::GetColor => "fn get_color(below: color, index: int) -> color",
+        }
+    }
+
+    // What the host gets from a machine that leaves the function out:
+    // nothing, or the color from the machine below it.
+    fn default_body(self) -> Vec<WordRef> {
+        match self {
+            Entry::Init | Entry::StartFrame => vec![WordRef::Literal(Ops::Exit.into())],
+            Entry::GetColor => vec![
+                WordRef::Literal(Ops::Pop.into()),
+                WordRef::Literal(Ops::Exit.into()),
+            ],
+        }
+    }
+}
+
+struct Signature {
+    index: ProgramWord,
+    entry: Option<Entry>,
+    params: Vec<Type>,
+    returns: Type,
+}
+
+struct MachineInfo {
+    type_id: MachineTypeId,
+    vars: HashMap<String, (Type, ProgramWord)>,
+    values: Vec<u32>,
+}
+
+pub fn generate(program: &Program) -> Result<ProgramGraph, Vec<Diagnostic>> {
+    let mut diagnostics = Vec::new();
+    let mut builder = ProgramGraphBuilder::new(SHARED_FUNCTION_COUNT);
+    let mut machines: HashMap<String, MachineInfo> = HashMap::new();
+    let mut names = HashSet::new();
+    for item in &program.items {
+        let name = match item {
+            Item::Machine(machine) => &machine.name,
+            Item::Instance(instance) => &instance.name,
+        };
+        if !names.insert(name.text.clone()) {
+            diagnostics.push(Diagnostic::new(
+                name.span,
+                format!("`{}` is already defined", name.text),
+            ));
+        }
+        match item {
+            Item::Machine(machine) => {
+                let info = machine_type(&mut builder, machine, &mut diagnostics);
+                builder.add_machine_instance_with_locals(info.type_id, initial_locals(&info.values));
+                machines.insert(machine.name.text.clone(), info);
+            }
+            Item::Instance(instance) => {
+                instance_of(&mut builder, instance, &machines, &mut diagnostics);
+            }
+        }
+    }
+    if machines.is_empty() {
+        diagnostics.push(Diagnostic::new(
+            Span::new(1, 0, 0),
+            "a program needs at least one `machine`".to_string(),
+        ));
+    }
+    if diagnostics.is_empty() {
+        Ok(builder.build())
+    } else {
+        diagnostics.sort_by_key(|diagnostic| (diagnostic.span.line, diagnostic.span.start));
+        Err(diagnostics)
+    }
+}
+
+// The locals an instance starts with; the VM zeroes the rest.
+fn initial_locals(values: &[u32]) -> Vec<(ProgramWord, ProgramWord)> {
+    values
+        .iter()
+        .enumerate()
+        .filter(|(_, value)| **value != 0)
+        .map(|(local, value)| (local as ProgramWord, *value as ProgramWord))
+        .collect()
+}
+
+// A `var` starts from a literal, stored as the instance's initial locals.
+fn literal(expr: &Expr) -> Result<(Type, Vec<u32>), Diagnostic> {
+    let (ty, values) = match expr.kind {
+        ExprKind::Int(value) => (Type::Int, vec![value]),
+        ExprKind::Bool(value) => (Type::Bool, vec![u32::from(value)]),
+        ExprKind::Color(r, g, b) => (Type::Color, vec![r.into(), g.into(), b.into()]),
+        _ => {
+            return Err(Diagnostic::new(
+                expr.span,
+                "a machine variable starts from a literal such as `0`, `true` or `#000000`; \
+                 compute other values in `init`"
+                    .to_string(),
+            ));
+        }
+    };
+    if let Some(value) = values.iter().find(|value| **value > u32::from(ProgramWord::MAX)) {
+        return Err(Diagnostic::new(
+            expr.span,
+            format!("starting values are 16 bits and `{value}` does not fit; set it in `init`"),
+        ));
+    }
+    Ok((ty, values))
+}
+
+fn machine_type(
+    builder: &mut ProgramGraphBuilder,
+    machine: &Machine,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> MachineInfo {
+    let mut vars = HashMap::new();
+    let mut values = Vec::new();
+    for var in &machine.vars {
+        let (ty, start) = match literal(&var.value) {
+            Ok(literal) => literal,
+            Err(err) => {
+                diagnostics.push(err);
+                (Type::Error, Vec::new())
+            }
+        };
+        if let Some((expected, span)) = var.ty
+            && !ty.fits(expected.into())
+        {
+            diagnostics.push(Diagnostic::new(
+                span,
+                format!("`{}` is declared `{}` but starts as `{}`", var.name.text, Type::from(expected).name(), ty.name()),
+            ));
+        }
+        if vars.contains_key(&var.name.text) {
+            diagnostics.push(Diagnostic::new(
+                var.name.span,
+                format!("`{}` is already a variable of `{}`", var.name.text, machine.name.text),
+            ));
+            continue;
+        }
+        vars.insert(var.name.text.clone(), (ty, values.len() as ProgramWord));
+        values.extend(start);
+    }
+
+    let mut signatures: HashMap<String, Signature> = HashMap::new();
+    let mut next_index = ENTRY_POINT_COUNT;
+    let mut compiled = Vec::new();
+    for function in &machine.functions {
+        let name = &function.name;
+        if BUILTINS.contains(&name.text.as_str()) {
+            diagnostics.push(Diagnostic::new(
+                name.span,
+                format!("`{}` is a built-in function and cannot be redefined", name.text),
+            ));
+            continue;
+        }
+        if signatures.contains_key(&name.text) {
+            diagnostics.push(Diagnostic::new(
+                name.span,
+                format!("`{}` is already a function of `{}`", name.text, machine.name.text),
+            ));
+            continue;
+        }
+        let params: Vec<Type> = function.params.iter().map(|param| param.ty.into()).collect();
+        let returns = function.returns.map_or(Type::Unit, |(ty, _)| ty.into());
+        let entry = Entry::from_name(&name.text);
+        let index = match entry {
+            Some(entry) => {
+                if params != entry.params() || returns != entry.returns() {
+                    diagnostics.push(Diagnostic::new(
+                        name.span,
+                        format!("the host calls `{}` as `{}`", name.text, entry.declaration()),
+                    ));
+                }
+                entry.index()
+            }
+            None => {
+                let index = next_index;
+                next_index = next_index.saturating_add(1);
+                index
+            }
+        };
+        signatures.insert(
+            name.text.clone(),
+            Signature {
+                index,
+                entry,
+                params,
+                returns,
+            },
+        );
+        compiled.push(function);
+    }
+
+    let mut items = Vec::new();
+    let mut sources = Vec::new();
+    for function in compiled {
+        let Some(signature) = signatures.get(&function.name.text) else {
+            continue;
+        };
+        let compiler = FunctionCompiler::new(&vars, &signatures, signature, diagnostics);
+        let (words, lines) = compiler.function(function);
+        let mut source = FunctionSource::new(Some(machine.name.text.clone()), function.name.text.clone());
+        for (offset, line) in lines.into_iter().enumerate() {
+            let offset = offset as ProgramWord;
+            source.add_words(offset, offset.saturating_add(1), None, line);
+        }
+        let function_id = builder.add_function(words);
+        items.push(TypeItem::Function(FunctionRef {
+            index: signature.index,
+            function_id,
+        }));
+        sources.push((signature.index, source));
+    }
+    for entry in [Entry::Init, Entry::StartFrame, Entry::GetColor] {
+        if signatures.values().any(|signature| signature.entry == Some(entry)) {
+            continue;
+        }
+        let function_id = builder.add_function(entry.default_body());
+        items.push(TypeItem::Function(FunctionRef {
+            index: entry.index(),
+            function_id,
+        }));
+    }
+
+    let type_id = builder.add_machine_type_with_sources(items, values.len() as ProgramWord, next_index, sources);
+    MachineInfo {
+        type_id,
+        vars,
+        values,
+    }
+}
+
+fn instance_of(
+    builder: &mut ProgramGraphBuilder,
+    instance: &Instance,
+    machines: &HashMap<String, MachineInfo>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let Some(machine) = machines.get(&instance.machine.text) else {
+        diagnostics.push(Diagnostic::new(
+            instance.machine.span,
+            format!("`{}` is not a machine defined above", instance.machine.text),
+        ));
+        return;
+    };
+    let mut values = machine.values.clone();
+    for (name, value) in &instance.values {
+        let Some((ty, local)) = machine.vars.get(&name.text) else {
+            diagnostics.push(Diagnostic::new(
+                name.span,
+                format!("`{}` has no variable `{}`", instance.machine.text, name.text),
+            ));
+            continue;
+        };
+        match literal(value) {
+            Ok((found, start)) if found.fits(*ty) => {
+                for (offset, word) in start.into_iter().enumerate() {
+                    if let Some(slot) = values.get_mut(usize::from(*local) + offset) {
+                        *slot = word;
+                    }
+                }
+            }
+            Ok((found, _)) => diagnostics.push(Diagnostic::new(
+                value.span,
+                format!("`{}` is `{}`, not `{}`", name.text, ty.name(), found.name()),
+            )),
+            Err(err) => diagnostics.push(err),
+        }
+    }
+    builder.add_machine_instance_with_locals(machine.type_id, initial_locals(&values));
+}
+
+// A stack slot relative to the frame pointer, and what it holds.
+struct Binding {
+    name: String,
+    ty: Type,
+    slot: ProgramWord,
+}
+
+// Where an assignment writes.
+enum Place {
+    Stack(ProgramWord),
+    Local(ProgramWord),
+}
+
+struct FunctionCompiler<'a> {
+    vars: &'a HashMap<String, (Type, ProgramWord)>,
+    signatures: &'a HashMap<String, Signature>,
+    signature: &'a Signature,
+    diagnostics: &'a mut Vec<Diagnostic>,
+    words: Vec<WordRef>,
+    lines: Vec<u32>,
+    labels: Vec<Option<ProgramWord>>,
+    fixups: Vec<(usize, usize)>,
+    scopes: Vec<Vec<Binding>>,
+    depth: ProgramWord,
+    line: u32,
+}
+
+impl<'a> FunctionCompiler<'a> {
+    fn new(
+        vars: &'a HashMap<String, (Type, ProgramWord)>,
+        signatures: &'a HashMap<String, Signature>,
+        signature: &'a Signature,
+        diagnostics: &'a mut Vec<Diagnostic>,
+    ) -> Self {
+        Self {
+            vars,
+            signatures,
+            signature,
+            diagnostics,
+            words: Vec::new(),
+            lines: Vec::new(),
+            labels: Vec::new(),
+            fixups: Vec::new(),
+            scopes: Vec::new(),
+            depth: 0,
+            line: 0,
+        }
+    }
+
+    // The function's words and the source line of each.
+    fn function(mut self, function: &Function) -> (Vec<WordRef>, Vec<u32>) {
+        self.line = function.name.span.line;
+        let mut params = Vec::new();
+        for param in &function.params {
+            if params.iter().any(|binding: &Binding| binding.name == param.name.text) {
+                self.error(param.name.span, format!("`{}` is already a parameter", param.name.text));
+            }
+            let ty = Type::from(param.ty);
+            params.push(Binding {
+                name: param.name.text.clone(),
+                ty,
+                slot: self.depth,
+            });
+            self.depth = self.depth.saturating_add(ty.size());
+        }
+        self.scopes.push(params);
+        let ty = self.block(&function.body);
+        let returns = self.signature.returns;
+        if !ty.fits(returns) {
+            let span = function.body.tail.as_ref().map_or(function.name.span, |tail| tail.span);
+            let message = if returns == Type::Unit {
+                format!("`{}` has no return type, so its body cannot end in a value", function.name.text)
+            } else {
+                format!("`{}` returns `{}`, not `{}`", function.name.text, returns.name(), ty.name())
+            };
+            self.error(span, message);
+        }
+        if ty != Type::Never {
+            self.line = function.body.span.line;
+            self.emit_return();
+        }
+        for (at, label) in std::mem::take(&mut self.fixups) {
+            // Every label is placed unless codegen itself has a bug; say so
+            // rather than emit a jump to nowhere.
+            let Some(offset) = self.labels.get(label).copied().flatten() else {
+                let message = format!("internal error: a jump target in `{}` was never placed", function.name.text);
+                self.error(function.name.span, message);
+                continue;
+            };
+            if let Some(word) = self.words.get_mut(at) {
+                *word = WordRef::LabelOffset(offset);
+            }
+        }
+        (self.words, self.lines)
+    }
+
+    fn error(&mut self, span: Span, message: String) {
+        self.diagnostics.push(Diagnostic::new(span, message));
+    }
+
+    fn word(&mut self, word: WordRef) {
+        self.words.push(word);
+        self.lines.push(self.line);
+    }
+
+    fn op(&mut self, op: Ops) {
+        self.word(WordRef::Literal(op.into()));
+    }
+
+    // `op` with an operand word, popping `pops` values and pushing `pushes`.
+    fn op_with(&mut self, op: Ops, operand: ProgramWord, pops: ProgramWord, pushes: ProgramWord) {
+        self.op(op);
+        self.word(WordRef::Literal(operand));
+        self.depth = self.depth.saturating_sub(pops).saturating_add(pushes);
+    }
+
+    // An op that pops two values and pushes one.
+    fn binary_op(&mut self, op: Ops) {
+        self.op(op);
+        self.depth = self.depth.saturating_sub(1);
+    }
+
+    fn push(&mut self, value: u32) {
+        let high = (value >> 16) as ProgramWord;
+        let low = value as ProgramWord;
+        if high == 0 {
+            self.op_with(Ops::Push, low, 0, 1);
+            return;
+        }
+        // PUSH takes one program word, so build wider values from halves.
+        self.op_with(Ops::Push, high, 0, 1);
+        for _ in 0..2 {
+            self.op_with(Ops::Push, 0x100, 0, 1);
+            self.binary_op(Ops::Multiply);
+        }
+        if low != 0 {
+            self.op_with(Ops::Push, low, 0, 1);
+            self.binary_op(Ops::Add);
+        }
+    }
+
+    fn pop(&mut self, count: ProgramWord) {
+        for _ in 0..count {
+            self.op(Ops::Pop);
+        }
+        self.depth = self.depth.saturating_sub(count);
+    }
+
+    fn load(&mut self, slot: ProgramWord) {
+        self.op_with(Ops::StackLoad, slot, 0, 1);
+    }
+
+    fn store(&mut self, slot: ProgramWord) {
+        self.op_with(Ops::StackStore, slot, 1, 0);
+    }
+
+    fn new_label(&mut self) -> usize {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+
+    fn place(&mut self, label: usize) {
+        let offset = self.words.len() as ProgramWord;
+        if let Some(slot) = self.labels.get_mut(label) {
+            *slot = Some(offset);
+        }
+    }
+
+    fn push_label(&mut self, label: usize) {
+        self.op(Ops::Push);
+        self.fixups.push((self.words.len(), label));
+        self.word(WordRef::LabelOffset(0));
+        self.depth = self.depth.saturating_add(1);
+    }
+
+    // A branch pops the two values it compares and the target.
+    fn branch(&mut self, op: Ops, label: usize) {
+        self.push_label(label);
+        self.op(op);
+        self.depth = self.depth.saturating_sub(3);
+    }
+
+    fn jump(&mut self, label: usize) {
+        self.push_label(label);
+        self.op(Ops::Jump);
+        self.depth = self.depth.saturating_sub(1);
+    }
+
+    // Leaves the function with a value of its return type on top.
+    fn emit_return(&mut self) {
+        let returns = self.signature.returns;
+        match self.signature.entry {
+            None => self.op_with(Ops::Return, returns.size(), 0, 0),
+            Some(Entry::Init | Entry::StartFrame) => self.op(Ops::Exit),
+            Some(Entry::GetColor) => {
+                // The host reads the color from the three slots it passed in.
+                let before = self.depth;
+                let from = before.saturating_sub(3);
+                for offset in 0..3 {
+                    self.load(from + offset);
+                    self.store(offset);
+                }
+                self.pop(before.saturating_sub(3));
+                self.op(Ops::Exit);
+                self.depth = before;
+            }
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<(Type, Place)> {
+        for scope in self.scopes.iter().rev() {
+            if let Some(binding) = scope.iter().rev().find(|binding| binding.name == name) {
+                return Some((binding.ty, Place::Stack(binding.slot)));
+            }
+        }
+        self.vars
+            .get(name)
+            .map(|(ty, local)| (*ty, Place::Local(*local)))
+    }
+
+    fn block(&mut self, block: &Block) -> Type {
+        let base = self.depth;
+        self.scopes.push(Vec::new());
+        let mut diverges = false;
+        for statement in &block.statements {
+            diverges |= self.statement(statement);
+        }
+        let lets = self.depth.saturating_sub(base);
+        let ty = match &block.tail {
+            Some(tail) => self.expr(tail),
+            None if diverges => Type::Never,
+            None => Type::Unit,
+        };
+        // Move the value down over the block's `let`s, then drop them.
+        if lets > 0 {
+            for offset in 0..ty.size() {
+                self.load(base + lets + offset);
+                self.store(base + offset);
+            }
+            self.pop(lets);
+        }
+        self.scopes.pop();
+        self.depth = base.saturating_add(ty.size());
+        ty
+    }
+
+    // Whether the statement always returns.
+    fn statement(&mut self, statement: &Statement) -> bool {
+        match statement {
+            Statement::Let { name, ty, value } => {
+                self.line = name.span.line;
+                let found = self.expr(value);
+                if matches!(found, Type::Unit | Type::Never) {
+                    self.error(value.span, format!("`{}` needs a value", name.text));
+                } else if let Some((expected, span)) = ty
+                    && !found.fits((*expected).into())
+                {
+                    self.error(
+                        *span,
+                        format!("`{}` is declared `{}` but its value is `{}`", name.text, Type::from(*expected).name(), found.name()),
+                    );
+                }
+                let ty = ty.map_or(found, |(expected, _)| expected.into());
+                let slot = self.depth.saturating_sub(found.size());
+                if let Some(scope) = self.scopes.last_mut() {
+                    scope.push(Binding {
+                        name: name.text.clone(),
+                        ty,
+                        slot,
+                    });
+                }
+                false
+            }
+            Statement::Assign { name, op, value } => {
+                self.line = name.span.line;
+                let Some((ty, place)) = self.lookup(&name.text) else {
+                    self.error(name.span, format!("unknown variable `{}`", name.text));
+                    self.expr(value);
+                    return false;
+                };
+                let found = match op {
+                    Some(op) => {
+                        self.read(ty, &place);
+                        let rhs = self.expr(value);
+                        self.arithmetic(*op, ty, rhs, value.span)
+                    }
+                    None => self.expr(value),
+                };
+                if !found.fits(ty) {
+                    self.error(value.span, format!("`{}` is `{}`, not `{}`", name.text, ty.name(), found.name()));
+                }
+                for offset in (0..found.size()).rev() {
+                    match place {
+                        Place::Stack(slot) => self.store(slot + offset),
+                        Place::Local(local) => self.op_with(Ops::LocalStore, local + offset, 1, 0),
+                    }
+                }
+                false
+            }
+            Statement::For { var, from, to, body } => {
+                self.line = var.span.line;
+                for bound in [from, to] {
+                    let ty = self.expr(bound);
+                    if !ty.fits(Type::Int) {
+                        self.error(bound.span, format!("loop bounds are `int`, not `{}`", ty.name()));
+                    }
+                }
+                // The counter, then the bound, which is evaluated once.
+                let slot = self.depth.saturating_sub(2);
+                self.scopes.push(vec![Binding {
+                    name: var.text.clone(),
+                    ty: Type::Int,
+                    slot,
+                }]);
+                let top = self.new_label();
+                let done = self.new_label();
+                self.place(top);
+                self.load(slot);
+                self.load(slot + 1);
+                self.branch(Ops::BranchGreaterThanEq, done);
+                let ty = self.block(body);
+                self.pop(ty.size());
+                self.line = var.span.line;
+                self.load(slot);
+                self.push(1);
+                self.binary_op(Ops::Add);
+                self.store(slot);
+                self.jump(top);
+                self.place(done);
+                self.pop(2);
+                self.scopes.pop();
+                false
+            }
+            Statement::Return { value, span } => {
+                self.line = span.line;
+                let before = self.depth;
+                let found = match value {
+                    Some(value) => self.expr(value),
+                    None => Type::Unit,
+                };
+                let returns = self.signature.returns;
+                if !found.fits(returns) {
+                    let span = value.as_ref().map_or(*span, |value| value.span);
+                    self.error(span, format!("this function returns `{}`, not `{}`", returns.name(), found.name()));
+                }
+                self.emit_return();
+                self.depth = before;
+                true
+            }
+            Statement::Expr(expr) => {
+                let ty = self.expr(expr);
+                self.pop(ty.size());
+                ty == Type::Never
+            }
+        }
+    }
+
+    fn read(&mut self, ty: Type, place: &Place) {
+        for offset in 0..ty.size() {
+            match place {
+                Place::Stack(slot) => self.load(slot + offset),
+                Place::Local(local) => self.op_with(Ops::LocalLoad, local + offset, 0, 1),
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Type {
+        let outer = self.line;
+        self.line = expr.span.line;
+        let ty = self.expr_kind(expr);
+        self.line = outer;
+        ty
+    }
+
+    fn expr_kind(&mut self, expr: &Expr) -> Type {
+        match &expr.kind {
+            ExprKind::Int(value) => {
+                self.push(*value);
+                Type::Int
+            }
+            ExprKind::Bool(value) => {
+                self.push(u32::from(*value));
+                Type::Bool
+            }
+            ExprKind::Color(r, g, b) => {
+                for component in [r, g, b] {
+                    self.push(u32::from(*component));
+                }
+                Type::Color
+            }
+            ExprKind::Name(name) => match self.lookup(name) {
+                Some((ty, place)) => {
+                    self.read(ty, &place);
+                    ty
+                }
+                None => {
+                    self.error(expr.span, format!("unknown name `{name}`"));
+                    Type::Error
+                }
+            },
+            ExprKind::Unary(UnaryOp::Negate, operand) => {
+                self.push(0);
+                let ty = self.expr(operand);
+                if !ty.fits(Type::Int) {
+                    self.error(operand.span, format!("`-` needs an `int`, not `{}`", ty.name()));
+                }
+                self.binary_op(Ops::Subtract);
+                Type::Int
+            }
+            ExprKind::Unary(UnaryOp::Not, operand) => {
+                let ty = self.expr(operand);
+                if !ty.fits(Type::Bool) {
+                    self.error(operand.span, format!("`!` needs a `bool`, not `{}`", ty.name()));
+                }
+                self.op(Ops::Not);
+                Type::Bool
+            }
+            ExprKind::Binary(op, lhs, rhs) => {
+                let left = self.expr(lhs);
+                let right = self.expr(rhs);
+                if op.is_comparison() {
+                    self.check_comparison(*op, left, right, expr.span);
+                    self.comparison_value(*op);
+                    Type::Bool
+                } else {
+                    self.arithmetic(*op, left, right, expr.span)
+                }
+            }
+            ExprKind::Call(name, args) => self.call(name, args, expr.span),
+            ExprKind::Field(value, field) => {
+                let ty = self.expr(value);
+                if ty == Type::Error {
+                    return Type::Error;
+                }
+                if ty != Type::Color {
+                    self.error(field.span, format!("only colors have fields, and this is `{}`", ty.name()));
+                    return Type::Error;
+                }
+                let base = self.depth.saturating_sub(3);
+                match field.text.as_str() {
+                    "r" => self.pop(2),
+                    "g" => {
+                        self.pop(1);
+                        self.op(Ops::Swap);
+                        self.pop(1);
+                    }
+                    "b" => {
+                        self.store(base);
+                        self.pop(1);
+                    }
+                    other => {
+                        self.error(field.span, format!("a color has fields `r`, `g` and `b`, not `{other}`"));
+                        return Type::Error;
+                    }
+                }
+                Type::Int
+            }
+            ExprKind::If {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let otherwise_label = self.new_label();
+                self.condition(condition, otherwise_label);
+                let base = self.depth;
+                let then_ty = self.block(then);
+                let ty = match otherwise {
+                    Some(otherwise) => {
+                        let end = self.new_label();
+                        self.jump(end);
+                        self.place(otherwise_label);
+                        self.depth = base;
+                        let otherwise_ty = self.expr(otherwise);
+                        self.place(end);
+                        match (then_ty, otherwise_ty) {
+                            (Type::Never, ty) | (ty, Type::Never) => ty,
+                            (Type::Error, _) | (_, Type::Error) => Type::Error,
+                            (a, b) if a == b => a,
+                            (a, b) => {
+                                self.error(
+                                    expr.span,
+                                    format!("`if` gives `{}` but `else` gives `{}`", a.name(), b.name()),
+                                );
+                                Type::Error
+                            }
+                        }
+                    }
+                    None => {
+                        self.place(otherwise_label);
+                        if !then_ty.fits(Type::Unit) {
+                            let span = then.tail.as_ref().map_or(then.span, |tail| tail.span);
+                            self.error(span, "an `if` without `else` cannot give a value".to_string());
+                            Type::Error
+                        } else {
+                            Type::Unit
+                        }
+                    }
+                };
+                self.depth = base.saturating_add(ty.size());
+                ty
+            }
+            ExprKind::Block(block) => self.block(block),
+        }
+    }
+
+    fn check_comparison(&mut self, op: BinaryOp, left: Type, right: Type, span: Span) {
+        let fits = match op {
+            BinaryOp::Equal | BinaryOp::NotEqual => {
+                matches!((left, right), (Type::Int, Type::Int) | (Type::Bool, Type::Bool))
+            }
+            _ => left == Type::Int && right == Type::Int,
+        };
+        if !fits && left != Type::Error && right != Type::Error {
+            let hint = if left == Type::Color || right == Type::Color {
+                "; compare the `r`, `g` and `b` fields instead"
+            } else {
+                ""
+            };
+            self.error(
+                span,
+                format!("cannot compare `{}` with `{}` using `{}`{hint}", left.name(), right.name(), op.symbol()),
+            );
+        }
+    }
+
+    // The two compared values are on top; leaves 1 or 0.
+    fn comparison_value(&mut self, op: BinaryOp) {
+        let base = self.depth.saturating_sub(2);
+        let taken = self.new_label();
+        let end = self.new_label();
+        let (branch, if_taken) = match op {
+            BinaryOp::NotEqual => (Ops::BranchEqual, 0),
+            _ => (branch_op(op), 1),
+        };
+        self.branch(branch, taken);
+        self.push(1 - if_taken);
+        self.jump(end);
+        self.place(taken);
+        self.depth = base;
+        self.push(if_taken);
+        self.place(end);
+    }
+
+    // Jumps to `otherwise` when `condition` is false.
+    fn condition(&mut self, condition: &Expr, otherwise: usize) {
+        if let ExprKind::Binary(op, lhs, rhs) = &condition.kind
+            && op.is_comparison()
+        {
+            let left = self.expr(lhs);
+            let right = self.expr(rhs);
+            self.line = condition.span.line;
+            self.check_comparison(*op, left, right, condition.span);
+            match op {
+                BinaryOp::Equal => {
+                    let then = self.new_label();
+                    self.branch(Ops::BranchEqual, then);
+                    self.jump(otherwise);
+                    self.place(then);
+                }
+                BinaryOp::NotEqual => self.branch(Ops::BranchEqual, otherwise),
+                BinaryOp::Less => self.branch(Ops::BranchGreaterThanEq, otherwise),
+                BinaryOp::LessEqual => self.branch(Ops::BranchGreaterThan, otherwise),
+                BinaryOp::Greater => self.branch(Ops::BranchLessThanEq, otherwise),
+                _ => self.branch(Ops::BranchLessThan, otherwise),
+            }
+            return;
+        }
+        let ty = self.expr(condition);
+        if !ty.fits(Type::Bool) {
+            self.error(condition.span, format!("conditions are `bool`, not `{}`", ty.name()));
+        }
+        self.push(0);
+        self.branch(Ops::BranchEqual, otherwise);
+    }
+
+    // Both operands are on top.
+    fn arithmetic(&mut self, op: BinaryOp, left: Type, right: Type, span: Span) -> Type {
+        if left == Type::Error || right == Type::Error {
+            return Type::Error;
+        }
+        match (op, left, right) {
+            (BinaryOp::And, Type::Bool, Type::Bool) => {
+                self.binary_op(Ops::And);
+                Type::Bool
+            }
+            (BinaryOp::Or, Type::Bool, Type::Bool) => {
+                self.binary_op(Ops::Or);
+                Type::Bool
+            }
+            (BinaryOp::And | BinaryOp::Or, _, _) => {
+                self.error(span, format!("`{}` needs two `bool`s", op.symbol()));
+                Type::Error
+            }
+            (_, Type::Int, Type::Int) => {
+                self.binary_op(int_op(op));
+                Type::Int
+            }
+            (BinaryOp::Add | BinaryOp::Subtract, Type::Color, Type::Color) => {
+                // Component by component, into the left color's slots.
+                let base = self.depth.saturating_sub(6);
+                for offset in 0..3 {
+                    self.load(base + offset);
+                    self.load(base + 3 + offset);
+                    self.binary_op(int_op(op));
+                    self.store(base + offset);
+                }
+                self.pop(3);
+                Type::Color
+            }
+            (BinaryOp::Multiply | BinaryOp::Divide, Type::Color, Type::Int) => {
+                let base = self.depth.saturating_sub(4);
+                for offset in 0..3 {
+                    self.load(base + offset);
+                    self.load(base + 3);
+                    self.binary_op(int_op(op));
+                    self.store(base + offset);
+                }
+                self.pop(1);
+                Type::Color
+            }
+            _ => {
+                let hint = match (left, right) {
+                    (Type::Int, Type::Color) => "; put the color first",
+                    (Type::Color, _) | (_, Type::Color) => {
+                        "; colors add and subtract colors, and multiply and divide by an `int`"
+                    }
+                    _ => "",
+                };
+                self.error(
+                    span,
+                    format!("cannot use `{}` on `{}` and `{}`{hint}", op.symbol(), left.name(), right.name()),
+                );
+                Type::Error
+            }
+        }
+    }
+
+    fn call(&mut self, name: &Name, args: &[Expr], span: Span) -> Type {
+        let base = self.depth;
+        let (params, returns): (Vec<Type>, Type) = match name.text.as_str() {
+            "rgb" => (vec![Type::Int; 3], Type::Color),
+            "min" | "max" => (vec![Type::Int; 2], Type::Int),
+            _ => match self.signatures.get(&name.text) {
+                Some(signature) if signature.entry.is_some() => {
+                    self.error(
+                        name.span,
+                        format!("`{}` is called by the host; move what you need into another function", name.text),
+                    );
+                    return Type::Error;
+                }
+                Some(signature) => (signature.params.clone(), signature.returns),
+                None => {
+                    self.error(name.span, format!("unknown function `{}`", name.text));
+                    return Type::Error;
+                }
+            },
+        };
+        if args.len() != params.len() {
+            self.error(
+                span,
+                format!("`{}` takes {} arguments but was given {}", name.text, params.len(), args.len()),
+            );
+            return Type::Error;
+        }
+        for (arg, expected) in args.iter().zip(&params) {
+            let ty = self.expr(arg);
+            if !ty.fits(*expected) {
+                self.error(arg.span, format!("expected `{}`, found `{}`", expected.name(), ty.name()));
+            }
+        }
+        self.line = span.line;
+        match name.text.as_str() {
+            "rgb" => {}
+            "min" | "max" => {
+                // Keep the first value if it wins, otherwise store the
+                // second over it.
+                let keep = self.new_label();
+                let end = self.new_label();
+                self.load(base);
+                self.load(base + 1);
+                let op = if name.text == "min" {
+                    Ops::BranchLessThan
+                } else {
+                    Ops::BranchGreaterThan
+                };
+                self.branch(op, keep);
+                self.store(base);
+                self.jump(end);
+                self.place(keep);
+                self.depth = base + 2;
+                self.pop(1);
+                self.place(end);
+            }
+            _ => {
+                let words: ProgramWord = params.iter().map(|ty| ty.size()).sum();
+                let index = self.signatures.get(&name.text).map_or(0, |signature| signature.index);
+                self.push(u32::from(words));
+                self.push(u32::from(index));
+                self.op(Ops::Call);
+            }
+        }
+        self.depth = base.saturating_add(returns.size());
+        returns
+    }
+}
+
+fn branch_op(op: BinaryOp) -> Ops {
+    match op {
+        BinaryOp::Less => Ops::BranchLessThan,
+        BinaryOp::LessEqual => Ops::BranchLessThanEq,
+        BinaryOp::Greater => Ops::BranchGreaterThan,
+        BinaryOp::GreaterEqual => Ops::BranchGreaterThanEq,
+        _ => Ops::BranchEqual,
+    }
+}
+
+fn int_op(op: BinaryOp) -> Ops {
+    match op {
+        BinaryOp::Add => Ops::Add,
+        BinaryOp::Subtract => Ops::Subtract,
+        BinaryOp::Multiply => Ops::Multiply,
+        BinaryOp::Divide => Ops::Divide,
+        BinaryOp::Mod => Ops::Mod,
+        BinaryOp::BitAnd => Ops::BitwiseAnd,
+        BinaryOp::BitOr => Ops::BitwiseOr,
+        BinaryOp::BitXor => Ops::BitwiseXor,
+        BinaryOp::And => Ops::And,
+        BinaryOp::Or => Ops::Or,
+        _ => unreachable!("comparisons are lowered to branches"),
+    }
+}
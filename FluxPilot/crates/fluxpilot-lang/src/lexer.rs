@@ -0,0 +1,272 @@
+// Splits source text into tokens, each with the line and columns it came
+// from so the parser and checker can point back at it.
+
+use crate::{Diagnostic, Span};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    Ident(String),
+    Int(u32),
+    Color(u8, u8, u8),
+    Machine,
+    Instance,
+    Of,
+    Var,
+    Let,
+    Fn,
+    If,
+    Else,
+    For,
+    In,
+    Return,
+    True,
+    False,
+    LeftBrace,
+    RightBrace,
+    LeftParen,
+    RightParen,
+    Comma,
+    Semicolon,
+    Colon,
+    Arrow,
+    Dot,
+    DotDot,
+    Assign,
+    PlusAssign,
+    MinusAssign,
+    StarAssign,
+    SlashAssign,
+    PercentAssign,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Ampersand,
+    Pipe,
+    Caret,
+    Bang,
+    AndAnd,
+    OrOr,
+    Eof,
+}
+
+impl TokenKind {
+    /// How the token is written, for "expected ..." messages.
+    pub fn describe(&self) -> String {
+        let text = match self {
+            TokenKind::Ident(name) => return format!("`{name}`"),
+            TokenKind::Int(value) => return format!("`{value}`"),
+            TokenKind::Color(r, g, b) => return format!("`#{r:02x}{g:02x}{b:02x}`"),
+            TokenKind::Machine => "machine",
+            TokenKind::Instance => "instance",
+            TokenKind::Of => "of",
+            TokenKind::Var => "var",
+            TokenKind::Let => "let",
+            TokenKind::Fn => "fn",
+            TokenKind::If => "if",
+            TokenKind::Else => "else",
+            TokenKind::For => "for",
+            TokenKind::In => "in",
+            TokenKind::Return => "return",
+            TokenKind::True => "true",
+            TokenKind::False => "false",
+            TokenKind::LeftBrace => "{",
+            TokenKind::RightBrace => "}",
+            TokenKind::LeftParen => "(",
+            TokenKind::RightParen => ")",
+            TokenKind::Comma => ",",
+            TokenKind::Semicolon => ";",
+            TokenKind::Colon => ":",
+            TokenKind::Arrow => "->",
+            TokenKind::Dot => ".",
+            TokenKind::DotDot => "..",
+            TokenKind::Assign => "=",
+            TokenKind::PlusAssign => "+=",
+            TokenKind::MinusAssign => "-=",
+            TokenKind::StarAssign => "*=",
+            TokenKind::SlashAssign => "/=",
+            TokenKind::PercentAssign => "%=",
+            TokenKind::Equal => "==",
+            TokenKind::NotEqual => "!=",
+            TokenKind::Less => "<",
+            TokenKind::LessEqual => "<=",
+            TokenKind::Greater => ">",
+            TokenKind::GreaterEqual => ">=",
+            TokenKind::Plus => "+",
+            TokenKind::Minus => "-",
+            TokenKind::Star => "*",
+            TokenKind::Slash => "/",
+            TokenKind::Percent => "%",
+            TokenKind::Ampersand => "&",
+            TokenKind::Pipe => "|",
+            TokenKind::Caret => "^",
+            TokenKind::Bang => "!",
+            TokenKind::AndAnd => "&&",
+            TokenKind::OrOr => "||",
+            TokenKind::Eof => return "the end of the source".to_string(),
+        };
+        format!("`{text}`")
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+pub fn tokenize(source: &str) -> Result<Vec<Token>, Diagnostic> {
+    let mut tokens = Vec::new();
+    let mut last_line = 1;
+    let mut last_column = 0;
+    for (index, text) in source.lines().enumerate() {
+        let line = index as u32 + 1;
+        let chars: Vec<char> = text.chars().collect();
+        let mut column = 0;
+        while column < chars.len() {
+            let c = chars[column];
+            let start = column;
+            if c.is_whitespace() {
+                column += 1;
+                continue;
+            }
+            if c == '/' && chars.get(column + 1) == Some(&'/') {
+                break;
+            }
+            let span = |end: usize| Span::new(line, start as u32, end as u32);
+            if c.is_ascii_alphabetic() || c == '_' {
+                while column < chars.len() && (chars[column].is_ascii_alphanumeric() || chars[column] == '_') {
+                    column += 1;
+                }
+                let word: String = chars[start..column].iter().collect();
+                tokens.push(Token {
+                    kind: keyword(&word).unwrap_or(TokenKind::Ident(word)),
+                    span: span(column),
+                });
+                continue;
+            }
+            if c.is_ascii_digit() {
+                while column < chars.len() && (chars[column].is_ascii_alphanumeric() || chars[column] == '_') {
+                    column += 1;
+                }
+                let word: String = chars[start..column].iter().filter(|c| **c != '_').collect();
+                let value = match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+                    Some(hex) => u32::from_str_radix(hex, 16),
+                    None => word.parse::<u32>(),
+                };
+                let value = value.map_err(|_| {
+                    Diagnostic::new(span(column), format!("`{word}` is not a number that fits in 32 bits"))
+                })?;
+                tokens.push(Token {
+                    kind: TokenKind::Int(value),
+                    span: span(column),
+                });
+                continue;
+            }
+            if c == '#' {
+                column += 1;
+                while column < chars.len() && chars[column].is_ascii_alphanumeric() {
+                    column += 1;
+                }
+                let digits: String = chars[start + 1..column].iter().collect();
+                let kind = parse_color(&digits).ok_or_else(|| {
+                    Diagnostic::new(span(column), format!("`#{digits}` is not a color; write it as `#rrggbb`"))
+                })?;
+                tokens.push(Token {
+                    kind,
+                    span: span(column),
+                });
+                continue;
+            }
+            let next = chars.get(column + 1).copied();
+            let (kind, width) = match (c, next) {
+                ('-', Some('>')) => (TokenKind::Arrow, 2),
+                ('.', Some('.')) => (TokenKind::DotDot, 2),
+                ('=', Some('=')) => (TokenKind::Equal, 2),
+                ('!', Some('=')) => (TokenKind::NotEqual, 2),
+                ('<', Some('=')) => (TokenKind::LessEqual, 2),
+                ('>', Some('=')) => (TokenKind::GreaterEqual, 2),
+                ('+', Some('=')) => (TokenKind::PlusAssign, 2),
+                ('-', Some('=')) => (TokenKind::MinusAssign, 2),
+                ('*', Some('=')) => (TokenKind::StarAssign, 2),
+                ('/', Some('=')) => (TokenKind::SlashAssign, 2),
+                ('%', Some('=')) => (TokenKind::PercentAssign, 2),
+                ('&', Some('&')) => (TokenKind::AndAnd, 2),
+                ('|', Some('|')) => (TokenKind::OrOr, 2),
+                ('{', _) => (TokenKind::LeftBrace, 1),
+                ('}', _) => (TokenKind::RightBrace, 1),
+                ('(', _) => (TokenKind::LeftParen, 1),
+                (')', _) => (TokenKind::RightParen, 1),
+                (',', _) => (TokenKind::Comma, 1),
+                (';', _) => (TokenKind::Semicolon, 1),
+                (':', _) => (TokenKind::Colon, 1),
+                ('.', _) => (TokenKind::Dot, 1),
+                ('=', _) => (TokenKind::Assign, 1),
+                ('<', _) => (TokenKind::Less, 1),
+                ('>', _) => (TokenKind::Greater, 1),
+                ('+', _) => (TokenKind::Plus, 1),
+                ('-', _) => (TokenKind::Minus, 1),
+                ('*', _) => (TokenKind::Star, 1),
+                ('/', _) => (TokenKind::Slash, 1),
+                ('%', _) => (TokenKind::Percent, 1),
+                ('&', _) => (TokenKind::Ampersand, 1),
+                ('|', _) => (TokenKind::Pipe, 1),
+                ('^', _) => (TokenKind::Caret, 1),
+                ('!', _) => (TokenKind::Bang, 1),
+                _ => {
+                    return Err(Diagnostic::new(span(column + 1), format!("unexpected character `{c}`")));
+                }
+            };
+            column += width;
+            tokens.push(Token {
+                kind,
+                span: span(column),
+            });
+        }
+        last_line = line;
+        last_column = chars.len() as u32;
+    }
+    tokens.push(Token {
+        kind: TokenKind::Eof,
+        span: Span::new(last_line, last_column, last_column),
+    });
+    Ok(tokens)
+}
+
+fn keyword(word: &str) -> Option<TokenKind> {
+    Some(match word {
+        "machine" => TokenKind::Machine,
+        "instance" => TokenKind::Instance,
+        "of" => TokenKind::Of,
+        "var" => TokenKind::Var,
+        "let" => TokenKind::Let,
+        "fn" => TokenKind::Fn,
+        "if" => TokenKind::If,
+        "else" => TokenKind::Else,
+        "for" => TokenKind::For,
+        "in" => TokenKind::In,
+        "return" => TokenKind::Return,
+        "true" => TokenKind::True,
+        "false" => TokenKind::False,
+        _ => return None,
+    })
+}
+
+fn parse_color(digits: &str) -> Option<TokenKind> {
+    if digits.len() != 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let value = u32::from_str_radix(digits, 16).ok()?;
+    Some(TokenKind::Color(
+        (value >> 16) as u8,
+        (value >> 8) as u8,
+        value as u8,
+    ))
+}
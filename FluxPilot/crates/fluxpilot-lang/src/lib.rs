@@ -0,0 +1,75 @@
+//! A small typed, expression-based language for light machines.
+//!
+//! `compile` turns source text into a flight-deck `ProgramGraph`, the same
+//! graph the assembler builds, so identical machines and functions dedupe
+//! and the result is emitted, optimized and source-mapped the same way. The
+//! language is described in this crate's `README.md`.
+
+use flight_deck::program_graph::ProgramGraph;
+
+mod ast;
+mod codegen;
+mod lexer;
+mod parser;
+
+#[cfg(test)]
+mod test;
+
+/// Characters `start..end` of `line`. Lines count from 1 and columns from 0,
+/// as in the assembler's diagnostics. A span that covers several lines
+/// keeps only its first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub line: u32,
+    pub start: u32,
+    pub end: u32,
+}
+
+impl Span {
+    pub fn new(line: u32, start: u32, end: u32) -> Self {
+        Self { line, start, end }
+    }
+
+    /// From the start of `self` to the end of `other`, if it is on the
+    /// same line.
+    pub fn to(self, other: Span) -> Span {
+        if other.line == self.line && other.end > self.end {
+            Span::new(self.line, self.start, other.end)
+        } else {
+            self
+        }
+    }
+}
+
+/// An error in the source, and where it is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(span: Span, message: String) -> Self {
+        Self { span, message }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.span.line,
+            self.span.start + 1,
+            self.message
+        )
+    }
+}
+
+/// Compiles a program. A syntax error stops at the first one; type errors
+/// are all reported, in source order.
+pub fn compile(source: &str) -> Result<ProgramGraph, Vec<Diagnostic>> {
+    let tokens = lexer::tokenize(source).map_err(|err| vec![err])?;
+    let program = parser::parse(tokens).map_err(|err| vec![err])?;
+    codegen::generate(&program)
+}
@@ -0,0 +1,457 @@
+// Recursive descent over the token list. Parsing stops at the first error,
+// since what follows a syntax error is rarely worth reporting.
+
+use crate::ast::{
+    BinaryOp, Block, Expr, ExprKind, Function, Instance, Item, Machine, Name, Param, Program, Statement,
+    TypeName, UnaryOp, Var,
+};
+use crate::lexer::{Token, TokenKind};
+use crate::{Diagnostic, Span};
+
+pub fn parse(tokens: Vec<Token>) -> Result<Program, Diagnostic> {
+    let mut parser = Parser { tokens, position: 0 };
+    let mut program = Program { items: Vec::new() };
+    loop {
+        match parser.peek() {
+            TokenKind::Machine => program.items.push(Item::Machine(parser.machine()?)),
+            TokenKind::Instance => program.items.push(Item::Instance(parser.instance()?)),
+            TokenKind::Eof => return Ok(program),
+            _ => return Err(parser.unexpected("`machine` or `instance`")),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+// Binary operators by precedence, loosest first.
+const PRECEDENCE: &[&[(TokenKind, BinaryOp)]] = &[
+    &[(TokenKind::OrOr, BinaryOp::Or)],
+    &[(TokenKind::AndAnd, BinaryOp::And)],
+    &[
+        (TokenKind::Equal, BinaryOp::Equal),
+        (TokenKind::NotEqual, BinaryOp::NotEqual),
+        (TokenKind::Less, BinaryOp::Less),
+        (TokenKind::LessEqual, BinaryOp::LessEqual),
+        (TokenKind::Greater, BinaryOp::Greater),
+        (TokenKind::GreaterEqual, BinaryOp::GreaterEqual),
+    ],
+    &[(TokenKind::Pipe, BinaryOp::BitOr)],
+    &[(TokenKind::Caret, BinaryOp::BitXor)],
+    &[(TokenKind::Ampersand, BinaryOp::BitAnd)],
+    &[(TokenKind::Plus, BinaryOp::Add), (TokenKind::Minus, BinaryOp::Subtract)],
+    &[
+        (TokenKind::Star, BinaryOp::Multiply),
+        (TokenKind::Slash, BinaryOp::Divide),
+        (TokenKind::Percent, BinaryOp::Mod),
+    ],
+];
+
+impl Parser {
+    fn peek(&self) -> &TokenKind {
+        &self.token().kind
+    }
+
+    fn peek_next(&self) -> &TokenKind {
+        self.tokens
+            .get(self.position + 1)
+            .map_or(&TokenKind::Eof, |token| &token.kind)
+    }
+
+    fn token(&self) -> &Token {
+        // The lexer always ends the list with `Eof`, and nothing moves past it.
+        &self.tokens[self.position.min(self.tokens.len() - 1)]
+    }
+
+    fn span(&self) -> Span {
+        self.token().span
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.token().clone();
+        if token.kind != TokenKind::Eof {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn eat(&mut self, kind: &TokenKind) -> bool {
+        if self.peek() == kind {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, kind: &TokenKind) -> Result<Span, Diagnostic> {
+        if self.peek() == kind {
+            Ok(self.advance().span)
+        } else {
+            Err(self.unexpected(&kind.describe()))
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> Diagnostic {
+        Diagnostic::new(
+            self.span(),
+            format!("expected {expected}, found {}", self.peek().describe()),
+        )
+    }
+
+    fn name(&mut self, what: &str) -> Result<Name, Diagnostic> {
+        match self.peek().clone() {
+            TokenKind::Ident(text) => Ok(Name {
+                text,
+                span: self.advance().span,
+            }),
+            _ => Err(self.unexpected(what)),
+        }
+    }
+
+    fn type_name(&mut self) -> Result<(TypeName, Span), Diagnostic> {
+        let name = self.name("a type")?;
+        let ty = match name.text.as_str() {
+            "int" => TypeName::Int,
+            "bool" => TypeName::Bool,
+            "color" => TypeName::Color,
+            other => {
+                return Err(Diagnostic::new(
+                    name.span,
+                    format!("unknown type `{other}`; the types are `int`, `bool` and `color`"),
+                ));
+            }
+        };
+        Ok((ty, name.span))
+    }
+
+    fn optional_type(&mut self) -> Result<Option<(TypeName, Span)>, Diagnostic> {
+        if self.eat(&TokenKind::Colon) {
+            Ok(Some(self.type_name()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn machine(&mut self) -> Result<Machine, Diagnostic> {
+        self.expect(&TokenKind::Machine)?;
+        let name = self.name("a machine name")?;
+        self.expect(&TokenKind::LeftBrace)?;
+        let mut machine = Machine {
+            name,
+            vars: Vec::new(),
+            functions: Vec::new(),
+        };
+        loop {
+            match self.peek() {
+                TokenKind::Var => {
+                    self.advance();
+                    let name = self.name("a variable name")?;
+                    let ty = self.optional_type()?;
+                    self.expect(&TokenKind::Assign)?;
+                    let value = self.expression()?;
+                    self.expect(&TokenKind::Semicolon)?;
+                    machine.vars.push(Var { name, ty, value });
+                }
+                TokenKind::Fn => machine.functions.push(self.function()?),
+                TokenKind::RightBrace => {
+                    self.advance();
+                    return Ok(machine);
+                }
+                _ => return Err(self.unexpected("`var`, `fn` or `}`")),
+            }
+        }
+    }
+
+    fn instance(&mut self) -> Result<Instance, Diagnostic> {
+        self.expect(&TokenKind::Instance)?;
+        let name = self.name("an instance name")?;
+        self.expect(&TokenKind::Of)?;
+        let machine = self.name("a machine name")?;
+        let mut values = Vec::new();
+        if self.eat(&TokenKind::LeftBrace) {
+            while !self.eat(&TokenKind::RightBrace) {
+                let var = self.name("a variable name")?;
+                self.expect(&TokenKind::Assign)?;
+                let value = self.expression()?;
+                values.push((var, value));
+                if !self.eat(&TokenKind::Comma) {
+                    self.expect(&TokenKind::RightBrace)?;
+                    break;
+                }
+            }
+        } else {
+            self.expect(&TokenKind::Semicolon)?;
+        }
+        Ok(Instance {
+            name,
+            machine,
+            values,
+        })
+    }
+
+    fn function(&mut self) -> Result<Function, Diagnostic> {
+        self.expect(&TokenKind::Fn)?;
+        let name = self.name("a function name")?;
+        self.expect(&TokenKind::LeftParen)?;
+        let mut params = Vec::new();
+        while !self.eat(&TokenKind::RightParen) {
+            let name = self.name("a parameter name")?;
+            self.expect(&TokenKind::Colon)?;
+            let (ty, _) = self.type_name()?;
+            params.push(Param { name, ty });
+            if !self.eat(&TokenKind::Comma) {
+                self.expect(&TokenKind::RightParen)?;
+                break;
+            }
+        }
+        let returns = if self.eat(&TokenKind::Arrow) {
+            Some(self.type_name()?)
+        } else {
+            None
+        };
+        let body = self.block()?;
+        Ok(Function {
+            name,
+            params,
+            returns,
+            body,
+        })
+    }
+
+    fn block(&mut self) -> Result<Block, Diagnostic> {
+        let open = self.expect(&TokenKind::LeftBrace)?;
+        let mut statements = Vec::new();
+        loop {
+            match self.peek() {
+                TokenKind::RightBrace => {
+                    let close = self.advance().span;
+                    return Ok(Block {
+                        statements,
+                        tail: None,
+                        span: open.to(close),
+                    });
+                }
+                TokenKind::Let => {
+                    self.advance();
+                    let name = self.name("a variable name")?;
+                    let ty = self.optional_type()?;
+                    self.expect(&TokenKind::Assign)?;
+                    let value = self.expression()?;
+                    self.expect(&TokenKind::Semicolon)?;
+                    statements.push(Statement::Let { name, ty, value });
+                }
+                TokenKind::For => {
+                    self.advance();
+                    let var = self.name("a loop variable")?;
+                    self.expect(&TokenKind::In)?;
+                    let from = self.expression()?;
+                    self.expect(&TokenKind::DotDot)?;
+                    let to = self.expression()?;
+                    let body = self.block()?;
+                    statements.push(Statement::For { var, from, to, body });
+                }
+                TokenKind::Return => {
+                    let span = self.advance().span;
+                    let value = if self.peek() == &TokenKind::Semicolon {
+                        None
+                    } else {
+                        Some(self.expression()?)
+                    };
+                    self.expect(&TokenKind::Semicolon)?;
+                    statements.push(Statement::Return { value, span });
+                }
+                TokenKind::Ident(_) if assign_op(self.peek_next()).is_some() => {
+                    let name = self.name("a variable name")?;
+                    let op = assign_op(self.peek()).flatten();
+                    self.advance();
+                    let value = self.expression()?;
+                    self.expect(&TokenKind::Semicolon)?;
+                    statements.push(Statement::Assign { name, op, value });
+                }
+                _ => {
+                    let expr = self.expression()?;
+                    if self.eat(&TokenKind::Semicolon) {
+                        statements.push(Statement::Expr(expr));
+                    } else if self.peek() == &TokenKind::RightBrace {
+                        let close = self.advance().span;
+                        return Ok(Block {
+                            statements,
+                            tail: Some(Box::new(expr)),
+                            span: open.to(close),
+                        });
+                    } else if matches!(expr.kind, ExprKind::If { .. } | ExprKind::Block(_)) {
+                        statements.push(Statement::Expr(expr));
+                    } else {
+                        return Err(self.unexpected("`;` or `}`"));
+                    }
+                }
+            }
+        }
+    }
+
+    fn expression(&mut self) -> Result<Expr, Diagnostic> {
+        self.binary(0)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, Diagnostic> {
+        let Some(ops) = PRECEDENCE.get(level) else {
+            return self.unary();
+        };
+        let mut lhs = self.binary(level + 1)?;
+        while let Some((_, op)) = ops.iter().find(|(kind, _)| kind == self.peek()) {
+            let op = *op;
+            self.advance();
+            let rhs = self.binary(level + 1)?;
+            if op.is_comparison()
+                && let ExprKind::Binary(inner, _, _) = &lhs.kind
+                && inner.is_comparison()
+            {
+                return Err(Diagnostic::new(
+                    lhs.span.to(rhs.span),
+                    "comparisons cannot be chained; join them with `&&`".to_string(),
+                ));
+            }
+            let span = lhs.span.to(rhs.span);
+            lhs = Expr {
+                kind: ExprKind::Binary(op, Box::new(lhs), Box::new(rhs)),
+                span,
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, Diagnostic> {
+        let op = match self.peek() {
+            TokenKind::Minus => UnaryOp::Negate,
+            TokenKind::Bang => UnaryOp::Not,
+            _ => return self.postfix(),
+        };
+        let start = self.advance().span;
+        let operand = self.unary()?;
+        let span = start.to(operand.span);
+        Ok(Expr {
+            kind: ExprKind::Unary(op, Box::new(operand)),
+            span,
+        })
+    }
+
+    fn postfix(&mut self) -> Result<Expr, Diagnostic> {
+        let mut expr = self.primary()?;
+        while self.eat(&TokenKind::Dot) {
+            let field = self.name("`r`, `g` or `b`")?;
+            let span = expr.span.to(field.span);
+            expr = Expr {
+                kind: ExprKind::Field(Box::new(expr), field),
+                span,
+            };
+        }
+        Ok(expr)
+    }
+
+    fn primary(&mut self) -> Result<Expr, Diagnostic> {
+        let span = self.span();
+        let kind = match self.peek().clone() {
+            TokenKind::Int(value) => {
+                self.advance();
+                ExprKind::Int(value)
+            }
+            TokenKind::True => {
+                self.advance();
+                ExprKind::Bool(true)
+            }
+            TokenKind::False => {
+                self.advance();
+                ExprKind::Bool(false)
+            }
+            TokenKind::Color(r, g, b) => {
+                self.advance();
+                ExprKind::Color(r, g, b)
+            }
+            TokenKind::Ident(_) => {
+                let name = self.name("a name")?;
+                if self.eat(&TokenKind::LeftParen) {
+                    let mut args = Vec::new();
+                    let close = loop {
+                        if self.peek() == &TokenKind::RightParen {
+                            break self.advance().span;
+                        }
+                        args.push(self.expression()?);
+                        if !self.eat(&TokenKind::Comma) {
+                            break self.expect(&TokenKind::RightParen)?;
+                        }
+                    };
+                    return Ok(Expr {
+                        span: name.span.to(close),
+                        kind: ExprKind::Call(name, args),
+                    });
+                }
+                ExprKind::Name(name.text)
+            }
+            TokenKind::LeftParen => {
+                self.advance();
+                let inner = self.expression()?;
+                let close = self.expect(&TokenKind::RightParen)?;
+                return Ok(Expr {
+                    kind: inner.kind,
+                    span: span.to(close),
+                });
+            }
+            TokenKind::LeftBrace => {
+                let block = self.block()?;
+                return Ok(Expr {
+                    span: block.span,
+                    kind: ExprKind::Block(block),
+                });
+            }
+            TokenKind::If => return self.if_expression(),
+            _ => return Err(self.unexpected("an expression")),
+        };
+        Ok(Expr { kind, span })
+    }
+
+    fn if_expression(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.expect(&TokenKind::If)?;
+        let condition = self.expression()?;
+        let then = self.block()?;
+        let mut end = then.span;
+        let otherwise = if self.eat(&TokenKind::Else) {
+            let otherwise = if self.peek() == &TokenKind::If {
+                self.if_expression()?
+            } else {
+                let block = self.block()?;
+                Expr {
+                    span: block.span,
+                    kind: ExprKind::Block(block),
+                }
+            };
+            end = otherwise.span;
+            Some(Box::new(otherwise))
+        } else {
+            None
+        };
+        Ok(Expr {
+            kind: ExprKind::If {
+                condition: Box::new(condition),
+                then,
+                otherwise,
+            },
+            span: start.to(end),
+        })
+    }
+}
+
+// `Some(None)` for a plain `=`, `Some(Some(op))` for a compound assignment.
+fn assign_op(kind: &TokenKind) -> Option<Option<BinaryOp>> {
+    Some(match kind {
+        TokenKind::Assign => None,
+        TokenKind::PlusAssign => Some(BinaryOp::Add),
+        TokenKind::MinusAssign => Some(BinaryOp::Subtract),
+        TokenKind::StarAssign => Some(BinaryOp::Multiply),
+        TokenKind::SlashAssign => Some(BinaryOp::Divide),
+        TokenKind::PercentAssign => Some(BinaryOp::Mod),
+        _ => return None,
+    })
+}
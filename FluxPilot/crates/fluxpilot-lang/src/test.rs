@@ -0,0 +1,302 @@
+use flight_deck::program_graph::optimize::optimize;
+use light_machine::assembler::source_map::SourceMap;
+use light_machine::builder::ProgramBuilder;
+use light_machine::{Program, ProgramWord};
+
+use crate::{Diagnostic, compile};
+
+const MACHINE_MAX: usize = 8;
+const FUNCTION_MAX: usize = 16;
+
+// A compiled program image, its source map and its machine count.
+fn build(source: &str, optimized: bool) -> (Vec<ProgramWord>, Vec<u8>, ProgramWord) {
+    let mut graph = compile(source).unwrap_or_else(|errors| panic!("{errors:?}"));
+    if optimized {
+        optimize(&mut graph);
+    }
+    let mut buffer = vec![0; 2048];
+    let builder = ProgramBuilder::<MACHINE_MAX, FUNCTION_MAX>::new(
+        &mut buffer,
+        graph.instance_count(),
+        graph.type_count(),
+        graph.shared_function_count(),
+    )
+    .unwrap();
+    let (descriptor, map) = graph.emit_with_source_map(builder).unwrap();
+    buffer.truncate(descriptor.length);
+    (buffer, map, graph.instance_count())
+}
+
+// Runs the program the way pliot does and returns, for each tick, the
+// colors of `leds` LEDs after every machine has drawn on them.
+fn render(source: &str, ticks: u32, leds: u16) -> Vec<Vec<(u8, u8, u8)>> {
+    let plain = render_image(&build(source, false), ticks, leds);
+    let optimized = render_image(&build(source, true), ticks, leds);
+    assert_eq!(plain, optimized, "the optimizer changed what the program draws");
+    plain
+}
+
+fn render_image(
+    (image, _, machines): &(Vec<ProgramWord>, Vec<u8>, ProgramWord),
+    ticks: u32,
+    leds: u16,
+) -> Vec<Vec<(u8, u8, u8)>> {
+    let mut memory = vec![0; 256];
+    let mut program = Program::new(image, &mut memory).unwrap();
+    program.set_step_limit(Some(10_000));
+    program.call_shared(0).unwrap();
+    for machine in 0..*machines {
+        program.stack_mut().clear();
+        program.init_machine(machine).unwrap();
+    }
+    let mut frames = Vec::new();
+    for tick in 0..ticks {
+        for machine in 0..*machines {
+            program.stack_mut().clear();
+            program.start_frame(machine, tick).unwrap();
+        }
+        let mut frame = Vec::new();
+        for led in 0..leds {
+            let mut color = (0, 0, 0);
+            for machine in 0..*machines {
+                let stack = program.stack_mut();
+                stack.clear();
+                for component in [color.0, color.1, color.2] {
+                    stack.push(component.into()).unwrap();
+                }
+                color = program.get_led_color(machine, led).unwrap();
+            }
+            frame.push(color);
+        }
+        frames.push(frame);
+    }
+    frames
+}
+
+fn errors(source: &str) -> Vec<(u32, u32, u32, String)> {
+    match compile(source) {
+        Ok(_) => panic!("expected errors"),
+        Err(errors) => errors
+            .into_iter()
+            .map(|Diagnostic { span, message }| (span.line, span.start, span.end, message))
+            .collect(),
+    }
+}
+
+#[test]
+fn crawler_moves_one_led_per_frame() {
+    let source = "
+        // One lit LED that walks along the strip.
+        machine crawler {
+            var position = 0;
+            var length = 4;
+            var tint: color = #ff8000;
+
+            fn start_frame(tick: int) {
+                position = tick % length;
+            }
+
+            fn get_color(below: color, index: int) -> color {
+                if index == position { tint } else { below }
+            }
+        }
+    ";
+    let frames = render(source, 5, 4);
+    for (tick, frame) in frames.iter().enumerate() {
+        for (led, color) in frame.iter().enumerate() {
+            let lit = led == tick % 4;
+            assert_eq!(*color, if lit { (0xff, 0x80, 0) } else { (0, 0, 0) }, "tick {tick} led {led}");
+        }
+    }
+}
+
+#[test]
+fn functions_loops_and_arithmetic() {
+    let source = "
+        machine math {
+            var total = 0;
+
+            fn init() {
+                // 1 + 2 + ... + 10, through a helper.
+                for i in 1..11 {
+                    total += twice(i) / 2;
+                }
+            }
+
+            fn twice(value: int) -> int {
+                let doubled = value * 2;
+                doubled
+            }
+
+            fn fib(n: int) -> int {
+                if n < 2 {
+                    return n;
+                }
+                fib(n - 1) + fib(n - 2)
+            }
+
+            fn get_color(below: color, index: int) -> color {
+                let big = 100000;
+                let picked = if index == 0 {
+                    total
+                } else if index == 1 {
+                    fib(10)
+                } else if index == 2 {
+                    big / 1000 + min(5, 9) + max(3, 1)
+                } else {
+                    -1 - 4294967294 + (6 & 3) + (4 | 1) + (7 ^ 2)
+                };
+                let ok = picked > 50 && !(picked == 56) || index == 3;
+                rgb(picked, if ok { 1 } else { 0 }, index)
+            }
+        }
+    ";
+    let frame = render(source, 1, 4).remove(0);
+    assert_eq!(frame[0], (55, 1, 0));
+    assert_eq!(frame[1], (55, 1, 1));
+    assert_eq!(frame[2], (108, 1, 2));
+    assert_eq!(frame[3], (1 + 2 + 5 + 5, 1, 3));
+}
+
+#[test]
+fn colors_mix_component_by_component() {
+    let source = "
+        machine mixer {
+            var base = #102030;
+
+            fn get_color(below: color, index: int) -> color {
+                let scaled = base * 2;
+                let faded = (scaled + #010101 - below) / 2;
+                if index == 0 {
+                    faded
+                } else {
+                    rgb(faded.b, faded.g, faded.r)
+                }
+            }
+        }
+    ";
+    let frame = render(source, 1, 2).remove(0);
+    assert_eq!(frame[0], (0x10, 0x20, 0x30));
+    assert_eq!(frame[1], (0x30, 0x20, 0x10));
+}
+
+#[test]
+fn machines_chain_and_instances_override_vars() {
+    let source = "
+        machine solid {
+            var tint = #000000;
+            var from = 0;
+
+            fn get_color(below: color, index: int) -> color {
+                if index >= from { below + tint } else { below }
+            }
+        }
+
+        instance red of solid { tint = #400000 }
+        instance blue of solid { tint = #000040, from = 1 }
+    ";
+    let frame = render(source, 1, 2).remove(0);
+    assert_eq!(frame, vec![(0x40, 0, 0), (0x40, 0, 0x40)]);
+}
+
+#[test]
+fn missing_entry_points_pass_the_color_through() {
+    let source = "
+        machine paint {
+            fn get_color(below: color, index: int) -> color { #0a0b0c }
+        }
+        machine idle {}
+    ";
+    assert_eq!(render(source, 2, 1), vec![vec![(10, 11, 12)]; 2]);
+}
+
+#[test]
+fn identical_machines_share_code() {
+    let source = "
+        machine left {
+            var speed = 1;
+            fn start_frame(tick: int) { speed = tick; }
+        }
+        machine right {
+            var pace = 1;
+            fn start_frame(tick: int) { pace = tick; }
+        }
+    ";
+    let graph = compile(source).unwrap();
+    assert_eq!(graph.instance_count(), 2);
+    assert_eq!(graph.type_count(), 1);
+}
+
+#[test]
+fn source_map_points_into_the_source() {
+    let source = "machine broken {
+    var divisor = 0;
+
+    fn start_frame(tick: int) {
+        let a = tick + 1;
+        let b = a / divisor;
+    }
+}";
+    let built = build(source, false);
+    let (image, map, _) = &built;
+    let mut memory = vec![0; 256];
+    let mut program = Program::new(image, &mut memory).unwrap();
+    program.init_machine(0).unwrap();
+    assert!(program.start_frame(0, 3).is_err());
+    let map = SourceMap::new(map).unwrap();
+    let range = map.lookup(program.last_pc()).unwrap();
+    assert_eq!(range.line, 6);
+    assert_eq!(range.machine, Some("broken"));
+    assert_eq!(range.function, Some("start_frame"));
+}
+
+#[test]
+fn type_errors_point_at_the_source() {
+    let source = "machine bad {
+    var level = true;
+    fn start_frame(tick: int) {
+        level = tick;
+        let c = #ffffff * #000000;
+        missing(1);
+    }
+    fn get_color(below: color) -> color { below }
+    fn helper() -> int { if level { 1 } }
+}";
+    assert_eq!(
+        errors(source),
+        vec![
+            (4, 16, 20, "`level` is `bool`, not `int`".to_string()),
+            (
+                5,
+                16,
+                33,
+                "cannot use `*` on `color` and `color`; colors add and subtract colors, and multiply and divide by an `int`"
+                    .to_string()
+            ),
+            (6, 8, 15, "unknown function `missing`".to_string()),
+            (
+                8,
+                7,
+                16,
+                "the host calls `get_color` as `fn get_color(below: color, index: int) -> color`".to_string()
+            ),
+            (9, 36, 37, "an `if` without `else` cannot give a value".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn syntax_errors_stop_at_the_first() {
+    assert_eq!(
+        errors("machine m {\n    fn init() { let x = 1 }\n}\nmachine"),
+        vec![(2, 26, 27, "expected `;`, found `}`".to_string())]
+    );
+    assert_eq!(
+        errors("machine m { var c = #12345; }"),
+        vec![(1, 20, 26, "`#12345` is not a color; write it as `#rrggbb`".to_string())]
+    );
+    assert_eq!(
+        errors("machine m { fn f() -> bool { 1 < 2 < 3 } }"),
+        vec![(1, 29, 38, "comparisons cannot be chained; join them with `&&`".to_string())]
+    );
+}
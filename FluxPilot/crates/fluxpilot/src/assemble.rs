@@ -0,0 +1,74 @@
+//! Assembling source files with flight-deck's compiler, so images match the
+//! ones the deck loads: includes, the I2C init program and the standard
+//! library are all linked the same way.
+
+use std::path::Path;
+
+use flight_deck::DiagnosticJs;
+use flight_deck::source_resolver::FsResolver;
+use light_machine::ProgramWord;
+
+// The compiler's builders keep fixed-capacity tables for hundreds of
+// machines on the stack, more than a test thread's default allows.
+const COMPILE_STACK_BYTES: usize = 16 * 1024 * 1024;
+
+pub struct Assembled {
+    pub image: Vec<ProgramWord>,
+    /// Lint warnings, formatted as by [`format_diagnostic`].
+    pub warnings: Vec<String>,
+}
+
+/// Assembles the file at `path`, resolving `.include`s next to it. On
+/// failure the error holds every problem found, formatted.
+pub fn assemble_file(path: &Path, optimize: bool) -> Result<Assembled, Vec<String>> {
+    let source = std::fs::read_to_string(path).map_err(|err| vec![format!("{}: {err}", path.display())])?;
+    let root = path.parent().unwrap_or(Path::new("."));
+    assemble(&source, root, &path.display().to_string(), optimize)
+}
+
+/// Assembles `source`. `name` stands in for the file in diagnostics about
+/// lines that were not included from elsewhere.
+pub fn assemble(source: &str, root: &Path, name: &str, optimize: bool) -> Result<Assembled, Vec<String>> {
+    let mut buffer = vec![0u16; usize::from(ProgramWord::MAX) + 1];
+    let format_all = |diagnostics: Vec<DiagnosticJs>| {
+        diagnostics
+            .iter()
+            .map(|diagnostic| format_diagnostic(name, diagnostic))
+            .collect::<Vec<_>>()
+    };
+    let resolver = FsResolver::new(root);
+    let descriptor = std::thread::scope(|scope| {
+        std::thread::Builder::new()
+            .stack_size(COMPILE_STACK_BYTES)
+            .spawn_scoped(scope, || {
+                flight_deck::compile_with_resolver(source, &[], &resolver, &mut buffer, optimize)
+            })
+            .map_err(|err| vec![format!("could not start the compiler: {err}")])?
+            .join()
+            .map_err(|_| vec![format!("{name}: the compiler panicked")])
+    })?
+    .map_err(format_all)?;
+    buffer.truncate(descriptor.length());
+    Ok(Assembled {
+        image: buffer,
+        warnings: format_all(descriptor.warnings()),
+    })
+}
+
+/// `file:line:column: severity: message [code]`, then the suggested fix if
+/// there is one. Lines and columns count from 1.
+pub fn format_diagnostic(name: &str, diagnostic: &DiagnosticJs) -> String {
+    let file = diagnostic.file().unwrap_or_else(|| name.to_string());
+    let mut text = match diagnostic.line() {
+        0 => file,
+        line => format!("{file}:{line}:{}", diagnostic.start() + 1),
+    };
+    text.push_str(&format!(": {}: {}", diagnostic.severity(), diagnostic.message()));
+    if let Some(code) = diagnostic.code() {
+        text.push_str(&format!(" [{code}]"));
+    }
+    if let Some(fix) = diagnostic.fix() {
+        text.push_str(&format!("\n  fix: {fix}"));
+    }
+    text
+}
@@ -0,0 +1,165 @@
+//! A virtual FluxPilot on a local socket, for testing hosts without a board.
+//!
+//! It is put together like the firmware: one `Pliot` behind a mutex, one
+//! thread running `led_loop`'s frames on a timer and one serving
+//! `io_loop`'s framing. Bytes gather until a zero ends a COBS frame, the
+//! frame goes to `process_message`, and any reply is written back as is.
+//! Clients are served one at a time, as a USB host would be.
+
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use light_machine::{ProgramWord, StackWord};
+use pliot::Storage;
+
+use crate::run::{Device, MEMORY_WORDS, format_frames, load_program, render_frame};
+
+/// Longest frame the device buffers; a longer one is dropped, as on the
+/// board.
+const FRAME_CAP: usize = 1024;
+const REPLY_CAP: usize = 1024;
+
+pub struct DeviceOptions {
+    pub leds: u16,
+    pub frame_ms: u64,
+    /// Print every frame's LEDs to stdout.
+    pub print: bool,
+    /// Instructions any one VM call may run; `None` for no limit.
+    pub step_limit: Option<u32>,
+}
+
+/// Runs a device on `storage` until the process is stopped. `restored`
+/// says `storage` already holds a program, as after a reboot; `image` is
+/// loaded over it as a host would.
+pub fn serve<S: Storage<L: Send> + Send>(
+    storage: &mut S,
+    restored: bool,
+    image: Option<&[ProgramWord]>,
+    listener: &Listener,
+    options: &DeviceOptions,
+) -> Result<(), String> {
+    let mut memory = vec![0 as StackWord; MEMORY_WORDS];
+    let mut device = Device::new(storage, &mut memory);
+    device.set_step_limit(options.step_limit);
+    if restored {
+        device.init().map_err(|err| format!("init of the stored program: {err:?}"))?;
+    }
+    if let Some(image) = image {
+        load_program(&mut device, image)?;
+    }
+    let device = Mutex::new(device);
+    std::thread::scope(|scope| {
+        scope.spawn(|| led_loop(&device, options));
+        listener.serve(&device);
+    });
+    Ok(())
+}
+
+/// Runs frames every `frame_ms` forever. Frames are skipped while there is
+/// no program to run.
+pub fn led_loop<S: Storage>(device: &Mutex<Device<'_, '_, S>>, options: &DeviceOptions) {
+    let frame_time = Duration::from_millis(options.frame_ms);
+    let mut tick = 0u32;
+    loop {
+        let start = Instant::now();
+        let frame = {
+            let mut device = device.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            render_frame(&mut device, options.leds, tick)
+        };
+        if let (Ok(colors), true) = (frame, options.print) {
+            let mut stdout = io::stdout().lock();
+            let _ = stdout.write_all(format_frames(&[colors], tick).as_bytes());
+            let _ = stdout.flush();
+        }
+        std::thread::sleep(frame_time.saturating_sub(start.elapsed()));
+        tick = tick.wrapping_add(1);
+    }
+}
+
+/// Serves one client until it disconnects.
+pub fn io_loop<S: Storage, C: Read + Write>(device: &Mutex<Device<'_, '_, S>>, client: &mut C) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(FRAME_CAP);
+    let mut buffer = [0u8; 256];
+    loop {
+        let read = client.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(());
+        }
+        for &byte in &buffer[..read] {
+            if frame.len() == FRAME_CAP {
+                frame.clear();
+                continue;
+            }
+            frame.push(byte);
+            if byte != 0 {
+                continue;
+            }
+            let mut reply = [0u8; REPLY_CAP];
+            let wrote = {
+                let mut device = device.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                device.process_message(&mut frame, &mut reply).unwrap_or_default()
+            };
+            frame.clear();
+            if wrote > 0 {
+                client.write_all(&reply[..wrote])?;
+                client.flush()?;
+            }
+        }
+    }
+}
+
+/// A socket the device listens on.
+pub enum Listener {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+impl Listener {
+    /// `unix:PATH` for a Unix socket, otherwise a TCP address such as
+    /// `127.0.0.1:7878`.
+    pub fn bind(address: &str) -> io::Result<Self> {
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix("unix:") {
+            // A socket left by an earlier run would stop the bind.
+            let _ = std::fs::remove_file(path);
+            return std::os::unix::net::UnixListener::bind(path).map(Listener::Unix);
+        }
+        std::net::TcpListener::bind(address).map(Listener::Tcp)
+    }
+
+    /// Where clients connect, for printing.
+    pub fn address(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => listener
+                .local_addr()
+                .map_or_else(|err| err.to_string(), |address| address.to_string()),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener
+                .local_addr()
+                .ok()
+                .and_then(|address| address.as_pathname().map(|path| format!("unix:{}", path.display())))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Accepts clients one after another forever.
+    pub fn serve<S: Storage>(&self, device: &Mutex<Device<'_, '_, S>>) {
+        loop {
+            let served = match self {
+                Listener::Tcp(listener) => listener.accept().and_then(|(mut client, _)| {
+                    client.set_nodelay(true)?;
+                    io_loop(device, &mut client)
+                }),
+                #[cfg(unix)]
+                Listener::Unix(listener) => listener
+                    .accept()
+                    .and_then(|(mut client, _)| io_loop(device, &mut client)),
+            };
+            if let Err(err) = served {
+                eprintln!("client: {err}");
+            }
+        }
+    }
+}
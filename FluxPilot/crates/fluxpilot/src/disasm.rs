@@ -0,0 +1,121 @@
+//! Disassembly of program images.
+//!
+//! Each function is decoded from its entry point up to the next entry point
+//! or the metadata sections. Data blocks live among the functions, so words
+//! that are not opcodes come out as `.word`. Names come from the image's
+//! symbols section when it has one.
+
+use std::fmt::Write;
+
+use light_machine::assembler::syntax::Mnemonic;
+use light_machine::metadata::SymbolKind;
+use light_machine::{Ops, ProgramWord};
+
+use crate::image::{Layout, Names};
+
+struct Entry {
+    address: usize,
+    label: String,
+    /// The machine type whose locals the function uses; `None` for shared
+    /// functions.
+    owner: Option<ProgramWord>,
+}
+
+pub fn disassemble(image: &[ProgramWord]) -> Result<String, String> {
+    let layout = Layout::read(image)?;
+    let names = Names::new(image);
+    let mut entries = Vec::new();
+    for (index, &address) in (0..).zip(&layout.shared_functions) {
+        let name = names
+            .get(SymbolKind::SharedFunction, 0, index)
+            .map_or(String::new(), |name| format!(" `{name}`"));
+        entries.push(Entry {
+            address: usize::from(address),
+            label: format!("shared function {index}{name}"),
+            owner: None,
+        });
+    }
+    for (type_id, machine_type) in (0..).zip(&layout.types) {
+        let type_name = names
+            .get(SymbolKind::Type, 0, type_id)
+            .map_or(String::new(), |name| format!(" `{name}`"));
+        for (index, &address) in (0..).zip(&machine_type.functions) {
+            let name = names
+                .get(SymbolKind::Function, type_id, index)
+                .map_or(String::new(), |name| format!(" `{name}`"));
+            entries.push(Entry {
+                address: usize::from(address),
+                label: format!("machine type {type_id}{type_name}, function {index}{name}"),
+                owner: Some(type_id),
+            });
+        }
+    }
+    // A zero entry point is a slot that was never defined.
+    entries.retain(|entry| entry.address != 0);
+    entries.sort_by_key(|entry| entry.address);
+
+    let mut out = String::new();
+    let mut next = 0;
+    while let Some(first) = entries.get(next) {
+        // Functions deduplicated by the compiler share an entry point.
+        let same = entries[next..]
+            .iter()
+            .take_while(|entry| entry.address == first.address)
+            .count();
+        let group = &entries[next..next + same];
+        next += same;
+        let end = entries
+            .get(next)
+            .map_or(layout.code_end, |entry| entry.address)
+            .min(layout.code_end);
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        for entry in group {
+            let _ = writeln!(out, "; {}", entry.label);
+        }
+        // Only name locals when every function here belongs to one type.
+        let owner = group[0].owner.filter(|&owner| group.iter().all(|entry| entry.owner == Some(owner)));
+        decode(image, first.address, end, owner, &names, &mut out);
+    }
+    Ok(out)
+}
+
+fn decode(
+    image: &[ProgramWord],
+    start: usize,
+    end: usize,
+    owner: Option<ProgramWord>,
+    names: &Names<'_>,
+    out: &mut String,
+) {
+    let mut address = start;
+    while address < end {
+        let word = image[address];
+        let _ = write!(out, "{address:5}: ");
+        let Ok(op) = Ops::try_from(word) else {
+            let _ = writeln!(out, ".word {word}");
+            address += 1;
+            continue;
+        };
+        let name = Mnemonic::from_opcode(op).map_or("?", Mnemonic::name);
+        if !op.has_operand() || address + 1 >= end {
+            let _ = writeln!(out, "{name}");
+            address += 1;
+            continue;
+        }
+        let operand = image[address + 1];
+        let _ = write!(out, "{name} {operand}");
+        let local = match (op, owner) {
+            (Ops::LocalLoad | Ops::LocalStore, Some(owner)) => names.get(SymbolKind::Local, owner, operand),
+            _ => None,
+        };
+        match local {
+            Some(local) => {
+                let _ = writeln!(out, " ; {local}");
+            }
+            None => out.push('\n'),
+        }
+        address += 2;
+    }
+}
@@ -0,0 +1,139 @@
+//! Program images on disk and the tables inside them.
+//!
+//! An image file holds the program words low byte first, the way the
+//! firmware keeps them in flash. The layout is described in
+//! `crates/light_machine/design.md`.
+
+use light_machine::metadata::{self, SymbolKind};
+use light_machine::{
+    INSTANCE_ENTRY_WORDS, INSTANCE_TABLE_OFFSET, MACHINE_COUNT_OFFSET, PARAMS_OFFSET,
+    PROGRAM_VERSION, ProgramWord, SHARED_FUNCTION_COUNT_OFFSET, SHARED_FUNCTION_TABLE_OFFSET,
+    SYMBOLS_OFFSET, TYPE_COUNT_OFFSET, TYPE_TABLE_OFFSET, VERSION_OFFSET,
+};
+
+pub fn from_bytes(bytes: &[u8]) -> Result<Vec<ProgramWord>, String> {
+    if !bytes.len().is_multiple_of(2) {
+        return Err(format!("{} bytes is not a whole number of words", bytes.len()));
+    }
+    Ok(bytes
+        .chunks_exact(2)
+        .map(|pair| ProgramWord::from_le_bytes([pair[0], pair[1]]))
+        .collect())
+}
+
+pub fn to_bytes(image: &[ProgramWord]) -> Vec<u8> {
+    image.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+/// The word at `index`, or an error naming what was being read.
+pub fn word(image: &[ProgramWord], index: usize, what: &str) -> Result<ProgramWord, String> {
+    image
+        .get(index)
+        .copied()
+        .ok_or_else(|| format!("{what} at word {index} is past the end of the image"))
+}
+
+pub struct Instance {
+    pub type_id: ProgramWord,
+    pub globals_base: ProgramWord,
+    /// Offset of the initial locals record, 0 for none.
+    pub initial_locals: ProgramWord,
+}
+
+pub struct MachineType {
+    pub function_table: ProgramWord,
+    /// Entry point of each function, by index.
+    pub functions: Vec<ProgramWord>,
+}
+
+/// The tables of an image, read once so the commands need not walk the
+/// header themselves.
+pub struct Layout {
+    pub instances: Vec<Instance>,
+    pub types: Vec<MachineType>,
+    /// Entry point of each shared function, by index.
+    pub shared_functions: Vec<ProgramWord>,
+    /// Where the metadata sections start; code and data come before.
+    pub code_end: usize,
+}
+
+impl Layout {
+    pub fn read(image: &[ProgramWord]) -> Result<Self, String> {
+        let version = word(image, VERSION_OFFSET, "version")?;
+        if version != PROGRAM_VERSION {
+            return Err(format!(
+                "image version {version} is not the supported version {PROGRAM_VERSION}"
+            ));
+        }
+        let header = |offset, what| word(image, offset, what).map(usize::from);
+
+        let instance_table = header(INSTANCE_TABLE_OFFSET, "instance table offset")?;
+        let instances = (0..header(MACHINE_COUNT_OFFSET, "machine count")?)
+            .map(|index| {
+                let entry = instance_table + index * INSTANCE_ENTRY_WORDS;
+                Ok(Instance {
+                    type_id: word(image, entry, "instance type")?,
+                    globals_base: word(image, entry + 1, "instance globals base")?,
+                    initial_locals: word(image, entry + 2, "instance initial locals")?,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        let type_table = header(TYPE_TABLE_OFFSET, "type table offset")?;
+        let types = (0..header(TYPE_COUNT_OFFSET, "type count")?)
+            .map(|index| {
+                let entry = type_table + index * 2;
+                let function_count = word(image, entry, "function count")?;
+                let function_table = word(image, entry + 1, "function table offset")?;
+                let functions = (0..usize::from(function_count))
+                    .map(|function| {
+                        word(image, usize::from(function_table) + function, "function entry point")
+                    })
+                    .collect::<Result<_, String>>()?;
+                Ok(MachineType {
+                    function_table,
+                    functions,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        let shared_table = header(SHARED_FUNCTION_TABLE_OFFSET, "shared function table offset")?;
+        let shared_functions = (0..header(SHARED_FUNCTION_COUNT_OFFSET, "shared function count")?)
+            .map(|index| word(image, shared_table + index, "shared function entry point"))
+            .collect::<Result<_, String>>()?;
+
+        let code_end = [PARAMS_OFFSET, SYMBOLS_OFFSET]
+            .into_iter()
+            .map(|offset| header(offset, "section offset"))
+            .collect::<Result<Vec<_>, String>>()?
+            .into_iter()
+            .filter(|&offset| offset != 0)
+            .min()
+            .unwrap_or(image.len())
+            .min(image.len());
+
+        Ok(Self {
+            instances,
+            types,
+            shared_functions,
+            code_end,
+        })
+    }
+}
+
+/// Looks names up in an image's symbols section. An image without one, or
+/// with one that does not read, names nothing.
+pub struct Names<'a> {
+    image: &'a [ProgramWord],
+}
+
+impl<'a> Names<'a> {
+    pub fn new(image: &'a [ProgramWord]) -> Self {
+        Self { image }
+    }
+
+    pub fn get(&self, kind: SymbolKind, owner: ProgramWord, index: ProgramWord) -> Option<String> {
+        let name = metadata::symbols(self.image).ok()?.find(kind, owner, index)?;
+        Some(String::from_utf8_lossy(&name.bytes().collect::<Vec<u8>>()).into_owned())
+    }
+}
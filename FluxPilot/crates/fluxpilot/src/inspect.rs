@@ -0,0 +1,113 @@
+//! A readable summary of an image: its header, tables, metadata and size.
+
+use std::fmt::Write;
+
+use light_machine::metadata::{self, ParamKind, ParamTarget, SymbolKind};
+use light_machine::{
+    GLOBALS_SIZE_OFFSET, HEADER_WORDS, PARAMS_OFFSET, ProgramWord, SYMBOLS_OFFSET, VERSION_OFFSET,
+};
+
+use crate::image::{Layout, Names, word};
+
+pub fn inspect(image: &[ProgramWord]) -> Result<String, String> {
+    let layout = Layout::read(image)?;
+    let names = Names::new(image);
+    let named = |kind, owner, index| {
+        names
+            .get(kind, owner, index)
+            .map_or(String::new(), |name| format!(" `{name}`"))
+    };
+    let mut out = String::new();
+
+    let _ = writeln!(out, "version {}", word(image, VERSION_OFFSET, "version")?);
+    let _ = writeln!(
+        out,
+        "size {} words ({} bytes), header {HEADER_WORDS} words, code and data end at {}",
+        image.len(),
+        image.len() * 2,
+        layout.code_end
+    );
+    let _ = writeln!(out, "globals {} words", word(image, GLOBALS_SIZE_OFFSET, "globals size")?);
+
+    let _ = writeln!(out, "\ninstances {}", layout.instances.len());
+    for (index, instance) in (0..).zip(&layout.instances) {
+        let _ = write!(
+            out,
+            "  {index}{}: type {}, globals at {}",
+            named(SymbolKind::Instance, instance.type_id, index),
+            instance.type_id,
+            instance.globals_base
+        );
+        match instance.initial_locals {
+            0 => out.push('\n'),
+            record => {
+                let _ = writeln!(out, ", initial locals at {record}");
+            }
+        }
+    }
+
+    let _ = writeln!(out, "\ntypes {}", layout.types.len());
+    for (type_id, machine_type) in (0..).zip(&layout.types) {
+        let _ = writeln!(
+            out,
+            "  {type_id}{}: {} functions, table at {}",
+            named(SymbolKind::Type, 0, type_id),
+            machine_type.functions.len(),
+            machine_type.function_table
+        );
+        for (index, entry) in (0..).zip(&machine_type.functions) {
+            let _ = writeln!(
+                out,
+                "    {index}{} at {entry}",
+                named(SymbolKind::Function, type_id, index)
+            );
+        }
+    }
+
+    let _ = writeln!(out, "\nshared functions {}", layout.shared_functions.len());
+    for (index, entry) in (0..).zip(&layout.shared_functions) {
+        let _ = writeln!(
+            out,
+            "  {index}{} at {entry}",
+            named(SymbolKind::SharedFunction, 0, index)
+        );
+    }
+
+    let params = metadata::params(image)
+        .map_err(|err| format!("params: {err}"))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("params: {err}"))?;
+    let _ = writeln!(
+        out,
+        "\nparams {} (section at {})",
+        params.len(),
+        word(image, PARAMS_OFFSET, "params offset")?
+    );
+    for param in &params {
+        let info = param.info;
+        let name = String::from_utf8_lossy(&param.name.bytes().collect::<Vec<u8>>()).into_owned();
+        let kind = match info.kind {
+            ParamKind::Range => "range",
+            ParamKind::Toggle => "toggle",
+            ParamKind::Color => "color",
+        };
+        let target = match info.target {
+            ParamTarget::Local(local) => format!("local {local}"),
+            ParamTarget::Function(function) => format!("function {function}"),
+        };
+        let _ = writeln!(
+            out,
+            "  `{name}`: type {}, {kind}, {target}, min {} max {} step {}, default {:?}",
+            info.type_id, info.min, info.max, info.step, info.default
+        );
+    }
+
+    let symbols = metadata::symbols(image).map_err(|err| format!("symbols: {err}"))?;
+    let _ = writeln!(
+        out,
+        "\nsymbols {} (section at {})",
+        symbols.remaining(),
+        word(image, SYMBOLS_OFFSET, "symbols offset")?
+    );
+    Ok(out)
+}
@@ -0,0 +1,254 @@
+//! Host tools for light machine programs.
+//!
+//!     fluxpilot asm [--optimize] SOURCE [-o IMAGE]   assemble to an image file
+//!     fluxpilot disasm INPUT                         print the image's code
+//!     fluxpilot inspect INPUT                        print its header and tables
+//!     fluxpilot run [OPTIONS] INPUT                  run frames, print LED colors
+//!     fluxpilot render [OPTIONS] -o OUTPUT INPUT     run frames into a PPM, PNG or GIF
+//!     fluxpilot lint SOURCE...                       exit 1 on any warning
+//!     fluxpilot device [OPTIONS] [INPUT]             a virtual device on a socket
+//!
+//! An INPUT ending in `.fpa` is assembled first; anything else is read as
+//! an image. See `README.md` for the options of `run`, `render` and
+//! `device`.
+
+#![allow(clippy::result_large_err)]
+
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use light_machine::ProgramWord;
+
+mod assemble;
+mod device;
+mod disasm;
+mod image;
+mod inspect;
+mod render;
+mod run;
+mod storage;
+
+#[cfg(test)]
+mod test;
+
+const USAGE: &str = "\
+usage: fluxpilot asm [--optimize] SOURCE [-o IMAGE]
+       fluxpilot disasm INPUT
+       fluxpilot inspect INPUT
+       fluxpilot run [--leds N] [--frames N] [--start TICK] [--step-limit N] [-o FILE] INPUT
+       fluxpilot render [--leds N] [--frames N] [--start TICK] [--step-limit N]
+                        [--layout FILE] [--scale N] [--delay MS] -o OUTPUT INPUT
+       fluxpilot lint SOURCE...
+       fluxpilot device [--listen ADDRESS] [--storage FILE] [--leds N] [--frame-ms MS]
+                        [--step-limit N] [--print] [INPUT]";
+
+const SOURCE_EXTENSION: &str = "fpa";
+const IMAGE_EXTENSION: &str = "bin";
+const DEFAULT_LEDS: u16 = 30;
+const DEFAULT_LISTEN: &str = "127.0.0.1:7878";
+/// Program storage of the virtual device, in words. `MemStorage` keeps two
+/// halves, so a program may use half of it.
+const DEVICE_PROGRAM_WORDS: usize = 65536;
+const DEVICE_UI_STATE_BYTES: usize = 16384;
+
+fn is_source(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == SOURCE_EXTENSION)
+}
+
+/// The image in `path`, assembling it first if it is a source file.
+fn load(path: &Path) -> Result<Vec<ProgramWord>, String> {
+    if is_source(path) {
+        return assemble::assemble_file(path, false)
+            .map(|assembled| assembled.image)
+            .map_err(|errors| errors.join("\n"));
+    }
+    let bytes = std::fs::read(path).map_err(|err| format!("{}: {err}", path.display()))?;
+    image::from_bytes(&bytes).map_err(|err| format!("{}: {err}", path.display()))
+}
+
+fn write(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    std::fs::write(path, bytes).map_err(|err| format!("{}: {err}", path.display()))
+}
+
+fn number<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{flag} needs a value\n{USAGE}"))?;
+    value
+        .parse()
+        .map_err(|_| format!("{flag}: `{value}` is not a number"))
+}
+
+/// The one input of a command, after its options.
+fn single(inputs: Vec<PathBuf>) -> Result<PathBuf, String> {
+    let [input] = <[PathBuf; 1]>::try_from(inputs).map_err(|_| USAGE.to_string())?;
+    Ok(input)
+}
+
+fn run() -> Result<bool, String> {
+    let mut args = std::env::args().skip(1);
+    let command = args.next().ok_or_else(|| USAGE.to_string())?;
+    if command == "-h" || command == "--help" {
+        println!("{USAGE}");
+        return Ok(true);
+    }
+
+    let runs = command == "run" || command == "render";
+    let serves = command == "device";
+    let renders = command == "render";
+    let mut optimize = false;
+    let mut output = None;
+    let mut leds = None;
+    let mut options = run::RunOptions {
+        leds: DEFAULT_LEDS,
+        frames: 1,
+        start_tick: 0,
+        step_limit: None,
+    };
+    let mut layout = None;
+    let mut scale = 8;
+    let mut delay_ms = 40;
+    let mut listen = DEFAULT_LISTEN.to_string();
+    let mut storage_path = None;
+    let mut frame_ms = 33;
+    let mut print = false;
+    let mut inputs = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--optimize" if command == "asm" => optimize = true,
+            "-o" if command == "asm" || runs => {
+                output = Some(PathBuf::from(args.next().ok_or_else(|| USAGE.to_string())?))
+            }
+            "--leds" if runs || serves => leds = Some(number(&arg, args.next())?),
+            "--frames" if runs => options.frames = number(&arg, args.next())?,
+            "--start" if runs => options.start_tick = number(&arg, args.next())?,
+            "--step-limit" if runs || serves => options.step_limit = Some(number(&arg, args.next())?),
+            "--layout" if renders => {
+                layout = Some(PathBuf::from(args.next().ok_or_else(|| USAGE.to_string())?))
+            }
+            "--scale" if renders => scale = number(&arg, args.next())?,
+            "--delay" if renders => delay_ms = number(&arg, args.next())?,
+            "--listen" if serves => listen = args.next().ok_or_else(|| USAGE.to_string())?,
+            "--storage" if serves => {
+                storage_path = Some(PathBuf::from(args.next().ok_or_else(|| USAGE.to_string())?))
+            }
+            "--frame-ms" if serves => frame_ms = number(&arg, args.next())?,
+            "--print" if serves => print = true,
+            _ if arg.starts_with('-') => return Err(USAGE.to_string()),
+            _ => inputs.push(PathBuf::from(arg)),
+        }
+    }
+
+    match command.as_str() {
+        "asm" => {
+            let source = single(inputs)?;
+            let assembled = assemble::assemble_file(&source, optimize).map_err(|errors| errors.join("\n"))?;
+            for warning in &assembled.warnings {
+                eprintln!("{warning}");
+            }
+            let output = output.unwrap_or_else(|| source.with_extension(IMAGE_EXTENSION));
+            write(&output, &image::to_bytes(&assembled.image))?;
+            Ok(true)
+        }
+        "disasm" => {
+            print!("{}", disasm::disassemble(&load(&single(inputs)?)?)?);
+            Ok(true)
+        }
+        "inspect" => {
+            print!("{}", inspect::inspect(&load(&single(inputs)?)?)?);
+            Ok(true)
+        }
+        "run" => {
+            options.leds = leds.unwrap_or(DEFAULT_LEDS);
+            let frames = run::run(&load(&single(inputs)?)?, &options)?;
+            let text = run::format_frames(&frames, options.start_tick);
+            match output {
+                Some(output) => write(&output, text.as_bytes())?,
+                None => print!("{text}"),
+            }
+            Ok(true)
+        }
+        "render" => {
+            let output = output.ok_or_else(|| format!("render needs -o OUTPUT\n{USAGE}"))?;
+            let format = render::Format::for_path(&output)
+                .ok_or_else(|| format!("{}: write a .ppm, .png or .gif file", output.display()))?;
+            if options.frames == 0 || scale == 0 {
+                return Err("--frames and --scale must be at least 1".to_string());
+            }
+            let layout = match layout {
+                Some(path) => {
+                    let text = std::fs::read_to_string(&path).map_err(|err| format!("{}: {err}", path.display()))?;
+                    render::Layout::parse(&text).map_err(|err| format!("{}: {err}", path.display()))?
+                }
+                None => render::Layout::strip(leds.unwrap_or(DEFAULT_LEDS)),
+            };
+            // A layout places its own number of LEDs unless told otherwise.
+            options.leds = match leds {
+                Some(leds) => leds,
+                None => u16::try_from(layout.led_count()).map_err(|_| "the layout places too many LEDs".to_string())?,
+            };
+            layout.check_render(scale, options.frames)?;
+            let frames = run::run(&load(&single(inputs)?)?, &options)?;
+            let pictures: Vec<_> = frames
+                .iter()
+                .map(|colors| render::frame_picture(colors, &layout, scale))
+                .collect::<Result<_, _>>()?;
+            write(&output, &render::encode(&pictures, format, delay_ms)?)?;
+            Ok(true)
+        }
+        "lint" => {
+            if inputs.is_empty() {
+                return Err(USAGE.to_string());
+            }
+            let mut clean = true;
+            for source in &inputs {
+                let problems = match assemble::assemble_file(source, false) {
+                    Ok(assembled) => assembled.warnings,
+                    Err(errors) => errors,
+                };
+                for problem in &problems {
+                    println!("{problem}");
+                }
+                clean &= problems.is_empty();
+            }
+            Ok(clean)
+        }
+        "device" => {
+            if inputs.len() > 1 {
+                return Err(USAGE.to_string());
+            }
+            let image = inputs.pop().map(|input| load(&input)).transpose()?;
+            let listener = device::Listener::bind(&listen).map_err(|err| format!("{listen}: {err}"))?;
+            eprintln!("listening on {}", listener.address());
+            let options = device::DeviceOptions {
+                leds: leds.unwrap_or(DEFAULT_LEDS),
+                frame_ms,
+                print,
+                step_limit: options.step_limit,
+            };
+            let mut program = vec![0 as ProgramWord; DEVICE_PROGRAM_WORDS];
+            let mut ui_state = vec![0u8; DEVICE_UI_STATE_BYTES];
+            match storage_path {
+                Some(path) => {
+                    let (mut storage, restored) = storage::FileStorage::open(path, &mut program, &mut ui_state)?;
+                    device::serve(&mut storage, restored, image.as_deref(), &listener, &options)?;
+                }
+                None => {
+                    let mut storage = pliot::meme_storage::MemStorage::new(&mut program, &mut ui_state);
+                    device::serve(&mut storage, false, image.as_deref(), &listener, &options)?;
+                }
+            }
+            Ok(true)
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(message) => {
+            eprintln!("{message}");
+            ExitCode::from(2)
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! Pictures of the frames [`crate::run::run`] produces, for previewing an
+//! animation without hardware.
+//!
+//! Each frame becomes one picture: the LEDs in a row, or placed by a
+//! [`Layout`], every LED a square of `scale` pixels. A still image (PPM or
+//! PNG) stacks the frames top to bottom, so time runs down the image; a GIF
+//! plays them.
+
+use std::path::Path;
+
+use crate::run::Color;
+
+/// The most pixels a render may draw, across all its frames, so a stray
+/// coordinate, scale or frame count is refused instead of allocating
+/// gigabytes.
+pub const PIXEL_CAP: u64 = 1 << 24;
+
+/// Where each LED sits on a grid, by LED index.
+pub struct Layout {
+    cells: Vec<(u32, u32)>,
+    width: u32,
+    height: u32,
+}
+
+impl Layout {
+    /// `leds` in a single row.
+    pub fn strip(leds: u16) -> Self {
+        Self {
+            cells: (0..u32::from(leds)).map(|x| (x, 0)).collect(),
+            width: u32::from(leds).max(1),
+            height: 1,
+        }
+    }
+
+    /// A layout file has a line `x y` for each LED, in LED order, with the
+    /// grid's top left at `0 0`. Blank lines and text after `#` are ignored.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut cells = Vec::new();
+        for (number, line) in (1..).zip(text.lines()) {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let coordinates = line
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<Result<Vec<u32>, _>>()
+                .map_err(|err| format!("line {number}: {err}"))?;
+            let [x, y] = coordinates[..] else {
+                return Err(format!("line {number}: expected `x y`"));
+            };
+            if x.checked_add(1).is_none() || y.checked_add(1).is_none() {
+                return Err(format!("line {number}: `{x} {y}` is off the grid"));
+            }
+            cells.push((x, y));
+        }
+        if cells.is_empty() {
+            return Err("the layout places no LEDs".to_string());
+        }
+        let width = cells.iter().map(|&(x, _)| x.saturating_add(1)).max().unwrap_or(1);
+        let height = cells.iter().map(|&(_, y)| y.saturating_add(1)).max().unwrap_or(1);
+        let layout = Self { cells, width, height };
+        layout.picture_size(1)?;
+        Ok(layout)
+    }
+
+    /// The width and height of a picture at `scale`, if it is under
+    /// [`PIXEL_CAP`].
+    fn picture_size(&self, scale: u32) -> Result<(u32, u32), String> {
+        let too_large = || format!("a {}x{} grid at scale {scale} is over {PIXEL_CAP} pixels", self.width, self.height);
+        let width = self.width.checked_mul(scale).ok_or_else(too_large)?;
+        let height = self.height.checked_mul(scale).ok_or_else(too_large)?;
+        if u64::from(width) * u64::from(height) > PIXEL_CAP {
+            return Err(too_large());
+        }
+        Ok((width, height))
+    }
+
+    /// Fails if `frames` pictures at `scale` would be over [`PIXEL_CAP`]
+    /// together, before any frame is run.
+    pub fn check_render(&self, scale: u32, frames: u32) -> Result<(), String> {
+        let (width, height) = self.picture_size(scale)?;
+        // Each factor is under 2^32, and the first two under 2^24 together.
+        if u64::from(width) * u64::from(height) * u64::from(frames) > PIXEL_CAP {
+            return Err(format!("{frames} frames of {width}x{height} are over {PIXEL_CAP} pixels"));
+        }
+        Ok(())
+    }
+
+    pub fn led_count(&self) -> usize {
+        self.cells.len()
+    }
+}
+
+/// Pixels, three bytes each, row by row.
+pub struct Picture {
+    pub width: u32,
+    pub height: u32,
+    pub rgb: Vec<u8>,
+}
+
+impl Picture {
+    fn black(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            rgb: vec![0; width as usize * height as usize * 3],
+        }
+    }
+
+    // Squares that would reach past the picture are clipped.
+    fn fill(&mut self, x: u32, y: u32, size: u32, (red, green, blue): Color) {
+        let width = self.width as usize;
+        for row in y..y.saturating_add(size) {
+            let offset = |column: usize| {
+                (row as usize)
+                    .checked_mul(width)?
+                    .checked_add(column)?
+                    .checked_mul(3)
+            };
+            let right = (x as usize).saturating_add(size as usize).min(width);
+            let (Some(start), Some(end)) = (offset(x as usize), offset(right)) else {
+                return;
+            };
+            let Some(pixels) = self.rgb.get_mut(start..end) else {
+                return;
+            };
+            for pixel in pixels.chunks_exact_mut(3) {
+                pixel.copy_from_slice(&[red, green, blue]);
+            }
+        }
+    }
+}
+
+/// One frame's LEDs placed by `layout`. LEDs the layout does not place are
+/// left out, and cells with no LED stay black. Fails if the picture would be
+/// over [`PIXEL_CAP`].
+pub fn frame_picture(colors: &[Color], layout: &Layout, scale: u32) -> Result<Picture, String> {
+    let (width, height) = layout.picture_size(scale)?;
+    let mut picture = Picture::black(width, height);
+    for (&(x, y), &color) in layout.cells.iter().zip(colors) {
+        // Within the picture, so neither overflows.
+        picture.fill(x.saturating_mul(scale), y.saturating_mul(scale), scale, color);
+    }
+    Ok(picture)
+}
+
+/// `pictures`, all the same size, one under the other. Fails if they are
+/// over [`PIXEL_CAP`] together.
+pub fn stack(pictures: &[Picture]) -> Result<Picture, String> {
+    let pixels: u64 = pictures
+        .iter()
+        .map(|picture| u64::from(picture.width) * u64::from(picture.height))
+        .sum();
+    if pixels > PIXEL_CAP {
+        return Err(format!("{} frames are over {PIXEL_CAP} pixels together", pictures.len()));
+    }
+    let width = pictures.first().map_or(0, |picture| picture.width);
+    let height = pictures
+        .iter()
+        .try_fold(0u32, |height, picture| height.checked_add(picture.height))
+        .ok_or_else(|| format!("{} frames are too tall for one picture", pictures.len()))?;
+    Ok(Picture {
+        width,
+        height,
+        rgb: pictures.iter().flat_map(|picture| picture.rgb.iter().copied()).collect(),
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Ppm,
+    Png,
+    Gif,
+}
+
+impl Format {
+    /// The format a file name's extension asks for.
+    pub fn for_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "ppm" => Some(Format::Ppm),
+            "png" => Some(Format::Png),
+            "gif" => Some(Format::Gif),
+            _ => None,
+        }
+    }
+}
+
+/// The frames encoded as `format`. A GIF shows each frame for `delay_ms`,
+/// rounded up to the format's hundredths of a second, and loops.
+pub fn encode(frames: &[Picture], format: Format, delay_ms: u32) -> Result<Vec<u8>, String> {
+    match format {
+        Format::Ppm => Ok(ppm(&stack(frames)?)),
+        Format::Png => png(&stack(frames)?),
+        Format::Gif => gif(frames, delay_ms),
+    }
+}
+
+fn ppm(picture: &Picture) -> Vec<u8> {
+    let mut bytes = format!("P6\n{} {}\n255\n", picture.width, picture.height).into_bytes();
+    bytes.extend_from_slice(&picture.rgb);
+    bytes
+}
+
+fn png(picture: &Picture) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut bytes, picture.width, picture.height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|err| format!("png: {err}"))?;
+    writer.write_image_data(&picture.rgb).map_err(|err| format!("png: {err}"))?;
+    writer.finish().map_err(|err| format!("png: {err}"))?;
+    Ok(bytes)
+}
+
+fn gif(frames: &[Picture], delay_ms: u32) -> Result<Vec<u8>, String> {
+    let (width, height) = frames
+        .first()
+        .map_or((1, 1), |picture| (picture.width, picture.height));
+    let too_large = || format!("gif: {width}x{height} is larger than a GIF can be");
+    let width = u16::try_from(width).map_err(|_| too_large())?;
+    let height = u16::try_from(height).map_err(|_| too_large())?;
+    let delay = u16::try_from(delay_ms.div_ceil(10)).unwrap_or(u16::MAX);
+
+    let mut bytes = Vec::new();
+    let mut encoder = gif::Encoder::new(&mut bytes, width, height, &[]).map_err(|err| format!("gif: {err}"))?;
+    encoder
+        .set_repeat(gif::Repeat::Infinite)
+        .map_err(|err| format!("gif: {err}"))?;
+    for picture in frames {
+        let mut frame = gif::Frame::from_rgb_speed(width, height, &picture.rgb, 10);
+        frame.delay = delay;
+        encoder.write_frame(&frame).map_err(|err| format!("gif: {err}"))?;
+    }
+    encoder.into_inner().map_err(|err| format!("gif: {err}"))?;
+    Ok(bytes)
+}
@@ -0,0 +1,119 @@
+//! Runs an image in the host VM the way the firmware's LED loop does: the
+//! image is loaded through the protocol, which runs `init`, then every frame
+//! calls `start_frame` on each machine and layers the machines' colors LED
+//! by LED.
+
+use std::fmt::Write;
+
+use light_machine::{ProgramWord, StackWord};
+use pliot::meme_storage::MemStorage;
+use pliot::protocol::{Controler, Protocol};
+use pliot::{Pliot, Storage};
+use postcard::{from_bytes_cobs, to_vec_cobs};
+
+// The sizes flight-deck speaks, so the deck can talk to a virtual device.
+pub const MAX_ARGS: usize = 10;
+pub const MAX_RESULT: usize = 3;
+pub const PROGRAM_BLOCK_SIZE: usize = 64;
+pub const UI_BLOCK_SIZE: usize = 128;
+// Room for a block of words once COBS framed.
+const MESSAGE_MAX: usize = 4 * PROGRAM_BLOCK_SIZE;
+/// Globals and stack, in `StackWord` cells.
+pub const MEMORY_WORDS: usize = 4096;
+
+pub type ProtocolType = Protocol<MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE>;
+pub type HostControler = Controler<MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE>;
+pub type Device<'a, 'b, S> = Pliot<'a, 'b, MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE, S>;
+
+pub type Color = (u8, u8, u8);
+
+pub struct RunOptions {
+    pub leds: u16,
+    pub frames: u32,
+    /// The tick of the first frame; each frame after adds one.
+    pub start_tick: u32,
+    /// Instructions any one VM call may run; `None` for no limit.
+    pub step_limit: Option<u32>,
+}
+
+/// Loads `image` into `device` with the messages a host would send.
+pub fn load_program<S: Storage>(device: &mut Device<'_, '_, S>, image: &[ProgramWord]) -> Result<(), String> {
+    let mut controler = HostControler::new();
+    let mut out = [0u8; MESSAGE_MAX];
+    for message in controler.get_program_loader(image, &[]) {
+        let mut framed = to_vec_cobs::<ProtocolType, MESSAGE_MAX>(&message)
+            .map_err(|err| format!("could not frame a load message: {err}"))?;
+        let wrote = device
+            .process_message(&mut framed, &mut out)
+            .map_err(|err| format!("load failed: {err:?}"))?;
+        if wrote == 0 {
+            continue;
+        }
+        let reply: ProtocolType =
+            from_bytes_cobs(&mut out[..wrote]).map_err(|err| format!("bad reply while loading: {err}"))?;
+        if let Protocol::Error {
+            error_type,
+            location,
+            ..
+        } = reply
+        {
+            return Err(format!("load failed: {error_type:?} {location:?}"));
+        }
+    }
+    Ok(())
+}
+
+/// One frame of `leds` LEDs at `tick`, as `led_loop` computes it. As on the
+/// board, a failed `start_frame` is ignored, and a failed `get_color` leaves
+/// the LED the color the machines before it built. Fails only when there is
+/// no program to run.
+pub fn render_frame<S: Storage>(device: &mut Device<'_, '_, S>, leds: u16, tick: u32) -> Result<Vec<Color>, String> {
+    let machine_count = device.machine_count().map_err(|err| format!("{err:?}"))?;
+    for machine in 0..machine_count {
+        let _ = device.start_frame(machine, tick);
+    }
+    let mut colors = Vec::with_capacity(usize::from(leds));
+    for led in 0..leds {
+        let mut color = (0, 0, 0);
+        for machine in 0..machine_count {
+            match device.get_led_color(machine, led, color) {
+                Ok(next) => color = next,
+                Err(_) => break,
+            }
+        }
+        colors.push(color);
+    }
+    Ok(colors)
+}
+
+/// The colors of every LED for each frame.
+pub fn run(image: &[ProgramWord], options: &RunOptions) -> Result<Vec<Vec<Color>>, String> {
+    // `MemStorage` keeps two halves so a load can swap them.
+    let mut storage_buffer = vec![0u16; image.len().max(1) * 2];
+    let mut ui_state = [0u8; 0];
+    let mut storage = MemStorage::new(&mut storage_buffer, &mut ui_state);
+    let mut memory = vec![0 as StackWord; MEMORY_WORDS];
+    let mut device = Device::new(&mut storage, &mut memory);
+    device.set_step_limit(options.step_limit);
+    load_program(&mut device, image)?;
+
+    (0..options.frames)
+        .map(|frame| {
+            render_frame(&mut device, options.leds, options.start_tick.wrapping_add(frame))
+                .map_err(|err| format!("frame {frame}: {err}"))
+        })
+        .collect()
+}
+
+/// One line per frame: the frame's tick, then each LED as `rrggbb`.
+pub fn format_frames(frames: &[Vec<Color>], start_tick: u32) -> String {
+    let mut out = String::new();
+    for (frame, colors) in (0u32..).zip(frames) {
+        let _ = write!(out, "{}:", start_tick.wrapping_add(frame));
+        for (red, green, blue) in colors {
+            let _ = write!(out, " {red:02x}{green:02x}{blue:02x}");
+        }
+        out.push('\n');
+    }
+    out
+}
@@ -0,0 +1,163 @@
+//! Storage for the virtual device that survives a restart, as flash does.
+//!
+//! `FileStorage` keeps everything in a `MemStorage` and, each time a load
+//! finishes, writes the program and UI state to a file. Opening the file
+//! again replays it into memory. The file is `FPVD`, the program's length in
+//! words and the UI state's length in bytes (each a `u32`), then the
+//! program words and the UI state, all low byte first.
+
+use std::path::PathBuf;
+
+use light_machine::{Program, ProgramWord, StackWord};
+use pliot::meme_storage::{MemProgrameLoader, MemStorage};
+use pliot::{ProgramNumber, Storage, StorageError};
+
+use crate::image;
+use crate::run::{PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE};
+
+const MAGIC: &[u8; 4] = b"FPVD";
+
+pub struct FileStorage<'a> {
+    memory: MemStorage<'a>,
+    path: PathBuf,
+}
+
+/// A load in progress, with a copy of what has arrived so far to save.
+pub struct FileLoader {
+    loader: MemProgrameLoader,
+    program: Vec<ProgramWord>,
+    ui_state: Vec<u8>,
+}
+
+impl<'a> FileStorage<'a> {
+    /// Storage in `program` and `ui_state`, as for `MemStorage::new`, saved
+    /// to `path`. Whatever `path` already holds is loaded. Returns whether
+    /// there was a program to load.
+    pub fn open(
+        path: impl Into<PathBuf>,
+        program: &'a mut [ProgramWord],
+        ui_state: &'a mut [u8],
+    ) -> Result<(Self, bool), String> {
+        let path = path.into();
+        let mut storage = Self {
+            memory: MemStorage::new(program, ui_state),
+            path,
+        };
+        let bytes = match std::fs::read(&storage.path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok((storage, false)),
+            Err(err) => return Err(format!("{}: {err}", storage.path.display())),
+        };
+        let (program, ui_state) = parse(&bytes).map_err(|err| format!("{}: {err}", storage.path.display()))?;
+        storage
+            .restore(&program, ui_state)
+            .map_err(|err| format!("{}: {err:?}", storage.path.display()))?;
+        Ok((storage, true))
+    }
+
+    fn restore(&mut self, program: &[ProgramWord], ui_state: &[u8]) -> Result<(), StorageError> {
+        let mut loader = self
+            .memory
+            .get_program_loader(program.len() as u32, ui_state.len() as u32)?;
+        for (number, block) in (0..).zip(program.chunks(PROGRAM_BLOCK_SIZE)) {
+            self.memory.add_block(&mut loader, number, block)?;
+        }
+        for (number, block) in (0..).zip(ui_state.chunks(UI_BLOCK_SIZE)) {
+            self.memory.add_ui_block(&mut loader, number, block)?;
+        }
+        self.memory.finish_load(loader)?;
+        Ok(())
+    }
+
+    fn save(&self, program: &[ProgramWord], ui_state: &[u8]) -> std::io::Result<()> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&(program.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(ui_state.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&image::to_bytes(program));
+        bytes.extend_from_slice(ui_state);
+        // Write aside and rename, so a crash cannot leave half a file.
+        let staged = self.path.with_extension("tmp");
+        std::fs::write(&staged, bytes)?;
+        std::fs::rename(staged, &self.path)
+    }
+}
+
+fn parse(bytes: &[u8]) -> Result<(Vec<ProgramWord>, &[u8]), String> {
+    let length = |at: usize| -> Result<usize, String> {
+        let field = bytes.get(at..at + 4).ok_or("the file is cut short")?;
+        Ok(u32::from_le_bytes([field[0], field[1], field[2], field[3]]) as usize)
+    };
+    if bytes.get(..4) != Some(MAGIC.as_slice()) {
+        return Err("not a virtual device storage file".to_string());
+    }
+    let program_words = length(4)?;
+    let ui_state_bytes = length(8)?;
+    let program_start = 12;
+    let ui_state_start = program_start + program_words * 2;
+    let program = bytes
+        .get(program_start..ui_state_start)
+        .ok_or("the file is cut short")?;
+    let ui_state = bytes
+        .get(ui_state_start..ui_state_start + ui_state_bytes)
+        .ok_or("the file is cut short")?;
+    Ok((image::from_bytes(program)?, ui_state))
+}
+
+impl Storage for FileStorage<'_> {
+    type L = FileLoader;
+
+    fn get_program_loader(&mut self, size: u32, ui_state_size: u32) -> Result<Self::L, StorageError> {
+        Ok(FileLoader {
+            loader: self.memory.get_program_loader(size, ui_state_size)?,
+            program: Vec::with_capacity(size as usize),
+            ui_state: Vec::with_capacity(ui_state_size as usize),
+        })
+    }
+
+    fn add_block(&mut self, loader: &mut Self::L, block_number: u32, block: &[ProgramWord]) -> Result<(), StorageError> {
+        self.memory.add_block(&mut loader.loader, block_number, block)?;
+        loader.program.extend_from_slice(block);
+        Ok(())
+    }
+
+    fn add_ui_block(&mut self, loader: &mut Self::L, block_number: u32, block: &[u8]) -> Result<(), StorageError> {
+        self.memory.add_ui_block(&mut loader.loader, block_number, block)?;
+        loader.ui_state.extend_from_slice(block);
+        Ok(())
+    }
+
+    fn finish_load(&mut self, loader: Self::L) -> Result<ProgramNumber, StorageError> {
+        let number = self.memory.finish_load(loader.loader)?;
+        // The device keeps running from memory if the file cannot be
+        // written; the next load tries again.
+        if let Err(err) = self.save(&loader.program, &loader.ui_state) {
+            eprintln!("{}: {err}", self.path.display());
+        }
+        Ok(number)
+    }
+
+    fn get_program<'b, 'c>(
+        &'b mut self,
+        program_number: ProgramNumber,
+        memory: &'c mut [StackWord],
+    ) -> Result<Program<'b, 'c>, StorageError> {
+        self.memory.get_program(program_number, memory)
+    }
+
+    fn get_program_len(&mut self, program_number: ProgramNumber) -> Result<u32, StorageError> {
+        self.memory.get_program_len(program_number)
+    }
+
+    fn get_ui_state_len(&mut self, program_number: ProgramNumber) -> Result<u32, StorageError> {
+        self.memory.get_ui_state_len(program_number)
+    }
+
+    fn read_ui_state_block(
+        &mut self,
+        program_number: ProgramNumber,
+        offset: u32,
+        out: &mut [u8],
+    ) -> Result<usize, StorageError> {
+        self.memory.read_ui_state_block(program_number, offset, out)
+    }
+}
@@ -0,0 +1,368 @@
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use light_machine::StackWord;
+use pliot::meme_storage::MemStorage;
+use postcard::{from_bytes_cobs, to_vec_cobs};
+
+use crate::assemble::assemble;
+use crate::device::io_loop;
+use crate::render::{Format, Layout, Picture, encode, frame_picture, stack};
+use crate::run::{
+    Device, HostControler, MEMORY_WORDS, ProtocolType, RunOptions, format_frames, load_program, render_frame, run,
+};
+use crate::storage::FileStorage;
+use crate::{disasm, image, inspect};
+
+// Blue is the LED index plus the frame's tick.
+const COUNTER: &str = "\
+.machine main locals 1 functions 3
+    .local tick 0
+    .func init index 0
+        EXIT
+    .end
+    .func start_frame index 1
+        LSTORE tick
+        EXIT
+    .end
+    .func get_color index 2
+        SWAP
+        POP
+        LLOAD tick
+        ADD
+        EXIT
+    .end
+.end
+";
+
+fn counter_image() -> Vec<u16> {
+    assemble(COUNTER, Path::new("."), "counter.fpa", false).unwrap().image
+}
+
+#[test]
+fn image_bytes_round_trip() {
+    let image = counter_image();
+    let bytes = image::to_bytes(&image);
+    assert_eq!(bytes.len(), image.len() * 2);
+    assert_eq!(image::from_bytes(&bytes).unwrap(), image);
+    assert!(image::from_bytes(&bytes[1..]).is_err());
+}
+
+#[test]
+fn run_layers_frames_like_the_firmware() {
+    let options = RunOptions {
+        leds: 3,
+        frames: 2,
+        start_tick: 10,
+        step_limit: Some(1000),
+    };
+    let frames = run(&counter_image(), &options).unwrap();
+    assert_eq!(
+        frames,
+        vec![vec![(0, 0, 10), (0, 0, 11), (0, 0, 12)], vec![(0, 0, 11), (0, 0, 12), (0, 0, 13)]]
+    );
+    assert_eq!(
+        format_frames(&frames, options.start_tick),
+        "10: 00000a 00000b 00000c\n11: 00000b 00000c 00000d\n"
+    );
+}
+
+// A machine that faults in both calls, then one that would paint every LED
+// white if it were reached.
+const FAULTING: &str = "\
+.machine faulty locals 0 functions 3
+    .func init index 0
+        EXIT
+    .end
+    .func start_frame index 1
+        POP
+        POP
+        EXIT
+    .end
+    .func get_color index 2
+        POP
+        POP
+        POP
+        POP
+        POP
+        EXIT
+    .end
+.end
+.machine white locals 0 functions 3
+    .func init index 0
+        EXIT
+    .end
+    .func start_frame index 1
+        EXIT
+    .end
+    .func get_color index 2
+        POP
+        POP
+        POP
+        POP
+        PUSH 255
+        PUSH 255
+        PUSH 255
+        EXIT
+    .end
+.end
+";
+
+#[test]
+fn run_keeps_the_color_built_before_a_fault() {
+    let source = format!("{COUNTER}{FAULTING}");
+    let image = assemble(&source, Path::new("."), "faulting.fpa", false).unwrap().image;
+    let options = RunOptions {
+        leds: 3,
+        frames: 1,
+        start_tick: 10,
+        step_limit: Some(1000),
+    };
+    // The counter's colors, as if the faulting machine and the one after it
+    // were not there.
+    assert_eq!(run(&image, &options).unwrap(), vec![vec![(0, 0, 10), (0, 0, 11), (0, 0, 12)]]);
+}
+
+#[test]
+fn disassembly_uses_symbol_names() {
+    let text = disasm::disassemble(&counter_image()).unwrap();
+    assert!(text.contains("; machine type 0 `main`, function 1 `start_frame`\n"), "{text}");
+    assert!(text.contains(": LSTORE 0 ; tick\n"), "{text}");
+    assert!(text.contains(": LLOAD 0 ; tick\n"), "{text}");
+    assert!(text.contains("; shared function 0 `init_program`\n"), "{text}");
+}
+
+#[test]
+fn inspect_lists_tables() {
+    let text = inspect::inspect(&counter_image()).unwrap();
+    assert!(text.contains("instances 1\n  0 `main`: type 0, globals at "), "{text}");
+    assert!(text.contains("    2 `get_color` at "), "{text}");
+    assert!(text.contains("\nparams 0 "), "{text}");
+}
+
+#[test]
+fn assemble_reports_problems_with_locations() {
+    let Err(errors) = assemble(".machine main locals 0 functions 1\n    BOGUS\n", Path::new("."), "bad.fpa", false)
+    else {
+        panic!("an unknown instruction should not assemble");
+    };
+    assert!(errors[0].starts_with("bad.fpa:2:5: error: "), "{errors:?}");
+}
+
+#[test]
+fn assemble_locates_a_missing_function() {
+    let source = "\
+.machine main locals 0 functions 3
+    .func init index 0
+        EXIT
+    .end
+    .func start_frame index 1
+        EXIT
+    .end
+.end
+";
+    let Err(errors) = assemble(source, Path::new("."), "missing.fpa", false) else {
+        panic!("a machine missing a function should not assemble");
+    };
+    assert_eq!(errors.len(), 1, "{errors:?}");
+    assert!(
+        errors[0].starts_with("missing.fpa:1:10: error: function 2 of machine `main` is never defined"),
+        "{errors:?}"
+    );
+    assert!(errors[0].contains("[undefined-function]"), "{errors:?}");
+}
+
+#[test]
+fn layout_rejects_other_versions() {
+    let mut image = counter_image();
+    image[light_machine::VERSION_OFFSET] = 2;
+    assert!(inspect::inspect(&image).is_err());
+    assert!(disasm::disassemble(&image).is_err());
+}
+
+#[test]
+fn layout_places_leds_on_a_grid() {
+    let layout = Layout::parse("# a 2x2 square, snaking\n0 0\n1 0\n\n1 1 # third\n0 1\n").unwrap();
+    assert_eq!(layout.led_count(), 4);
+    let picture = frame_picture(&[(1, 0, 0), (2, 0, 0), (3, 0, 0), (4, 0, 0)], &layout, 1).unwrap();
+    assert_eq!((picture.width, picture.height), (2, 2));
+    assert_eq!(picture.rgb, vec![1, 0, 0, 2, 0, 0, 4, 0, 0, 3, 0, 0]);
+
+    let Err(err) = Layout::parse("0 0\n1\n") else {
+        panic!("a line without y should not parse");
+    };
+    assert!(err.starts_with("line 2:"), "{err}");
+    assert!(Layout::parse("# nothing\n").is_err());
+}
+
+#[test]
+fn layout_rejects_grids_past_the_pixel_cap() {
+    let Err(err) = Layout::parse("0 0\n4294967295 0\n") else {
+        panic!("a cell at the last column should not fit");
+    };
+    assert!(err.starts_with("line 2:"), "{err}");
+    // 70000 by 70001 cells is over the cap without overflowing.
+    assert!(Layout::parse("0 0\n69999 70000\n").is_err());
+}
+
+#[test]
+fn frame_picture_rejects_scales_past_the_pixel_cap() {
+    let layout = Layout::parse("69999 0\n").unwrap();
+    // 70000 * 70000 overflows a u32.
+    assert!(frame_picture(&[(1, 2, 3)], &layout, 70000).is_err());
+    // 5000 * 5000 does not, but is over the cap.
+    assert!(frame_picture(&[(1, 2, 3)], &Layout::strip(1), 5000).is_err());
+    assert!(frame_picture(&[(1, 2, 3)], &Layout::strip(1), 4000).is_ok());
+}
+
+#[test]
+fn renders_are_capped_across_all_frames() {
+    let layout = Layout::strip(30);
+    assert!(layout.check_render(8, 1000).is_ok());
+    // Each frame is small; together they are over the cap.
+    assert!(layout.check_render(8, 10_000).is_err());
+    assert!(layout.check_render(8, u32::MAX).is_err());
+    let frame = || Picture {
+        width: 4096,
+        height: 4096,
+        rgb: Vec::new(),
+    };
+    assert!(stack(&[frame()]).is_ok());
+    assert!(stack(&[frame(), frame()]).is_err());
+}
+
+#[test]
+fn still_images_run_time_down_the_page() {
+    let options = RunOptions {
+        leds: 3,
+        frames: 2,
+        start_tick: 0,
+        step_limit: Some(1000),
+    };
+    let frames = run(&counter_image(), &options).unwrap();
+    let pictures: Vec<_> = frames
+        .iter()
+        .map(|colors| frame_picture(colors, &Layout::strip(options.leds), 2).unwrap())
+        .collect();
+
+    let ppm = encode(&pictures, Format::Ppm, 40).unwrap();
+    let header = b"P6\n6 4\n255\n";
+    assert_eq!(&ppm[..header.len()], header);
+    // The second frame starts on the third row; its first LED has blue 1.
+    let second_frame = header.len() + 2 * 6 * 3;
+    assert_eq!(&ppm[second_frame..second_frame + 3], &[0, 0, 1]);
+
+    let png = encode(&pictures, Format::Png, 40).unwrap();
+    let mut reader = png::Decoder::new(std::io::Cursor::new(png)).read_info().unwrap();
+    let mut pixels = vec![0; reader.output_buffer_size().unwrap()];
+    reader.next_frame(&mut pixels).unwrap();
+    assert_eq!(pixels, ppm[header.len()..]);
+}
+
+#[test]
+fn gifs_play_one_frame_per_tick() {
+    let frames = [vec![(255, 0, 0), (0, 0, 0)], vec![(0, 0, 0), (255, 0, 0)]];
+    let pictures: Vec<_> = frames
+        .iter()
+        .map(|colors| frame_picture(colors, &Layout::strip(2), 1).unwrap())
+        .collect();
+    let gif = encode(&pictures, Format::Gif, 45).unwrap();
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    let mut decoder = options.read_info(gif.as_slice()).unwrap();
+    let mut delays = Vec::new();
+    while let Some(frame) = decoder.read_next_frame().unwrap() {
+        assert_eq!((frame.width, frame.height), (2, 1));
+        delays.push(frame.delay);
+    }
+    assert_eq!(delays, vec![5, 5]);
+}
+
+#[test]
+fn output_format_follows_the_extension() {
+    assert_eq!(Format::for_path(Path::new("out.PNG")), Some(Format::Png));
+    assert_eq!(Format::for_path(Path::new("out.gif")), Some(Format::Gif));
+    assert_eq!(Format::for_path(Path::new("out.ppm")), Some(Format::Ppm));
+    assert_eq!(Format::for_path(Path::new("out.jpg")), None);
+}
+
+/// A client that sends `input` and records what comes back.
+struct Client {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl Read for Client {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Client {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn device_loads_programs_over_a_stream() {
+    let image = counter_image();
+    // An overlong frame is dropped and the next one still gets through.
+    let mut input = vec![1u8; 3000];
+    input.push(0);
+    for message in HostControler::new().get_program_loader(&image, &[]) {
+        input.extend_from_slice(&to_vec_cobs::<ProtocolType, 256>(&message).unwrap());
+    }
+    let mut client = Client {
+        input: Cursor::new(input),
+        output: Vec::new(),
+    };
+
+    let mut program = vec![0; 1024];
+    let mut ui_state = [0u8; 0];
+    let mut storage = MemStorage::new(&mut program, &mut ui_state);
+    let mut memory = vec![0 as StackWord; MEMORY_WORDS];
+    let device = Mutex::new(Device::new(&mut storage, &mut memory));
+    io_loop(&device, &mut client).unwrap();
+
+    for reply in client.output.split_inclusive_mut(|&byte| byte == 0) {
+        let reply: ProtocolType = from_bytes_cobs(reply).unwrap();
+        assert!(!matches!(reply, ProtocolType::Error { .. }), "{reply:?}");
+    }
+    let mut device = device.into_inner().unwrap();
+    assert_eq!(render_frame(&mut device, 2, 5).unwrap(), vec![(0, 0, 5), (0, 0, 6)]);
+}
+
+#[test]
+fn file_storage_keeps_the_program_across_restarts() {
+    let path = std::env::temp_dir().join(format!("fluxpilot-storage-{}.fpvd", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut program = vec![0; 1024];
+    let mut ui_state = [0u8; 16];
+    let mut memory = vec![0 as StackWord; MEMORY_WORDS];
+
+    let (mut storage, restored) = FileStorage::open(&path, &mut program, &mut ui_state).unwrap();
+    assert!(!restored);
+    let mut device = Device::new(&mut storage, &mut memory);
+    load_program(&mut device, &counter_image()).unwrap();
+    drop(device);
+    drop(storage);
+
+    program.fill(0);
+    let (mut storage, restored) = FileStorage::open(&path, &mut program, &mut ui_state).unwrap();
+    assert!(restored);
+    let mut device = Device::new(&mut storage, &mut memory);
+    device.init().unwrap();
+    assert_eq!(render_frame(&mut device, 1, 7).unwrap(), vec![(0, 0, 7)]);
+    drop(device);
+    drop(storage);
+
+    std::fs::write(&path, b"nope").unwrap();
+    assert!(FileStorage::open(&path, &mut program, &mut ui_state).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
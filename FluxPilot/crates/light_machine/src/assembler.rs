@@ -15,29 +15,96 @@ use crate::builder::{
     ProgramBuilder,
     SharedFunctionBuilder,
 };
+use crate::metadata::{ParamInfo, ParamSpec, ParamTarget};
 use crate::ProgramWord;
 
-const MAX_TOKENS: usize = 6;
+pub mod control;
+pub mod diagnostic;
+pub mod expression;
+pub mod format;
+pub mod include;
+pub mod macros;
+pub mod signature;
+pub mod source_map;
+pub mod syntax;
+
+use control::ControlStack;
+use diagnostic::Diagnostic;
+use expression::{parse_word, Value};
+use include::{SourceLocation, SourceResolver};
+use macros::{MacroLine, MacroLineBuffer, MacroTable, MAX_MACRO_DEPTH};
+use signature::{FunctionHeader, Signature};
+use source_map::{SliceSink, SourceMapWriter};
+use syntax::{
+    is_identifier,
+    parse_line,
+    split_first_token,
+    strip_comment,
+    DataWord,
+    Directive,
+    InstanceDirective,
+    Instruction,
+    Line,
+    Mnemonic,
+    Operand,
+    OperandKind,
+    ParamDirective,
+    ParamTargetName,
+};
+
 const NAME_CAP: usize = 32;
+// Macro bodies share one buffer; sized for a handful of short helpers.
+const MACRO_TEXT_CAP: usize = 1024;
+const MACRO_COUNT_CAP: usize = 8;
+// `.param` controls across the whole program.
+const PARAM_CAP: usize = 16;
 
 #[derive(Debug)]
 pub enum AssemblerError {
     Kind(AssemblerErrorKind),
     WithLine { line: u32, kind: AssemblerErrorKind },
+    InFile {
+        file: String<NAME_CAP>,
+        line: u32,
+        kind: AssemblerErrorKind,
+    },
 }
 
 impl AssemblerError {
     fn with_line(self, line: u32) -> Self {
         match self {
-            AssemblerError::WithLine { .. } => self,
+            AssemblerError::WithLine { .. } | AssemblerError::InFile { .. } => self,
             AssemblerError::Kind(kind) => AssemblerError::WithLine { line, kind },
         }
     }
 
+    /// Tags the error with where its line came from. Errors that already
+    /// name an included file keep it; any other line number is replaced,
+    /// since the assembler counts lines across includes.
+    pub fn at_location(self, location: SourceLocation<'_>) -> Self {
+        let kind = match self {
+            AssemblerError::InFile { .. } => return self,
+            AssemblerError::Kind(kind) | AssemblerError::WithLine { kind, .. } => kind,
+        };
+        let line = location.line;
+        match location.file.map(to_name) {
+            Some(Ok(file)) => AssemblerError::InFile { file, line, kind },
+            _ => AssemblerError::WithLine { line, kind },
+        }
+    }
+
     pub fn line_number(&self) -> Option<u32> {
         match self {
             Self::Kind(_) => None,
-            Self::WithLine { line, .. } => Some(*line)
+            Self::WithLine { line, .. } | Self::InFile { line, .. } => Some(*line),
+        }
+    }
+
+    /// The included file the error came from, `None` for the root source.
+    pub fn file_name(&self) -> Option<&str> {
+        match self {
+            Self::InFile { file, .. } => Some(file.as_str()),
+            _ => None,
         }
     }
 
@@ -45,11 +112,12 @@ impl AssemblerError {
         match self {
             Self::Kind(kind) => kind,
             Self::WithLine { kind, .. } => kind,
+            Self::InFile { kind, .. } => kind,
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum AssemblerErrorKind {
     EmptyLine,
     TooManyTokens,
@@ -76,6 +144,25 @@ pub enum AssemblerErrorKind {
     LineNumberOverflow,
     CursorOverflow,
     DataTooLarge,
+    InvalidExpression,
+    ExpressionOutOfRange,
+    DuplicateConst,
+    IncludeNotFound,
+    IncludeCycle,
+    IncludeTooDeep,
+    DuplicateMacro,
+    MacroArgumentCount,
+    MacroTooDeep,
+    MacroTooLarge,
+    UnmatchedControl,
+    UnclosedControl,
+    ControlTooDeep,
+    SourceMapTooLarge,
+    UnknownMachine,
+    SignatureMismatch,
+    ArgumentCountMismatch,
+    ReturnCountMismatch,
+    InvalidParam,
     Builder(MachineBuilderError),
 }
 
@@ -94,12 +181,37 @@ struct Label {
 struct Fixup {
     name: String<NAME_CAP>,
     at: ProgramWord,
+    offset: i64,
+}
+
+// Label bases seen by expressions. Code and static addresses are already
+// absolute here; only forward code labels wait for a fixup.
+#[derive(PartialEq)]
+enum Symbol {
+    Code,
+    Static,
+    Forward(String<NAME_CAP>),
+}
+
+// A finished `.machine` block; its position is its type id.
+struct MachineEntry<const LABEL_CAP: usize> {
+    name: String<NAME_CAP>,
+    locals: Vec<Label, LABEL_CAP>,
+}
+
+// A `.param` waiting for the end of the program. A function target is
+// looked up when its machine ends, since the function may come later.
+struct ParamEntry {
+    name: String<NAME_CAP>,
+    info: ParamInfo,
+    function: Option<String<NAME_CAP>>,
 }
 
 struct FuncEntry {
     name: String<NAME_CAP>,
     index: ProgramWord,
     defined: bool,
+    signature: Signature,
 }
 
 enum BlockKind {
@@ -119,12 +231,20 @@ pub struct Assembler<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MA
     block: BlockKind,
     labels: Vec<Label, LABEL_CAP>,
     static_labels: Vec<Label, LABEL_CAP>,
+    // `.shared_data` labels, which stay visible in every later machine.
+    shared_static_labels: Vec<Label, LABEL_CAP>,
     fixups: Vec<Fixup, LABEL_CAP>,
     funcs: Vec<FuncEntry, FUNCTION_COUNT_MAX>,
     shared_funcs: Vec<FuncEntry, FUNCTION_COUNT_MAX>,
     globals: Vec<Label, LABEL_CAP>,
     shared_globals: Vec<Label, LABEL_CAP>,
     stack_slots: Vec<Label, LABEL_CAP>,
+    consts: Vec<Label, LABEL_CAP>,
+    machines: Vec<MachineEntry<LABEL_CAP>, MACHINE_COUNT_MAX>,
+    params: Vec<ParamEntry, PARAM_CAP>,
+    instance_count: ProgramWord,
+    macros: MacroTable<MACRO_TEXT_CAP, MACRO_COUNT_CAP>,
+    control: ControlStack,
     data: Vec<ProgramWord, DATA_CAP>,
     cursor: ProgramWord,
     function_base: ProgramWord,
@@ -137,6 +257,10 @@ pub struct Assembler<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MA
     shared_globals_size: ProgramWord,
     shared_globals_locked: bool,
     line_number: u32,
+    signature: Signature,
+    machine_name: String<NAME_CAP>,
+    source_map: Option<SourceMapWriter<SliceSink<'a>>>,
+    source_line: u32,
 }
 
 impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const LABEL_CAP: usize, const DATA_CAP: usize>
@@ -152,12 +276,19 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
             block: BlockKind::None,
             labels: Vec::new(),
             static_labels: Vec::new(),
+            shared_static_labels: Vec::new(),
             fixups: Vec::new(),
             funcs: Vec::new(),
             shared_funcs: Vec::new(),
             globals: Vec::new(),
             shared_globals: Vec::new(),
             stack_slots: Vec::new(),
+            consts: Vec::new(),
+            machines: Vec::new(),
+            params: Vec::new(),
+            instance_count: 0,
+            macros: MacroTable::new(),
+            control: ControlStack::new(),
             data: Vec::new(),
             cursor: 0,
             function_base: 0,
@@ -170,67 +301,145 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
             shared_globals_size: 0,
             shared_globals_locked: false,
             line_number: 0,
+            signature: Signature::default(),
+            machine_name: String::new(),
+            source_map: None,
+            source_line: 0,
         }
     }
 
+    /// Records a source map into `buffer` while assembling. Its length is
+    /// returned by [`Self::finish_with_source_map`].
+    pub fn with_source_map(mut self, buffer: &'a mut [u8]) -> Self {
+        self.source_map = Some(SourceMapWriter::new(SliceSink::new(buffer)));
+        self
+    }
+
     pub fn add_line(&mut self, line: &str) -> Result<(), AssemblerError> {
+        self.add_line_from(None, line)
+    }
+
+    /// Like [`Self::add_line`] for a line read from `location`, which the
+    /// source map records instead of the running line count.
+    pub fn add_line_at(&mut self, location: SourceLocation<'_>, line: &str) -> Result<(), AssemblerError> {
+        self.add_line_from(Some(location), line)
+    }
+
+    fn add_line_from(&mut self, location: Option<SourceLocation<'_>>, line: &str) -> Result<(), AssemblerError> {
         self.line_number = self
             .line_number
             .checked_add(1)
             .ok_or(AssemblerError::Kind(AssemblerErrorKind::LineNumberOverflow))?;
         let line_number = self.line_number;
-        let line = strip_comment(line);
-        let line = line.trim();
-        if line.is_empty() {
-            return Ok(());
-        }
+        let location = location.unwrap_or(SourceLocation {
+            file: None,
+            line: line_number,
+        });
+        self.source_line = location.line;
+        if let Some(map) = self.source_map.as_mut() {
+            map.set_file(location.file)
+                .map_err(|err| err.with_line(line_number))?;
+        }
+        self.expand_line(line, 0)
+            .map_err(|err| err.with_line(line_number))
+    }
 
-        // Token limit keeps parsing bounded in no_std/heapless mode.
-        let mut tokens: Vec<&str, MAX_TOKENS> = Vec::new();
-        for token in line.split_whitespace() {
-            tokens.push(token).map_err(|_| {
-                AssemblerError::Kind(AssemblerErrorKind::TooManyTokens).with_line(line_number)
-            })?;
-        }
-        if tokens.is_empty() {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::EmptyLine).with_line(line_number));
+    // Lines produced by a macro call are assembled under the line number of
+    // the call.
+    fn expand_line(&mut self, line: &str, depth: usize) -> Result<(), AssemblerError> {
+        let mut call = match self.macros.classify(line)? {
+            MacroLine::Plain => return self.lower_line(line),
+            MacroLine::Recorded => return Ok(()),
+            MacroLine::Call(call) => call,
+        };
+        let depth = depth
+            .checked_add(1)
+            .filter(|depth| *depth <= MAX_MACRO_DEPTH)
+            .ok_or(AssemblerError::Kind(AssemblerErrorKind::MacroTooDeep))?;
+        let mut expanded = MacroLineBuffer::new();
+        while self.macros.expand_next(&mut call, &mut expanded)? {
+            self.expand_line(&expanded, depth)?;
         }
+        Ok(())
+    }
 
-        let first = match tokens.first() {
-            Some(token) => *token,
-            None => {
-                return Err(AssemblerError::Kind(AssemblerErrorKind::EmptyLine).with_line(line_number));
-            }
+    fn lower_line(&mut self, line: &str) -> Result<(), AssemblerError> {
+        let in_function = matches!(self.block, BlockKind::Function | BlockKind::SharedFunction);
+        let stack_slots = &self.stack_slots;
+        let lowered = self
+            .control
+            .lower(line, in_function, |name| stack_slots.iter().any(|slot| slot.name == name))?;
+        let Some(lowered) = lowered else {
+            return self.assemble_line(line);
         };
-
-        // Labels must be a single token ending with ':' to keep parsing one-pass.
-        if tokens.len() == 1 && first.ends_with(':') {
-            return self
-                .add_label(first)
-                .map_err(|err| err.with_line(line_number));
+        for line in &lowered {
+            self.assemble_line(line)?;
         }
+        Ok(())
+    }
 
-        if matches!(self.block, BlockKind::Data | BlockKind::SharedData) && first != ".end" {
-            return self
-                .handle_data_line(&tokens)
-                .map_err(|err| err.with_line(line_number));
-        }
+    fn assemble_line(&mut self, line: &str) -> Result<(), AssemblerError> {
+        let line_number = self.line_number;
+        let in_data = matches!(self.block, BlockKind::Data | BlockKind::SharedData);
+        let start = self.cursor;
+        parse_line(line, in_data)
+            .and_then(|line| match line {
+                Line::Empty => Ok(()),
+                Line::Label(name) => self.add_label(name),
+                Line::Directive(directive) => self.handle_directive(directive),
+                Line::Data(word) => self.handle_data_line(word),
+                Line::Instruction(instruction) => self
+                    .handle_instruction(instruction)
+                    .and_then(|()| self.map_words(start)),
+            })
+            .map_err(|err| err.with_line(line_number))
+    }
 
-        // Directives always start with '.' to avoid ambiguity with mnemonics.
-        if first.starts_with('.') {
-            return self
-                .handle_directive(&tokens)
-                .map_err(|err| err.with_line(line_number));
-        }
+    // Source map entry for the words emitted since `start`.
+    fn map_words(&mut self, start: ProgramWord) -> Result<(), AssemblerError> {
+        let Some(map) = self.source_map.as_mut() else {
+            return Ok(());
+        };
+        let absolute = |offset: ProgramWord| {
+            self.function_base
+                .checked_add(offset)
+                .ok_or(AssemblerError::Kind(AssemblerErrorKind::CursorOverflow))
+        };
+        map.add_words(absolute(start)?, absolute(self.cursor)?, self.source_line)
+    }
 
-        self.handle_instruction(&tokens)
-            .map_err(|err| err.with_line(line_number))
+    /// Assembles a whole source, expanding `.include` through `resolver`.
+    pub fn add_source<R: SourceResolver>(&mut self, source: &str, resolver: &R) -> Result<(), AssemblerError> {
+        include::for_each_line(source, resolver, |location, line| self.add_line_at(location, line))
+    }
+
+    /// Like [`Self::add_source`], but carries on after recoverable errors and
+    /// passes every problem to `report`. Returns `false` if it had to stop.
+    pub fn add_source_reporting<R: SourceResolver>(
+        &mut self,
+        source: &str,
+        resolver: &R,
+        report: impl FnMut(Diagnostic),
+    ) -> bool {
+        diagnostic::for_each_line_reporting(
+            source,
+            resolver,
+            |location, line| self.add_line_at(location, line),
+            report,
+        )
     }
 
+    pub fn finish(self) -> Result<crate::ProgramDescriptor<MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>, AssemblerError> {
+        self.finish_with_source_map().map(|(descriptor, _)| descriptor)
+    }
 
-    pub fn finish(mut self) -> Result<crate::ProgramDescriptor<MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>, AssemblerError> {
+    /// Finishes the program and the source map, returning how many bytes of
+    /// the map buffer were used (0 without [`Self::with_source_map`]).
+    pub fn finish_with_source_map(
+        mut self,
+    ) -> Result<(crate::ProgramDescriptor<MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>, usize), AssemblerError> {
         match self.block {
-            BlockKind::None => {}
+            BlockKind::None if !self.macros.is_recording() => {}
             _ => return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective)),
         }
         for entry in self.shared_funcs.iter() {
@@ -240,21 +449,34 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
                 ));
             }
         }
-        let program = self
+        let mut program = self
             .program
             .take()
             .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingProgram))?;
-        Ok(program.finish_program())
+        let mut params: Vec<ParamSpec<'_>, PARAM_CAP> = Vec::new();
+        for entry in self.params.iter() {
+            params
+                .push(ParamSpec {
+                    name: entry.name.as_str(),
+                    info: entry.info,
+                })
+                .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MaxLabelsExceeded))?;
+        }
+        program.add_params(&params)?;
+        let map_len = match self.source_map.take() {
+            Some(map) => map.finish()?.len(),
+            None => 0,
+        };
+        Ok((program.finish_program()?, map_len))
     }
 
-    fn add_label(&mut self, token: &str) -> Result<(), AssemblerError> {
-        let name = token.trim_end_matches(':');
+    fn add_label(&mut self, name: &str) -> Result<(), AssemblerError> {
         let name = to_name(name)?;
         if self.labels.iter().any(|label| label.name == name) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::DuplicateLabel));
         }
         let offset = match self.block {
-            BlockKind::Function => self
+            BlockKind::Function | BlockKind::SharedFunction => self
                 .function_base
                 .checked_add(self.cursor)
                 .ok_or(AssemblerError::Kind(AssemblerErrorKind::CursorOverflow))?,
@@ -267,45 +489,153 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
         Ok(())
     }
 
-    fn handle_directive(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
-        let Some(token) = tokens.first() else {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
+    fn handle_directive(&mut self, directive: Directive<'_>) -> Result<(), AssemblerError> {
+        match directive {
+            Directive::Machine {
+                name,
+                locals,
+                functions,
+            } => self.start_machine(name, locals, functions),
+            Directive::Instance(instance) => self.add_instance(instance),
+            Directive::Func(header) => self.start_function(header),
+            Directive::FuncDecl(header) => self.declare_function(header),
+            Directive::SharedFunc(header) => self.start_shared_function(header),
+            Directive::SharedFuncDecl(header) => self.declare_shared_function(header),
+            Directive::Local { name, index } => self.declare_local(name, index),
+            Directive::Shared { name, index } => self.declare_shared(name, index),
+            Directive::Frame { name, offset } => self.declare_stack_slot(name, offset),
+            Directive::Data { .. } => self.start_data(),
+            Directive::SharedData { .. } => self.start_shared_data(),
+            Directive::Const { name, expr } => self.define_const(name, expr),
+            Directive::Param(param) => self.declare_param(param),
+            Directive::End => self.end_block(),
+        }
+    }
+
+    fn declare_param(&mut self, param: ParamDirective<'_>) -> Result<(), AssemblerError> {
+        if !matches!(self.block, BlockKind::Machine) {
+            return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
+        }
+        let type_id = ProgramWord::try_from(self.machines.len())
+            .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MaxLabelsExceeded))?;
+        let name = to_name(param.name)?;
+        if self
+            .params
+            .iter()
+            .any(|entry| entry.info.type_id == type_id && entry.name == name)
+        {
+            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidParam));
+        }
+        let (target, function) = match param.target {
+            ParamTargetName::Local(local) => {
+                let local = self
+                    .resolve_local_operand(local)?
+                    .ok_or(AssemblerError::Kind(AssemblerErrorKind::UnknownLabel))?;
+                let last = local
+                    .checked_add(param.kind.value_count())
+                    .ok_or(AssemblerError::Kind(AssemblerErrorKind::GlobalIndexOutOfRange))?;
+                if last > self.globals_size {
+                    return Err(AssemblerError::Kind(AssemblerErrorKind::GlobalIndexOutOfRange));
+                }
+                (ParamTarget::Local(local), None)
+            }
+            ParamTargetName::Function(function) => (ParamTarget::Function(0), Some(to_name(function)?)),
         };
-        match *token {
-            ".machine" => self.start_machine(tokens),
-            ".func" => self.start_function(tokens),
-            ".func_decl" => self.declare_function(tokens),
-            ".shared_func" => self.start_shared_function(tokens),
-            ".shared_func_decl" => self.declare_shared_function(tokens),
-            ".local" => self.declare_local(tokens),
-            ".shared" => self.declare_shared(tokens),
-            ".frame" => self.declare_stack_slot(tokens),
-            ".data" => self.start_data(tokens),
-            ".shared_data" => self.start_shared_data(tokens),
-            ".end" => self.end_block(),
-            _ => Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective)),
+        let info = param.info(type_id, target, |text| self.evaluate(text, false)?.to_word())?;
+        self.params
+            .push(ParamEntry { name, info, function })
+            .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MaxLabelsExceeded))
+    }
+
+    // Points this machine's function params at their functions.
+    fn resolve_param_functions(&mut self) -> Result<(), AssemblerError> {
+        for entry in self.params.iter_mut() {
+            let Some(function) = entry.function.take() else {
+                continue;
+            };
+            let func = self
+                .funcs
+                .iter()
+                .find(|func| func.name == function)
+                .ok_or(AssemblerError::Kind(AssemblerErrorKind::UnknownLabel))?;
+            entry.info.target = ParamTarget::Function(func.index);
         }
+        Ok(())
+    }
+
+    fn define_const(&mut self, name: &str, expr: &str) -> Result<(), AssemblerError> {
+        let name = to_name(name)?;
+        if self.consts.iter().any(|entry| entry.name == name) {
+            return Err(AssemblerError::Kind(AssemblerErrorKind::DuplicateConst));
+        }
+        let offset = self.evaluate(expr, false)?.to_word()?;
+        self.consts
+            .push(Label { name, offset })
+            .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MaxLabelsExceeded))?;
+        Ok(())
     }
 
-    fn start_machine(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+    fn add_instance(&mut self, instance: InstanceDirective<'_>) -> Result<(), AssemblerError> {
         if !matches!(self.block, BlockKind::None) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
         }
-        // Strict token shape keeps the grammar unambiguous.
-        let locals_token = tokens.get(2).copied();
-        let functions_token = tokens.get(4).copied();
-        if tokens.len() != 6
-            || !matches!(locals_token, Some("locals") | Some("globals"))
-            || functions_token != Some("functions")
-        {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        }
-        let globals_size = parse_word(tokens.get(3).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
-        let function_count = parse_word(tokens.get(5).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
+        let name = to_name(instance.name)?;
+        if self.consts.iter().any(|entry| entry.name == name) {
+            return Err(AssemblerError::Kind(AssemblerErrorKind::DuplicateConst));
+        }
+        let (type_id, entry) = self
+            .machines
+            .iter()
+            .enumerate()
+            .find(|(_, entry)| entry.name.as_str() == instance.machine)
+            .ok_or(AssemblerError::Kind(AssemblerErrorKind::UnknownMachine))?;
+        let type_id = ProgramWord::try_from(type_id)
+            .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MaxLabelsExceeded))?;
+        let mut initial_locals: Vec<(ProgramWord, ProgramWord), LABEL_CAP> = Vec::new();
+        for assignment in instance.assignments() {
+            let (local, expr) = assignment?;
+            let local = entry
+                .locals
+                .iter()
+                .find(|label| label.name.as_str() == local)
+                .ok_or(AssemblerError::Kind(AssemblerErrorKind::UnknownLabel))?;
+            let value = self.evaluate(expr, false)?.to_word()?;
+            initial_locals
+                .push((local.offset, value))
+                .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MaxLabelsExceeded))?;
+        }
+        self.program
+            .as_mut()
+            .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingProgram))?
+            .add_instance_with_locals(type_id, &initial_locals)?;
+        // The name stands for the machine index hosts pass to the VM.
+        self.consts
+            .push(Label {
+                name,
+                offset: self.instance_count,
+            })
+            .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MaxLabelsExceeded))?;
+        self.next_instance()
+    }
+
+    fn next_instance(&mut self) -> Result<(), AssemblerError> {
+        self.instance_count = self
+            .instance_count
+            .checked_add(1)
+            .ok_or(AssemblerError::Kind(AssemblerErrorKind::MaxLabelsExceeded))?;
+        Ok(())
+    }
+
+    fn start_machine(
+        &mut self,
+        name: &str,
+        globals_size: ProgramWord,
+        function_count: ProgramWord,
+    ) -> Result<(), AssemblerError> {
+        if !matches!(self.block, BlockKind::None) {
+            return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
+        }
+        self.machine_name = to_name(name)?;
         self.labels.clear();
         self.static_labels.clear();
         self.fixups.clear();
@@ -331,23 +661,13 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
         Ok(())
     }
 
-    fn start_shared_function(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+    fn start_shared_function(&mut self, header: FunctionHeader<'_>) -> Result<(), AssemblerError> {
         if !matches!(self.block, BlockKind::None) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
         }
-        if tokens.len() != 2 && tokens.len() != 4 {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        }
-        let name = to_name(tokens.get(1).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
-        let index = if tokens.len() == 4 {
-            if tokens.get(2).copied() != Some("index") {
-                return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-            }
-            parse_word(tokens.get(3).ok_or(AssemblerError::Kind(
-                AssemblerErrorKind::InvalidDirective,
-            ))?)?
+        let name = to_name(header.name)?;
+        let index = if let Some(index) = header.index {
+            index
         } else if let Some(entry) = self.shared_funcs.iter().find(|entry| entry.name == name) {
             entry.index
         } else {
@@ -359,7 +679,7 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
                 AssemblerErrorKind::FunctionIndexOutOfRange,
             ));
         }
-        self.mark_shared_function_defined(&name, index)?;
+        self.signature = self.mark_shared_function_defined(&name, index, header.signature)?;
 
         self.labels.clear();
         self.fixups.clear();
@@ -376,30 +696,25 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
         }
         let function = program.new_shared_function_at_index(FunctionIndex::new(index))?;
         self.function_base = function.function_start();
+        if let Some(map) = self.source_map.as_mut() {
+            map.set_function(None, &name)?;
+        }
         self.shared_function = Some(function);
         self.block = BlockKind::SharedFunction;
         Ok(())
     }
 
-    fn declare_local(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+    fn declare_local(&mut self, name: &str, index: ProgramWord) -> Result<(), AssemblerError> {
         if !matches!(self.block, BlockKind::Machine) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
         }
-        if tokens.len() != 3 {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        }
-        let name = to_name(tokens.get(1).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
+        let name = to_name(name)?;
         if self.globals.iter().any(|entry| entry.name == name) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::DuplicateGlobal));
         }
         if self.shared_globals.iter().any(|entry| entry.name == name) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::DuplicateGlobal));
         }
-        let index = parse_word(tokens.get(2).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
         if index >= self.globals_size {
             return Err(AssemblerError::Kind(
                 AssemblerErrorKind::GlobalIndexOutOfRange,
@@ -411,22 +726,14 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
         Ok(())
     }
 
-    fn declare_shared(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+    fn declare_shared(&mut self, name: &str, index: ProgramWord) -> Result<(), AssemblerError> {
         if !matches!(self.block, BlockKind::None) || self.shared_globals_locked {
             return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
         }
-        if tokens.len() != 3 {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        }
-        let name = to_name(tokens.get(1).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
+        let name = to_name(name)?;
         if self.shared_globals.iter().any(|entry| entry.name == name) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::DuplicateGlobal));
         }
-        let index = parse_word(tokens.get(2).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
         let next_size = index
             .checked_add(1)
             .ok_or(AssemblerError::Kind(AssemblerErrorKind::GlobalIndexOutOfRange))?;
@@ -439,46 +746,27 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
         Ok(())
     }
 
-    fn declare_stack_slot(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+    fn declare_stack_slot(&mut self, name: &str, offset: ProgramWord) -> Result<(), AssemblerError> {
         if !matches!(self.block, BlockKind::Function) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
         }
-        if tokens.len() != 3 {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        }
-        let name = to_name(tokens.get(1).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
+        let name = to_name(name)?;
         if self.stack_slots.iter().any(|entry| entry.name == name) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::DuplicateStackSlot));
         }
-        let offset = parse_word(tokens.get(2).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
         self.stack_slots
             .push(Label { name, offset })
             .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MaxLabelsExceeded))?;
         Ok(())
     }
 
-    fn start_function(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+    fn start_function(&mut self, header: FunctionHeader<'_>) -> Result<(), AssemblerError> {
         if !matches!(self.block, BlockKind::Machine) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
         }
-        // Allow optional `index <I>`; any other shape is rejected.
-        if tokens.len() != 2 && tokens.len() != 4 {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        }
-        let name = to_name(tokens.get(1).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
-        let index = if tokens.len() == 4 {
-            if tokens.get(2).copied() != Some("index") {
-                return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-            }
-            parse_word(tokens.get(3).ok_or(AssemblerError::Kind(
-                AssemblerErrorKind::InvalidDirective,
-            ))?)?
+        let name = to_name(header.name)?;
+        let index = if let Some(index) = header.index {
+            index
         } else if let Some(entry) = self.funcs.iter().find(|entry| entry.name == name) {
             entry.index
         } else {
@@ -490,7 +778,7 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
                 AssemblerErrorKind::FunctionIndexOutOfRange,
             ));
         }
-        self.mark_function_defined(&name, index)?;
+        self.signature = self.mark_function_defined(&name, index, header.signature)?;
 
         self.labels.clear();
         self.fixups.clear();
@@ -502,31 +790,22 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
             .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingMachine))?;
         let function = machine.new_function_at_index(FunctionIndex::new(index))?;
         self.function_base = function.function_start();
+        if let Some(map) = self.source_map.as_mut() {
+            map.set_function(Some(&self.machine_name), &name)?;
+        }
         self.function = Some(function);
         self.block = BlockKind::Function;
         Ok(())
     }
 
-    fn declare_function(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+    fn declare_function(&mut self, header: FunctionHeader<'_>) -> Result<(), AssemblerError> {
         if !matches!(self.block, BlockKind::Machine) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
         }
-        // Allow optional `index <I>`; any other shape is rejected.
-        if tokens.len() != 2 && tokens.len() != 4 {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        }
-        let name = to_name(tokens.get(1).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
-        let index = if tokens.len() == 4 {
-            if tokens.get(2).copied() != Some("index") {
-                return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-            }
-            parse_word(tokens.get(3).ok_or(AssemblerError::Kind(
-                AssemblerErrorKind::InvalidDirective,
-            ))?)?
-        } else {
-            self.next_free_function_index()?
+        let name = to_name(header.name)?;
+        let index = match header.index {
+            Some(index) => index,
+            None => self.next_free_function_index()?,
         };
 
         if index >= self.function_count {
@@ -544,30 +823,20 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
                 name,
                 index,
                 defined: false,
+                signature: header.signature,
             })
             .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MaxFunctionsExceeded))?;
         Ok(())
     }
 
-    fn declare_shared_function(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+    fn declare_shared_function(&mut self, header: FunctionHeader<'_>) -> Result<(), AssemblerError> {
         if !matches!(self.block, BlockKind::None) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
         }
-        if tokens.len() != 2 && tokens.len() != 4 {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        }
-        let name = to_name(tokens.get(1).ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?)?;
-        let index = if tokens.len() == 4 {
-            if tokens.get(2).copied() != Some("index") {
-                return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-            }
-            parse_word(tokens.get(3).ok_or(AssemblerError::Kind(
-                AssemblerErrorKind::InvalidDirective,
-            ))?)?
-        } else {
-            self.next_free_shared_function_index()?
+        let name = to_name(header.name)?;
+        let index = match header.index {
+            Some(index) => index,
+            None => self.next_free_shared_function_index()?,
         };
 
         if index >= self.shared_function_count {
@@ -585,19 +854,16 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
                 name,
                 index,
                 defined: false,
+                signature: header.signature,
             })
             .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MaxFunctionsExceeded))?;
         Ok(())
     }
 
-    fn start_data(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+    fn start_data(&mut self) -> Result<(), AssemblerError> {
         if !matches!(self.block, BlockKind::Machine) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
         }
-        // `.data <name>` is the only accepted form.
-        if tokens.len() != 2 {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        }
         self.labels.clear();
         self.data.clear();
         self.cursor = 0;
@@ -605,13 +871,10 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
         Ok(())
     }
 
-    fn start_shared_data(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+    fn start_shared_data(&mut self) -> Result<(), AssemblerError> {
         if !matches!(self.block, BlockKind::None) {
             return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
         }
-        if tokens.len() != 2 {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        }
         self.labels.clear();
         self.data.clear();
         self.cursor = 0;
@@ -626,22 +889,22 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
                     .function
                     .take()
                     .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingFunction))?;
-                let function = self.resolve_fixups(function)?;
+                let (function, resolved) = self.resolve_fixups(function)?;
                 let (_index, machine) = function.finish()?;
                 self.machine = Some(machine);
                 self.block = BlockKind::Machine;
-                Ok(())
+                resolved
             }
             BlockKind::SharedFunction => {
                 let function = self
                     .shared_function
                     .take()
                     .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingFunction))?;
-                let function = self.resolve_shared_fixups(function)?;
+                let (function, resolved) = self.resolve_shared_fixups(function)?;
                 let (_index, program) = function.finish()?;
                 self.program = Some(program);
                 self.block = BlockKind::None;
-                Ok(())
+                resolved
             }
             BlockKind::Data => {
                 let machine = self
@@ -678,7 +941,7 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
                     let absolute = data_base
                         .checked_add(label.offset)
                         .ok_or(AssemblerError::Kind(AssemblerErrorKind::CursorOverflow))?;
-                    self.static_labels
+                    self.shared_static_labels
                         .push(Label {
                             name: label.name.clone(),
                             offset: absolute,
@@ -701,6 +964,7 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
                         ));
                     }
                 }
+                self.resolve_param_functions()?;
                 let machine = self
                     .machine
                     .take()
@@ -708,42 +972,38 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
                 let program = machine.finish()?;
                 self.program = Some(program);
                 self.block = BlockKind::None;
-                Ok(())
+                self.machines
+                    .push(MachineEntry {
+                        name: self.machine_name.clone(),
+                        locals: self.globals.clone(),
+                    })
+                    .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MaxLabelsExceeded))?;
+                self.next_instance()
             }
             BlockKind::None => Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective)),
         }
     }
 
-    fn handle_instruction(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
+    fn handle_instruction(&mut self, instruction: Instruction<'_>) -> Result<(), AssemblerError> {
         match self.block {
-            BlockKind::Function => self.handle_function_instruction(tokens),
-            BlockKind::Data => self.handle_data_line(tokens),
-            BlockKind::SharedFunction => self.handle_function_instruction(tokens),
-            BlockKind::SharedData => self.handle_data_line(tokens),
+            BlockKind::Function | BlockKind::SharedFunction => self.handle_function_instruction(instruction),
             _ => Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedInstruction)),
         }
     }
 
-    fn handle_data_line(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
-        // Data blocks allow labels for future LOAD_STATIC references.
-        let first = tokens.first().copied().ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidDirective,
-        ))?;
-        if tokens.len() == 1 && first.ends_with(':') {
-            return self.add_label(first);
-        }
-        // Only allow `.word <num>` or a bare `<num>` to keep parsing simple.
-        let value = if tokens.len() == 2 && first == ".word" {
-            parse_word(tokens.get(1).ok_or(AssemblerError::Kind(
-                AssemblerErrorKind::InvalidDirective,
-            ))?)?
-        } else if tokens.len() == 1 {
-            parse_word(first)?
-        } else {
-            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
-        };
+    fn handle_data_line(&mut self, word: DataWord<'_>) -> Result<(), AssemblerError> {
+        match word {
+            DataWord::Color(color) => color.into_iter().try_for_each(|word| self.add_data_word(word)),
+            DataWord::Expr(text) => {
+                let value = self.evaluate(text, false)?.to_word()?;
+                self.add_data_word(value)
+            }
+        }
+    }
+
+    fn add_data_word(&mut self, word: ProgramWord) -> Result<(), AssemblerError> {
         self.data
-            .push(value)
+            .push(word)
             .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::DataTooLarge))?;
         self.cursor = self
             .cursor
@@ -752,177 +1012,75 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
         Ok(())
     }
 
-    fn handle_function_instruction(&mut self, tokens: &[&str]) -> Result<(), AssemblerError> {
-        let mnemonic = tokens.first().copied().ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidInstruction,
-        ))?;
-        match mnemonic {
-            "LOAD_STATIC" | "load_static" => self.emit_stack_target(tokens, Op::LoadStatic),
-            "JUMP" | "jump" => self.emit_stack_target(tokens, Op::Jump),
-            "CALL" | "call" => self.emit_stack_target(tokens, Op::Call),
-            "CALL_SHARED" | "call_shared" => self.emit_shared_stack_target(tokens, Op::CallShared),
-            "BRLT" | "brlt" => self.emit_stack_target(tokens, Op::BranchLessThan),
-            "BRLTE" | "brlte" => self.emit_stack_target(tokens, Op::BranchLessThanEq),
-            "BRGT" | "brgt" => self.emit_stack_target(tokens, Op::BranchGreaterThan),
-            "BRGTE" | "brgte" => self.emit_stack_target(tokens, Op::BranchGreaterThanEq),
-            "BREQ" | "breq" => self.emit_stack_target(tokens, Op::BranchEqual),
+    fn handle_function_instruction(&mut self, instruction: Instruction<'_>) -> Result<(), AssemblerError> {
+        let Instruction { mnemonic, operand } = instruction;
+        let text = match operand {
+            Operand::None => return self.emit_ops(1, [mnemonic.op(0)]),
+            Operand::Call { name, argc } => return self.emit_call(mnemonic, name, argc),
+            Operand::Color(color) => return self.emit_ops(6, color.map(Op::Push)),
+            Operand::Expr(text) => text,
+        };
+        let operand = match mnemonic.operand_kind() {
+            OperandKind::StackSlot => self.resolve_stack_operand(text)?,
+            OperandKind::Local => self.resolve_local_operand(text)?,
+            OperandKind::SharedGlobal => self.resolve_shared_global_operand(text)?,
+            OperandKind::SharedTarget => self.resolve_shared_function_operand(text)?,
+            _ => self.resolve_operand(text)?,
+        }
+        .ok_or(AssemblerError::Kind(AssemblerErrorKind::InvalidInstruction))?;
+        match mnemonic.operand_kind() {
+            // A stack target is pushed ahead of the op that pops it.
+            OperandKind::Target | OperandKind::SharedTarget => {
+                self.emit_ops(3, [Op::Push(operand), mnemonic.op(0)])
+            }
             _ => {
-                let (op, width) = self.parse_op(tokens)?;
-                if width == 0 {
-                    return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidInstruction));
-                }
-                self.cursor = self
-                    .cursor
-                    .checked_add(width)
-                    .ok_or(AssemblerError::Kind(AssemblerErrorKind::CursorOverflow))?;
-                match self.block {
-                    BlockKind::Function => {
-                        let function = self
-                            .function
-                            .as_mut()
-                            .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingFunction))?;
-                        function.add_op(op)?;
-                    }
-                    BlockKind::SharedFunction => {
-                        let function = self
-                            .shared_function
-                            .as_mut()
-                            .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingFunction))?;
-                        function.add_op(op)?;
-                    }
-                    _ => {
-                        return Err(AssemblerError::Kind(
-                            AssemblerErrorKind::UnexpectedInstruction,
-                        ))
-                    }
+                if mnemonic == Mnemonic::Return {
+                    self.signature.check_returns(operand)?;
                 }
-                Ok(())
+                self.emit_ops(2, [mnemonic.op(operand)])
             }
         }
     }
 
-    fn emit_stack_target(&mut self, tokens: &[&str], op: Op) -> Result<(), AssemblerError> {
-        match tokens.len() {
-            1 => {
-                self.cursor = self
-                    .cursor
-                    .checked_add(1)
-                    .ok_or(AssemblerError::Kind(AssemblerErrorKind::CursorOverflow))?;
-                match self.block {
-                    BlockKind::Function => {
-                        let function = self
-                            .function
-                            .as_mut()
-                            .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingFunction))?;
-                        function.add_op(op)?;
-                    }
-                    BlockKind::SharedFunction => {
-                        let function = self
-                            .shared_function
-                            .as_mut()
-                            .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingFunction))?;
-                        function.add_op(op)?;
-                    }
-                    _ => return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedInstruction)),
-                }
-                Ok(())
-            }
-            2 => {
-                let operand_token = tokens.get(1).copied().ok_or(AssemblerError::Kind(
-                    AssemblerErrorKind::InvalidInstruction,
-                ))?;
-                let operand = self
-                    .resolve_operand(operand_token)?
-                    .ok_or(AssemblerError::Kind(AssemblerErrorKind::InvalidInstruction))?;
-                self.cursor = self
-                    .cursor
-                    .checked_add(3)
-                    .ok_or(AssemblerError::Kind(AssemblerErrorKind::CursorOverflow))?;
-                match self.block {
-                    BlockKind::Function => {
-                        let function = self
-                            .function
-                            .as_mut()
-                            .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingFunction))?;
-                        function.add_op(Op::Push(operand))?;
-                        function.add_op(op)?;
-                    }
-                    BlockKind::SharedFunction => {
-                        let function = self
-                            .shared_function
-                            .as_mut()
-                            .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingFunction))?;
-                        function.add_op(Op::Push(operand))?;
-                        function.add_op(op)?;
-                    }
-                    _ => return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedInstruction)),
-                }
-                Ok(())
-            }
-            _ => Err(AssemblerError::Kind(AssemblerErrorKind::InvalidInstruction)),
-        }
+    // `CALL <name> <argc>`: pushes the argument count and the function index
+    // the VM expects on top of the arguments.
+    fn emit_call(&mut self, mnemonic: Mnemonic, name: &str, argc: &str) -> Result<(), AssemblerError> {
+        let shared = mnemonic == Mnemonic::CallShared;
+        let functions = if shared { &self.shared_funcs } else { &self.funcs };
+        let entry = functions
+            .iter()
+            .find(|entry| entry.name.as_str() == name)
+            .ok_or(AssemblerError::Kind(AssemblerErrorKind::UnknownLabel))?;
+        let (index, signature) = (entry.index, entry.signature);
+        let argc = self
+            .immediate(argc)?
+            .ok_or(AssemblerError::Kind(AssemblerErrorKind::InvalidInstruction))?;
+        signature.check_args(argc)?;
+        self.emit_ops(5, [Op::Push(argc), Op::Push(index), mnemonic.op(0)])
     }
 
-    fn emit_shared_stack_target(&mut self, tokens: &[&str], op: Op) -> Result<(), AssemblerError> {
-        match tokens.len() {
-            1 => {
-                self.cursor = self
-                    .cursor
-                    .checked_add(1)
-                    .ok_or(AssemblerError::Kind(AssemblerErrorKind::CursorOverflow))?;
-                match self.block {
-                    BlockKind::Function => {
-                        let function = self
-                            .function
-                            .as_mut()
-                            .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingFunction))?;
-                        function.add_op(op)?;
-                    }
-                    BlockKind::SharedFunction => {
-                        let function = self
-                            .shared_function
-                            .as_mut()
-                            .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingFunction))?;
-                        function.add_op(op)?;
-                    }
-                    _ => return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedInstruction)),
-                }
-                Ok(())
-            }
-            2 => {
-                let operand_token = tokens.get(1).copied().ok_or(AssemblerError::Kind(
-                    AssemblerErrorKind::InvalidInstruction,
-                ))?;
-                let operand = self
-                    .resolve_shared_function_operand(operand_token)?
-                    .ok_or(AssemblerError::Kind(AssemblerErrorKind::InvalidInstruction))?;
-                self.cursor = self
-                    .cursor
-                    .checked_add(3)
-                    .ok_or(AssemblerError::Kind(AssemblerErrorKind::CursorOverflow))?;
-                match self.block {
-                    BlockKind::Function => {
-                        let function = self
-                            .function
-                            .as_mut()
-                            .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingFunction))?;
-                        function.add_op(Op::Push(operand))?;
-                        function.add_op(op)?;
-                    }
-                    BlockKind::SharedFunction => {
-                        let function = self
-                            .shared_function
-                            .as_mut()
-                            .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingFunction))?;
-                        function.add_op(Op::Push(operand))?;
-                        function.add_op(op)?;
-                    }
-                    _ => return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedInstruction)),
-                }
-                Ok(())
+    // Adds a fixed sequence of ops that together take `width` words.
+    fn emit_ops<const N: usize>(&mut self, width: ProgramWord, ops: [Op; N]) -> Result<(), AssemblerError> {
+        self.cursor = self
+            .cursor
+            .checked_add(width)
+            .ok_or(AssemblerError::Kind(AssemblerErrorKind::CursorOverflow))?;
+        for op in ops {
+            match self.block {
+                BlockKind::Function => self
+                    .function
+                    .as_mut()
+                    .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingFunction))?
+                    .add_op(op)?,
+                BlockKind::SharedFunction => self
+                    .shared_function
+                    .as_mut()
+                    .ok_or(AssemblerError::Kind(AssemblerErrorKind::MissingFunction))?
+                    .add_op(op)?,
+                _ => return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedInstruction)),
             }
-            _ => Err(AssemblerError::Kind(AssemblerErrorKind::InvalidInstruction)),
         }
+        Ok(())
     }
 
     fn next_free_function_index(&mut self) -> Result<ProgramWord, AssemblerError> {
@@ -961,7 +1119,13 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
         Ok(index)
     }
 
-    fn mark_function_defined(&mut self, name: &String<NAME_CAP>, index: ProgramWord) -> Result<(), AssemblerError> {
+    // Returns the function's signature, merged with any earlier declaration.
+    fn mark_function_defined(
+        &mut self,
+        name: &String<NAME_CAP>,
+        index: ProgramWord,
+        signature: Signature,
+    ) -> Result<Signature, AssemblerError> {
         if let Some(entry) = self.funcs.iter_mut().find(|entry| entry.name == *name) {
             if entry.defined {
                 return Err(AssemblerError::Kind(
@@ -974,22 +1138,26 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
                     AssemblerErrorKind::FunctionIndexDuplicate,
                 ));
             }
-            return Ok(());
+            entry.signature = entry.signature.merge(signature)?;
+            return Ok(entry.signature);
         }
         self.funcs
             .push(FuncEntry {
                 name: name.clone(),
                 index,
                 defined: true,
+                signature,
             })
-            .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MaxFunctionsExceeded))
+            .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MaxFunctionsExceeded))?;
+        Ok(signature)
     }
 
     fn mark_shared_function_defined(
         &mut self,
         name: &String<NAME_CAP>,
         index: ProgramWord,
-    ) -> Result<(), AssemblerError> {
+        signature: Signature,
+    ) -> Result<Signature, AssemblerError> {
         if let Some(entry) = self
             .shared_funcs
             .iter_mut()
@@ -1006,149 +1174,69 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
                     AssemblerErrorKind::FunctionIndexDuplicate,
                 ));
             }
-            return Ok(());
+            entry.signature = entry.signature.merge(signature)?;
+            return Ok(entry.signature);
         }
         self.shared_funcs
             .push(FuncEntry {
                 name: name.clone(),
                 index,
                 defined: true,
+                signature,
             })
-            .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MaxFunctionsExceeded))
+            .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MaxFunctionsExceeded))?;
+        Ok(signature)
     }
 
+    // Labels that were never defined are left unpatched and come back as the
+    // second value, so the block can still be closed and assembly carry on.
     fn resolve_fixups(
         &mut self,
         mut function: FunctionBuilder<'a, MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>,
-    ) -> Result<FunctionBuilder<'a, MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>, AssemblerError> {
+    ) -> Result<(FunctionBuilder<'a, MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>, Result<(), AssemblerError>), AssemblerError>
+    {
+        let mut resolved = Ok(());
         while let Some(fixup) = self.fixups.pop() {
-            let label = self
-                .labels
-                .iter()
-                .find(|label| label.name == fixup.name)
-                .ok_or(AssemblerError::Kind(AssemblerErrorKind::UnknownLabel))?;
-            function.patch_word(fixup.at, label.offset)?;
+            let Some(label) = self.labels.iter().find(|label| label.name == fixup.name) else {
+                resolved = Err(AssemblerError::Kind(AssemblerErrorKind::UnknownLabel));
+                continue;
+            };
+            function.patch_word(fixup.at, expression::relocate(label.offset, fixup.offset)?)?;
         }
-        Ok(function)
+        Ok((function, resolved))
     }
 
     fn resolve_shared_fixups(
         &mut self,
         mut function: SharedFunctionBuilder<'a, MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>,
-    ) -> Result<SharedFunctionBuilder<'a, MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>, AssemblerError> {
+    ) -> Result<
+        (SharedFunctionBuilder<'a, MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>, Result<(), AssemblerError>),
+        AssemblerError,
+    > {
+        let mut resolved = Ok(());
         while let Some(fixup) = self.fixups.pop() {
-            let label = self
-                .labels
-                .iter()
-                .find(|label| label.name == fixup.name)
-                .ok_or(AssemblerError::Kind(AssemblerErrorKind::UnknownLabel))?;
-            function.patch_word(fixup.at, label.offset)?;
+            let Some(label) = self.labels.iter().find(|label| label.name == fixup.name) else {
+                resolved = Err(AssemblerError::Kind(AssemblerErrorKind::UnknownLabel));
+                continue;
+            };
+            function.patch_word(fixup.at, expression::relocate(label.offset, fixup.offset)?)?;
         }
-        Ok(function)
-    }
-
-    fn parse_op(&mut self, tokens: &[&str]) -> Result<(Op, ProgramWord), AssemblerError> {
-        let mnemonic = tokens.first().copied().ok_or(AssemblerError::Kind(
-            AssemblerErrorKind::InvalidInstruction,
-        ))?;
-        let operand_token = tokens.get(1).copied();
-        let expects_operand = matches!(
-            mnemonic,
-            "PUSH"
-                | "push"
-                | "LLOAD"
-                | "lload"
-                | "LSTORE"
-                | "lstore"
-                | "GLOAD"
-                | "gload"
-                | "GSTORE"
-                | "gstore"
-                | "SLOAD"
-                | "sload"
-                | "SSTORE"
-                | "sstore"
-                | "RET"
-                | "ret"
-        );
-
-        let operand = if expects_operand {
-            let token = operand_token.ok_or(AssemblerError::Kind(
-                AssemblerErrorKind::InvalidInstruction,
-            ))?;
-            if matches!(mnemonic, "SLOAD" | "sload" | "SSTORE" | "sstore") {
-                self.resolve_stack_operand(token)?
-            } else if matches!(mnemonic, "LLOAD" | "lload" | "LSTORE" | "lstore") {
-                self.resolve_local_operand(token)?
-            } else if matches!(mnemonic, "GLOAD" | "gload" | "GSTORE" | "gstore") {
-                self.resolve_shared_global_operand(token)?
-            } else {
-                self.resolve_operand(token)?
-            }
-        } else {
-            None
-        };
-
-        let op = match mnemonic {
-            "PUSH" | "push" => Op::Push(operand.ok_or(AssemblerError::Kind(
-                AssemblerErrorKind::InvalidInstruction,
-            ))?),
-            "POP" | "pop" => Op::Pop,
-            "LLOAD" | "lload" => Op::LocalLoad(operand.ok_or(AssemblerError::Kind(
-                AssemblerErrorKind::InvalidInstruction,
-            ))?),
-            "LSTORE" | "lstore" => Op::LocalStore(operand.ok_or(AssemblerError::Kind(
-                AssemblerErrorKind::InvalidInstruction,
-            ))?),
-            "GLOAD" | "gload" => Op::GlobalLoad(operand.ok_or(AssemblerError::Kind(
-                AssemblerErrorKind::InvalidInstruction,
-            ))?),
-            "GSTORE" | "gstore" => Op::GlobalStore(operand.ok_or(AssemblerError::Kind(
-                AssemblerErrorKind::InvalidInstruction,
-            ))?),
-            "SLOAD" | "sload" => Op::StackLoad(operand.ok_or(AssemblerError::Kind(
-                AssemblerErrorKind::InvalidInstruction,
-            ))?),
-            "SSTORE" | "sstore" => Op::StackStore(operand.ok_or(AssemblerError::Kind(
-                AssemblerErrorKind::InvalidInstruction,
-            ))?),
-            "DUP" | "dup" => Op::Dup,
-            "SWAP" | "swap" => Op::Swap,
-            "RET" | "ret" => Op::Return(operand.ok_or(AssemblerError::Kind(
-                AssemblerErrorKind::InvalidInstruction,
-            ))?),
-            "LOAD_STATIC" | "load_static" => Op::LoadStatic,
-            "JUMP" | "jump" => Op::Jump,
-            "CALL" | "call" => Op::Call,
-            "CALL_SHARED" | "call_shared" => Op::CallShared,
-            "BRLT" | "brlt" => Op::BranchLessThan,
-            "BRLTE" | "brlte" => Op::BranchLessThanEq,
-            "BRGT" | "brgt" => Op::BranchGreaterThan,
-            "BRGTE" | "brgte" => Op::BranchGreaterThanEq,
-            "BREQ" | "breq" => Op::BranchEqual,
-            "EXIT" | "exit" => Op::Exit,
-            "AND" | "and" => Op::And,
-            "OR" | "or" => Op::Or,
-            "XOR" | "xor" => Op::Xor,
-            "NOT" | "not" => Op::Not,
-            "BAND" | "band" => Op::BitwiseAnd,
-            "BOR" | "bor" => Op::BitwiseOr,
-            "BXOR" | "bxor" => Op::BitwiseXor,
-            "BNOT" | "bnot" => Op::BitwiseNot,
-            "ADD" | "add" => Op::Add,
-            "SUB" | "sub" => Op::Subtract,
-            "MUL" | "mul" => Op::Multiply,
-            "DIV" | "div" => Op::Devide,
-            "MOD" | "mod" => Op::Mod,
-            _ => return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidInstruction)),
-        };
-
-        let width = if expects_operand { 2 } else { 1 };
-        Ok((op, width))
+        Ok((function, resolved))
     }
 
     fn resolve_operand(&mut self, token: &str) -> Result<Option<ProgramWord>, AssemblerError> {
-        if let Ok(value) = parse_word(token) {
+        if expression::is_expression(token) {
+            let value = self.evaluate(token, true)?;
+            return match value.base {
+                Some(Symbol::Forward(name)) => {
+                    self.add_fixup(name, value.offset)?;
+                    Ok(Some(0))
+                }
+                None => expression::to_literal_word(value.offset).map(Some),
+                _ => expression::to_word(value.offset).map(Some),
+            };
+        }
+        if let Some(value) = self.immediate(token)? {
             return Ok(Some(value));
         }
 
@@ -1156,7 +1244,12 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
         if let Some(label) = self.labels.iter().find(|label| label.name == name) {
             return Ok(Some(label.offset));
         }
-        if let Some(label) = self.static_labels.iter().find(|label| label.name == name) {
+        if let Some(label) = self
+            .static_labels
+            .iter()
+            .chain(self.shared_static_labels.iter())
+            .find(|label| label.name == name)
+        {
             return Ok(Some(label.offset));
         }
         if let Some(entry) = self.funcs.iter().find(|entry| entry.name == name) {
@@ -1169,26 +1262,87 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
             return Ok(Some(entry.offset));
         }
 
-        if matches!(self.block, BlockKind::Function) {
-            let at = self
-                .function_base
-                .checked_add(self.cursor)
-                .and_then(|base| base.checked_add(1))
-                .ok_or(AssemblerError::Kind(AssemblerErrorKind::CursorOverflow))?;
-            self.fixups
-                .push(Fixup { name, at })
-                .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MaxLabelsExceeded))?;
+        if matches!(self.block, BlockKind::Function | BlockKind::SharedFunction) {
+            self.add_fixup(name, 0)?;
             return Ok(Some(0));
         }
 
         Err(AssemblerError::Kind(AssemblerErrorKind::UnknownLabel))
     }
 
+    fn add_fixup(&mut self, name: String<NAME_CAP>, offset: i64) -> Result<(), AssemblerError> {
+        // Operands are always the word after the opcode being emitted.
+        let at = self
+            .function_base
+            .checked_add(self.cursor)
+            .and_then(|base| base.checked_add(1))
+            .ok_or(AssemblerError::Kind(AssemblerErrorKind::CursorOverflow))?;
+        self.fixups
+            .push(Fixup { name, at, offset })
+            .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MaxLabelsExceeded))
+    }
+
+    /// Numbers, consts and expressions that reduce to a number. Returns `None`
+    /// for a bare name so callers can apply their own lookup.
+    fn immediate(&self, token: &str) -> Result<Option<ProgramWord>, AssemblerError> {
+        // Names cannot start with a digit or a quote, so a bad number is not a
+        // forward label.
+        if token.starts_with(|c: char| c.is_ascii_digit() || c == '\'') && !expression::is_expression(token) {
+            return parse_word(token).map(Some);
+        }
+        if let Some(entry) = self.consts.iter().find(|entry| entry.name.as_str() == token) {
+            return Ok(Some(entry.offset));
+        }
+        if expression::is_expression(token) {
+            return self.evaluate(token, false)?.to_word().map(Some);
+        }
+        Ok(None)
+    }
+
+    fn evaluate(&self, text: &str, forward: bool) -> Result<Value<Symbol>, AssemblerError> {
+        expression::evaluate(text, |name| self.lookup_symbol(name, forward))
+    }
+
+    // Names in expressions resolve like a PUSH operand, with consts first.
+    fn lookup_symbol(&self, name: &str, forward: bool) -> Result<Value<Symbol>, AssemblerError> {
+        if let Some(entry) = self.consts.iter().find(|entry| entry.name.as_str() == name) {
+            return Ok(Value::literal(i64::from(entry.offset)));
+        }
+        // Data block labels are relative until `.end` places the block.
+        let in_function = matches!(self.block, BlockKind::Function | BlockKind::SharedFunction);
+        if in_function
+            && let Some(label) = self.labels.iter().find(|label| label.name.as_str() == name)
+        {
+            return Ok(Value::symbol(Symbol::Code, i64::from(label.offset)));
+        }
+        if let Some(label) = self
+            .static_labels
+            .iter()
+            .chain(self.shared_static_labels.iter())
+            .find(|label| label.name.as_str() == name)
+        {
+            return Ok(Value::symbol(Symbol::Static, i64::from(label.offset)));
+        }
+        if let Some(entry) = self.funcs.iter().find(|entry| entry.name.as_str() == name) {
+            return Ok(Value::literal(i64::from(entry.index)));
+        }
+        if let Some(entry) = self.globals.iter().find(|entry| entry.name.as_str() == name) {
+            return Ok(Value::literal(i64::from(entry.offset)));
+        }
+        if let Some(entry) = self.shared_globals.iter().find(|entry| entry.name.as_str() == name) {
+            return Ok(Value::literal(i64::from(entry.offset)));
+        }
+        if forward && in_function {
+            return Ok(Value::symbol(Symbol::Forward(to_name(name)?), 0));
+        }
+        Err(AssemblerError::Kind(AssemblerErrorKind::UnknownLabel))
+    }
+
     fn resolve_shared_function_operand(
         &mut self,
         token: &str,
     ) -> Result<Option<ProgramWord>, AssemblerError> {
-        if let Ok(value) = parse_word(token) {
+        if let Some(value) = self.immediate(token)? {
             return Ok(Some(value));
         }
         let name = to_name(token)?;
@@ -1199,7 +1353,7 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
     }
 
     fn resolve_stack_operand(&mut self, token: &str) -> Result<Option<ProgramWord>, AssemblerError> {
-        if let Ok(value) = parse_word(token) {
+        if let Some(value) = self.immediate(token)? {
             return Ok(Some(value));
         }
         let name = to_name(token)?;
@@ -1210,7 +1364,7 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
     }
 
     fn resolve_local_operand(&mut self, token: &str) -> Result<Option<ProgramWord>, AssemblerError> {
-        if let Ok(value) = parse_word(token) {
+        if let Some(value) = self.immediate(token)? {
             if matches!(self.block, BlockKind::SharedFunction) {
                 return Ok(Some(value));
             }
@@ -1232,7 +1386,7 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
         &mut self,
         token: &str,
     ) -> Result<Option<ProgramWord>, AssemblerError> {
-        if let Ok(value) = parse_word(token) {
+        if let Some(value) = self.immediate(token)? {
             if value >= self.shared_globals_size {
                 return Err(AssemblerError::Kind(
                     AssemblerErrorKind::GlobalIndexOutOfRange,
@@ -1248,24 +1402,6 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize, const
     }
 }
 
-fn parse_word(token: &str) -> Result<ProgramWord, AssemblerError> {
-    if let Some(hex) = token.strip_prefix("0x") {
-        u16::from_str_radix(hex, 16)
-            .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::InvalidNumber))
-    } else {
-        token
-            .parse::<u16>()
-            .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::InvalidNumber))
-    }
-}
-
-fn strip_comment(line: &str) -> &str {
-    match line.split(';').next() {
-        Some(part) => part,
-        None => line,
-    }
-}
-
 fn to_name(name: &str) -> Result<String<NAME_CAP>, AssemblerError> {
     // Cap name length to keep identifiers bounded in heapless storage.
     let mut out: String<NAME_CAP> = String::new();
@@ -0,0 +1,246 @@
+// Structured control flow: `.if`/`.else`/`.endif`, `.while`/`.do`/`.endwhile`
+// and `.for`/`.endfor`. Each directive is lowered to a few ordinary lines
+// (labels, PUSH and branch instructions) that the assembler then assembles
+// as if they had been written by hand. Generated labels are named
+// `__ctl<n>_<part>`, with `n` counting blocks across the whole program.
+//
+// Conditions compare the two values on top of the stack the same way the
+// branch ops do: `lt` is true when the deeper value is less than the top.
+
+use core::fmt::Write;
+
+use heapless::{String, Vec};
+
+use super::{split_first_token, strip_comment, to_name, AssemblerError, AssemblerErrorKind, NAME_CAP};
+
+/// How many control blocks may be open inside one another.
+pub const MAX_CONTROL_DEPTH: usize = 16;
+/// Longest line a control directive may lower to.
+pub const CONTROL_LINE_CAP: usize = 128;
+
+const MAX_LOWERED_LINES: usize = 8;
+
+pub type LoweredLines = Vec<String<CONTROL_LINE_CAP>, MAX_LOWERED_LINES>;
+
+#[derive(Clone, Copy)]
+enum Condition {
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+    Eq,
+    Ne,
+}
+
+enum Block {
+    If { id: u32, has_else: bool },
+    While { id: u32, tested: bool },
+    For { id: u32, var: String<NAME_CAP>, frame: bool },
+}
+
+/// Open control blocks of the function being assembled.
+pub struct ControlStack {
+    blocks: Vec<Block, MAX_CONTROL_DEPTH>,
+    next_id: u32,
+}
+
+impl Default for ControlStack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ControlStack {
+    pub const fn new() -> Self {
+        Self {
+            blocks: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Lowers `line` if it is a control directive and returns `None`
+    /// otherwise. `.end` is checked here too, so a function cannot close with
+    /// a block still open. `is_frame_slot` tells `.for` whether its counter
+    /// is a `.frame` slot rather than a local.
+    pub fn lower(
+        &mut self,
+        line: &str,
+        in_function: bool,
+        is_frame_slot: impl Fn(&str) -> bool,
+    ) -> Result<Option<LoweredLines>, AssemblerError> {
+        let (first, rest) = split_first_token(strip_comment(line).trim());
+        if !matches!(
+            first,
+            ".if" | ".else" | ".endif" | ".while" | ".do" | ".endwhile" | ".for" | ".endfor"
+        ) {
+            if first == ".end" && !self.blocks.is_empty() {
+                return Err(AssemblerError::Kind(AssemblerErrorKind::UnclosedControl));
+            }
+            return Ok(None);
+        }
+        if !in_function {
+            return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective));
+        }
+        let mut out = LoweredLines::new();
+        match first {
+            ".if" => {
+                let id = self.open(|id| Block::If { id, has_else: false })?;
+                test(rest, id, "else", &mut out)?;
+            }
+            ".else" => {
+                no_operands(rest)?;
+                let Some(Block::If { id, has_else }) = self.blocks.last_mut() else {
+                    return Err(unmatched());
+                };
+                if *has_else {
+                    return Err(unmatched());
+                }
+                *has_else = true;
+                emit(&mut out, format_args!("JUMP __ctl{}_end", id))?;
+                emit(&mut out, format_args!("__ctl{}_else:", id))?;
+            }
+            ".endif" => {
+                no_operands(rest)?;
+                let Some(Block::If { id, has_else }) = self.blocks.pop() else {
+                    return Err(unmatched());
+                };
+                let part = if has_else { "end" } else { "else" };
+                emit(&mut out, format_args!("__ctl{}_{}:", id, part))?;
+            }
+            ".while" => {
+                no_operands(rest)?;
+                let id = self.open(|id| Block::While { id, tested: false })?;
+                emit(&mut out, format_args!("__ctl{}_top:", id))?;
+            }
+            ".do" => {
+                let Some(Block::While { id, tested }) = self.blocks.last_mut() else {
+                    return Err(unmatched());
+                };
+                if *tested {
+                    return Err(unmatched());
+                }
+                *tested = true;
+                test(rest, *id, "end", &mut out)?;
+            }
+            ".endwhile" => {
+                no_operands(rest)?;
+                let Some(Block::While { id, tested: true }) = self.blocks.pop() else {
+                    return Err(unmatched());
+                };
+                emit(&mut out, format_args!("JUMP __ctl{}_top", id))?;
+                emit(&mut out, format_args!("__ctl{}_end:", id))?;
+            }
+            ".for" => {
+                let (var, range) = split_first_token(rest);
+                let (from, to) = range
+                    .split_once(',')
+                    .map(|(from, to)| (from.trim(), to.trim()))
+                    .filter(|(from, to)| !var.is_empty() && !from.is_empty() && !to.is_empty())
+                    .ok_or(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective))?;
+                let name = to_name(var)?;
+                let frame = is_frame_slot(var);
+                let (load, store) = access(frame);
+                let id = self.open(|id| Block::For { id, var: name, frame })?;
+                emit(&mut out, format_args!("PUSH {}", from))?;
+                emit(&mut out, format_args!("{} {}", store, var))?;
+                emit(&mut out, format_args!("__ctl{}_top:", id))?;
+                emit(&mut out, format_args!("{} {}", load, var))?;
+                emit(&mut out, format_args!("PUSH {}", to))?;
+                emit(&mut out, format_args!("BRGTE __ctl{}_end", id))?;
+            }
+            _ => {
+                no_operands(rest)?;
+                let Some(Block::For { id, var, frame }) = self.blocks.pop() else {
+                    return Err(unmatched());
+                };
+                let (load, store) = access(frame);
+                emit(&mut out, format_args!("{} {}", load, var))?;
+                emit(&mut out, format_args!("PUSH 1"))?;
+                emit(&mut out, format_args!("ADD"))?;
+                emit(&mut out, format_args!("{} {}", store, var))?;
+                emit(&mut out, format_args!("JUMP __ctl{}_top", id))?;
+                emit(&mut out, format_args!("__ctl{}_end:", id))?;
+            }
+        }
+        Ok(Some(out))
+    }
+
+    fn open(&mut self, block: impl FnOnce(u32) -> Block) -> Result<u32, AssemblerError> {
+        let id = self.next_id;
+        self.blocks
+            .push(block(id))
+            .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::ControlTooDeep))?;
+        self.next_id = id
+            .checked_add(1)
+            .ok_or(AssemblerError::Kind(AssemblerErrorKind::ControlTooDeep))?;
+        Ok(id)
+    }
+}
+
+// `<cond> [<expr>]`: pushes the expression if there is one, then branches to
+// `__ctl<id>_<target>` when the condition does not hold.
+fn test(rest: &str, id: u32, target: &str, out: &mut LoweredLines) -> Result<(), AssemblerError> {
+    let (condition, operand) = split_first_token(rest);
+    let condition = parse_condition(condition)?;
+    if !operand.is_empty() {
+        emit(out, format_args!("PUSH {}", operand))?;
+    }
+    let branch = match condition {
+        Condition::Lt => "BRGTE",
+        Condition::Lte => "BRGT",
+        Condition::Gt => "BRLTE",
+        Condition::Gte => "BRLT",
+        Condition::Ne => "BREQ",
+        // There is no branch-if-not-equal, so step over a jump instead.
+        Condition::Eq => {
+            emit(out, format_args!("BREQ __ctl{}_then", id))?;
+            emit(out, format_args!("JUMP __ctl{}_{}", id, target))?;
+            return emit(out, format_args!("__ctl{}_then:", id));
+        }
+    };
+    emit(out, format_args!("{} __ctl{}_{}", branch, id, target))
+}
+
+fn parse_condition(token: &str) -> Result<Condition, AssemblerError> {
+    let conditions = [
+        ("lt", Condition::Lt),
+        ("lte", Condition::Lte),
+        ("gt", Condition::Gt),
+        ("gte", Condition::Gte),
+        ("eq", Condition::Eq),
+        ("ne", Condition::Ne),
+    ];
+    conditions
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(token))
+        .map(|(_, condition)| *condition)
+        .ok_or(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective))
+}
+
+fn access(frame: bool) -> (&'static str, &'static str) {
+    if frame {
+        ("SLOAD", "SSTORE")
+    } else {
+        ("LLOAD", "LSTORE")
+    }
+}
+
+fn no_operands(rest: &str) -> Result<(), AssemblerError> {
+    if rest.is_empty() {
+        Ok(())
+    } else {
+        Err(AssemblerError::Kind(AssemblerErrorKind::TooManyTokens))
+    }
+}
+
+fn emit(out: &mut LoweredLines, args: core::fmt::Arguments<'_>) -> Result<(), AssemblerError> {
+    let mut line: String<CONTROL_LINE_CAP> = String::new();
+    line.write_fmt(args)
+        .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::TooManyTokens))?;
+    out.push(line)
+        .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::TooManyTokens))
+}
+
+fn unmatched() -> AssemblerError {
+    AssemblerError::Kind(AssemblerErrorKind::UnmatchedControl)
+}
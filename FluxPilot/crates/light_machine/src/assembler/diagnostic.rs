@@ -0,0 +1,305 @@
+// Diagnostics: assembler errors turned into something an editor can show,
+// with the columns of the offending text, a message and a suggested fix.
+//
+// Assembly is one-pass, so the assemblers do not track columns while
+// parsing. The span is instead recovered from the failing line and the kind
+// of error: operand problems point at the operand, unknown mnemonics at the
+// mnemonic, duplicates at the repeated name, and so on.
+
+use heapless::String;
+
+use super::include::{self, SourceLocation, SourceResolver};
+use super::{split_first_token, strip_comment, AssemblerError, AssemblerErrorKind, NAME_CAP};
+use crate::builder::MachineBuilderError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+/// One problem in a source. `start..end` are 0-based character columns in
+/// the line; an empty span means the problem is not tied to any part of it.
+/// `line` is 0 for problems that are not tied to a line at all.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub file: Option<String<NAME_CAP>>,
+    pub line: u32,
+    pub start: u32,
+    pub end: u32,
+    pub severity: Severity,
+    pub kind: AssemblerErrorKind,
+}
+
+impl Diagnostic {
+    /// Builds an error diagnostic from `err`, raised while assembling `text`.
+    pub fn from_error(err: AssemblerError, text: &str) -> Self {
+        let file = err.file_name().and_then(|name| String::try_from(name).ok());
+        let line = err.line_number().unwrap_or(0);
+        let kind = err.error_kind().clone();
+        let (start, end) = span(&kind, text);
+        Self {
+            file,
+            line,
+            start,
+            end,
+            severity: Severity::Error,
+            kind,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        self.kind.message()
+    }
+
+    pub fn fix(&self) -> Option<&'static str> {
+        self.kind.fix()
+    }
+}
+
+impl AssemblerErrorKind {
+    pub fn message(&self) -> &'static str {
+        match self {
+            AssemblerErrorKind::EmptyLine => "empty line",
+            AssemblerErrorKind::TooManyTokens => "too many tokens",
+            AssemblerErrorKind::InvalidDirective => "invalid directive",
+            AssemblerErrorKind::InvalidInstruction => "invalid instruction",
+            AssemblerErrorKind::InvalidNumber => "invalid number",
+            AssemblerErrorKind::NameTooLong => "name too long",
+            AssemblerErrorKind::DuplicateLabel => "duplicate label",
+            AssemblerErrorKind::DuplicateGlobal => "duplicate global",
+            AssemblerErrorKind::DuplicateStackSlot => "duplicate stack slot",
+            AssemblerErrorKind::GlobalIndexOutOfRange => "global index out of range",
+            AssemblerErrorKind::MaxLabelsExceeded => "max labels exceeded",
+            AssemblerErrorKind::UnknownLabel => "unknown label",
+            AssemblerErrorKind::MissingMachine => "missing machine",
+            AssemblerErrorKind::MissingFunction => "missing function",
+            AssemblerErrorKind::MissingProgram => "missing program",
+            AssemblerErrorKind::UnexpectedDirective => "unexpected directive",
+            AssemblerErrorKind::UnexpectedInstruction => "unexpected instruction",
+            AssemblerErrorKind::FunctionAlreadyDefined => "function already defined",
+            AssemblerErrorKind::FunctionNotDeclared => "function not declared",
+            AssemblerErrorKind::FunctionIndexOutOfRange => "function index out of range",
+            AssemblerErrorKind::FunctionIndexDuplicate => "function index duplicate",
+            AssemblerErrorKind::MaxFunctionsExceeded => "max functions exceeded",
+            AssemblerErrorKind::LineNumberOverflow => "line number overflow",
+            AssemblerErrorKind::CursorOverflow => "cursor overflow",
+            AssemblerErrorKind::DataTooLarge => "data too large",
+            AssemblerErrorKind::InvalidExpression => "invalid expression",
+            AssemblerErrorKind::ExpressionOutOfRange => "expression out of range",
+            AssemblerErrorKind::DuplicateConst => "duplicate const",
+            AssemblerErrorKind::IncludeNotFound => "include not found",
+            AssemblerErrorKind::IncludeCycle => "include cycle",
+            AssemblerErrorKind::IncludeTooDeep => "includes nested too deeply",
+            AssemblerErrorKind::DuplicateMacro => "duplicate macro",
+            AssemblerErrorKind::MacroArgumentCount => "wrong number of macro arguments",
+            AssemblerErrorKind::MacroTooDeep => "macro calls nested too deeply",
+            AssemblerErrorKind::MacroTooLarge => "macro too large",
+            AssemblerErrorKind::UnmatchedControl => "unmatched control directive",
+            AssemblerErrorKind::UnclosedControl => "control block not closed",
+            AssemblerErrorKind::ControlTooDeep => "control blocks nested too deeply",
+            AssemblerErrorKind::SourceMapTooLarge => "source map too large",
+            AssemblerErrorKind::UnknownMachine => "unknown machine",
+            AssemblerErrorKind::SignatureMismatch => "signature does not match the declaration",
+            AssemblerErrorKind::ArgumentCountMismatch => "wrong number of arguments",
+            AssemblerErrorKind::ReturnCountMismatch => "wrong number of return values",
+            AssemblerErrorKind::InvalidParam => "invalid param",
+            AssemblerErrorKind::Builder(MachineBuilderError::UndefinedFunction { .. }) => {
+                "a function slot of a machine is never defined"
+            }
+            AssemblerErrorKind::Builder(MachineBuilderError::UndefinedSharedFunction(_)) => {
+                "a shared function slot is never defined"
+            }
+            AssemblerErrorKind::Builder(_) => "builder error",
+        }
+    }
+
+    /// A short hint on how to fix the problem, where there is an obvious one.
+    pub fn fix(&self) -> Option<&'static str> {
+        let fix = match self {
+            AssemblerErrorKind::InvalidNumber => {
+                "write a decimal, 0x hex, 0b binary or 'c' character number, or a #rrggbb color"
+            }
+            AssemblerErrorKind::NameTooLong => "use a name of at most 32 characters",
+            AssemblerErrorKind::DuplicateLabel
+            | AssemblerErrorKind::DuplicateGlobal
+            | AssemblerErrorKind::DuplicateStackSlot
+            | AssemblerErrorKind::DuplicateConst
+            | AssemblerErrorKind::DuplicateMacro => "rename one of the definitions",
+            AssemblerErrorKind::GlobalIndexOutOfRange => "raise the machine's `locals` count or use a smaller index",
+            AssemblerErrorKind::UnknownLabel => {
+                "define the name as a label, .const, .local, .shared or .frame, or check its spelling"
+            }
+            AssemblerErrorKind::MissingMachine => "open a block with `.machine <name> locals <N> functions <M>` first",
+            AssemblerErrorKind::MissingFunction | AssemblerErrorKind::UnexpectedInstruction => {
+                "move the instruction inside a .func or .shared_func block"
+            }
+            AssemblerErrorKind::FunctionAlreadyDefined => "rename the function or remove the second body",
+            AssemblerErrorKind::FunctionNotDeclared => "give every declared function a body",
+            AssemblerErrorKind::FunctionIndexOutOfRange => "raise the machine's `functions` count",
+            AssemblerErrorKind::FunctionIndexDuplicate => "give each function its own index",
+            AssemblerErrorKind::InvalidExpression => {
+                "only label + n, label - n and label - label may use labels; everything else must be a number"
+            }
+            AssemblerErrorKind::ExpressionOutOfRange => "keep the value between 0 and 0xFFFF",
+            AssemblerErrorKind::IncludeNotFound => "add the source to the library or fix the name",
+            AssemblerErrorKind::IncludeCycle => "remove the include that leads back to this file",
+            AssemblerErrorKind::MacroArgumentCount => "pass one comma-separated argument per macro parameter",
+            AssemblerErrorKind::MacroTooDeep => "check for a macro that calls itself",
+            AssemblerErrorKind::UnmatchedControl => "check that every .if, .while and .for is closed in order",
+            AssemblerErrorKind::UnclosedControl => "close the open .if, .while or .for before .end",
+            AssemblerErrorKind::SourceMapTooLarge => "give the source map a larger buffer",
+            AssemblerErrorKind::UnknownMachine => "instance a machine only after its .machine block has ended",
+            AssemblerErrorKind::SignatureMismatch => "give the declaration and the body the same args and returns",
+            AssemblerErrorKind::ArgumentCountMismatch => "pass as many arguments as the function's `args`",
+            AssemblerErrorKind::ReturnCountMismatch => "return as many values as the function's `returns`",
+            AssemblerErrorKind::InvalidParam => {
+                "keep min <= default <= max and step above 0, give a color only a #rrggbb default, and name each param once"
+            }
+            AssemblerErrorKind::Builder(MachineBuilderError::UndefinedFunction { .. }) => {
+                "define a .func for every index below the machine's `functions` count, or lower the count"
+            }
+            _ => return None,
+        };
+        Some(fix)
+    }
+}
+
+/// Whether assembly can usefully carry on after `kind` was raised for `text`.
+/// Errors on lines that open or close blocks are not: the block structure
+/// would be off and every following line would report a bogus error. The
+/// exception is an undefined forward label, which is only found at `.end`
+/// after the block has been closed.
+pub fn is_recoverable(kind: &AssemblerErrorKind, text: &str) -> bool {
+    let (first, _) = split_first_token(strip_comment(text).trim());
+    if first == ".end" && matches!(kind, AssemblerErrorKind::UnknownLabel) {
+        return true;
+    }
+    let structural = matches!(
+        first,
+        ".machine"
+            | ".func"
+            | ".func_decl"
+            | ".shared_func"
+            | ".shared_func_decl"
+            | ".data"
+            | ".shared_data"
+            | ".end"
+            | ".macro"
+    );
+    !structural
+        && !matches!(
+            kind,
+            AssemblerErrorKind::MaxLabelsExceeded
+                | AssemblerErrorKind::MissingProgram
+                | AssemblerErrorKind::MaxFunctionsExceeded
+                | AssemblerErrorKind::LineNumberOverflow
+                | AssemblerErrorKind::CursorOverflow
+                | AssemblerErrorKind::DataTooLarge
+                | AssemblerErrorKind::MacroTooLarge
+                | AssemblerErrorKind::ControlTooDeep
+                | AssemblerErrorKind::SourceMapTooLarge
+                | AssemblerErrorKind::Builder(_)
+        )
+}
+
+/// Feeds every line of `source` to `add_line`, expanding includes, and
+/// passes each error to `report` instead of stopping at the first one.
+/// Returns `false` if assembly had to stop early, in which case finishing
+/// the program would only produce follow-on errors.
+pub fn for_each_line_reporting<R, F, D>(source: &str, resolver: &R, mut add_line: F, mut report: D) -> bool
+where
+    R: SourceResolver,
+    F: FnMut(SourceLocation<'_>, &str) -> Result<(), AssemblerError>,
+    D: FnMut(Diagnostic),
+{
+    let mut reported = false;
+    let result = include::for_each_line(source, resolver, |location, text| {
+        let Err(err) = add_line(location, text) else {
+            return Ok(());
+        };
+        let recoverable = is_recoverable(err.error_kind(), text);
+        let kind = err.error_kind().clone();
+        report(Diagnostic::from_error(err.at_location(location), text));
+        if recoverable {
+            return Ok(());
+        }
+        reported = true;
+        Err(AssemblerError::Kind(kind))
+    });
+    match result {
+        Ok(()) => true,
+        Err(_) if reported => false,
+        // Include errors come from the expansion itself, not from a line.
+        Err(err) => {
+            report(Diagnostic::from_error(err, ""));
+            false
+        }
+    }
+}
+
+// Character columns of the text the error is about.
+fn span(kind: &AssemblerErrorKind, text: &str) -> (u32, u32) {
+    let code = strip_comment(text);
+    let trimmed = code.trim();
+    if trimmed.is_empty() {
+        return (0, 0);
+    }
+    let (first, rest) = split_first_token(trimmed);
+    let (second, _) = split_first_token(rest);
+    let target = match kind {
+        AssemblerErrorKind::InvalidInstruction
+        | AssemblerErrorKind::UnexpectedInstruction
+        | AssemblerErrorKind::InvalidDirective
+        | AssemblerErrorKind::UnexpectedDirective
+        | AssemblerErrorKind::MissingMachine
+        | AssemblerErrorKind::MissingFunction
+        | AssemblerErrorKind::UnmatchedControl
+        | AssemblerErrorKind::UnclosedControl => first,
+        AssemblerErrorKind::DuplicateLabel if rest.is_empty() => first,
+        AssemblerErrorKind::DuplicateLabel
+        | AssemblerErrorKind::DuplicateGlobal
+        | AssemblerErrorKind::DuplicateStackSlot
+        | AssemblerErrorKind::DuplicateConst
+        | AssemblerErrorKind::DuplicateMacro
+        | AssemblerErrorKind::FunctionAlreadyDefined
+        | AssemblerErrorKind::NameTooLong
+            if !second.is_empty() =>
+        {
+            second
+        }
+        AssemblerErrorKind::InvalidNumber
+        | AssemblerErrorKind::InvalidExpression
+        | AssemblerErrorKind::ExpressionOutOfRange
+        | AssemblerErrorKind::UnknownLabel
+        | AssemblerErrorKind::GlobalIndexOutOfRange
+        | AssemblerErrorKind::MacroArgumentCount
+        | AssemblerErrorKind::ArgumentCountMismatch
+        | AssemblerErrorKind::ReturnCountMismatch
+            if !rest.is_empty() =>
+        {
+            rest
+        }
+        _ => trimmed,
+    };
+    columns(text, target)
+}
+
+// `part` is always a subslice of `text`, so its position follows from the
+// pointer offset.
+fn columns(text: &str, part: &str) -> (u32, u32) {
+    let offset = (part.as_ptr() as usize).saturating_sub(text.as_ptr() as usize);
+    let before = text.get(..offset).map_or(0, |before| before.chars().count());
+    let start = u32::try_from(before).unwrap_or(u32::MAX);
+    let width = u32::try_from(part.chars().count()).unwrap_or(u32::MAX);
+    (start, start.saturating_add(width))
+}
@@ -0,0 +1,346 @@
+// Integer expressions for `.const`, `.word` and instruction operands.
+//
+// Expressions are evaluated with checked i64 arithmetic. A value can carry
+// one relocatable base symbol (a label whose final address the caller does
+// not know yet) plus an integer offset. Only `+` and `-` may touch such a
+// value, and subtracting two labels with the same base cancels it, so
+// `end - start` is always a plain number.
+//
+// Numbers are decimal, `0x` hex, `0b` binary or a character literal such as
+// `'A'` or `'\n'`. A leading `-` negates; a negative constant becomes a
+// 16-bit two's complement word, so `-1` is `0xFFFF`. `#rrggbb` colors are
+// not numbers: they stand for three words and are only accepted as a whole
+// `PUSH` operand or data word (see [`parse_color`]).
+
+use super::{AssemblerError, AssemblerErrorKind};
+use crate::ProgramWord;
+
+/// Parenthesis and unary minus nesting accepted before an expression is
+/// rejected. Keeps the recursive descent bounded on small stacks.
+pub const MAX_DEPTH: usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Value<S> {
+    pub base: Option<S>,
+    pub offset: i64,
+}
+
+impl<S> Value<S> {
+    pub fn literal(offset: i64) -> Self {
+        Self { base: None, offset }
+    }
+
+    pub fn symbol(base: S, offset: i64) -> Self {
+        Self {
+            base: Some(base),
+            offset,
+        }
+    }
+
+    /// Returns the value as a program word. Values that still carry a base
+    /// symbol are not constants and are rejected.
+    pub fn to_word(&self) -> Result<ProgramWord, AssemblerError> {
+        if self.base.is_some() {
+            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidExpression));
+        }
+        to_literal_word(self.offset)
+    }
+}
+
+/// Converts an evaluated number to a program word.
+pub fn to_word(value: i64) -> Result<ProgramWord, AssemblerError> {
+    ProgramWord::try_from(value)
+        .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::ExpressionOutOfRange))
+}
+
+/// Like [`to_word`], but a negative constant down to `-32768` is stored as
+/// its 16-bit two's complement.
+pub fn to_literal_word(value: i64) -> Result<ProgramWord, AssemblerError> {
+    match i16::try_from(value) {
+        Ok(negative) if negative < 0 => Ok(u16::from_ne_bytes(negative.to_ne_bytes())),
+        _ => to_word(value),
+    }
+}
+
+/// Parses a single literal such as `42`, `-3`, `0b1010` or `'A'`.
+pub fn parse_word(token: &str) -> Result<ProgramWord, AssemblerError> {
+    let invalid_number = || AssemblerError::Kind(AssemblerErrorKind::InvalidNumber);
+    let value = match token.strip_prefix('-') {
+        Some(digits) => parse_unsigned(digits)?.checked_neg().ok_or(invalid_number())?,
+        None => parse_unsigned(token)?,
+    };
+    to_literal_word(value).map_err(|_| invalid_number())
+}
+
+fn parse_unsigned(token: &str) -> Result<i64, AssemblerError> {
+    match char_literal(token.as_bytes()) {
+        Some((value, len)) if len == token.len() => Ok(value),
+        Some(_) => Err(AssemblerError::Kind(AssemblerErrorKind::InvalidNumber)),
+        None => parse_number(token),
+    }
+}
+
+/// Parses a `#rrggbb` color into its red, green and blue words. Returns
+/// `None` when `token` is not a color at all.
+pub fn parse_color(token: &str) -> Option<Result<[ProgramWord; 3], AssemblerError>> {
+    token.strip_prefix('#').map(color_channels)
+}
+
+fn color_channels(hex: &str) -> Result<[ProgramWord; 3], AssemblerError> {
+    let invalid_number = || AssemblerError::Kind(AssemblerErrorKind::InvalidNumber);
+    if hex.len() != 6 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(invalid_number());
+    }
+    let channel = |range: core::ops::Range<usize>| {
+        hex.get(range)
+            .and_then(|digits| u16::from_str_radix(digits, 16).ok())
+            .ok_or(invalid_number())
+    };
+    Ok([channel(0..2)?, channel(2..4)?, channel(4..6)?])
+}
+
+/// Reads a `'c'` or `'\n'` character literal at the start of `text`,
+/// returning its code point and length in bytes.
+fn char_literal(text: &[u8]) -> Option<(i64, usize)> {
+    let rest = text.strip_prefix(b"'")?;
+    let rest = core::str::from_utf8(rest).ok()?;
+    let mut chars = rest.chars();
+    let value = match chars.next()? {
+        '\\' => match chars.next()? {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            '0' => '\0',
+            '\\' => '\\',
+            '\'' => '\'',
+            _ => return None,
+        },
+        '\'' => return None,
+        other => other,
+    };
+    if chars.next()? != '\'' {
+        return None;
+    }
+    let len = text.len().checked_sub(chars.as_str().len())?;
+    Some((i64::from(u32::from(value)), len))
+}
+
+/// Applies the offset recorded with a label reference once the label is known.
+pub fn relocate(address: ProgramWord, offset: i64) -> Result<ProgramWord, AssemblerError> {
+    let value = i64::from(address)
+        .checked_add(offset)
+        .ok_or(AssemblerError::Kind(AssemblerErrorKind::ExpressionOutOfRange))?;
+    to_word(value)
+}
+
+/// True when `text` has to go through [`evaluate`] rather than being a single
+/// number or name.
+pub fn is_expression(text: &str) -> bool {
+    text.contains(|c: char| {
+        c.is_whitespace() || matches!(c, '+' | '-' | '*' | '/' | '<' | '>' | '&' | '|' | '(' | ')')
+    })
+}
+
+/// Evaluates `text`. Names are passed to `lookup`, which decides what they
+/// mean in the caller's scope.
+pub fn evaluate<S, F>(text: &str, lookup: F) -> Result<Value<S>, AssemblerError>
+where
+    S: PartialEq,
+    F: FnMut(&str) -> Result<Value<S>, AssemblerError>,
+{
+    let mut parser = Parser {
+        text: text.as_bytes(),
+        pos: 0,
+        lookup,
+    };
+    let value = parser.or(0)?;
+    parser.skip_whitespace();
+    if parser.pos != parser.text.len() {
+        return Err(invalid());
+    }
+    Ok(value)
+}
+
+struct Parser<'t, F> {
+    text: &'t [u8],
+    pos: usize,
+    lookup: F,
+}
+
+impl<S, F> Parser<'_, F>
+where
+    S: PartialEq,
+    F: FnMut(&str) -> Result<Value<S>, AssemblerError>,
+{
+    // Precedence from loosest to tightest: | & (<< >>) (+ -) (* /).
+
+    fn or(&mut self, depth: usize) -> Result<Value<S>, AssemblerError> {
+        let mut left = self.and(depth)?;
+        while self.eat("|") {
+            let right = self.and(depth)?;
+            left = absolute(left, right, |a, b| Some(a | b))?;
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self, depth: usize) -> Result<Value<S>, AssemblerError> {
+        let mut left = self.shift(depth)?;
+        while self.eat("&") {
+            let right = self.shift(depth)?;
+            left = absolute(left, right, |a, b| Some(a & b))?;
+        }
+        Ok(left)
+    }
+
+    fn shift(&mut self, depth: usize) -> Result<Value<S>, AssemblerError> {
+        let mut left = self.sum(depth)?;
+        loop {
+            if self.eat("<<") {
+                let right = self.sum(depth)?;
+                left = absolute(left, right, |a, b| {
+                    let factor = 1i64.checked_shl(u32::try_from(b).ok().filter(|b| *b < 63)?)?;
+                    a.checked_mul(factor)
+                })?;
+            } else if self.eat(">>") {
+                let right = self.sum(depth)?;
+                left = absolute(left, right, |a, b| {
+                    a.checked_shr(u32::try_from(b).ok().filter(|b| *b < 64)?)
+                })?;
+            } else {
+                return Ok(left);
+            }
+        }
+    }
+
+    fn sum(&mut self, depth: usize) -> Result<Value<S>, AssemblerError> {
+        let mut left = self.product(depth)?;
+        loop {
+            if self.eat("+") {
+                let right = self.product(depth)?;
+                let offset = left
+                    .offset
+                    .checked_add(right.offset)
+                    .ok_or(AssemblerError::Kind(AssemblerErrorKind::ExpressionOutOfRange))?;
+                let base = match (left.base, right.base) {
+                    (Some(_), Some(_)) => return Err(invalid()),
+                    (base, None) | (None, base) => base,
+                };
+                left = Value { base, offset };
+            } else if self.eat("-") {
+                let right = self.product(depth)?;
+                let offset = left
+                    .offset
+                    .checked_sub(right.offset)
+                    .ok_or(AssemblerError::Kind(AssemblerErrorKind::ExpressionOutOfRange))?;
+                let base = match (left.base, right.base) {
+                    (base, None) => base,
+                    (Some(a), Some(b)) if a == b => None,
+                    _ => return Err(invalid()),
+                };
+                left = Value { base, offset };
+            } else {
+                return Ok(left);
+            }
+        }
+    }
+
+    fn product(&mut self, depth: usize) -> Result<Value<S>, AssemblerError> {
+        let mut left = self.primary(depth)?;
+        loop {
+            if self.eat("*") {
+                let right = self.primary(depth)?;
+                left = absolute(left, right, i64::checked_mul)?;
+            } else if self.eat("/") {
+                let right = self.primary(depth)?;
+                left = absolute(left, right, i64::checked_div)?;
+            } else {
+                return Ok(left);
+            }
+        }
+    }
+
+    fn primary(&mut self, depth: usize) -> Result<Value<S>, AssemblerError> {
+        self.skip_whitespace();
+        if self.eat("-") {
+            let depth = depth.checked_add(1).filter(|depth| *depth <= MAX_DEPTH).ok_or(invalid())?;
+            let value = self.primary(depth)?;
+            return absolute(Value::literal(0), value, i64::checked_sub);
+        }
+        if let Some((value, len)) = self.text.get(self.pos..).and_then(char_literal) {
+            self.pos = self.pos.saturating_add(len);
+            return Ok(Value::literal(value));
+        }
+        if self.eat("(") {
+            let depth = depth.checked_add(1).filter(|depth| *depth <= MAX_DEPTH).ok_or(invalid())?;
+            let value = self.or(depth)?;
+            if !self.eat(")") {
+                return Err(invalid());
+            }
+            return Ok(value);
+        }
+        let start = self.pos;
+        while self
+            .text
+            .get(self.pos)
+            .is_some_and(|byte| byte.is_ascii_alphanumeric() || *byte == b'_')
+        {
+            self.pos = self.pos.saturating_add(1);
+        }
+        let word = self
+            .text
+            .get(start..self.pos)
+            .and_then(|word| core::str::from_utf8(word).ok())
+            .ok_or(invalid())?;
+        match word.as_bytes().first() {
+            None => Err(invalid()),
+            Some(first) if first.is_ascii_digit() => parse_number(word).map(Value::literal),
+            Some(_) => (self.lookup)(word),
+        }
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        let end = self.pos.saturating_add(token.len());
+        if self.text.get(self.pos..end) != Some(token.as_bytes()) {
+            return false;
+        }
+        self.pos = end;
+        true
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.text.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos = self.pos.saturating_add(1);
+        }
+    }
+}
+
+fn absolute<S>(
+    left: Value<S>,
+    right: Value<S>,
+    op: impl FnOnce(i64, i64) -> Option<i64>,
+) -> Result<Value<S>, AssemblerError> {
+    if left.base.is_some() || right.base.is_some() {
+        return Err(invalid());
+    }
+    op(left.offset, right.offset)
+        .map(Value::literal)
+        .ok_or(AssemblerError::Kind(AssemblerErrorKind::ExpressionOutOfRange))
+}
+
+fn parse_number(word: &str) -> Result<i64, AssemblerError> {
+    let parsed = if let Some(hex) = word.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16)
+    } else if let Some(binary) = word.strip_prefix("0b") {
+        u32::from_str_radix(binary, 2)
+    } else {
+        word.parse::<u32>()
+    };
+    parsed
+        .map(i64::from)
+        .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::InvalidNumber))
+}
+
+fn invalid() -> AssemblerError {
+    AssemblerError::Kind(AssemblerErrorKind::InvalidExpression)
+}
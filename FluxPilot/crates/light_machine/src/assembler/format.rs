@@ -0,0 +1,248 @@
+// Canonical layout for assembly source.
+//
+// `format_source` re-emits a program one line at a time:
+// - The contents of each `.machine`, `.func`, `.shared_func`, `.data`,
+//   `.shared_data`, `.macro` and control block are indented one level further
+//   than the directive that opened it. The closing `.end`/`.endm`/`.end*` and
+//   the middle `.else`/`.do` sit at the opener's level.
+// - Labels sit one level left of the code they label.
+// - Mnemonics are written in upper case.
+// - Runs of whitespace outside character literals become one space.
+// - Trailing comments on consecutive lines are aligned to one column.
+// - Runs of blank lines become one.
+//
+// Comments are kept as written. Lines the assembler would reject are still
+// laid out, so half-written programs can be formatted too. The formatter
+// only looks at the shape of each line and needs no allocator.
+
+use core::fmt::{self, Write};
+
+use heapless::Vec;
+
+use super::syntax::{split_first_token, strip_comment, Mnemonic};
+
+/// Spaces per indentation level.
+pub const INDENT: usize = 4;
+/// Gap between the longest line of code and an aligned trailing comment.
+pub const COMMENT_GAP: usize = 2;
+/// Blocks nested deeper than this are not indented further.
+const MAX_NESTING: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Block {
+    Machine,
+    Function,
+    Data,
+    Control,
+    Macro,
+}
+
+/// How one source line is laid out.
+struct Layout<'s> {
+    indent: usize,
+    code: &'s str,
+    comment: Option<&'s str>,
+    /// Upper-case the first token if it is a mnemonic.
+    mnemonic: bool,
+}
+
+#[derive(Clone)]
+struct BlockStack {
+    blocks: Vec<Block, MAX_NESTING>,
+    /// Blocks opened past `MAX_NESTING`, so their closers still balance.
+    overflow: usize,
+}
+
+impl BlockStack {
+    const fn new() -> Self {
+        Self {
+            blocks: Vec::new(),
+            overflow: 0,
+        }
+    }
+
+    fn depth(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn top(&self) -> Option<Block> {
+        if self.overflow > 0 {
+            return None;
+        }
+        self.blocks.last().copied()
+    }
+
+    fn open(&mut self, block: Block) {
+        if self.blocks.push(block).is_err() {
+            self.overflow = self.overflow.saturating_add(1);
+        }
+    }
+
+    fn close(&mut self) {
+        if self.overflow > 0 {
+            self.overflow = self.overflow.saturating_sub(1);
+        } else {
+            self.blocks.pop();
+        }
+    }
+
+    /// Lays out `line` and updates the open blocks to include its effect.
+    fn layout<'s>(&mut self, line: &'s str) -> Layout<'s> {
+        let code = strip_comment(line);
+        let comment = line
+            .get(code.len()..)
+            .map(str::trim_end)
+            .filter(|comment| !comment.is_empty());
+        let code = code.trim();
+        let (first, _) = split_first_token(code);
+        let depth = self.depth();
+        let mut layout = Layout {
+            indent: depth,
+            code,
+            comment,
+            mnemonic: false,
+        };
+        match first {
+            ".machine" => self.open(Block::Machine),
+            ".func" | ".shared_func" => self.open(Block::Function),
+            ".data" | ".shared_data" => self.open(Block::Data),
+            ".if" | ".while" | ".for" => self.open(Block::Control),
+            ".macro" => self.open(Block::Macro),
+            ".end" | ".endm" | ".endif" | ".endwhile" | ".endfor" => {
+                self.close();
+                layout.indent = self.depth();
+            }
+            ".else" | ".do" if self.top() == Some(Block::Control) => {
+                layout.indent = depth.saturating_sub(1);
+            }
+            _ if first.ends_with(':') && first.len() == code.len() => {
+                layout.indent = depth.saturating_sub(1);
+            }
+            _ => layout.mnemonic = self.top() != Some(Block::Data),
+        }
+        layout
+    }
+}
+
+/// Counts the characters written through it.
+struct Width(usize);
+
+impl Write for Width {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 = self.0.saturating_add(s.chars().count());
+        Ok(())
+    }
+}
+
+fn write_indent<W: Write>(out: &mut W, levels: usize) -> fmt::Result {
+    for _ in 0..levels.saturating_mul(INDENT) {
+        out.write_char(' ')?;
+    }
+    Ok(())
+}
+
+/// Writes `text` with whitespace outside character literals collapsed.
+fn write_collapsed<W: Write>(out: &mut W, text: &str) -> fmt::Result {
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut space = false;
+    for c in text.chars() {
+        if !quoted && c.is_whitespace() {
+            space = true;
+            continue;
+        }
+        if space {
+            out.write_char(' ')?;
+            space = false;
+        }
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '\'' => quoted = !quoted,
+            _ => {}
+        }
+        out.write_char(c)?;
+    }
+    Ok(())
+}
+
+/// Writes the indented code of `layout`, without its comment.
+fn write_code<W: Write>(out: &mut W, layout: &Layout<'_>) -> fmt::Result {
+    write_indent(out, layout.indent)?;
+    let (first, _) = split_first_token(layout.code);
+    match Mnemonic::parse(first).filter(|_| layout.mnemonic) {
+        Some(mnemonic) => {
+            out.write_str(mnemonic.name())?;
+            write_collapsed(out, layout.code.get(first.len()..).unwrap_or_default())
+        }
+        None => write_collapsed(out, layout.code),
+    }
+}
+
+fn code_width(layout: &Layout<'_>) -> usize {
+    let mut width = Width(0);
+    // Writing to `Width` cannot fail.
+    let _ = write_code(&mut width, layout);
+    width.0
+}
+
+/// The column for the trailing comments of the run of lines that starts
+/// with `lines`, laid out from `blocks`.
+fn comment_column<'s>(mut blocks: BlockStack, lines: impl Iterator<Item = &'s str>) -> usize {
+    let mut widest = 0;
+    for line in lines {
+        let layout = blocks.layout(line);
+        if layout.code.is_empty() || layout.comment.is_none() {
+            break;
+        }
+        widest = widest.max(code_width(&layout));
+    }
+    widest.saturating_add(COMMENT_GAP)
+}
+
+/// Writes `source` to `out` in canonical layout. Formatting its own output
+/// changes nothing.
+pub fn format_source<W: Write>(source: &str, out: &mut W) -> fmt::Result {
+    let mut blocks = BlockStack::new();
+    let mut lines = source.lines();
+    let mut started = false;
+    let mut blank = false;
+    let mut column = None;
+    while let Some(line) = lines.next() {
+        let before = blocks.clone();
+        let layout = blocks.layout(line);
+        if layout.code.is_empty() && layout.comment.is_none() {
+            blank = started;
+            column = None;
+            continue;
+        }
+        if blank {
+            out.write_char('\n')?;
+            blank = false;
+        }
+        started = true;
+
+        match layout.comment {
+            Some(comment) if layout.code.is_empty() => {
+                column = None;
+                write_indent(out, layout.indent)?;
+                out.write_str(comment)?;
+            }
+            Some(comment) => {
+                let column =
+                    *column.get_or_insert_with(|| comment_column(before, core::iter::once(line).chain(lines.clone())));
+                write_code(out, &layout)?;
+                for _ in code_width(&layout)..column {
+                    out.write_char(' ')?;
+                }
+                out.write_str(comment)?;
+            }
+            None => {
+                column = None;
+                write_code(out, &layout)?;
+            }
+        }
+        out.write_char('\n')?;
+    }
+    Ok(())
+}
@@ -0,0 +1,137 @@
+// `.include "<name>"` expansion. Includes are expanded before lines reach an
+// assembler, so both assemblers share this code. Nothing is allocated: the
+// included text is borrowed from the resolver while it is being read, and
+// the stack of open names is bounded by MAX_INCLUDE_DEPTH.
+
+use heapless::{String, Vec};
+
+use super::{strip_comment, to_name, AssemblerError, AssemblerErrorKind, NAME_CAP};
+
+/// How many includes may be open at once, not counting the root source.
+pub const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Finds the text behind an `.include` name.
+pub trait SourceResolver {
+    /// Calls `read` with the text of `name`, or returns `None` if there is no
+    /// such source.
+    fn with_source<R>(&self, name: &str, read: impl FnOnce(&str) -> R) -> Option<R>;
+}
+
+/// Resolves names from a fixed table, e.g. a library bundled into firmware
+/// with `include_str!`.
+pub struct StaticResolver<'s> {
+    sources: &'s [(&'s str, &'s str)],
+}
+
+impl<'s> StaticResolver<'s> {
+    pub const fn new(sources: &'s [(&'s str, &'s str)]) -> Self {
+        Self { sources }
+    }
+}
+
+impl SourceResolver for StaticResolver<'_> {
+    fn with_source<R>(&self, name: &str, read: impl FnOnce(&str) -> R) -> Option<R> {
+        self.sources
+            .iter()
+            .find(|(source_name, _)| *source_name == name)
+            .map(|(_, text)| read(text))
+    }
+}
+
+/// Resolver for programs that are not allowed to include anything.
+pub struct NoIncludes;
+
+impl SourceResolver for NoIncludes {
+    fn with_source<R>(&self, _name: &str, _read: impl FnOnce(&str) -> R) -> Option<R> {
+        None
+    }
+}
+
+/// Where a line came from. `file` is `None` for the root source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SourceLocation<'n> {
+    pub file: Option<&'n str>,
+    pub line: u32,
+}
+
+/// Calls `sink` for every line of `source` with includes expanded in place.
+///
+/// Errors returned by `sink` are tagged with the location of the line that
+/// caused them, replacing whatever line number the assembler counted.
+pub fn for_each_line<R, F>(source: &str, resolver: &R, mut sink: F) -> Result<(), AssemblerError>
+where
+    R: SourceResolver,
+    F: FnMut(SourceLocation<'_>, &str) -> Result<(), AssemblerError>,
+{
+    let mut open: Vec<String<NAME_CAP>, MAX_INCLUDE_DEPTH> = Vec::new();
+    expand(source, None, resolver, &mut open, &mut sink)
+}
+
+fn expand<R, F>(
+    source: &str,
+    file: Option<&str>,
+    resolver: &R,
+    open: &mut Vec<String<NAME_CAP>, MAX_INCLUDE_DEPTH>,
+    sink: &mut F,
+) -> Result<(), AssemblerError>
+where
+    R: SourceResolver,
+    F: FnMut(SourceLocation<'_>, &str) -> Result<(), AssemblerError>,
+{
+    let mut line_number: u32 = 0;
+    for line in source.lines() {
+        line_number = line_number
+            .checked_add(1)
+            .ok_or(AssemblerError::Kind(AssemblerErrorKind::LineNumberOverflow))?;
+        let location = SourceLocation {
+            file,
+            line: line_number,
+        };
+        let result = match include_name(line) {
+            Some(name) => name.and_then(|name| include(name, resolver, open, sink)),
+            None => sink(location, line),
+        };
+        result.map_err(|err| err.at_location(location))?;
+    }
+    Ok(())
+}
+
+fn include<R, F>(
+    name: &str,
+    resolver: &R,
+    open: &mut Vec<String<NAME_CAP>, MAX_INCLUDE_DEPTH>,
+    sink: &mut F,
+) -> Result<(), AssemblerError>
+where
+    R: SourceResolver,
+    F: FnMut(SourceLocation<'_>, &str) -> Result<(), AssemblerError>,
+{
+    let name = to_name(name)?;
+    if open.contains(&name) {
+        return Err(AssemblerError::Kind(AssemblerErrorKind::IncludeCycle));
+    }
+    open.push(name.clone())
+        .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::IncludeTooDeep))?;
+    let result = resolver
+        .with_source(name.as_str(), |text| {
+            expand(text, Some(name.as_str()), resolver, open, sink)
+        })
+        .unwrap_or(Err(AssemblerError::Kind(AssemblerErrorKind::IncludeNotFound)));
+    open.pop();
+    result
+}
+
+// Returns `None` when the line is not an include, so it goes to the assembler.
+fn include_name(line: &str) -> Option<Result<&str, AssemblerError>> {
+    let rest = strip_comment(line).trim().strip_prefix(".include")?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let name = rest
+        .trim()
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .filter(|name| !name.is_empty() && !name.contains('"'))
+        .ok_or(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
+    Some(name)
+}
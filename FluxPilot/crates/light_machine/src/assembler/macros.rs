@@ -0,0 +1,228 @@
+// `.macro <name> <params...>` / `.endm` definitions and their expansion.
+// Both assemblers keep a MacroTable and route every line through `classify`
+// before assembling it. Bodies are stored as comment-stripped text in one
+// fixed buffer; expanding a call produces one substituted line at a time so
+// nothing is allocated.
+//
+// Parameters are replaced wherever they appear as a whole identifier. Labels
+// defined inside a body are renamed to `__<n>_<label>` for the n-th
+// expansion, so a macro with an internal loop can be used more than once.
+
+use core::fmt::Write;
+
+use heapless::{String, Vec};
+
+use super::{is_identifier, split_first_token, strip_comment, to_name, AssemblerError, AssemblerErrorKind, NAME_CAP};
+
+/// Parameters a single macro may take.
+pub const MAX_MACRO_PARAMS: usize = 8;
+/// How many macro calls may be nested inside one another.
+pub const MAX_MACRO_DEPTH: usize = 8;
+/// Longest line a macro expansion may produce.
+pub const MACRO_LINE_CAP: usize = 128;
+
+pub type MacroLineBuffer = String<MACRO_LINE_CAP>;
+
+struct MacroDef {
+    name: String<NAME_CAP>,
+    params: Vec<String<NAME_CAP>, MAX_MACRO_PARAMS>,
+    start: usize,
+    end: usize,
+}
+
+/// What a source line means to the macro table.
+pub enum MacroLine<'l> {
+    /// Not macro related; assemble it as written.
+    Plain,
+    /// Consumed by a definition.
+    Recorded,
+    /// A call to expand with [`MacroTable::expand_next`].
+    Call(MacroCall<'l>),
+}
+
+pub struct MacroCall<'l> {
+    index: usize,
+    args: Vec<&'l str, MAX_MACRO_PARAMS>,
+    serial: u32,
+    at: usize,
+}
+
+/// Macro definitions. `TEXT_CAP` bounds the combined size of all bodies and
+/// `MACRO_CAP` the number of macros.
+pub struct MacroTable<const TEXT_CAP: usize, const MACRO_CAP: usize> {
+    text: String<TEXT_CAP>,
+    macros: Vec<MacroDef, MACRO_CAP>,
+    recording: bool,
+    serial: u32,
+}
+
+impl<const TEXT_CAP: usize, const MACRO_CAP: usize> Default for MacroTable<TEXT_CAP, MACRO_CAP> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const TEXT_CAP: usize, const MACRO_CAP: usize> MacroTable<TEXT_CAP, MACRO_CAP> {
+    pub const fn new() -> Self {
+        Self {
+            text: String::new(),
+            macros: Vec::new(),
+            recording: false,
+            serial: 0,
+        }
+    }
+
+    /// True while a `.macro` has not been closed by `.endm`.
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    pub fn classify<'l>(&mut self, line: &'l str) -> Result<MacroLine<'l>, AssemblerError> {
+        let line = strip_comment(line).trim();
+        let (first, rest) = split_first_token(line);
+        if self.recording {
+            match first {
+                ".endm" if rest.is_empty() => self.recording = false,
+                ".endm" => return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective)),
+                ".macro" => return Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective)),
+                "" => {}
+                _ => self.record(line)?,
+            }
+            return Ok(MacroLine::Recorded);
+        }
+        match first {
+            ".macro" => {
+                self.define(rest)?;
+                Ok(MacroLine::Recorded)
+            }
+            ".endm" => Err(AssemblerError::Kind(AssemblerErrorKind::UnexpectedDirective)),
+            _ => match self.macros.iter().position(|def| def.name == first) {
+                Some(index) => self.call(index, rest).map(MacroLine::Call),
+                None => Ok(MacroLine::Plain),
+            },
+        }
+    }
+
+    /// Writes the next line of `call` into `out`. Returns `false` once the
+    /// body is exhausted.
+    pub fn expand_next(&self, call: &mut MacroCall<'_>, out: &mut MacroLineBuffer) -> Result<bool, AssemblerError> {
+        out.clear();
+        let body = self
+            .macros
+            .get(call.index)
+            .and_then(|def| self.text.get(def.start..def.end).map(|body| (def, body)));
+        let Some((def, body)) = body else {
+            return Ok(false);
+        };
+        let Some(remaining) = body.get(call.at..).filter(|rest| !rest.is_empty()) else {
+            return Ok(false);
+        };
+        let line = remaining.split('\n').next().unwrap_or(remaining);
+        call.at = call.at.saturating_add(line.len()).saturating_add(1);
+
+        let mut pos = 0;
+        while let Some(tail) = line.get(pos..).filter(|tail| !tail.is_empty()) {
+            let word_len = tail
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(tail.len());
+            let (piece, len) = match word_len {
+                0 => {
+                    let len = tail.chars().next().map_or(1, char::len_utf8);
+                    (tail.get(..len).unwrap_or(tail), len)
+                }
+                _ => (tail.get(..word_len).unwrap_or(tail), word_len),
+            };
+            pos = pos.saturating_add(len);
+            if !is_identifier(piece) {
+                push(out, piece)?;
+            } else if let Some(arg) = def
+                .params
+                .iter()
+                .position(|param| param == piece)
+                .and_then(|index| call.args.get(index))
+            {
+                push(out, arg)?;
+            } else if body.split('\n').any(|body_line| body_line.strip_suffix(':') == Some(piece)) {
+                write!(out, "__{}_{}", call.serial, piece)
+                    .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MacroTooLarge))?;
+            } else {
+                push(out, piece)?;
+            }
+        }
+        Ok(true)
+    }
+
+    fn define(&mut self, header: &str) -> Result<(), AssemblerError> {
+        let mut words = header.split(|c: char| c.is_whitespace() || c == ',').filter(|word| !word.is_empty());
+        let name = words
+            .next()
+            .filter(|name| is_identifier(name))
+            .ok_or(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective))?;
+        if self.macros.iter().any(|def| def.name == name) {
+            return Err(AssemblerError::Kind(AssemblerErrorKind::DuplicateMacro));
+        }
+        let mut params: Vec<String<NAME_CAP>, MAX_MACRO_PARAMS> = Vec::new();
+        for param in words {
+            if !is_identifier(param) || params.iter().any(|seen| seen == param) {
+                return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
+            }
+            params
+                .push(to_name(param)?)
+                .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::TooManyTokens))?;
+        }
+        let start = self.text.len();
+        self.macros
+            .push(MacroDef {
+                name: to_name(name)?,
+                params,
+                start,
+                end: start,
+            })
+            .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MacroTooLarge))?;
+        self.recording = true;
+        Ok(())
+    }
+
+    fn record(&mut self, line: &str) -> Result<(), AssemblerError> {
+        self.text
+            .push_str(line)
+            .and_then(|_| self.text.push('\n'))
+            .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MacroTooLarge))?;
+        if let Some(def) = self.macros.last_mut() {
+            def.end = self.text.len();
+        }
+        Ok(())
+    }
+
+    fn call<'l>(&mut self, index: usize, rest: &'l str) -> Result<MacroCall<'l>, AssemblerError> {
+        let mut args: Vec<&str, MAX_MACRO_PARAMS> = Vec::new();
+        if !rest.is_empty() {
+            for arg in rest.split(',').map(str::trim) {
+                if arg.is_empty() {
+                    return Err(AssemblerError::Kind(AssemblerErrorKind::MacroArgumentCount));
+                }
+                args.push(arg)
+                    .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MacroArgumentCount))?;
+            }
+        }
+        let expected = self.macros.get(index).map_or(0, |def| def.params.len());
+        if args.len() != expected {
+            return Err(AssemblerError::Kind(AssemblerErrorKind::MacroArgumentCount));
+        }
+        self.serial = self
+            .serial
+            .checked_add(1)
+            .ok_or(AssemblerError::Kind(AssemblerErrorKind::MacroTooLarge))?;
+        Ok(MacroCall {
+            index,
+            args,
+            serial: self.serial,
+            at: 0,
+        })
+    }
+}
+
+fn push(out: &mut MacroLineBuffer, text: &str) -> Result<(), AssemblerError> {
+    out.push_str(text)
+        .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::MacroTooLarge))
+}
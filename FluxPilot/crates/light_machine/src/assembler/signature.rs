@@ -0,0 +1,94 @@
+// Function headers and call sugar.
+//
+// `.func`, `.func_decl`, `.shared_func` and `.shared_func_decl` take a name
+// followed by optional `index <I>`, `args <N>` and `returns <M>` clauses in
+// any order. A signature lets the assembler check `CALL <name> <argc>` sites
+// and every `RET` in the body; functions without one are not checked.
+
+use super::{is_identifier, parse_word, AssemblerError, AssemblerErrorKind};
+use crate::ProgramWord;
+
+/// Declared argument and return counts; `None` where the header left it out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Signature {
+    pub args: Option<ProgramWord>,
+    pub returns: Option<ProgramWord>,
+}
+
+impl Signature {
+    /// Combines a declaration with a later definition. Each count may be
+    /// given by either side, but both sides must agree where they both do.
+    pub fn merge(self, other: Signature) -> Result<Signature, AssemblerError> {
+        Ok(Signature {
+            args: merge_count(self.args, other.args)?,
+            returns: merge_count(self.returns, other.returns)?,
+        })
+    }
+
+    pub fn check_args(&self, argc: ProgramWord) -> Result<(), AssemblerError> {
+        match self.args {
+            Some(args) if args != argc => Err(AssemblerError::Kind(AssemblerErrorKind::ArgumentCountMismatch)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_returns(&self, count: ProgramWord) -> Result<(), AssemblerError> {
+        match self.returns {
+            Some(returns) if returns != count => Err(AssemblerError::Kind(AssemblerErrorKind::ReturnCountMismatch)),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn merge_count(a: Option<ProgramWord>, b: Option<ProgramWord>) -> Result<Option<ProgramWord>, AssemblerError> {
+    match (a, b) {
+        (Some(a), Some(b)) if a != b => Err(AssemblerError::Kind(AssemblerErrorKind::SignatureMismatch)),
+        (a, b) => Ok(a.or(b)),
+    }
+}
+
+/// A parsed `.func`-style directive.
+pub struct FunctionHeader<'t> {
+    pub name: &'t str,
+    pub index: Option<ProgramWord>,
+    pub signature: Signature,
+}
+
+/// Parses `<directive> <name> [index <I>] [args <N>] [returns <M>]`.
+pub fn parse_function_header<'t>(tokens: &[&'t str]) -> Result<FunctionHeader<'t>, AssemblerError> {
+    let invalid = || AssemblerError::Kind(AssemblerErrorKind::InvalidDirective);
+    let (name, clauses) = match tokens {
+        [_, name, clauses @ ..] => (*name, clauses),
+        _ => return Err(invalid()),
+    };
+    let mut header = FunctionHeader {
+        name,
+        index: None,
+        signature: Signature::default(),
+    };
+    for clause in clauses.chunks(2) {
+        let [keyword, value] = clause else {
+            return Err(invalid());
+        };
+        let slot = match *keyword {
+            "index" => &mut header.index,
+            "args" => &mut header.signature.args,
+            "returns" => &mut header.signature.returns,
+            _ => return Err(invalid()),
+        };
+        if slot.replace(parse_word(value)?).is_some() {
+            return Err(invalid());
+        }
+    }
+    Ok(header)
+}
+
+/// Splits the `<name> <argc>` operand of the call sugar. Returns `None` for
+/// the plain forms, where the operand is a single expression such as
+/// `helper` or `base + 2`.
+pub fn split_call_operand(operand: &str) -> Option<(&str, &str)> {
+    let (name, argc) = operand.split_once(char::is_whitespace)?;
+    let argc = argc.trim();
+    let operator = argc.starts_with(['+', '-', '*', '/', '&', '|', '<', '>', ')']);
+    (is_identifier(name) && !argc.is_empty() && !operator).then_some((name, argc))
+}
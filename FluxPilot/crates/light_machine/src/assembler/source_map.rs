@@ -0,0 +1,339 @@
+// Source maps: which assembly line each program word came from, so a fault
+// at some PC can be reported against the source.
+//
+// The map is a byte string meant to be kept next to the program (FlightDeck
+// stores it in the UI state blob), so it is a stream of small records rather
+// than a table:
+//
+//   header    'S' 'M' <version>
+//   file      0x01 <len> <name>                      len 0 is the root source
+//   function  0x02 <len> <machine> <len> <function>  machine len 0 is shared
+//   range     0x03 <start> <count> <line>            LEB128 varints
+//
+// A range belongs to the file and function records before it. Words emitted
+// back to back for the same line are merged into one range, which keeps the
+// map at a few bytes per source line.
+
+use heapless::String;
+
+use super::{AssemblerError, AssemblerErrorKind};
+use crate::ProgramWord;
+
+pub const SOURCE_MAP_VERSION: u8 = 1;
+/// File, machine and function names longer than this are cut short.
+pub const SOURCE_NAME_CAP: usize = 64;
+
+const MAGIC: [u8; 2] = [b'S', b'M'];
+const FILE: u8 = 0x01;
+const FUNCTION: u8 = 0x02;
+const RANGE: u8 = 0x03;
+
+/// Where a [`SourceMapWriter`] puts its bytes.
+pub trait SourceMapSink {
+    /// Appends `byte`, returning `false` when there is no room for it.
+    fn push_byte(&mut self, byte: u8) -> bool;
+}
+
+impl<const N: usize> SourceMapSink for heapless::Vec<u8, N> {
+    fn push_byte(&mut self, byte: u8) -> bool {
+        self.push(byte).is_ok()
+    }
+}
+
+/// Writes into a caller-provided buffer.
+pub struct SliceSink<'b> {
+    buffer: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> SliceSink<'b> {
+    pub fn new(buffer: &'b mut [u8]) -> Self {
+        Self { buffer, len: 0 }
+    }
+
+    /// Bytes written so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl SourceMapSink for SliceSink<'_> {
+    fn push_byte(&mut self, byte: u8) -> bool {
+        let Some(slot) = self.buffer.get_mut(self.len) else {
+            return false;
+        };
+        *slot = byte;
+        self.len = self.len.saturating_add(1);
+        true
+    }
+}
+
+#[derive(Clone, Copy)]
+struct PendingRange {
+    start: ProgramWord,
+    end: ProgramWord,
+    line: u32,
+}
+
+/// Builds a source map as words are emitted. Addresses must be absolute
+/// program indexes, the same ones the VM uses for its PC.
+pub struct SourceMapWriter<S> {
+    sink: S,
+    started: bool,
+    file: String<SOURCE_NAME_CAP>,
+    pending: Option<PendingRange>,
+}
+
+impl<S: SourceMapSink> SourceMapWriter<S> {
+    pub fn new(sink: S) -> Self {
+        Self {
+            sink,
+            started: false,
+            file: String::new(),
+            pending: None,
+        }
+    }
+
+    /// Sets the file the following lines come from; `None` is the root
+    /// source. Nothing is written if the file has not changed.
+    pub fn set_file(&mut self, file: Option<&str>) -> Result<(), AssemblerError> {
+        let file = truncated(file.unwrap_or(""));
+        if self.file == file {
+            return Ok(());
+        }
+        self.flush()?;
+        self.byte(FILE)?;
+        self.name(&file)?;
+        self.file = file;
+        Ok(())
+    }
+
+    /// Starts a function body. `machine` is `None` for shared functions.
+    pub fn set_function(&mut self, machine: Option<&str>, function: &str) -> Result<(), AssemblerError> {
+        self.flush()?;
+        self.byte(FUNCTION)?;
+        self.name(&truncated(machine.unwrap_or("")))?;
+        self.name(&truncated(function))
+    }
+
+    /// Records that words `start..end` were assembled from `line`.
+    pub fn add_words(&mut self, start: ProgramWord, end: ProgramWord, line: u32) -> Result<(), AssemblerError> {
+        if end <= start {
+            return Ok(());
+        }
+        if let Some(pending) = self.pending.as_mut()
+            && pending.end == start
+            && pending.line == line
+        {
+            pending.end = end;
+            return Ok(());
+        }
+        self.flush()?;
+        self.pending = Some(PendingRange { start, end, line });
+        Ok(())
+    }
+
+    /// Writes anything still buffered and hands back the sink.
+    pub fn finish(mut self) -> Result<S, AssemblerError> {
+        self.flush()?;
+        self.header()?;
+        Ok(self.sink)
+    }
+
+    fn flush(&mut self) -> Result<(), AssemblerError> {
+        let Some(range) = self.pending.take() else {
+            return Ok(());
+        };
+        self.byte(RANGE)?;
+        self.varint(u32::from(range.start))?;
+        self.varint(u32::from(range.end.saturating_sub(range.start)))?;
+        self.varint(range.line)
+    }
+
+    fn header(&mut self) -> Result<(), AssemblerError> {
+        if self.started {
+            return Ok(());
+        }
+        self.started = true;
+        for byte in MAGIC.into_iter().chain([SOURCE_MAP_VERSION]) {
+            self.push(byte)?;
+        }
+        Ok(())
+    }
+
+    fn byte(&mut self, byte: u8) -> Result<(), AssemblerError> {
+        self.header()?;
+        self.push(byte)
+    }
+
+    fn name(&mut self, name: &str) -> Result<(), AssemblerError> {
+        let len = u8::try_from(name.len()).map_err(|_| too_large())?;
+        self.byte(len)?;
+        for byte in name.bytes() {
+            self.byte(byte)?;
+        }
+        Ok(())
+    }
+
+    fn varint(&mut self, mut value: u32) -> Result<(), AssemblerError> {
+        loop {
+            let low = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                return self.byte(low);
+            }
+            self.byte(low | 0x80)?;
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> Result<(), AssemblerError> {
+        if self.sink.push_byte(byte) {
+            Ok(())
+        } else {
+            Err(too_large())
+        }
+    }
+}
+
+/// Program words `start..end` and the source they were assembled from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SourceRange<'m> {
+    pub start: ProgramWord,
+    pub end: ProgramWord,
+    /// `None` for the root source.
+    pub file: Option<&'m str>,
+    pub line: u32,
+    /// `None` for shared functions.
+    pub machine: Option<&'m str>,
+    pub function: Option<&'m str>,
+}
+
+impl SourceRange<'_> {
+    pub fn contains(&self, address: usize) -> bool {
+        usize::from(self.start) <= address && address < usize::from(self.end)
+    }
+}
+
+/// A serialized source map, read in place.
+#[derive(Clone, Copy)]
+pub struct SourceMap<'m> {
+    records: &'m [u8],
+}
+
+impl<'m> SourceMap<'m> {
+    /// Checks the header. Returns `None` if `bytes` is not a map this
+    /// version understands.
+    pub fn new(bytes: &'m [u8]) -> Option<Self> {
+        let (magic, rest) = bytes.split_at_checked(MAGIC.len())?;
+        let (version, records) = rest.split_first()?;
+        (magic == MAGIC && *version == SOURCE_MAP_VERSION).then_some(Self { records })
+    }
+
+    pub fn ranges(&self) -> SourceRanges<'m> {
+        SourceRanges {
+            records: self.records,
+            file: None,
+            machine: None,
+            function: None,
+        }
+    }
+
+    /// The source of the instruction at `address`, typically the PC the VM
+    /// stopped at.
+    pub fn lookup(&self, address: usize) -> Option<SourceRange<'m>> {
+        self.ranges().find(|range| range.contains(address))
+    }
+}
+
+/// Every range of a [`SourceMap`] in the order it was written. Iteration
+/// stops early at the first malformed record.
+pub struct SourceRanges<'m> {
+    records: &'m [u8],
+    file: Option<&'m str>,
+    machine: Option<&'m str>,
+    function: Option<&'m str>,
+}
+
+impl<'m> SourceRanges<'m> {
+    fn byte(&mut self) -> Option<u8> {
+        let (byte, rest) = self.records.split_first()?;
+        self.records = rest;
+        Some(*byte)
+    }
+
+    fn name(&mut self) -> Option<Option<&'m str>> {
+        let len = usize::from(self.byte()?);
+        let (name, rest) = self.records.split_at_checked(len)?;
+        self.records = rest;
+        let name = core::str::from_utf8(name).ok()?;
+        Some((!name.is_empty()).then_some(name))
+    }
+
+    fn parse_next(&mut self) -> Option<SourceRange<'m>> {
+        loop {
+            match self.byte()? {
+                FILE => self.file = self.name()?,
+                FUNCTION => {
+                    self.machine = self.name()?;
+                    self.function = self.name()?;
+                }
+                RANGE => {
+                    let start = ProgramWord::try_from(self.varint()?).ok()?;
+                    let count = ProgramWord::try_from(self.varint()?).ok()?;
+                    let line = self.varint()?;
+                    return Some(SourceRange {
+                        start,
+                        end: start.checked_add(count)?,
+                        file: self.file,
+                        line,
+                        machine: self.machine,
+                        function: self.function,
+                    });
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    fn varint(&mut self) -> Option<u32> {
+        let mut value: u32 = 0;
+        for shift in (0..32).step_by(7) {
+            let byte = self.byte()?;
+            value |= u32::from(byte & 0x7F).checked_shl(shift)?;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+}
+
+impl<'m> Iterator for SourceRanges<'m> {
+    type Item = SourceRange<'m>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.parse_next();
+        if item.is_none() {
+            self.records = &[];
+        }
+        item
+    }
+}
+
+fn truncated(name: &str) -> String<SOURCE_NAME_CAP> {
+    let mut out = String::new();
+    for ch in name.chars() {
+        if out.push(ch).is_err() {
+            break;
+        }
+    }
+    out
+}
+
+fn too_large() -> AssemblerError {
+    AssemblerError::Kind(AssemblerErrorKind::SourceMapTooLarge)
+}
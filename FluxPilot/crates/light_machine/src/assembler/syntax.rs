@@ -0,0 +1,589 @@
+// The line grammar, shared by this crate's `Assembler` and flight-deck's
+// graph assembler.
+//
+// `parse_line` turns one line, after macro expansion and control lowering,
+// into a `Line` that borrows from the text. It checks the shape of the line
+// and parses literal numbers, but leaves names and expressions for the
+// back-end to resolve, since only the back-end knows what has been defined.
+// The only context it needs is whether the line sits in a data block.
+
+use heapless::Vec;
+
+use super::expression::{self, parse_word};
+use super::signature::{parse_function_header, split_call_operand, FunctionHeader};
+use super::{AssemblerError, AssemblerErrorKind};
+use crate::builder::Op;
+use crate::metadata::{ParamInfo, ParamKind, ParamTarget};
+use crate::{Ops, ProgramWord};
+
+/// Directives may have at most this many whitespace-separated tokens.
+pub const MAX_TOKENS: usize = 8;
+
+pub enum Line<'t> {
+    Empty,
+    /// `name:`, without the colon.
+    Label(&'t str),
+    Directive(Directive<'t>),
+    Instruction(Instruction<'t>),
+    /// One entry of a `.data` or `.shared_data` block.
+    Data(DataWord<'t>),
+}
+
+pub enum Directive<'t> {
+    Machine {
+        name: &'t str,
+        locals: ProgramWord,
+        functions: ProgramWord,
+    },
+    Instance(InstanceDirective<'t>),
+    Func(FunctionHeader<'t>),
+    FuncDecl(FunctionHeader<'t>),
+    SharedFunc(FunctionHeader<'t>),
+    SharedFuncDecl(FunctionHeader<'t>),
+    Local { name: &'t str, index: ProgramWord },
+    Shared { name: &'t str, index: ProgramWord },
+    Frame { name: &'t str, offset: ProgramWord },
+    Data { name: &'t str },
+    SharedData { name: &'t str },
+    Const { name: &'t str, expr: &'t str },
+    Param(ParamDirective<'t>),
+    End,
+}
+
+/// `.instance <name> of <machine> [<local> = <expr>, ...]`
+pub struct InstanceDirective<'t> {
+    pub name: &'t str,
+    pub machine: &'t str,
+    assignments: &'t str,
+}
+
+impl<'t> InstanceDirective<'t> {
+    /// The `(local, expr)` pairs, in source order.
+    pub fn assignments(&self) -> impl Iterator<Item = Result<(&'t str, &'t str), AssemblerError>> + 't {
+        let text = self.assignments;
+        text.split(',').filter(move |_| !text.is_empty()).map(|assignment| {
+            let (local, expr) = assignment
+                .split_once('=')
+                .ok_or(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective))?;
+            Ok((local.trim(), expr.trim()))
+        })
+    }
+}
+
+/// `.param <name> range|toggle|color [min <expr>] [max <expr>] [step <expr>]
+/// [default <expr>|#rrggbb] local|func <target>`
+pub struct ParamDirective<'t> {
+    pub name: &'t str,
+    pub kind: ParamKind,
+    pub min: Option<&'t str>,
+    pub max: Option<&'t str>,
+    pub step: Option<&'t str>,
+    pub default: Option<&'t str>,
+    pub target: ParamTargetName<'t>,
+}
+
+/// The local or function a `.param` names, for the back-end to resolve.
+#[derive(Clone, Copy)]
+pub enum ParamTargetName<'t> {
+    Local(&'t str),
+    Function(&'t str),
+}
+
+impl ParamDirective<'_> {
+    /// The param with its defaults filled in and its values checked.
+    /// `evaluate` resolves the `min`, `max`, `step` and `default`
+    /// expressions. Ranges default to 0 to 255 in steps of 1, toggles to 0
+    /// to 1, and the default to `min`; a color's range is always 0 to 255.
+    pub fn info(
+        &self,
+        type_id: ProgramWord,
+        target: ParamTarget,
+        mut evaluate: impl FnMut(&str) -> Result<ProgramWord, AssemblerError>,
+    ) -> Result<ParamInfo, AssemblerError> {
+        let invalid = || AssemblerError::Kind(AssemblerErrorKind::InvalidParam);
+        let mut value = |text: Option<&str>, fallback: ProgramWord| match text {
+            Some(text) => evaluate(text),
+            None => Ok(fallback),
+        };
+        let (min, max, step, default) = match self.kind {
+            ParamKind::Color => {
+                if self.min.is_some() || self.max.is_some() || self.step.is_some() {
+                    return Err(invalid());
+                }
+                let default = match self.default {
+                    Some(text) => expression::parse_color(text).ok_or_else(invalid)??,
+                    None => [0; 3],
+                };
+                (0, 255, 1, default)
+            }
+            ParamKind::Range | ParamKind::Toggle => {
+                let top = if self.kind == ParamKind::Toggle { 1 } else { 255 };
+                let min = value(self.min, 0)?;
+                let max = value(self.max, top)?;
+                let step = value(self.step, 1)?;
+                let default = value(self.default, min)?;
+                if self.kind == ParamKind::Toggle && max > 1 {
+                    return Err(invalid());
+                }
+                (min, max, step, [default, 0, 0])
+            }
+        };
+        let [first, ..] = default;
+        let in_range = match self.kind {
+            ParamKind::Color => default.iter().all(|channel| *channel <= 255),
+            _ => (min..=max).contains(&first),
+        };
+        if step == 0 || min > max || !in_range {
+            return Err(invalid());
+        }
+        Ok(ParamInfo {
+            type_id,
+            kind: self.kind,
+            target,
+            min,
+            max,
+            step,
+            default,
+        })
+    }
+}
+
+pub enum DataWord<'t> {
+    Expr(&'t str),
+    /// `#rrggbb`, stored as three words.
+    Color([ProgramWord; 3]),
+}
+
+pub struct Instruction<'t> {
+    pub mnemonic: Mnemonic,
+    pub operand: Operand<'t>,
+}
+
+pub enum Operand<'t> {
+    None,
+    /// Everything after the mnemonic, so it can be an expression.
+    Expr(&'t str),
+    /// `PUSH #rrggbb`.
+    Color([ProgramWord; 3]),
+    /// `CALL <name> <argc>` and `CALL_SHARED <name> <argc>`.
+    Call { name: &'t str, argc: &'t str },
+}
+
+/// What a mnemonic's operand names, which decides how a back-end resolves
+/// it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperandKind {
+    None,
+    Value,
+    StackSlot,
+    Local,
+    SharedGlobal,
+    /// Optional; with one, the assembler pushes it before the op.
+    Target,
+    /// Like `Target`, naming a shared function.
+    SharedTarget,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mnemonic {
+    Push,
+    Pop,
+    LocalLoad,
+    LocalStore,
+    GlobalLoad,
+    GlobalStore,
+    StackLoad,
+    StackStore,
+    Dup,
+    Swap,
+    Return,
+    LoadStatic,
+    Jump,
+    Call,
+    CallShared,
+    BranchLessThan,
+    BranchLessThanEq,
+    BranchGreaterThan,
+    BranchGreaterThanEq,
+    BranchEqual,
+    Exit,
+    And,
+    Or,
+    Xor,
+    Not,
+    BitwiseAnd,
+    BitwiseOr,
+    BitwiseXor,
+    BitwiseNot,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Mod,
+}
+
+const MNEMONICS: [(&str, Mnemonic); 34] = [
+    ("PUSH", Mnemonic::Push),
+    ("POP", Mnemonic::Pop),
+    ("LLOAD", Mnemonic::LocalLoad),
+    ("LSTORE", Mnemonic::LocalStore),
+    ("GLOAD", Mnemonic::GlobalLoad),
+    ("GSTORE", Mnemonic::GlobalStore),
+    ("SLOAD", Mnemonic::StackLoad),
+    ("SSTORE", Mnemonic::StackStore),
+    ("DUP", Mnemonic::Dup),
+    ("SWAP", Mnemonic::Swap),
+    ("RET", Mnemonic::Return),
+    ("LOAD_STATIC", Mnemonic::LoadStatic),
+    ("JUMP", Mnemonic::Jump),
+    ("CALL", Mnemonic::Call),
+    ("CALL_SHARED", Mnemonic::CallShared),
+    ("BRLT", Mnemonic::BranchLessThan),
+    ("BRLTE", Mnemonic::BranchLessThanEq),
+    ("BRGT", Mnemonic::BranchGreaterThan),
+    ("BRGTE", Mnemonic::BranchGreaterThanEq),
+    ("BREQ", Mnemonic::BranchEqual),
+    ("EXIT", Mnemonic::Exit),
+    ("AND", Mnemonic::And),
+    ("OR", Mnemonic::Or),
+    ("XOR", Mnemonic::Xor),
+    ("NOT", Mnemonic::Not),
+    ("BAND", Mnemonic::BitwiseAnd),
+    ("BOR", Mnemonic::BitwiseOr),
+    ("BXOR", Mnemonic::BitwiseXor),
+    ("BNOT", Mnemonic::BitwiseNot),
+    ("ADD", Mnemonic::Add),
+    ("SUB", Mnemonic::Subtract),
+    ("MUL", Mnemonic::Multiply),
+    ("DIV", Mnemonic::Divide),
+    ("MOD", Mnemonic::Mod),
+];
+
+impl Mnemonic {
+    /// Mnemonics are written all upper case or all lower case.
+    pub fn parse(text: &str) -> Option<Mnemonic> {
+        let lower = !text.bytes().any(|byte| byte.is_ascii_uppercase());
+        MNEMONICS
+            .iter()
+            .find(|(name, _)| *name == text || (lower && name.eq_ignore_ascii_case(text)))
+            .map(|(_, mnemonic)| *mnemonic)
+    }
+
+    /// The upper-case spelling.
+    pub fn name(self) -> &'static str {
+        MNEMONICS
+            .iter()
+            .find(|(_, mnemonic)| *mnemonic == self)
+            .map_or("", |(name, _)| *name)
+    }
+
+    /// The mnemonic that assembles to `op`, for disassembly.
+    pub fn from_opcode(op: Ops) -> Option<Mnemonic> {
+        MNEMONICS
+            .iter()
+            .map(|(_, mnemonic)| *mnemonic)
+            .find(|mnemonic| mnemonic.opcode() == op)
+    }
+
+    pub fn opcode(self) -> Ops {
+        match self {
+            Mnemonic::Push => Ops::Push,
+            Mnemonic::Pop => Ops::Pop,
+            Mnemonic::LocalLoad => Ops::LocalLoad,
+            Mnemonic::LocalStore => Ops::LocalStore,
+            Mnemonic::GlobalLoad => Ops::GlobalLoad,
+            Mnemonic::GlobalStore => Ops::GlobalStore,
+            Mnemonic::StackLoad => Ops::StackLoad,
+            Mnemonic::StackStore => Ops::StackStore,
+            Mnemonic::Dup => Ops::Dup,
+            Mnemonic::Swap => Ops::Swap,
+            Mnemonic::Return => Ops::Return,
+            Mnemonic::LoadStatic => Ops::LoadStatic,
+            Mnemonic::Jump => Ops::Jump,
+            Mnemonic::Call => Ops::Call,
+            Mnemonic::CallShared => Ops::CallShared,
+            Mnemonic::BranchLessThan => Ops::BranchLessThan,
+            Mnemonic::BranchLessThanEq => Ops::BranchLessThanEq,
+            Mnemonic::BranchGreaterThan => Ops::BranchGreaterThan,
+            Mnemonic::BranchGreaterThanEq => Ops::BranchGreaterThanEq,
+            Mnemonic::BranchEqual => Ops::BranchEqual,
+            Mnemonic::Exit => Ops::Exit,
+            Mnemonic::And => Ops::And,
+            Mnemonic::Or => Ops::Or,
+            Mnemonic::Xor => Ops::Xor,
+            Mnemonic::Not => Ops::Not,
+            Mnemonic::BitwiseAnd => Ops::BitwiseAnd,
+            Mnemonic::BitwiseOr => Ops::BitwiseOr,
+            Mnemonic::BitwiseXor => Ops::BitwiseXor,
+            Mnemonic::BitwiseNot => Ops::BitwiseNot,
+            Mnemonic::Add => Ops::Add,
+            Mnemonic::Subtract => Ops::Subtract,
+            Mnemonic::Multiply => Ops::Multiply,
+            Mnemonic::Divide => Ops::Divide,
+            Mnemonic::Mod => Ops::Mod,
+        }
+    }
+
+    pub fn operand_kind(self) -> OperandKind {
+        match self {
+            Mnemonic::Push | Mnemonic::Return => OperandKind::Value,
+            Mnemonic::LocalLoad | Mnemonic::LocalStore => OperandKind::Local,
+            Mnemonic::GlobalLoad | Mnemonic::GlobalStore => OperandKind::SharedGlobal,
+            Mnemonic::StackLoad | Mnemonic::StackStore => OperandKind::StackSlot,
+            Mnemonic::LoadStatic
+            | Mnemonic::Jump
+            | Mnemonic::Call
+            | Mnemonic::BranchLessThan
+            | Mnemonic::BranchLessThanEq
+            | Mnemonic::BranchGreaterThan
+            | Mnemonic::BranchGreaterThanEq
+            | Mnemonic::BranchEqual => OperandKind::Target,
+            Mnemonic::CallShared => OperandKind::SharedTarget,
+            _ => OperandKind::None,
+        }
+    }
+
+    /// The builder op; `operand` is ignored by ops without an inline word.
+    pub fn op(self, operand: ProgramWord) -> Op {
+        match self {
+            Mnemonic::Push => Op::Push(operand),
+            Mnemonic::Pop => Op::Pop,
+            Mnemonic::LocalLoad => Op::LocalLoad(operand),
+            Mnemonic::LocalStore => Op::LocalStore(operand),
+            Mnemonic::GlobalLoad => Op::GlobalLoad(operand),
+            Mnemonic::GlobalStore => Op::GlobalStore(operand),
+            Mnemonic::StackLoad => Op::StackLoad(operand),
+            Mnemonic::StackStore => Op::StackStore(operand),
+            Mnemonic::Dup => Op::Dup,
+            Mnemonic::Swap => Op::Swap,
+            Mnemonic::Return => Op::Return(operand),
+            Mnemonic::LoadStatic => Op::LoadStatic,
+            Mnemonic::Jump => Op::Jump,
+            Mnemonic::Call => Op::Call,
+            Mnemonic::CallShared => Op::CallShared,
+            Mnemonic::BranchLessThan => Op::BranchLessThan,
+            Mnemonic::BranchLessThanEq => Op::BranchLessThanEq,
+            Mnemonic::BranchGreaterThan => Op::BranchGreaterThan,
+            Mnemonic::BranchGreaterThanEq => Op::BranchGreaterThanEq,
+            Mnemonic::BranchEqual => Op::BranchEqual,
+            Mnemonic::Exit => Op::Exit,
+            Mnemonic::And => Op::And,
+            Mnemonic::Or => Op::Or,
+            Mnemonic::Xor => Op::Xor,
+            Mnemonic::Not => Op::Not,
+            Mnemonic::BitwiseAnd => Op::BitwiseAnd,
+            Mnemonic::BitwiseOr => Op::BitwiseOr,
+            Mnemonic::BitwiseXor => Op::BitwiseXor,
+            Mnemonic::BitwiseNot => Op::BitwiseNot,
+            Mnemonic::Add => Op::Add,
+            Mnemonic::Subtract => Op::Subtract,
+            Mnemonic::Multiply => Op::Multiply,
+            Mnemonic::Divide => Op::Devide,
+            Mnemonic::Mod => Op::Mod,
+        }
+    }
+}
+
+/// Parses one line. `in_data` is whether the line is inside a `.data` or
+/// `.shared_data` block, where everything but labels, `.const` and `.end`
+/// is a data word.
+pub fn parse_line(line: &str, in_data: bool) -> Result<Line<'_>, AssemblerError> {
+    let line = strip_comment(line).trim();
+    if line.is_empty() {
+        return Ok(Line::Empty);
+    }
+    let (first, rest) = split_first_token(line);
+
+    // `.const` and `.instance` run to the end of the line, and `.const` may
+    // appear in any block, including data blocks.
+    if first == ".const" {
+        let (name, expr) = split_first_token(rest);
+        if expr.is_empty() || !is_identifier(name) {
+            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
+        }
+        return Ok(Line::Directive(Directive::Const { name, expr }));
+    }
+    // `.param` can have more options than `MAX_TOKENS` allows a directive.
+    if first == ".param" {
+        return parse_param(rest).map(|param| Line::Directive(Directive::Param(param)));
+    }
+    if first == ".instance" {
+        let (name, rest) = split_first_token(rest);
+        let (of, rest) = split_first_token(rest);
+        let (machine, assignments) = split_first_token(rest);
+        if !is_identifier(name) || of != "of" || machine.is_empty() {
+            return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective));
+        }
+        return Ok(Line::Directive(Directive::Instance(InstanceDirective {
+            name,
+            machine,
+            assignments,
+        })));
+    }
+
+    // Labels must be a single token ending with ':' to keep parsing one-pass.
+    if rest.is_empty() && let Some(name) = first.strip_suffix(':') {
+        return Ok(Line::Label(name));
+    }
+
+    if in_data && first != ".end" {
+        return parse_data_word(line).map(Line::Data);
+    }
+
+    // Directives always start with '.' to avoid ambiguity with mnemonics.
+    if first.starts_with('.') {
+        return parse_directive(line).map(Line::Directive);
+    }
+
+    parse_instruction(first, rest).map(Line::Instruction)
+}
+
+fn parse_data_word(line: &str) -> Result<DataWord<'_>, AssemblerError> {
+    // Only `.word <expr>` or a bare `<expr>`.
+    let text = match line.strip_prefix(".word") {
+        Some(rest) if rest.starts_with(char::is_whitespace) => rest.trim(),
+        Some(_) => return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective)),
+        None => line,
+    };
+    match expression::parse_color(text) {
+        Some(color) => color.map(DataWord::Color),
+        None => Ok(DataWord::Expr(text)),
+    }
+}
+
+fn parse_directive(line: &str) -> Result<Directive<'_>, AssemblerError> {
+    // Token limit keeps parsing bounded in no_std/heapless mode.
+    let mut tokens: Vec<&str, MAX_TOKENS> = Vec::new();
+    for token in line.split_whitespace() {
+        tokens
+            .push(token)
+            .map_err(|_| AssemblerError::Kind(AssemblerErrorKind::TooManyTokens))?;
+    }
+    let directive = match *tokens.as_slice() {
+        [".machine", name, "locals" | "globals", locals, "functions", functions] => Directive::Machine {
+            name,
+            locals: parse_word(locals)?,
+            functions: parse_word(functions)?,
+        },
+        [".func", ..] => Directive::Func(parse_function_header(&tokens)?),
+        [".func_decl", ..] => Directive::FuncDecl(parse_function_header(&tokens)?),
+        [".shared_func", ..] => Directive::SharedFunc(parse_function_header(&tokens)?),
+        [".shared_func_decl", ..] => Directive::SharedFuncDecl(parse_function_header(&tokens)?),
+        [".local", name, index] => Directive::Local {
+            name,
+            index: parse_word(index)?,
+        },
+        [".shared", name, index] => Directive::Shared {
+            name,
+            index: parse_word(index)?,
+        },
+        [".frame", name, offset] => Directive::Frame {
+            name,
+            offset: parse_word(offset)?,
+        },
+        [".data", name] => Directive::Data { name },
+        [".shared_data", name] => Directive::SharedData { name },
+        [".end"] => Directive::End,
+        _ => return Err(AssemblerError::Kind(AssemblerErrorKind::InvalidDirective)),
+    };
+    Ok(directive)
+}
+
+fn parse_param(text: &str) -> Result<ParamDirective<'_>, AssemblerError> {
+    let invalid = || AssemblerError::Kind(AssemblerErrorKind::InvalidDirective);
+    let mut tokens = text.split_whitespace();
+    let name = tokens.next().filter(|name| is_identifier(name)).ok_or_else(invalid)?;
+    let kind = match tokens.next() {
+        Some("range") => ParamKind::Range,
+        Some("toggle") => ParamKind::Toggle,
+        Some("color") => ParamKind::Color,
+        _ => return Err(invalid()),
+    };
+    // Options come in `key value` pairs, in any order, each at most once.
+    let [mut min, mut max, mut step, mut default, mut local, mut function] = [None; 6];
+    while let Some(key) = tokens.next() {
+        let value = tokens.next().ok_or_else(invalid)?;
+        let slot = match key {
+            "min" => &mut min,
+            "max" => &mut max,
+            "step" => &mut step,
+            "default" => &mut default,
+            "local" => &mut local,
+            "func" => &mut function,
+            _ => return Err(invalid()),
+        };
+        if slot.replace(value).is_some() {
+            return Err(invalid());
+        }
+    }
+    let target = match (local, function) {
+        (Some(local), None) => ParamTargetName::Local(local),
+        (None, Some(function)) => ParamTargetName::Function(function),
+        _ => return Err(invalid()),
+    };
+    Ok(ParamDirective {
+        name,
+        kind,
+        min,
+        max,
+        step,
+        default,
+        target,
+    })
+}
+
+fn parse_instruction<'t>(first: &'t str, rest: &'t str) -> Result<Instruction<'t>, AssemblerError> {
+    let invalid = || AssemblerError::Kind(AssemblerErrorKind::InvalidInstruction);
+    let mnemonic = Mnemonic::parse(first).ok_or_else(invalid)?;
+    let kind = mnemonic.operand_kind();
+    let operand = if rest.is_empty() {
+        match kind {
+            OperandKind::None | OperandKind::Target | OperandKind::SharedTarget => Operand::None,
+            _ => return Err(invalid()),
+        }
+    } else if kind == OperandKind::None {
+        return Err(invalid());
+    } else if matches!(mnemonic, Mnemonic::Call | Mnemonic::CallShared)
+        && let Some((name, argc)) = split_call_operand(rest)
+    {
+        Operand::Call { name, argc }
+    } else if mnemonic == Mnemonic::Push
+        && let Some(color) = expression::parse_color(rest)
+    {
+        Operand::Color(color?)
+    } else {
+        Operand::Expr(rest)
+    };
+    Ok(Instruction { mnemonic, operand })
+}
+
+pub fn split_first_token(text: &str) -> (&str, &str) {
+    match text.split_once(char::is_whitespace) {
+        Some((first, rest)) => (first, rest.trim()),
+        None => (text, ""),
+    }
+}
+
+pub fn is_identifier(token: &str) -> bool {
+    let mut bytes = token.bytes();
+    bytes
+        .next()
+        .is_some_and(|byte| byte.is_ascii_alphabetic() || byte == b'_')
+        && bytes.all(|byte| byte.is_ascii_alphanumeric() || byte == b'_')
+}
+
+pub fn strip_comment(line: &str) -> &str {
+    // A `;` inside a character literal such as `';'` does not start a comment.
+    let mut quoted = false;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '\'' => quoted = !quoted,
+            ';' if !quoted => return line.get(..index).unwrap_or(line),
+            _ => {}
+        }
+    }
+    line
+}
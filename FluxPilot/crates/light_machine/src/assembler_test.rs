@@ -1,3 +1,6 @@
+use crate::assembler::diagnostic::{Diagnostic, Severity};
+use crate::assembler::include::{NoIncludes, StaticResolver};
+use crate::assembler::source_map::SourceMap;
 use crate::assembler::{Assembler, AssemblerError, AssemblerErrorKind};
 use crate::builder::ProgramBuilder;
 
@@ -114,6 +117,26 @@ fn supports_shared_functions_and_data() {
     assert_eq!(descriptor.instances.len(), 1);
 }
 
+#[test]
+fn shared_data_labels_are_visible_in_machines() {
+    let mut buffer = [0u16; 128];
+    let builder = ProgramBuilder::<2, 1>::new(&mut buffer, 2, 2, 0).unwrap();
+    let mut asm: Assembler<2, 1, 16, 16> = Assembler::new(builder);
+    asm.add_line(".shared_data config").unwrap();
+    asm.add_line("value:").unwrap();
+    asm.add_line(".word 42").unwrap();
+    asm.add_line(".end").unwrap();
+    for machine in [".machine first locals 0 functions 1", ".machine second locals 0 functions 1"] {
+        asm.add_line(machine).unwrap();
+        asm.add_line(".func init index 0").unwrap();
+        asm.add_line("LOAD_STATIC value").unwrap();
+        asm.add_line("EXIT").unwrap();
+        asm.add_line(".end").unwrap();
+        asm.add_line(".end").unwrap();
+    }
+    asm.finish().unwrap();
+}
+
 #[test]
 fn shared_function_requires_declaration() {
     let mut buffer = [0u16; 64];
@@ -252,3 +275,664 @@ fn supports_named_stack_slots() {
     let descriptor = asm.finish().unwrap();
     assert_eq!(descriptor.instances.len(), 1);
 }
+
+fn assemble_error(lines: &[&str]) -> AssemblerErrorKind {
+    let mut buffer = [0u16; 128];
+    let builder = ProgramBuilder::<1, 1>::new(&mut buffer, 1, 1, 0).unwrap();
+    let mut asm: Assembler<1, 1, 16, 16> = Assembler::new(builder);
+    for line in lines {
+        if let Err(err) = asm.add_line(line) {
+            let AssemblerError::WithLine { kind, .. } = err else {
+                panic!("expected line-numbered error");
+            };
+            return kind;
+        }
+    }
+    panic!("expected an error");
+}
+
+#[test]
+fn const_rejects_duplicate_name() {
+    let kind = assemble_error(&[".const LEDS 8", ".const LEDS 9"]);
+    assert!(matches!(kind, AssemblerErrorKind::DuplicateConst));
+}
+
+#[test]
+fn const_rejects_values_wider_than_a_word() {
+    let kind = assemble_error(&[".const BIG 0xFFFF + 1"]);
+    assert!(matches!(kind, AssemblerErrorKind::ExpressionOutOfRange));
+    let kind = assemble_error(&[".const NEG 1 - 0x8002"]);
+    assert!(matches!(kind, AssemblerErrorKind::ExpressionOutOfRange));
+    let kind = assemble_error(&[".const BAD 1 / 0"]);
+    assert!(matches!(kind, AssemblerErrorKind::ExpressionOutOfRange));
+}
+
+#[test]
+fn const_rejects_malformed_expressions() {
+    for line in [
+        ".const X (1 + 2",
+        ".const X 1 +",
+        ".const X 1 2",
+        ".const X 1 < 2",
+        ".const X ((((((((((((((((((1))))))))))))))))))",
+    ] {
+        let kind = assemble_error(&[line]);
+        assert!(matches!(kind, AssemblerErrorKind::InvalidExpression), "{line}");
+    }
+}
+
+#[test]
+fn const_cannot_capture_label_address() {
+    let kind = assemble_error(&[
+        ".machine main locals 0 functions 1",
+        ".func main index 0",
+        "here:",
+        ".const WHERE here",
+    ]);
+    assert!(matches!(kind, AssemblerErrorKind::InvalidExpression));
+}
+
+#[test]
+fn const_allows_label_differences() {
+    let mut buffer = [0u16; 128];
+    let builder = ProgramBuilder::<1, 1>::new(&mut buffer, 1, 1, 0).unwrap();
+    let mut asm: Assembler<1, 1, 16, 16> = Assembler::new(builder);
+    asm.add_line(".machine main locals 0 functions 1").unwrap();
+    asm.add_line(".func main index 0").unwrap();
+    asm.add_line("start:").unwrap();
+    asm.add_line("PUSH 1").unwrap();
+    asm.add_line("end:").unwrap();
+    asm.add_line(".const LEN end - start").unwrap();
+    asm.add_line("PUSH LEN").unwrap();
+    asm.add_line("EXIT").unwrap();
+    asm.add_line(".end").unwrap();
+    asm.add_line(".end").unwrap();
+    asm.finish().unwrap();
+}
+
+#[test]
+fn expression_rejects_adding_two_labels() {
+    let kind = assemble_error(&[
+        ".machine main locals 0 functions 1",
+        ".func main index 0",
+        "a:",
+        "b:",
+        "JUMP a + b",
+    ]);
+    assert!(matches!(kind, AssemblerErrorKind::InvalidExpression));
+}
+
+#[test]
+fn local_operand_expression_is_range_checked() {
+    let kind = assemble_error(&[
+        ".const LAST 1",
+        ".machine main locals 2 functions 1",
+        ".func main index 0",
+        "LLOAD LAST + 1",
+    ]);
+    assert!(matches!(kind, AssemblerErrorKind::GlobalIndexOutOfRange));
+}
+
+const LIBRARY: StaticResolver = StaticResolver::new(&[
+    (
+        "colors.fpa",
+        ".const RED 0xFF0000 >> 16\n.include \"helpers.fpa\"\n",
+    ),
+    (
+        "helpers.fpa",
+        ".shared_func dim index 0\n    PUSH RED / 2\n    RET 1\n.end\n",
+    ),
+    ("broken.fpa", "; fine\n    BADOP\n"),
+    ("loop_a.fpa", ".include \"loop_b.fpa\"\n"),
+    ("loop_b.fpa", "\n.include \"loop_a.fpa\" ; back again\n"),
+]);
+
+#[test]
+fn include_expands_nested_sources() {
+    let mut buffer = [0u16; 128];
+    let builder = ProgramBuilder::<1, 1>::new(&mut buffer, 1, 1, 1).unwrap();
+    let mut asm: Assembler<1, 1, 16, 16> = Assembler::new(builder);
+    let source = "\
+.include \"colors.fpa\"
+.machine main locals 0 functions 1
+.func main index 0
+    PUSH RED
+    CALL_SHARED dim
+    EXIT
+.end
+.end
+";
+    asm.add_source(source, &LIBRARY).unwrap();
+    asm.finish().unwrap();
+}
+
+#[test]
+fn source_map_records_file_line_and_function() {
+    let mut buffer = [0u16; 128];
+    let mut map_buffer = [0u8; 128];
+    let builder = ProgramBuilder::<1, 1>::new(&mut buffer, 1, 1, 1).unwrap();
+    let mut asm: Assembler<1, 1, 16, 16> = Assembler::new(builder).with_source_map(&mut map_buffer);
+    let source = "\
+.include \"colors.fpa\"
+.machine main locals 1 functions 1
+.func main index 0
+    PUSH RED
+    CALL_SHARED dim
+    .if eq 0
+    EXIT
+    .endif
+    EXIT
+.end
+.end
+";
+    asm.add_source(source, &LIBRARY).unwrap();
+    let (_, map_len) = asm.finish_with_source_map().unwrap();
+    let map = SourceMap::new(&map_buffer[..map_len]).unwrap();
+    let ranges: heapless::Vec<_, 8> = map
+        .ranges()
+        .map(|range| (range.file, range.line, range.machine, range.function))
+        .collect();
+    assert_eq!(
+        ranges.as_slice(),
+        &[
+            (Some("helpers.fpa"), 2, None, Some("dim")),
+            (Some("helpers.fpa"), 3, None, Some("dim")),
+            (None, 4, Some("main"), Some("main")),
+            (None, 5, Some("main"), Some("main")),
+            (None, 6, Some("main"), Some("main")),
+            (None, 7, Some("main"), Some("main")),
+            (None, 9, Some("main"), Some("main")),
+        ]
+    );
+    // The lowered `.if` spans several instructions but one line.
+    let if_line = map.ranges().nth(4).unwrap();
+    assert!(if_line.end - if_line.start > 2);
+    assert_eq!(map.lookup(usize::from(if_line.end) - 1), Some(if_line));
+    assert_eq!(map.lookup(usize::from(u16::MAX)), None);
+}
+
+#[test]
+fn source_map_must_fit_its_buffer() {
+    let mut buffer = [0u16; 128];
+    let mut map_buffer = [0u8; 8];
+    let builder = ProgramBuilder::<1, 1>::new(&mut buffer, 1, 1, 0).unwrap();
+    let mut asm: Assembler<1, 1, 16, 16> = Assembler::new(builder).with_source_map(&mut map_buffer);
+    asm.add_line(".machine main locals 0 functions 1").unwrap();
+    let err = asm.add_line(".func a_long_function_name index 0").unwrap_err();
+    assert_eq!(err.line_number(), Some(2));
+    assert!(matches!(err.error_kind(), AssemblerErrorKind::SourceMapTooLarge));
+}
+
+#[test]
+fn include_errors_report_file_and_line() {
+    let mut buffer = [0u16; 128];
+    let builder = ProgramBuilder::<1, 1>::new(&mut buffer, 1, 1, 0).unwrap();
+    let mut asm: Assembler<1, 1, 16, 16> = Assembler::new(builder);
+    let source = ".machine main locals 0 functions 1\n.func main index 0\n.include \"broken.fpa\"\n";
+    let err = asm.add_source(source, &LIBRARY).unwrap_err();
+    assert_eq!(err.file_name(), Some("broken.fpa"));
+    assert_eq!(err.line_number(), Some(2));
+    assert!(matches!(err.error_kind(), AssemblerErrorKind::InvalidInstruction));
+}
+
+#[test]
+fn include_root_lines_keep_their_own_numbers() {
+    let mut buffer = [0u16; 128];
+    let builder = ProgramBuilder::<1, 1>::new(&mut buffer, 1, 1, 1).unwrap();
+    let mut asm: Assembler<1, 1, 16, 16> = Assembler::new(builder);
+    let source = ".include \"colors.fpa\"\n.machine main locals 0 functions 1\nBADOP\n";
+    let err = asm.add_source(source, &LIBRARY).unwrap_err();
+    assert_eq!(err.file_name(), None);
+    assert_eq!(err.line_number(), Some(3));
+}
+
+#[test]
+fn include_detects_cycles() {
+    let mut buffer = [0u16; 64];
+    let builder = ProgramBuilder::<1, 1>::new(&mut buffer, 1, 1, 0).unwrap();
+    let mut asm: Assembler<1, 1, 16, 16> = Assembler::new(builder);
+    let err = asm.add_source(".include \"loop_a.fpa\"", &LIBRARY).unwrap_err();
+    assert_eq!(err.file_name(), Some("loop_b.fpa"));
+    assert_eq!(err.line_number(), Some(2));
+    assert!(matches!(err.error_kind(), AssemblerErrorKind::IncludeCycle));
+}
+
+#[test]
+fn include_reports_missing_source() {
+    let mut buffer = [0u16; 64];
+    let builder = ProgramBuilder::<1, 1>::new(&mut buffer, 1, 1, 0).unwrap();
+    let mut asm: Assembler<1, 1, 16, 16> = Assembler::new(builder);
+    let err = asm.add_source("\n.include \"nope.fpa\"", &NoIncludes).unwrap_err();
+    assert_eq!(err.line_number(), Some(2));
+    assert!(matches!(err.error_kind(), AssemblerErrorKind::IncludeNotFound));
+    let err = asm.add_source(".include nope.fpa", &NoIncludes).unwrap_err();
+    assert!(matches!(err.error_kind(), AssemblerErrorKind::InvalidDirective));
+}
+
+#[test]
+fn macro_errors_report_the_calling_line() {
+    let mut buffer = [0u16; 128];
+    let builder = ProgramBuilder::<1, 1>::new(&mut buffer, 1, 1, 0).unwrap();
+    let mut asm: Assembler<1, 1, 16, 16> = Assembler::new(builder);
+    let source = "\
+.macro store_to slot
+    LSTORE slot
+.endm
+.machine main locals 1 functions 1
+.func main index 0
+    PUSH 1
+    store_to 0
+    store_to 4
+";
+    let err = asm.add_source(source, &NoIncludes).unwrap_err();
+    assert_eq!(err.line_number(), Some(8));
+    assert!(matches!(err.error_kind(), AssemblerErrorKind::GlobalIndexOutOfRange));
+}
+
+#[test]
+fn macro_checks_argument_count() {
+    let kind = assemble_error(&[".macro twice value", "PUSH value", "PUSH value", ".endm", "twice 1, 2"]);
+    assert!(matches!(kind, AssemblerErrorKind::MacroArgumentCount));
+    let kind = assemble_error(&[".macro twice value", ".endm", "twice"]);
+    assert!(matches!(kind, AssemblerErrorKind::MacroArgumentCount));
+}
+
+#[test]
+fn macro_rejects_redefinition_and_nesting() {
+    let kind = assemble_error(&[".macro noop", ".endm", ".macro noop", ".endm"]);
+    assert!(matches!(kind, AssemblerErrorKind::DuplicateMacro));
+    let kind = assemble_error(&[".macro outer", ".macro inner"]);
+    assert!(matches!(kind, AssemblerErrorKind::UnexpectedDirective));
+    let kind = assemble_error(&[".endm"]);
+    assert!(matches!(kind, AssemblerErrorKind::UnexpectedDirective));
+}
+
+#[test]
+fn macro_recursion_is_bounded() {
+    let kind = assemble_error(&[".macro forever", "forever", ".endm", "forever"]);
+    assert!(matches!(kind, AssemblerErrorKind::MacroTooDeep));
+}
+
+#[test]
+fn unterminated_macro_fails_finish() {
+    let mut buffer = [0u16; 64];
+    let builder = ProgramBuilder::<1, 1>::new(&mut buffer, 1, 1, 0).unwrap();
+    let mut asm: Assembler<1, 1, 16, 16> = Assembler::new(builder);
+    asm.add_line(".macro open").unwrap();
+    let err = asm.finish().unwrap_err();
+    assert!(matches!(err.error_kind(), AssemblerErrorKind::UnexpectedDirective));
+}
+
+#[test]
+fn control_blocks_must_match() {
+    let function = [".machine main locals 1 functions 1", ".func main index 0"];
+    for lines in [
+        &[".endif"][..],
+        &[".if lt 1", ".else", ".else"],
+        &[".while", ".endwhile"],
+        &[".if lt 1", ".endwhile"],
+        &[".do lt 1"],
+    ] {
+        let mut all: [&str; 5] = [""; 5];
+        for (slot, line) in all.iter_mut().zip(function.iter().chain(lines)) {
+            *slot = line;
+        }
+        let kind = assemble_error(&all);
+        assert!(matches!(kind, AssemblerErrorKind::UnmatchedControl));
+    }
+}
+
+#[test]
+fn control_blocks_must_close_before_end() {
+    let kind = assemble_error(&[
+        ".machine main locals 1 functions 1",
+        ".func main index 0",
+        ".for 0 0, 3",
+        ".end",
+    ]);
+    assert!(matches!(kind, AssemblerErrorKind::UnclosedControl));
+    let kind = assemble_error(&[".machine main locals 1 functions 1", ".if lt 1"]);
+    assert!(matches!(kind, AssemblerErrorKind::UnexpectedDirective));
+    let kind = assemble_error(&[
+        ".machine main locals 1 functions 1",
+        ".func main index 0",
+        ".if maybe 1",
+    ]);
+    assert!(matches!(kind, AssemblerErrorKind::InvalidDirective));
+}
+
+#[test]
+fn reporting_continues_after_recoverable_errors() {
+    let mut buffer = [0u16; 128];
+    let builder = ProgramBuilder::<1, 1>::new(&mut buffer, 1, 1, 0).unwrap();
+    let mut asm: Assembler<1, 1, 16, 16> = Assembler::new(builder);
+    let source = "\
+.machine main locals 1 functions 1
+.func main index 0
+    BADOP 1
+    LLOAD count ; typo
+    JUMP later + 1
+top:
+top:
+    EXIT
+.end
+.end
+";
+    let mut found: heapless::Vec<Diagnostic, 4> = heapless::Vec::new();
+    assert!(asm.add_source_reporting(source, &NoIncludes, |diagnostic| {
+        found.push(diagnostic).unwrap();
+    }));
+    let summary: heapless::Vec<(u32, u32, u32, &str), 4> = found
+        .iter()
+        .map(|d| (d.line, d.start, d.end, d.message()))
+        .collect();
+    assert_eq!(
+        summary.as_slice(),
+        &[
+            (3, 4, 9, "invalid instruction"),
+            (4, 10, 15, "unknown label"),
+            (7, 0, 4, "duplicate label"),
+            (9, 0, 4, "unknown label"),
+        ]
+    );
+    assert!(found.iter().all(|d| d.severity == Severity::Error));
+    assert!(found.get(1).and_then(Diagnostic::fix).is_some());
+}
+
+#[test]
+fn reporting_stops_at_structural_errors() {
+    let mut buffer = [0u16; 128];
+    let builder = ProgramBuilder::<1, 1>::new(&mut buffer, 1, 1, 0).unwrap();
+    let mut asm: Assembler<1, 1, 16, 16> = Assembler::new(builder);
+    let source = ".func main index 0\nBADOP\n";
+    let mut count = 0;
+    assert!(!asm.add_source_reporting(source, &NoIncludes, |diagnostic| {
+        assert!(matches!(diagnostic.kind, AssemblerErrorKind::UnexpectedDirective));
+        assert_eq!((diagnostic.start, diagnostic.end), (0, 5));
+        count += 1;
+    }));
+    assert_eq!(count, 1);
+}
+
+#[test]
+fn instance_directive_checks_machine_and_locals() {
+    let mut buffer = [0u16; 128];
+    let builder = ProgramBuilder::<3, 1>::new(&mut buffer, 3, 1, 0).unwrap();
+    let mut asm: Assembler<3, 1, 16, 16> = Assembler::new(builder);
+
+    let err = asm.add_line(".instance early of comet").unwrap_err();
+    assert!(matches!(err, AssemblerError::WithLine { kind: AssemblerErrorKind::UnknownMachine, .. }));
+    asm.add_line(".machine comet locals 2 functions 1").unwrap();
+    asm.add_line(".local hue 1").unwrap();
+    let err = asm.add_line(".instance inner of comet").unwrap_err();
+    assert!(matches!(err, AssemblerError::WithLine { kind: AssemblerErrorKind::UnexpectedDirective, .. }));
+    asm.add_line(".func init index 0").unwrap();
+    asm.add_line("EXIT").unwrap();
+    asm.add_line(".end").unwrap();
+    asm.add_line(".end").unwrap();
+
+    let err = asm.add_line(".instance red of comet size = 4").unwrap_err();
+    assert!(matches!(err, AssemblerError::WithLine { kind: AssemblerErrorKind::UnknownLabel, .. }));
+    let err = asm.add_line(".instance red comet").unwrap_err();
+    assert!(matches!(err, AssemblerError::WithLine { kind: AssemblerErrorKind::InvalidDirective, .. }));
+    asm.add_line(".instance red of comet hue = 0").unwrap();
+    let err = asm.add_line(".instance red of comet").unwrap_err();
+    assert!(matches!(err, AssemblerError::WithLine { kind: AssemblerErrorKind::DuplicateConst, .. }));
+    asm.add_line(".instance blue of comet hue = 170").unwrap();
+
+    let descriptor = asm.finish().unwrap();
+    assert_eq!(descriptor.instances.len(), 3);
+    assert!(descriptor.instances.iter().all(|instance| instance.type_id == 0));
+}
+
+#[test]
+fn function_signatures_are_checked() {
+    let mut buffer = [0u16; 128];
+    let builder = ProgramBuilder::<1, 3>::new(&mut buffer, 1, 1, 0).unwrap();
+    let mut asm: Assembler<1, 3, 16, 16> = Assembler::new(builder);
+
+    asm.add_line(".machine main locals 0 functions 3").unwrap();
+    asm.add_line(".func_decl mix index 1 args 2 returns 1").unwrap();
+    asm.add_line(".func_decl spare index 2 returns 0").unwrap();
+    let err = asm.add_line(".func_decl twice args 1 args 2").unwrap_err();
+    assert!(matches!(err, AssemblerError::WithLine { kind: AssemblerErrorKind::InvalidDirective, .. }));
+    asm.add_line(".func main index 0").unwrap();
+    let err = asm.add_line("CALL mix 3").unwrap_err();
+    assert!(matches!(err, AssemblerError::WithLine { kind: AssemblerErrorKind::ArgumentCountMismatch, .. }));
+    let err = asm.add_line("CALL missing 1").unwrap_err();
+    assert!(matches!(err, AssemblerError::WithLine { kind: AssemblerErrorKind::UnknownLabel, .. }));
+    asm.add_line("PUSH 1").unwrap();
+    asm.add_line("PUSH 2").unwrap();
+    asm.add_line("CALL mix 2").unwrap();
+    asm.add_line("EXIT").unwrap();
+    asm.add_line(".end").unwrap();
+    asm.add_line(".func mix args 2").unwrap();
+    let err = asm.add_line("RET 0").unwrap_err();
+    assert!(matches!(err, AssemblerError::WithLine { kind: AssemblerErrorKind::ReturnCountMismatch, .. }));
+    asm.add_line("ADD").unwrap();
+    asm.add_line("RET 1").unwrap();
+    asm.add_line(".end").unwrap();
+    let err = asm.add_line(".func spare returns 2").unwrap_err();
+    assert!(matches!(err, AssemblerError::WithLine { kind: AssemblerErrorKind::SignatureMismatch, .. }));
+}
+
+#[test]
+fn rejects_malformed_literals() {
+    let mut buffer = [0u16; 128];
+    let builder = ProgramBuilder::<1, 1>::new(&mut buffer, 1, 1, 0).unwrap();
+    let mut asm: Assembler<1, 1, 16, 16> = Assembler::new(builder);
+
+    asm.add_line(".machine main locals 0 functions 1").unwrap();
+    asm.add_line(".func main index 0").unwrap();
+    for (line, expected) in [
+        ("PUSH #ff80", AssemblerErrorKind::InvalidNumber),
+        ("PUSH #ff80zz", AssemblerErrorKind::InvalidNumber),
+        ("PUSH 0b102", AssemblerErrorKind::InvalidNumber),
+        ("PUSH 'ab'", AssemblerErrorKind::InvalidNumber),
+        ("PUSH -40000", AssemblerErrorKind::ExpressionOutOfRange),
+        ("PUSH #ff8000 + 1", AssemblerErrorKind::InvalidNumber),
+        ("PUSH 1 + #ff8000", AssemblerErrorKind::InvalidExpression),
+    ] {
+        let err = asm.add_line(line).unwrap_err();
+        assert!(
+            matches!(err, AssemblerError::WithLine { kind, .. } if core::mem::discriminant(&kind) == core::mem::discriminant(&expected)),
+            "{line}"
+        );
+    }
+}
+
+
+#[test]
+fn parses_lines_into_the_shared_syntax() {
+    use crate::assembler::syntax::{parse_line, DataWord, Directive, Line, Mnemonic, Operand};
+
+    assert!(matches!(parse_line("  ; only a comment", false), Ok(Line::Empty)));
+    assert!(matches!(parse_line("again:", false), Ok(Line::Label("again"))));
+    assert!(matches!(
+        parse_line(".machine strip locals 2 functions 1", false),
+        Ok(Line::Directive(Directive::Machine { name: "strip", locals: 2, functions: 1 }))
+    ));
+    assert!(matches!(parse_line(".word base + 1", true), Ok(Line::Data(DataWord::Expr("base + 1")))));
+    assert!(matches!(parse_line("#010203", true), Ok(Line::Data(DataWord::Color([1, 2, 3])))));
+    assert!(matches!(parse_line(".end", true), Ok(Line::Directive(Directive::End))));
+
+    let Ok(Line::Instruction(call)) = parse_line("call mix 2", false) else {
+        panic!("expected an instruction");
+    };
+    assert_eq!(call.mnemonic, Mnemonic::Call);
+    assert!(matches!(call.operand, Operand::Call { name: "mix", argc: "2" }));
+    let Ok(Line::Instruction(jump)) = parse_line("JUMP done + 1", false) else {
+        panic!("expected an instruction");
+    };
+    assert!(matches!(jump.operand, Operand::Expr("done + 1")));
+
+    for (line, expected) in [
+        ("Push 1", AssemblerErrorKind::InvalidInstruction),
+        ("PUSH", AssemblerErrorKind::InvalidInstruction),
+        ("EXIT 0", AssemblerErrorKind::InvalidInstruction),
+        (".data", AssemblerErrorKind::InvalidDirective),
+        (".end now", AssemblerErrorKind::InvalidDirective),
+        (".func a index 0 args 1 returns 1 extra", AssemblerErrorKind::TooManyTokens),
+    ] {
+        let Err(AssemblerError::Kind(kind)) = parse_line(line, false) else {
+            panic!("{line} should not parse");
+        };
+        assert_eq!(core::mem::discriminant(&kind), core::mem::discriminant(&expected), "{line}");
+    }
+}
+
+#[test]
+fn every_opcode_has_a_mnemonic() {
+    use crate::assembler::syntax::Mnemonic;
+    use crate::Ops;
+
+    let mut word = 0;
+    while let Ok(op) = Ops::try_from(word) {
+        let mnemonic = Mnemonic::from_opcode(op).unwrap();
+        assert_eq!(mnemonic.opcode(), op);
+        assert_eq!(Mnemonic::parse(mnemonic.name()), Some(mnemonic));
+        word += 1;
+    }
+    assert_eq!(word, crate::ProgramWord::from(Ops::Return) + 1);
+}
+
+const UNFORMATTED: &str = "
+; colors
+
+.machine   main locals 2 functions 1
+.local red 0 ; first local
+.local  green   1      ; second local
+
+
+.func init index 0
+push   ';' ; a semicolon
+  LSTORE red
+loop:
+    .if gt 3
+pop
+    .else
+dup
+.endif
+  exit
+   .end
+.data table
+    .word 1   ; one
+  2
+.end
+.end
+";
+
+const FORMATTED: &str = "; colors
+
+.machine main locals 2 functions 1
+    .local red 0    ; first local
+    .local green 1  ; second local
+
+    .func init index 0
+        PUSH ';'  ; a semicolon
+        LSTORE red
+    loop:
+        .if gt 3
+            POP
+        .else
+            DUP
+        .endif
+        EXIT
+    .end
+    .data table
+        .word 1  ; one
+        2
+    .end
+.end
+";
+
+#[test]
+fn formats_source_in_canonical_layout() {
+    let mut out: heapless::String<1024> = heapless::String::new();
+    crate::assembler::format::format_source(UNFORMATTED, &mut out).unwrap();
+    assert_eq!(out.as_str(), FORMATTED);
+
+    let mut again: heapless::String<1024> = heapless::String::new();
+    crate::assembler::format::format_source(FORMATTED, &mut again).unwrap();
+    assert_eq!(again.as_str(), FORMATTED);
+}
+
+#[test]
+fn param_directive_writes_params() {
+    use crate::metadata::{params, ParamKind, ParamTarget};
+
+    let mut buffer = [0u16; 256];
+    let builder = ProgramBuilder::<1, 2>::new(&mut buffer, 1, 1, 0).unwrap();
+    let mut asm: Assembler<1, 2, 16, 16> = Assembler::new(builder);
+    let source = "\
+.const SLOWEST 1000
+.machine crawler locals 4 functions 2
+.local speed 0
+.param speed range min 10 max SLOWEST step 10 default 500 local speed
+.param tint color default #468bc0 func set_tint
+.param on toggle local 3
+.func init index 0
+    EXIT
+.end
+.func set_tint index 1
+    EXIT
+.end
+.end
+";
+    asm.add_source(source, &NoIncludes).unwrap();
+    let length = asm.finish().unwrap().length;
+
+    let found: heapless::Vec<_, 4> = params(&buffer[..length]).unwrap().map(Result::unwrap).collect();
+    assert_eq!(found.len(), 3);
+    assert!(found[0].name.eq_str("speed"));
+    assert_eq!(found[0].info.kind, ParamKind::Range);
+    assert_eq!(found[0].info.target, ParamTarget::Local(0));
+    assert_eq!((found[0].info.min, found[0].info.max, found[0].info.step), (10, 1000, 10));
+    assert_eq!(found[0].info.default[0], 500);
+    assert!(found[1].name.eq_str("tint"));
+    assert_eq!(found[1].info.target, ParamTarget::Function(1));
+    assert_eq!(found[1].info.default, [0x46, 0x8b, 0xc0]);
+    assert_eq!((found[2].info.kind, found[2].info.max), (ParamKind::Toggle, 1));
+}
+
+#[test]
+fn param_directive_checks_its_values() {
+    let mut buffer = [0u16; 256];
+    let builder = ProgramBuilder::<1, 1>::new(&mut buffer, 1, 1, 0).unwrap();
+    let mut asm: Assembler<1, 1, 16, 16> = Assembler::new(builder);
+    let kind = |err: AssemblerError| err.error_kind().clone();
+
+    let err = asm.add_line(".param early range local 0").unwrap_err();
+    assert!(matches!(kind(err), AssemblerErrorKind::UnexpectedDirective));
+    asm.add_line(".machine main locals 3 functions 1").unwrap();
+    for (line, expected) in [
+        (".param a range min 10 max 5 local 0", AssemblerErrorKind::InvalidParam),
+        (".param a range default 300 local 0", AssemblerErrorKind::InvalidParam),
+        (".param a range step 0 local 0", AssemblerErrorKind::InvalidParam),
+        (".param a toggle max 2 local 0", AssemblerErrorKind::InvalidParam),
+        (".param a color min 1 local 0", AssemblerErrorKind::InvalidParam),
+        (".param a color local 1", AssemblerErrorKind::GlobalIndexOutOfRange),
+        (".param a range local 0 func init", AssemblerErrorKind::InvalidDirective),
+        (".param a dial local 0", AssemblerErrorKind::InvalidDirective),
+        (".param a range min 1 min 2 local 0", AssemblerErrorKind::InvalidDirective),
+        (".param a range local nowhere", AssemblerErrorKind::UnknownLabel),
+    ] {
+        let err = asm.add_line(line).unwrap_err();
+        assert_eq!(
+            core::mem::discriminant(&kind(err)),
+            core::mem::discriminant(&expected),
+            "{line}"
+        );
+    }
+    asm.add_line(".param a range local 0").unwrap();
+    let err = asm.add_line(".param a toggle local 1").unwrap_err();
+    assert!(matches!(kind(err), AssemblerErrorKind::InvalidParam));
+    asm.add_line(".param b range func missing").unwrap();
+    asm.add_line(".func init index 0").unwrap();
+    let err = asm.add_line(".param c range local 0").unwrap_err();
+    assert!(matches!(kind(err), AssemblerErrorKind::UnexpectedDirective));
+    asm.add_line("EXIT").unwrap();
+    asm.add_line(".end").unwrap();
+    let err = asm.add_line(".end").unwrap_err();
+    assert!(matches!(kind(err), AssemblerErrorKind::UnknownLabel));
+}
@@ -1,6 +1,9 @@
 use super::*;
+use crate::metadata::{
+    ParamSpec, SymbolKind, SymbolSpec, PARAM_NAME_MAX, SYMBOLS_MAX_WORDS, SYMBOL_NAME_MAX,
+};
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone)]
 pub enum MachineBuilderError {
     BufferTooSmall,
     MachineCountOverflowsWord(usize),
@@ -8,6 +11,23 @@ pub enum MachineBuilderError {
     FunctionCoutExceeded,
     GlobalOutOfRange(ProgramWord),
     MachineCountExceeded,
+    /// `finish_program` was called with fewer types than `new` declared.
+    TypeCountMismatch { declared: ProgramWord, defined: ProgramWord },
+    /// `finish_program` was called with fewer instances than `new` declared.
+    InstanceCountMismatch { declared: ProgramWord, added: ProgramWord },
+    /// A function slot of a type was never defined.
+    UndefinedFunction { type_id: ProgramWord, index: ProgramWord },
+    /// A shared function slot was never defined.
+    UndefinedSharedFunction(ProgramWord),
+    /// The locals of this instance overlap the shared globals or the locals
+    /// of the instance before it.
+    GlobalsOverlap(ProgramWord),
+    /// Param `n` names a type that does not exist or has too long a name,
+    /// or the params were already written.
+    InvalidParam(usize),
+    /// Symbol `n` names something the program does not have or has too
+    /// long a name, or the symbols were already written.
+    InvalidSymbol(usize),
 }
 
 /// Index for static data.
@@ -53,6 +73,7 @@ pub struct ProgramBuilder<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COU
     shared_globals_size: ProgramWord,
     shared_function_count: ProgramWord,
     next_shared_function_number: ProgramWord,
+    strip_symbols: bool,
 }
 
 impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize>
@@ -73,7 +94,7 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize>
         // the smallest usafal machine and ensure we have room
         // for that too.
         let instance_table_words = (instance_count as usize)
-            .checked_mul(2)
+            .checked_mul(INSTANCE_ENTRY_WORDS)
             .ok_or(MachineBuilderError::MachineCountOverflowsWord(
                 instance_count as usize,
             ))?;
@@ -114,6 +135,8 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize>
             type_count,
             MachineBuilderError::BufferTooSmall,
         )?;
+        set_value(buffer, PARAMS_OFFSET, 0, MachineBuilderError::BufferTooSmall)?;
+        set_value(buffer, SYMBOLS_OFFSET, 0, MachineBuilderError::BufferTooSmall)?;
         let instance_table_offset = ProgramWord::try_from(HEADER_WORDS)
             .map_err(|_| MachineBuilderError::MachineCountOverflowsWord(HEADER_WORDS))?;
         set_value(
@@ -153,6 +176,12 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize>
             .ok_or(MachineBuilderError::MachineCountOverflowsWord(
                 shared_function_count as usize,
             ))?;
+        // Zeroed tables mark undefined slots for `finish_program`; no entry
+        // point can be 0, since the header is there.
+        buffer
+            .get_mut(HEADER_WORDS..usize::from(free))
+            .ok_or(MachineBuilderError::BufferTooSmall)?
+            .fill(0);
         Ok(Self {
             buffer,
             instance_count,
@@ -164,6 +193,7 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize>
             shared_globals_size: 0,
             shared_function_count,
             next_shared_function_number: 0,
+            strip_symbols: false,
         })
     }
 
@@ -194,6 +224,18 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize>
         function_count: ProgramWord,
         globals_size: ProgramWord,
     ) -> Result<MachineBuilder<'a, MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>, MachineBuilderError>
+    {
+        self.new_machine_with_locals(function_count, globals_size, &[])
+    }
+
+    /// Starts a new type whose first instance begins with `initial_locals`,
+    /// as in [`Self::add_instance_with_locals`].
+    pub fn new_machine_with_locals(
+        mut self,
+        function_count: ProgramWord,
+        globals_size: ProgramWord,
+        initial_locals: &[(ProgramWord, ProgramWord)],
+    ) -> Result<MachineBuilder<'a, MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>, MachineBuilderError>
     {
         if self.next_type_builder >= self.type_count {
             return Err(MachineBuilderError::MachineCountExceeded);
@@ -211,31 +253,13 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize>
                 self.next_instance_number as usize,
             ));
         };
-        let instance_table_offset = read_static(INSTANCE_TABLE_OFFSET, self.buffer)
-            .map_err(|_| MachineBuilderError::BufferTooSmall)? as usize;
-        let instance_entry_index = (self.next_instance_number as usize)
-            .checked_mul(2)
-            .and_then(|offset| instance_table_offset.checked_add(offset))
-            .ok_or(MachineBuilderError::BufferTooSmall)?;
         let globals_base = *self
             .buffer
             .get(GLOBALS_SIZE_OFFSET)
             .ok_or(MachineBuilderError::BufferTooSmall)?;
         let type_id = self.next_type_builder;
-        set_value(
-            self.buffer,
-            instance_entry_index,
-            type_id,
-            MachineBuilderError::BufferTooSmall,
-        )?;
-        set_value(
-            self.buffer,
-            instance_entry_index
-                .checked_add(1)
-                .ok_or(MachineBuilderError::BufferTooSmall)?,
-            globals_base,
-            MachineBuilderError::BufferTooSmall,
-        )?;
+        let initial_locals_offset = self.add_initial_locals(globals_size, initial_locals)?;
+        self.write_instance_entry(type_id, globals_base, initial_locals_offset)?;
         let type_table_offset = read_static(TYPE_TABLE_OFFSET, self.buffer)
             .map_err(|_| MachineBuilderError::BufferTooSmall)? as usize;
         let type_entry_index = (type_id as usize)
@@ -270,46 +294,40 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize>
     pub fn add_instance(
         &mut self,
         type_id: ProgramWord,
+    ) -> Result<(), MachineBuilderError> {
+        self.add_instance_with_locals(type_id, &[])
+    }
+
+    /// Adds another instance of an already finished type. Each
+    /// `(local, value)` pair is written into the instance's locals by
+    /// `Program::init_machine` before the type's init function runs.
+    pub fn add_instance_with_locals(
+        &mut self,
+        type_id: ProgramWord,
+        initial_locals: &[(ProgramWord, ProgramWord)],
     ) -> Result<(), MachineBuilderError> {
         if self.next_instance_number >= self.instance_count {
             return Err(MachineBuilderError::MachineCountExceeded);
         }
-        let type_descriptor = self
+        let globals_size = self
             .descriptor
             .types
             .get(type_id as usize)
-            .ok_or(MachineBuilderError::MachineCountExceeded)?;
-        let instance_table_offset = read_static(INSTANCE_TABLE_OFFSET, self.buffer)
-            .map_err(|_| MachineBuilderError::BufferTooSmall)? as usize;
-        let instance_entry_index = (self.next_instance_number as usize)
-            .checked_mul(2)
-            .and_then(|offset| instance_table_offset.checked_add(offset))
-            .ok_or(MachineBuilderError::BufferTooSmall)?;
+            .ok_or(MachineBuilderError::MachineCountExceeded)?
+            .globals_size;
         let globals_base = *self
             .buffer
             .get(GLOBALS_SIZE_OFFSET)
             .ok_or(MachineBuilderError::BufferTooSmall)?;
-        set_value(
-            self.buffer,
-            instance_entry_index,
-            type_id,
-            MachineBuilderError::BufferTooSmall,
-        )?;
-        set_value(
-            self.buffer,
-            instance_entry_index
-                .checked_add(1)
-                .ok_or(MachineBuilderError::BufferTooSmall)?,
-            globals_base,
-            MachineBuilderError::BufferTooSmall,
-        )?;
+        let initial_locals_offset = self.add_initial_locals(globals_size, initial_locals)?;
+        self.write_instance_entry(type_id, globals_base, initial_locals_offset)?;
         let globals_slot = get_mut_or(
             self.buffer,
             GLOBALS_SIZE_OFFSET,
             MachineBuilderError::BufferTooSmall,
         )?;
-        let Some(new_globals_size) = globals_slot.checked_add(type_descriptor.globals_size) else {
-            return Err(MachineBuilderError::TooLarge(type_descriptor.globals_size as usize));
+        let Some(new_globals_size) = globals_slot.checked_add(globals_size) else {
+            return Err(MachineBuilderError::TooLarge(globals_size as usize));
         };
         *globals_slot = new_globals_size;
         let Some(next_instance_number) = self.next_instance_number.checked_add(1) else {
@@ -354,6 +372,51 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize>
         Ok(SharedFunctionBuilder::new(self, index))
     }
 
+    /// Writes an initial locals record and returns its offset, or `0` when
+    /// there is nothing to initialize.
+    fn add_initial_locals(
+        &mut self,
+        globals_size: ProgramWord,
+        initial_locals: &[(ProgramWord, ProgramWord)],
+    ) -> Result<ProgramWord, MachineBuilderError> {
+        if initial_locals.is_empty() {
+            return Ok(0);
+        }
+        let count = ProgramWord::try_from(initial_locals.len())
+            .map_err(|_| MachineBuilderError::TooLarge(initial_locals.len()))?;
+        if let Some((local, _)) = initial_locals.iter().find(|(local, _)| *local >= globals_size) {
+            return Err(MachineBuilderError::GlobalOutOfRange(*local));
+        }
+        let offset = self.free;
+        self.add_word(count)?;
+        for (local, value) in initial_locals {
+            self.add_word(*local)?;
+            self.add_word(*value)?;
+        }
+        Ok(offset)
+    }
+
+    fn write_instance_entry(
+        &mut self,
+        type_id: ProgramWord,
+        globals_base: ProgramWord,
+        initial_locals_offset: ProgramWord,
+    ) -> Result<(), MachineBuilderError> {
+        let instance_table_offset = read_static(INSTANCE_TABLE_OFFSET, self.buffer)
+            .map_err(|_| MachineBuilderError::BufferTooSmall)? as usize;
+        let entry_index = (self.next_instance_number as usize)
+            .checked_mul(INSTANCE_ENTRY_WORDS)
+            .and_then(|offset| instance_table_offset.checked_add(offset))
+            .ok_or(MachineBuilderError::BufferTooSmall)?;
+        for (word, value) in [type_id, globals_base, initial_locals_offset].into_iter().enumerate() {
+            let index = entry_index
+                .checked_add(word)
+                .ok_or(MachineBuilderError::BufferTooSmall)?;
+            set_value(self.buffer, index, value, MachineBuilderError::BufferTooSmall)?;
+        }
+        Ok(())
+    }
+
     fn allocate(&mut self, word_count: ProgramWord) -> Result<(), MachineBuilderError> {
         let free = usize::from(self.free);
         let word_count = usize::from(word_count);
@@ -480,10 +543,167 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize>
         Ok(index)
     }
 
-    pub fn finish_program(self) -> ProgramDescriptor<MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX> {
+    /// Writes the params section (see [`crate::metadata`]) and points the
+    /// header at it. A program has at most one; writing none leaves the
+    /// image without a section.
+    pub fn add_params(&mut self, params: &[ParamSpec<'_>]) -> Result<(), MachineBuilderError> {
+        if params.is_empty() {
+            return Ok(());
+        }
+        if read_static(PARAMS_OFFSET, self.buffer).map_err(|_| MachineBuilderError::BufferTooSmall)? != 0 {
+            return Err(MachineBuilderError::InvalidParam(0));
+        }
+        let count = ProgramWord::try_from(params.len())
+            .map_err(|_| MachineBuilderError::TooLarge(params.len()))?;
+        for (index, param) in params.iter().enumerate() {
+            if param.info.type_id >= self.type_count || param.name.len() > PARAM_NAME_MAX {
+                return Err(MachineBuilderError::InvalidParam(index));
+            }
+        }
+        let offset = self.free;
+        self.add_word(count)?;
+        for param in params {
+            for word in param.info.words() {
+                self.add_word(word)?;
+            }
+            self.add_name(param.name)?;
+        }
+        set_value(self.buffer, PARAMS_OFFSET, offset, MachineBuilderError::BufferTooSmall)
+    }
+
+    /// Leave the symbols section out of the image, for targets short on
+    /// flash: [`Self::add_symbols`] then writes nothing.
+    pub fn strip_symbols(&mut self, strip: bool) {
+        self.strip_symbols = strip;
+    }
+
+    /// Writes the symbols section (see [`crate::metadata`]) and points the
+    /// header at it, unless symbols are stripped. Symbols that would take
+    /// the section past `SYMBOLS_MAX_WORDS` are left out. Returns how many
+    /// were written.
+    pub fn add_symbols(&mut self, symbols: &[SymbolSpec<'_>]) -> Result<usize, MachineBuilderError> {
+        if self.strip_symbols || symbols.is_empty() {
+            return Ok(0);
+        }
+        if read_static(SYMBOLS_OFFSET, self.buffer).map_err(|_| MachineBuilderError::BufferTooSmall)? != 0 {
+            return Err(MachineBuilderError::InvalidSymbol(0));
+        }
+        for (index, symbol) in symbols.iter().enumerate() {
+            let exists = match symbol.kind {
+                SymbolKind::Type => symbol.index < self.type_count,
+                SymbolKind::Instance => symbol.index < self.instance_count && symbol.owner < self.type_count,
+                SymbolKind::Function | SymbolKind::Local => symbol.owner < self.type_count,
+                SymbolKind::SharedFunction => symbol.index < self.shared_function_count,
+            };
+            if !exists || symbol.name.len() > SYMBOL_NAME_MAX {
+                return Err(MachineBuilderError::InvalidSymbol(index));
+            }
+        }
+        let mut words: usize = 1;
+        let count = symbols
+            .iter()
+            .take_while(|symbol| {
+                words = words.saturating_add(symbol.words());
+                words <= SYMBOLS_MAX_WORDS
+            })
+            .count();
+        if count == 0 {
+            return Ok(0);
+        }
+        let offset = self.free;
+        // The count fits; every record takes more than one word.
+        self.add_word(count as ProgramWord)?;
+        for symbol in symbols.iter().take(count) {
+            self.add_word(symbol.kind.to_word())?;
+            self.add_word(symbol.owner)?;
+            self.add_word(symbol.index)?;
+            self.add_name(symbol.name)?;
+        }
+        set_value(self.buffer, SYMBOLS_OFFSET, offset, MachineBuilderError::BufferTooSmall)?;
+        Ok(count)
+    }
+
+    // A metadata name: its length, then its bytes two to a word.
+    fn add_name(&mut self, name: &str) -> Result<(), MachineBuilderError> {
+        // The length fits; names are checked against their maximum first.
+        self.add_word(name.len() as ProgramWord)?;
+        for pair in name.as_bytes().chunks(2) {
+            let low = pair.first().copied().unwrap_or(0);
+            let high = pair.get(1).copied().unwrap_or(0);
+            self.add_word(ProgramWord::from_le_bytes([low, high]))?;
+        }
+        Ok(())
+    }
+
+    /// Checks that the program is complete and returns its descriptor. Every
+    /// type and instance declared in [`Self::new`] must have been added,
+    /// every function and shared function slot defined, and each instance's
+    /// locals must sit past the shared globals and the instances before it.
+    pub fn finish_program(
+        self,
+    ) -> Result<ProgramDescriptor<MACHINE_COUNT_MAX, FUNCTION_COUNT_MAX>, MachineBuilderError> {
+        if self.next_type_builder != self.type_count {
+            return Err(MachineBuilderError::TypeCountMismatch {
+                declared: self.type_count,
+                defined: self.next_type_builder,
+            });
+        }
+        if self.next_instance_number != self.instance_count {
+            return Err(MachineBuilderError::InstanceCountMismatch {
+                declared: self.instance_count,
+                added: self.next_instance_number,
+            });
+        }
+        let read = |index: usize| read_static(index, self.buffer).map_err(|_| MachineBuilderError::BufferTooSmall);
+        let offset = |base: ProgramWord, index: ProgramWord| {
+            usize::from(base)
+                .checked_add(usize::from(index))
+                .ok_or(MachineBuilderError::BufferTooSmall)
+        };
+
+        let type_table = read(TYPE_TABLE_OFFSET)?;
+        for type_id in 0..self.type_count {
+            let entry = type_id
+                .checked_mul(2)
+                .ok_or(MachineBuilderError::MachineCountOverflowsWord(usize::from(type_id)))?;
+            let entry = offset(type_table, entry)?;
+            let function_count = read(entry)?;
+            let function_table = read(entry.checked_add(1).ok_or(MachineBuilderError::BufferTooSmall)?)?;
+            for index in 0..function_count {
+                if read(offset(function_table, index)?)? == 0 {
+                    return Err(MachineBuilderError::UndefinedFunction { type_id, index });
+                }
+            }
+        }
+        let shared_table = read(SHARED_FUNCTION_TABLE_OFFSET)?;
+        for index in 0..self.shared_function_count {
+            if read(offset(shared_table, index)?)? == 0 {
+                return Err(MachineBuilderError::UndefinedSharedFunction(index));
+            }
+        }
+
+        let mut globals_end = self.shared_globals_size;
+        for (instance, descriptor) in self.descriptor.instances.iter().enumerate() {
+            let instance = ProgramWord::try_from(instance)
+                .map_err(|_| MachineBuilderError::MachineCountOverflowsWord(instance))?;
+            if descriptor.globals_base < globals_end {
+                return Err(MachineBuilderError::GlobalsOverlap(instance));
+            }
+            let globals_size = self
+                .descriptor
+                .types
+                .get(usize::from(descriptor.type_id))
+                .ok_or(MachineBuilderError::MachineCountExceeded)?
+                .globals_size;
+            globals_end = descriptor
+                .globals_base
+                .checked_add(globals_size)
+                .ok_or(MachineBuilderError::TooLarge(usize::from(globals_size)))?;
+        }
+
         let mut descriptor = self.descriptor;
         descriptor.length = self.free as usize;
-        descriptor
+        Ok(descriptor)
     }
 }
 
@@ -526,6 +746,14 @@ impl<'a, const MACHINE_COUNT_MAX: usize, const FUNCTION_COUNT_MAX: usize>
     ) -> Result<Self, MachineBuilderError> {
         let function_table_start = program.free;
         program.allocate(function_count)?;
+        let table_end = usize::from(function_table_start)
+            .checked_add(usize::from(function_count))
+            .ok_or(MachineBuilderError::BufferTooSmall)?;
+        program
+            .buffer
+            .get_mut(usize::from(function_table_start)..table_end)
+            .ok_or(MachineBuilderError::BufferTooSmall)?
+            .fill(0);
         Ok(Self {
             program,
             function_table_start,
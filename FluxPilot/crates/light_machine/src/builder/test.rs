@@ -43,27 +43,29 @@ fn test_new_machine_builder() -> Result<(), MachineBuilderError> {
     assert_eq!(buffer[TYPE_COUNT_OFFSET], 3);
     assert_eq!(buffer[SHARED_FUNCTION_COUNT_OFFSET], 0);
     assert_eq!(buffer[INSTANCE_TABLE_OFFSET], HEADER_WORDS as u16);
-    assert_eq!(buffer[TYPE_TABLE_OFFSET], (HEADER_WORDS + 6) as u16);
+    assert_eq!(buffer[TYPE_TABLE_OFFSET], (HEADER_WORDS + 9) as u16);
     assert_eq!(
         buffer[SHARED_FUNCTION_TABLE_OFFSET],
-        (HEADER_WORDS + 12) as u16
+        (HEADER_WORDS + 15) as u16
     );
     let instance_table = HEADER_WORDS;
     assert_eq!(buffer[instance_table], 0);
     assert_eq!(buffer[instance_table + 1], 0);
-    assert_eq!(buffer[instance_table + 2], 1);
-    assert_eq!(buffer[instance_table + 3], 0);
-    let type_table = HEADER_WORDS + 6;
+    assert_eq!(buffer[instance_table + 2], 0);
+    assert_eq!(buffer[instance_table + 3], 1);
+    assert_eq!(buffer[instance_table + 4], 0);
+    assert_eq!(buffer[instance_table + 5], 0);
+    let type_table = HEADER_WORDS + 9;
     assert_eq!(buffer[type_table], FUNCTION_COUNT as u16);
-    assert_eq!(buffer[type_table + 1], (HEADER_WORDS + 12) as u16);
+    assert_eq!(buffer[type_table + 1], (HEADER_WORDS + 15) as u16);
     assert_eq!(buffer[type_table + 2], FUNCTION_COUNT as u16);
-    assert_eq!(buffer[type_table + 3], (HEADER_WORDS + 20) as u16);
-    assert_eq!(buffer[HEADER_WORDS + 12 + 5], 17);
-    assert_eq!(buffer[HEADER_WORDS + 12 + 6], 31);
-    assert_eq!(buffer[HEADER_WORDS + 12 + 7], 71);
-    assert_eq!(buffer[HEADER_WORDS + 20 + 5], 7);
-    assert_eq!(buffer[HEADER_WORDS + 20 + 6], 11);
-    assert_eq!(buffer[HEADER_WORDS + 20 + 7], 97);
+    assert_eq!(buffer[type_table + 3], (HEADER_WORDS + 23) as u16);
+    assert_eq!(buffer[HEADER_WORDS + 15 + 5], 17);
+    assert_eq!(buffer[HEADER_WORDS + 15 + 6], 31);
+    assert_eq!(buffer[HEADER_WORDS + 15 + 7], 71);
+    assert_eq!(buffer[HEADER_WORDS + 23 + 5], 7);
+    assert_eq!(buffer[HEADER_WORDS + 23 + 6], 11);
+    assert_eq!(buffer[HEADER_WORDS + 23 + 7], 97);
     Ok(())
 }
 
@@ -102,12 +104,277 @@ fn test_new_function_builder() -> Result<(), MachineBuilderError> {
     let instance_table = HEADER_WORDS;
     assert_eq!(buffer[instance_table], 0);
     assert_eq!(buffer[instance_table + 1], 0);
-    let type_table = HEADER_WORDS + 2;
+    let type_table = HEADER_WORDS + 3;
     assert_eq!(buffer[type_table], FUNCTION_COUNT as u16);
-    assert_eq!(buffer[type_table + 1], (HEADER_WORDS + 4) as u16);
-    let static_start = HEADER_WORDS + 6;
+    assert_eq!(buffer[type_table + 1], (HEADER_WORDS + 5) as u16);
+    let static_start = HEADER_WORDS + 7;
     assert_eq!(buffer[static_start], 17);
     assert_eq!(buffer[static_start + 1], 31);
     assert_eq!(buffer[static_start + 2], 71);
     Ok(())
 }
+
+#[test]
+fn test_add_instance_with_locals() -> Result<(), MachineBuilderError> {
+    const MACHINE_COUNT: usize = 2;
+    const FUNCTION_COUNT: usize = 1;
+
+    let mut buffer = [0u16; 64];
+    let program = ProgramBuilder::<'_, MACHINE_COUNT, FUNCTION_COUNT>::new(&mut buffer, 2, 1, 0)?;
+    let machine = program.new_machine(FUNCTION_COUNT as u16, 3)?;
+    let mut program = machine.finish()?;
+
+    assert!(matches!(
+        program.add_instance_with_locals(0, &[(3, 7)]),
+        Err(MachineBuilderError::GlobalOutOfRange(3))
+    ));
+    let record = program.program_free();
+    program.add_instance_with_locals(0, &[(1, 200), (2, 9)])?;
+    let _ = program.finish_program();
+
+    let instance_table = HEADER_WORDS;
+    assert_eq!(buffer[instance_table + 2], 0);
+    assert_eq!(buffer[instance_table + 3], 0);
+    assert_eq!(buffer[instance_table + 4], 3);
+    assert_eq!(buffer[instance_table + 5], record);
+    let record = usize::from(record);
+    assert_eq!(&buffer[record..record + 5], &[2, 1, 200, 2, 9]);
+    Ok(())
+}
+
+#[test]
+fn test_finish_program_accepts_complete_program() -> Result<(), MachineBuilderError> {
+    let mut buffer = [0u16; 64];
+    let mut program = ProgramBuilder::<'_, 2, 2>::new(&mut buffer, 2, 1, 1)?;
+    program.set_shared_globals_size(2)?;
+    let mut shared = program.new_shared_function()?;
+    shared.add_op(Op::Exit)?;
+    let (_, program) = shared.finish()?;
+    let mut machine = program.new_machine(2, 3)?;
+    for _ in 0..2 {
+        let mut function = machine.new_function()?;
+        function.add_op(Op::Exit)?;
+        (_, machine) = function.finish()?;
+    }
+    let mut program = machine.finish()?;
+    program.add_instance(0)?;
+    let descriptor = program.finish_program()?;
+    assert_eq!(descriptor.instances[0].globals_base, 2);
+    assert_eq!(descriptor.instances[1].globals_base, 5);
+    Ok(())
+}
+
+#[test]
+fn test_finish_program_rejects_undefined_function() -> Result<(), MachineBuilderError> {
+    // Slot 0 is left out, so its table entry would point at the header.
+    let mut buffer = [0xffffu16; 64];
+    let program = ProgramBuilder::<'_, 1, 2>::new(&mut buffer, 1, 1, 0)?;
+    let machine = program.new_machine(2, 0)?;
+    let mut function = machine.new_function_at_index(FunctionIndex::new(1))?;
+    function.add_op(Op::Exit)?;
+    let (_, machine) = function.finish()?;
+    assert!(matches!(
+        machine.finish()?.finish_program(),
+        Err(MachineBuilderError::UndefinedFunction { type_id: 0, index: 0 })
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_finish_program_rejects_undefined_shared_function() -> Result<(), MachineBuilderError> {
+    let mut buffer = [0xffffu16; 64];
+    let program = ProgramBuilder::<'_, 1, 1>::new(&mut buffer, 0, 0, 2)?;
+    let mut shared = program.new_shared_function_at_index(FunctionIndex::new(0))?;
+    shared.add_op(Op::Exit)?;
+    let (_, program) = shared.finish()?;
+    assert!(matches!(
+        program.finish_program(),
+        Err(MachineBuilderError::UndefinedSharedFunction(1))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_finish_program_rejects_missing_types_and_instances() -> Result<(), MachineBuilderError> {
+    let mut buffer = [0u16; 64];
+    let program = ProgramBuilder::<'_, 2, 1>::new(&mut buffer, 2, 2, 0)?;
+    assert!(matches!(
+        program.finish_program(),
+        Err(MachineBuilderError::TypeCountMismatch { declared: 2, defined: 0 })
+    ));
+
+    let mut buffer = [0u16; 64];
+    let program = ProgramBuilder::<'_, 2, 1>::new(&mut buffer, 2, 1, 0)?;
+    let program = program.new_machine(0, 1)?.finish()?;
+    assert!(matches!(
+        program.finish_program(),
+        Err(MachineBuilderError::InstanceCountMismatch { declared: 2, added: 1 })
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_params_round_trip() -> Result<(), MachineBuilderError> {
+    use crate::metadata::{ParamInfo, ParamKind, ParamSpec, ParamTarget};
+
+    let mut buffer = [0xffffu16; 128];
+    let mut program = ProgramBuilder::<'_, 1, 1>::new(&mut buffer, 1, 1, 0)?;
+    let speed = ParamInfo {
+        type_id: 0,
+        kind: ParamKind::Range,
+        target: ParamTarget::Local(2),
+        min: 10,
+        max: 1000,
+        step: 10,
+        default: [500, 0, 0],
+    };
+    let tint = ParamInfo {
+        kind: ParamKind::Color,
+        target: ParamTarget::Function(3),
+        min: 0,
+        max: 255,
+        step: 1,
+        default: [70, 139, 192],
+        ..speed
+    };
+    program.add_params(&[ParamSpec { name: "speed", info: speed }, ParamSpec { name: "tint", info: tint }])?;
+    assert!(matches!(
+        program.add_params(&[ParamSpec { name: "again", info: speed }]),
+        Err(MachineBuilderError::InvalidParam(0))
+    ));
+    let mut machine = program.new_machine(1, 3)?;
+    let mut function = machine.new_function()?;
+    function.add_op(Op::Exit)?;
+    (_, machine) = function.finish()?;
+    let descriptor = machine.finish()?.finish_program()?;
+
+    let image = &buffer[..descriptor.length];
+    assert_eq!(usize::from(image[PARAMS_OFFSET]), HEADER_WORDS + 3 + 2);
+    let params: std::vec::Vec<_> = crate::metadata::params(image)
+        .map_err(|_| MachineBuilderError::BufferTooSmall)?
+        .map(|param| param.map_err(|_| MachineBuilderError::BufferTooSmall))
+        .collect::<Result<_, _>>()?;
+    assert_eq!(params.len(), 2);
+    assert!(params[0].name.eq_str("speed"));
+    assert_eq!(params[0].info, speed);
+    assert!(params[1].name.eq_str("tint"));
+    assert_eq!(params[1].info, tint);
+    assert_eq!(crate::metadata::instance_type(image, 0).ok(), Some(0));
+    assert!(crate::metadata::instance_type(image, 1).is_err());
+
+    let mut memory = [0u32; 64];
+    let program = Program::new(image, &mut memory).map_err(|_| MachineBuilderError::BufferTooSmall)?;
+    assert_eq!(program.params().map(|params| params.count()).ok(), Some(2));
+    Ok(())
+}
+
+#[test]
+fn test_params_reject_unknown_type() -> Result<(), MachineBuilderError> {
+    use crate::metadata::{ParamInfo, ParamKind, ParamSpec, ParamTarget};
+
+    let mut buffer = [0u16; 64];
+    let mut program = ProgramBuilder::<'_, 1, 1>::new(&mut buffer, 1, 1, 0)?;
+    let info = ParamInfo {
+        type_id: 1,
+        kind: ParamKind::Toggle,
+        target: ParamTarget::Local(0),
+        min: 0,
+        max: 1,
+        step: 1,
+        default: [0; 3],
+    };
+    assert!(matches!(
+        program.add_params(&[ParamSpec { name: "on", info }]),
+        Err(MachineBuilderError::InvalidParam(0))
+    ));
+    let image_params = crate::metadata::params(&buffer).map(|params| params.count()).ok();
+    assert_eq!(image_params, Some(0));
+    Ok(())
+}
+
+#[test]
+fn test_symbols_round_trip() -> Result<(), MachineBuilderError> {
+    use crate::metadata::{SymbolKind, SymbolSpec};
+
+    let symbols = [
+        SymbolSpec { kind: SymbolKind::Type, owner: 0, index: 0, name: "comet" },
+        SymbolSpec { kind: SymbolKind::Instance, owner: 0, index: 0, name: "comet" },
+        SymbolSpec { kind: SymbolKind::Function, owner: 0, index: 0, name: "init" },
+        SymbolSpec { kind: SymbolKind::Local, owner: 0, index: 1, name: "speed" },
+        SymbolSpec { kind: SymbolKind::SharedFunction, owner: 0, index: 0, name: "blend" },
+    ];
+    let build = |buffer: &mut [ProgramWord], strip: bool| -> Result<(usize, usize), MachineBuilderError> {
+        let mut program = ProgramBuilder::<'_, 1, 1>::new(buffer, 1, 1, 1)?;
+        program.strip_symbols(strip);
+        let mut shared = program.new_shared_function()?;
+        shared.add_op(Op::Push(7))?;
+        shared.add_op(Op::Exit)?;
+        let (_, program) = shared.finish()?;
+        let mut machine = program.new_machine(1, 2)?;
+        let mut function = machine.new_function()?;
+        function.add_op(Op::Push(3))?;
+        function.add_op(Op::Exit)?;
+        (_, machine) = function.finish()?;
+        let mut program = machine.finish()?;
+        let written = program.add_symbols(&symbols)?;
+        Ok((written, program.finish_program()?.length))
+    };
+
+    let mut buffer = [0u16; 128];
+    let (written, length) = build(&mut buffer, false)?;
+    assert_eq!(written, symbols.len());
+    let image = &buffer[..length];
+    let read: std::vec::Vec<_> = crate::metadata::symbols(image)
+        .map_err(|_| MachineBuilderError::BufferTooSmall)?
+        .map(|symbol| symbol.map_err(|_| MachineBuilderError::BufferTooSmall))
+        .collect::<Result<_, _>>()?;
+    assert_eq!(read.len(), symbols.len());
+    for (read, written) in read.iter().zip(symbols.iter()) {
+        assert_eq!((read.kind, read.owner, read.index), (written.kind, written.owner, written.index));
+        assert!(read.name.eq_str(written.name));
+    }
+    let found = crate::metadata::symbols(image)
+        .ok()
+        .and_then(|symbols| symbols.find(SymbolKind::Local, 0, 1));
+    assert!(found.is_some_and(|name| name.eq_str("speed")));
+
+    let mut stripped = [0u16; 128];
+    let (written, stripped_length) = build(&mut stripped, true)?;
+    assert_eq!(written, 0);
+    assert_eq!(stripped[SYMBOLS_OFFSET], 0);
+    // Apart from the header word, symbols only add to the end.
+    assert_eq!(usize::from(image[SYMBOLS_OFFSET]), stripped_length);
+    assert_eq!(&image[HEADER_WORDS..stripped_length], &stripped[HEADER_WORDS..stripped_length]);
+
+    // Running never looks at the symbols.
+    let mut memory = [0u32; 64];
+    let mut program = Program::new(image, &mut memory).map_err(|_| MachineBuilderError::BufferTooSmall)?;
+    program.call(0, 0).map_err(|_| MachineBuilderError::BufferTooSmall)?;
+    assert_eq!(program.stack().as_slice(), &[3]);
+    Ok(())
+}
+
+#[test]
+fn test_symbols_stop_at_the_cap() -> Result<(), MachineBuilderError> {
+    use crate::metadata::{SymbolKind, SymbolSpec, SYMBOLS_MAX_WORDS};
+
+    let name = "a_name_that_takes_sixteen_words!";
+    let symbol = SymbolSpec { kind: SymbolKind::Local, owner: 0, index: 0, name };
+    let symbols = std::vec![symbol; SYMBOLS_MAX_WORDS];
+    let mut buffer = std::vec![0u16; SYMBOLS_MAX_WORDS * 2];
+    let mut program = ProgramBuilder::<'_, 1, 1>::new(&mut buffer, 0, 1, 0)?;
+    let written = program.add_symbols(&symbols)?;
+    assert_eq!(written, (SYMBOLS_MAX_WORDS - 1) / symbol.words());
+
+    let too_long = SymbolSpec { name: "a_name_longer_than_thirty_two_bytes", ..symbol };
+    let unknown_type = SymbolSpec { owner: 1, ..symbol };
+    for bad in [too_long, unknown_type] {
+        let mut buffer = [0u16; 64];
+        let mut program = ProgramBuilder::<'_, 1, 1>::new(&mut buffer, 0, 1, 0)?;
+        assert!(matches!(
+            program.add_symbols(&[symbol, bad]),
+            Err(MachineBuilderError::InvalidSymbol(1))
+        ));
+    }
+    Ok(())
+}
@@ -27,6 +27,7 @@ use crate::builder::FunctionIndex;
 
 pub mod builder;
 pub mod assembler;
+pub mod metadata;
 
 #[cfg(test)]
 mod assembler_test;
@@ -213,7 +214,7 @@ fn set_value<E>(
 }
 
 #[repr(u16)] // Must match ProgramWord
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Ops {
     Pop,
     Push,
@@ -251,6 +252,23 @@ pub enum Ops {
     Return,
 }
 
+impl Ops {
+    /// True for ops followed by one immediate operand word.
+    pub fn has_operand(self) -> bool {
+        matches!(
+            self,
+            Ops::Push
+                | Ops::LocalLoad
+                | Ops::LocalStore
+                | Ops::GlobalLoad
+                | Ops::GlobalStore
+                | Ops::StackLoad
+                | Ops::StackStore
+                | Ops::Return
+        )
+    }
+}
+
 impl From<Ops> for ProgramWord {
     fn from(op: Ops) -> ProgramWord {
         op as ProgramWord
@@ -336,9 +354,15 @@ pub enum MachineError {
     InvalidProgramVersion(ProgramWord),
     #[error("memory buffer too small (needed {needed}, provided {provided})")]
     MemoryBufferTooSmall { needed: usize, provided: usize },
+    #[error("execution exceeded the step limit of {0} instructions")]
+    StepLimitExceeded(u32),
+    #[error("param record at {0} is not valid")]
+    InvalidParam(usize),
+    #[error("symbol record at {0} is not valid")]
+    InvalidSymbol(usize),
 }
 
-pub const PROGRAM_VERSION: ProgramWord = 2;
+pub const PROGRAM_VERSION: ProgramWord = 3;
 pub const VERSION_OFFSET: usize = 0;
 pub const MACHINE_COUNT_OFFSET: usize = VERSION_OFFSET + 1;
 pub const GLOBALS_SIZE_OFFSET: usize = MACHINE_COUNT_OFFSET + 1;
@@ -347,7 +371,15 @@ pub const TYPE_COUNT_OFFSET: usize = SHARED_FUNCTION_COUNT_OFFSET + 1;
 pub const INSTANCE_TABLE_OFFSET: usize = TYPE_COUNT_OFFSET + 1;
 pub const TYPE_TABLE_OFFSET: usize = INSTANCE_TABLE_OFFSET + 1;
 pub const SHARED_FUNCTION_TABLE_OFFSET: usize = TYPE_TABLE_OFFSET + 1;
-pub const HEADER_WORDS: usize = SHARED_FUNCTION_TABLE_OFFSET + 1;
+/// Offset of the optional params section, 0 when there is none. See
+/// [`metadata`].
+pub const PARAMS_OFFSET: usize = SHARED_FUNCTION_TABLE_OFFSET + 1;
+/// Offset of the optional symbols section, 0 when there is none. See
+/// [`metadata`].
+pub const SYMBOLS_OFFSET: usize = PARAMS_OFFSET + 1;
+pub const HEADER_WORDS: usize = SYMBOLS_OFFSET + 1;
+/// Instance table entries are `[TYPE_ID, GLOBALS_BASE, INITIAL_LOCALS_OFFSET]`.
+pub const INSTANCE_ENTRY_WORDS: usize = 3;
 
 const INIT_OFFSET: usize = 0;
 const START_FRAME_OFFSET: usize = INIT_OFFSET + 1;
@@ -431,6 +463,8 @@ pub struct Program<'a, 'b> {
     stack: StackSlice<'b>,
     frame_pointer: StackWord,
     locals_base: ProgramWord,
+    step_limit: Option<u32>,
+    last_pc: usize,
 }
 
 impl<'a, 'b> Program<'a, 'b> {
@@ -459,9 +493,24 @@ impl<'a, 'b> Program<'a, 'b> {
             stack: memory.stack,
             frame_pointer: 0,
             locals_base: 0,
+            step_limit: None,
+            last_pc: 0,
         })
     }
 
+    /// Bound the number of instructions a single host call may execute.
+    /// `None` (the default) runs until `EXIT` or an error.
+    pub fn set_step_limit(&mut self, limit: Option<u32>) {
+        self.step_limit = limit;
+    }
+
+    /// Address of the last instruction the VM started executing. After a
+    /// call fails this is the instruction that faulted, which a source map
+    /// can turn back into an assembly line.
+    pub fn last_pc(&self) -> usize {
+        self.last_pc
+    }
+
 
     pub fn machine_count(&self) -> Result<ProgramWord, MachineError> {
         let Some(count) = self.static_data.get(MACHINE_COUNT_OFFSET) else {
@@ -491,6 +540,22 @@ impl<'a, 'b> Program<'a, 'b> {
         Ok(*count)
     }
 
+    /// The params the image describes, as in [`metadata::params`].
+    pub fn params(&self) -> Result<metadata::Params<'a>, MachineError> {
+        metadata::params(self.static_data)
+    }
+
+    /// The names the image carries, as in [`metadata::symbols`]. The VM
+    /// never reads them.
+    pub fn symbols(&self) -> Result<metadata::Symbols<'a>, MachineError> {
+        metadata::symbols(self.static_data)
+    }
+
+    /// The whole program image, for a host to read back.
+    pub fn static_data(&self) -> &'a [ProgramWord] {
+        self.static_data
+    }
+
     fn instance_table_offset(&self) -> Result<usize, MachineError> {
         let offset = read_static(INSTANCE_TABLE_OFFSET, self.static_data)?;
         Ok(offset as usize)
@@ -510,34 +575,68 @@ impl<'a, 'b> Program<'a, 'b> {
         &self,
         machine_number: ProgramWord,
     ) -> Result<ProgramWord, MachineError> {
+        let entry_index = self.instance_entry_index(machine_number)?;
+        let globals_base_index = entry_index
+            .checked_add(1)
+            .ok_or(MachineError::OutOfBoudsStaticRead(entry_index))?;
+        read_static(globals_base_index, self.static_data)
+    }
+
+    fn instance_entry_index(&self, machine_number: ProgramWord) -> Result<usize, MachineError> {
         let machine_count = self.machine_count()?;
         if machine_number >= machine_count {
             return Err(MachineError::MachineIndexOutOfRange(machine_number));
         };
         let table_offset = self.instance_table_offset()?;
-        let entry_index = (machine_number as usize)
-            .checked_mul(2)
+        (machine_number as usize)
+            .checked_mul(INSTANCE_ENTRY_WORDS)
             .and_then(|offset| table_offset.checked_add(offset))
-            .ok_or(MachineError::OutOfBoudsStaticRead(table_offset))?;
-        let globals_base_index = entry_index
-            .checked_add(1)
+            .ok_or(MachineError::OutOfBoudsStaticRead(table_offset))
+    }
+
+    /// Writes the instance's initial locals record, if it has one, into its
+    /// globals. The record is `[COUNT, (LOCAL, VALUE)...]`.
+    fn apply_initial_locals(&mut self, machine_number: ProgramWord) -> Result<(), MachineError> {
+        let entry_index = self.instance_entry_index(machine_number)?;
+        let record_index = entry_index
+            .checked_add(2)
             .ok_or(MachineError::OutOfBoudsStaticRead(entry_index))?;
-        read_static(globals_base_index, self.static_data)
+        let record = read_static(record_index, self.static_data)? as usize;
+        if record == 0 {
+            return Ok(());
+        }
+        let globals_base = self.instance_globals_offset(machine_number)?;
+        let count = read_static(record, self.static_data)? as usize;
+        let mut pair = record
+            .checked_add(1)
+            .ok_or(MachineError::OutOfBoudsStaticRead(record))?;
+        for _ in 0..count {
+            let local = read_static(pair, self.static_data)?;
+            let value_index = pair
+                .checked_add(1)
+                .ok_or(MachineError::OutOfBoudsStaticRead(pair))?;
+            let value = read_static(value_index, self.static_data)?;
+            let index = globals_base
+                .checked_add(local)
+                .ok_or(MachineError::OutOfBoundsGlobalsAccess(usize::from(globals_base)))?;
+            let index = usize::from(index);
+            let slot = self
+                .globals
+                .get_mut(index)
+                .ok_or(MachineError::OutOfBoundsGlobalsAccess(index))?;
+            *slot = StackWord::from(value);
+            pair = value_index
+                .checked_add(1)
+                .ok_or(MachineError::OutOfBoudsStaticRead(value_index))?;
+        }
+        Ok(())
     }
 
     fn get_type_for_instance(
         &self,
         machine_number: ProgramWord,
     ) -> Result<ProgramWord, MachineError> {
-        let machine_count = self.machine_count()?;
-        if machine_number >= machine_count {
-            return Err(MachineError::MachineIndexOutOfRange(machine_number));
-        };
-        let table_offset = self.instance_table_offset()?;
-        let entry_index = (machine_number as usize)
-            .checked_mul(2)
-            .and_then(|offset| table_offset.checked_add(offset))
-            .ok_or(MachineError::OutOfBoudsStaticRead(table_offset))?;
+        let entry_index = self.instance_entry_index(machine_number)?;
         read_static(entry_index, self.static_data)
     }
 
@@ -614,6 +713,7 @@ impl<'a, 'b> Program<'a, 'b> {
         machine_number: ProgramWord,
     ) -> Result<(), MachineError> {
         let entry_point = self.get_function_entry(machine_number, INIT_OFFSET)?;
+        self.apply_initial_locals(machine_number)?;
         self.run(machine_number, entry_point)?;
         Ok(())
     }
@@ -689,7 +789,15 @@ impl<'a, 'b> Program<'a, 'b> {
         let mut pc = entry_point;
         let locals_base = self.instance_globals_offset(machine_number)?;
         self.locals_base = locals_base;
+        let mut steps: u32 = 0;
         loop {
+            if let Some(limit) = self.step_limit {
+                if steps >= limit {
+                    return Err(MachineError::StepLimitExceeded(limit));
+                }
+                steps = steps.saturating_add(1);
+            }
+            self.last_pc = pc;
             let word = read_static(pc, self.static_data)?;
             let op = word.try_into()?;
             match op {
@@ -1090,11 +1198,9 @@ impl<'a, 'b> Program<'a, 'b> {
                         StackWord::try_from(new_frame_pointer)
                             .map_err(|_| MachineError::StackOverflow)?;
                     self.frame_pointer = new_frame_pointer;
-                    let entry_point = self.get_function_entry(machine_number, function_index)?;
-                    self.run(machine_number, entry_point)?;
-                    // Restore caller's frame pointer after returning.
-                    self.frame_pointer = saved_frame_pointer;
-                    pc = stack_word_to_program_index(return_pc)?;
+                    // RET unwinds the frame and jumps back to return_pc, so the
+                    // callee runs in this loop rather than a nested `run`.
+                    pc = self.get_function_entry(machine_number, function_index)?;
                     continue;
                 }
                 Ops::CallShared => {
@@ -1136,10 +1242,7 @@ impl<'a, 'b> Program<'a, 'b> {
                         StackWord::try_from(new_frame_pointer)
                             .map_err(|_| MachineError::StackOverflow)?;
                     self.frame_pointer = new_frame_pointer;
-                    let entry_point = self.get_shared_function_entry(function_index)?;
-                    self.run(machine_number, entry_point)?;
-                    self.frame_pointer = saved_frame_pointer;
-                    pc = stack_word_to_program_index(return_pc)?;
+                    pc = self.get_shared_function_entry(function_index)?;
                     continue;
                 }
                 Ops::Return => {
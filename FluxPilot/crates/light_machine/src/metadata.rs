@@ -0,0 +1,424 @@
+// Metadata a host reads from a program image and the VM never runs.
+//
+// Params describe a machine type's controls: what to show, its range and
+// starting value, and the local or function a change goes to. They sit in
+// an optional section at `PARAMS_OFFSET`, so a host can build its controls
+// from whatever image a device holds.
+//
+// The section is `[PARAM_COUNT, record...]`. Each record is
+//
+// ```text
+// [TYPE_ID][KIND][TARGET_KIND][TARGET][MIN][MAX][STEP]
+// [DEFAULT_0][DEFAULT_1][DEFAULT_2][NAME_LENGTH][NAME...]
+// ```
+//
+// with the name's bytes two to a word, low byte first.
+//
+// Symbols name what a debugger shows: machine types and instances, their
+// functions and locals, and shared functions. They sit at `SYMBOLS_OFFSET`
+// as `[SYMBOL_COUNT, record...]`, each record
+//
+// ```text
+// [KIND][OWNER][INDEX][NAME_LENGTH][NAME...]
+// ```
+//
+// where `OWNER` is the machine type of an instance, function or local.
+
+use crate::{
+    read_static, MachineError, ProgramWord, INSTANCE_ENTRY_WORDS, INSTANCE_TABLE_OFFSET,
+    MACHINE_COUNT_OFFSET, PARAMS_OFFSET, SYMBOLS_OFFSET,
+};
+
+/// Longest param name, in bytes.
+pub const PARAM_NAME_MAX: usize = 32;
+
+/// Words in a record before the name.
+pub const PARAM_FIXED_WORDS: usize = 11;
+
+/// Longest symbol name, in bytes.
+pub const SYMBOL_NAME_MAX: usize = 32;
+
+/// Words in a symbol record before the name.
+pub const SYMBOL_FIXED_WORDS: usize = 4;
+
+/// Most words the symbols section may take, count included. Symbols past
+/// it are left out of the image.
+pub const SYMBOLS_MAX_WORDS: usize = 1024;
+
+/// What kind of control a param is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ParamKind {
+    /// A number from `min` to `max` in steps of `step`.
+    Range,
+    /// Off (0) or on (1).
+    Toggle,
+    /// Red, green and blue, each 0 to 255. A local target is the first of
+    /// three locals; a function target is called with three arguments.
+    Color,
+}
+
+impl ParamKind {
+    pub fn to_word(self) -> ProgramWord {
+        match self {
+            ParamKind::Range => 0,
+            ParamKind::Toggle => 1,
+            ParamKind::Color => 2,
+        }
+    }
+
+    pub fn from_word(word: ProgramWord) -> Option<Self> {
+        match word {
+            0 => Some(ParamKind::Range),
+            1 => Some(ParamKind::Toggle),
+            2 => Some(ParamKind::Color),
+            _ => None,
+        }
+    }
+
+    /// How many values a change carries.
+    pub fn value_count(self) -> ProgramWord {
+        match self {
+            ParamKind::Range | ParamKind::Toggle => 1,
+            ParamKind::Color => 3,
+        }
+    }
+}
+
+/// Where a param's value goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ParamTarget {
+    /// Stored straight into this local of the instance.
+    Local(ProgramWord),
+    /// Passed as the arguments of this function of the machine.
+    Function(ProgramWord),
+}
+
+impl ParamTarget {
+    fn kind_word(self) -> ProgramWord {
+        match self {
+            ParamTarget::Local(_) => 0,
+            ParamTarget::Function(_) => 1,
+        }
+    }
+
+    fn index(self) -> ProgramWord {
+        match self {
+            ParamTarget::Local(index) | ParamTarget::Function(index) => index,
+        }
+    }
+
+    fn from_words(kind: ProgramWord, index: ProgramWord) -> Option<Self> {
+        match kind {
+            0 => Some(ParamTarget::Local(index)),
+            1 => Some(ParamTarget::Function(index)),
+            _ => None,
+        }
+    }
+}
+
+/// Everything about a param but its name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ParamInfo {
+    /// The machine type the param belongs to; every instance of the type
+    /// has it.
+    pub type_id: ProgramWord,
+    pub kind: ParamKind,
+    pub target: ParamTarget,
+    pub min: ProgramWord,
+    pub max: ProgramWord,
+    pub step: ProgramWord,
+    /// The starting value, in the first word, or a color's red, green and
+    /// blue.
+    pub default: [ProgramWord; 3],
+}
+
+impl ParamInfo {
+    pub(crate) fn words(&self) -> [ProgramWord; PARAM_FIXED_WORDS - 1] {
+        let [red, green, blue] = self.default;
+        [
+            self.type_id,
+            self.kind.to_word(),
+            self.target.kind_word(),
+            self.target.index(),
+            self.min,
+            self.max,
+            self.step,
+            red,
+            green,
+            blue,
+        ]
+    }
+}
+
+/// A param to write with `ProgramBuilder::add_params`.
+#[derive(Clone, Copy, Debug)]
+pub struct ParamSpec<'n> {
+    pub name: &'n str,
+    pub info: ParamInfo,
+}
+
+/// A param read back from an image.
+#[derive(Clone, Copy, Debug)]
+pub struct Param<'a> {
+    pub name: PackedName<'a>,
+    pub info: ParamInfo,
+}
+
+/// A name read from an image, still packed in program words.
+#[derive(Clone, Copy, Debug)]
+pub struct PackedName<'a> {
+    words: &'a [ProgramWord],
+    len: usize,
+}
+
+impl<'a> PackedName<'a> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn bytes(&self) -> impl Iterator<Item = u8> + 'a {
+        self.words
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .take(self.len)
+    }
+
+    pub fn eq_str(&self, name: &str) -> bool {
+        self.len == name.len() && self.bytes().eq(name.bytes())
+    }
+}
+
+/// Words the name of `len` bytes takes.
+pub(crate) fn name_words(len: usize) -> usize {
+    len.div_ceil(2)
+}
+
+// The name of `len` bytes at `start`, and the index just past it.
+fn read_name(image: &[ProgramWord], start: usize, len: ProgramWord) -> Result<(PackedName<'_>, usize), MachineError> {
+    let len = usize::from(len);
+    let end = start
+        .checked_add(name_words(len))
+        .ok_or(MachineError::OutOfBoudsStaticRead(start))?;
+    let words = image
+        .get(start..end)
+        .ok_or(MachineError::OutOfBoudsStaticRead(start))?;
+    Ok((PackedName { words, len }, end))
+}
+
+// Where a section starts and how many records it holds.
+fn section(image: &[ProgramWord], header: usize) -> Result<(usize, ProgramWord), MachineError> {
+    let offset = usize::from(read_static(header, image)?);
+    if offset == 0 {
+        return Ok((offset, 0));
+    }
+    let next = offset.checked_add(1).ok_or(MachineError::OutOfBoudsStaticRead(offset))?;
+    Ok((next, read_static(offset, image)?))
+}
+
+/// The params of `image`, in the order they were written. Empty for an
+/// image without a params section.
+pub fn params(image: &[ProgramWord]) -> Result<Params<'_>, MachineError> {
+    let (next, remaining) = section(image, PARAMS_OFFSET)?;
+    Ok(Params { image, next, remaining })
+}
+
+/// The machine type of `instance` in `image`, so a host can find the
+/// params each instance has.
+pub fn instance_type(image: &[ProgramWord], instance: ProgramWord) -> Result<ProgramWord, MachineError> {
+    if instance >= read_static(MACHINE_COUNT_OFFSET, image)? {
+        return Err(MachineError::MachineIndexOutOfRange(instance));
+    }
+    let table = usize::from(read_static(INSTANCE_TABLE_OFFSET, image)?);
+    let entry = usize::from(instance)
+        .checked_mul(INSTANCE_ENTRY_WORDS)
+        .and_then(|offset| offset.checked_add(table))
+        .ok_or(MachineError::OutOfBoudsStaticRead(table))?;
+    read_static(entry, image)
+}
+
+pub struct Params<'a> {
+    image: &'a [ProgramWord],
+    next: usize,
+    remaining: ProgramWord,
+}
+
+impl<'a> Params<'a> {
+    /// How many params are left to read.
+    pub fn remaining(&self) -> ProgramWord {
+        self.remaining
+    }
+
+    fn read(&mut self) -> Result<Param<'a>, MachineError> {
+        let start = self.next;
+        let fixed = start
+            .checked_add(PARAM_FIXED_WORDS)
+            .and_then(|end| self.image.get(start..end))
+            .ok_or(MachineError::OutOfBoudsStaticRead(start))?;
+        let &[type_id, kind, target_kind, target, min, max, step, red, green, blue, len] = fixed else {
+            return Err(MachineError::OutOfBoudsStaticRead(start));
+        };
+        let kind = ParamKind::from_word(kind).ok_or(MachineError::InvalidParam(start))?;
+        let target =
+            ParamTarget::from_words(target_kind, target).ok_or(MachineError::InvalidParam(start))?;
+        let name_start = start
+            .checked_add(PARAM_FIXED_WORDS)
+            .ok_or(MachineError::OutOfBoudsStaticRead(start))?;
+        let (name, next) = read_name(self.image, name_start, len)?;
+        self.next = next;
+        Ok(Param {
+            name,
+            info: ParamInfo {
+                type_id,
+                kind,
+                target,
+                min,
+                max,
+                step,
+                default: [red, green, blue],
+            },
+        })
+    }
+}
+
+impl<'a> Iterator for Params<'a> {
+    type Item = Result<Param<'a>, MachineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let remaining = self.remaining.checked_sub(1)?;
+        let param = self.read();
+        // A bad record ends the section; the rest cannot be found.
+        self.remaining = if param.is_ok() { remaining } else { 0 };
+        Some(param)
+    }
+}
+
+/// What a symbol names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SymbolKind {
+    /// A machine type, by type id.
+    Type,
+    /// A machine instance, by machine index.
+    Instance,
+    /// A function of the owning type.
+    Function,
+    /// A local of the owning type.
+    Local,
+    /// A shared function, by index.
+    SharedFunction,
+}
+
+impl SymbolKind {
+    pub fn to_word(self) -> ProgramWord {
+        match self {
+            SymbolKind::Type => 0,
+            SymbolKind::Instance => 1,
+            SymbolKind::Function => 2,
+            SymbolKind::Local => 3,
+            SymbolKind::SharedFunction => 4,
+        }
+    }
+
+    pub fn from_word(word: ProgramWord) -> Option<Self> {
+        match word {
+            0 => Some(SymbolKind::Type),
+            1 => Some(SymbolKind::Instance),
+            2 => Some(SymbolKind::Function),
+            3 => Some(SymbolKind::Local),
+            4 => Some(SymbolKind::SharedFunction),
+            _ => None,
+        }
+    }
+}
+
+/// A symbol to write with `ProgramBuilder::add_symbols`.
+#[derive(Clone, Copy, Debug)]
+pub struct SymbolSpec<'n> {
+    pub kind: SymbolKind,
+    /// The machine type an instance, function or local belongs to; 0 for
+    /// the other kinds.
+    pub owner: ProgramWord,
+    pub index: ProgramWord,
+    pub name: &'n str,
+}
+
+impl SymbolSpec<'_> {
+    /// Words the record takes in the image.
+    pub fn words(&self) -> usize {
+        SYMBOL_FIXED_WORDS.saturating_add(name_words(self.name.len()))
+    }
+}
+
+/// A symbol read back from an image.
+#[derive(Clone, Copy, Debug)]
+pub struct Symbol<'a> {
+    pub kind: SymbolKind,
+    pub owner: ProgramWord,
+    pub index: ProgramWord,
+    pub name: PackedName<'a>,
+}
+
+/// The symbols of `image`, in the order they were written. Empty for an
+/// image without a symbols section.
+pub fn symbols(image: &[ProgramWord]) -> Result<Symbols<'_>, MachineError> {
+    let (next, remaining) = section(image, SYMBOLS_OFFSET)?;
+    Ok(Symbols { image, next, remaining })
+}
+
+pub struct Symbols<'a> {
+    image: &'a [ProgramWord],
+    next: usize,
+    remaining: ProgramWord,
+}
+
+impl<'a> Symbols<'a> {
+    /// How many symbols are left to read.
+    pub fn remaining(&self) -> ProgramWord {
+        self.remaining
+    }
+
+    /// The name of the symbol of `kind` at `index` of `owner`, if the image
+    /// has one.
+    pub fn find(self, kind: SymbolKind, owner: ProgramWord, index: ProgramWord) -> Option<PackedName<'a>> {
+        self.map_while(Result::ok)
+            .find(|symbol| symbol.kind == kind && symbol.owner == owner && symbol.index == index)
+            .map(|symbol| symbol.name)
+    }
+
+    fn read(&mut self) -> Result<Symbol<'a>, MachineError> {
+        let start = self.next;
+        let fixed = start
+            .checked_add(SYMBOL_FIXED_WORDS)
+            .and_then(|end| self.image.get(start..end))
+            .ok_or(MachineError::OutOfBoudsStaticRead(start))?;
+        let &[kind, owner, index, len] = fixed else {
+            return Err(MachineError::OutOfBoudsStaticRead(start));
+        };
+        let kind = SymbolKind::from_word(kind).ok_or(MachineError::InvalidSymbol(start))?;
+        let name_start = start
+            .checked_add(SYMBOL_FIXED_WORDS)
+            .ok_or(MachineError::OutOfBoudsStaticRead(start))?;
+        let (name, next) = read_name(self.image, name_start, len)?;
+        self.next = next;
+        Ok(Symbol {
+            kind,
+            owner,
+            index,
+            name,
+        })
+    }
+}
+
+impl<'a> Iterator for Symbols<'a> {
+    type Item = Result<Symbol<'a>, MachineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let remaining = self.remaining.checked_sub(1)?;
+        let symbol = self.read();
+        self.remaining = if symbol.is_ok() { remaining } else { 0 };
+        Some(symbol)
+    }
+}
@@ -884,6 +884,40 @@ fn op_lstore_named_local() -> Result<(), MachineError> {
     Ok(())
 }
 
+#[test]
+fn assembler_accepts_color_binary_negative_and_char_literals() -> Result<(), MachineError> {
+    let program = assemble_program(&[
+        ".const NEG -2",
+        ".machine main locals 0 functions 1",
+        ".data palette",
+        "palette:",
+        "#102030",
+        ".word 'z'",
+        ".end",
+        ".func main index 0",
+        "PUSH #ff8000",
+        "PUSH 0b1010",
+        "PUSH -1",
+        "PUSH NEG",
+        "PUSH 'A'",
+        "PUSH ';' ; a comment",
+        "PUSH '\\n' + 1",
+        "LOAD_STATIC palette + 1",
+        "LOAD_STATIC palette + 3",
+        "EXIT",
+        ".end",
+        ".end",
+    ]);
+    let mut globals = [0u32; 1];
+    let mut stack: Vec<StackWord, STACK_CAP> = Vec::new();
+    run_single(&program, &mut globals, &mut stack)?;
+    assert_eq!(
+        stack.as_slice(),
+        &[0xFF, 0x80, 0x00, 0b1010, 0xFFFF, 0xFFFE, 65, 59, 11, 0x20, 122]
+    );
+    Ok(())
+}
+
 #[test]
 fn op_load_static() -> Result<(), MachineError> {
     let program = assemble_program(&[
@@ -1519,3 +1553,366 @@ fn op_return() -> Result<(), MachineError> {
     assert_eq!(stack.as_slice(), &[77, 88, 99]);
     Ok(())
 }
+
+#[test]
+fn op_call_resumes_caller_once() -> Result<(), MachineError> {
+    let program = assemble_program(&[
+        ".machine main locals 0 functions 2",
+        ".func helper index 1",
+        "RET 0",
+        ".end",
+        ".func main index 0",
+        "PUSH 0",
+        "CALL helper",
+        "PUSH 5",
+        "EXIT",
+        ".end",
+        ".end",
+    ]);
+    let mut globals = [0u32; 1];
+    let mut stack: Vec<StackWord, STACK_CAP> = Vec::new();
+    run_single(&program, &mut globals, &mut stack)?;
+    assert_eq!(stack.as_slice(), &[5]);
+    Ok(())
+}
+
+#[test]
+fn call_sugar_pushes_argument_count_and_index() -> Result<(), MachineError> {
+    let program = assemble_program(&[
+        ".machine main locals 0 functions 2",
+        ".func_decl add index 1 args 2 returns 1",
+        ".func main index 0",
+        "PUSH 3",
+        "PUSH 4",
+        "CALL add 2",
+        "EXIT",
+        ".end",
+        ".func add",
+        ".frame a 0",
+        ".frame b 1",
+        "SLOAD a",
+        "SLOAD b",
+        "ADD",
+        "RET 1",
+        ".end",
+        ".end",
+    ]);
+    let mut globals = [0u32; 1];
+    let mut stack: Vec<StackWord, STACK_CAP> = Vec::new();
+    run_single(&program, &mut globals, &mut stack)?;
+    assert_eq!(stack.as_slice(), &[7]);
+    Ok(())
+}
+
+#[test]
+fn unbounded_recursion_overflows_vm_stack() {
+    let program = assemble_program(&[
+        ".machine main locals 0 functions 1",
+        ".func main index 0",
+        "PUSH 0",
+        "CALL main",
+        "EXIT",
+        ".end",
+        ".end",
+    ]);
+    let mut globals = [0u32; 1];
+    let mut stack: Vec<StackWord, STACK_CAP> = Vec::new();
+    let result = run_single(&program, &mut globals, &mut stack);
+    assert!(matches!(result, Err(MachineError::StackOverflow)));
+}
+
+#[test]
+fn step_limit_stops_infinite_loop() {
+    let program = assemble_program(&[
+        ".machine main locals 0 functions 1",
+        ".func main index 0",
+        "top:",
+        "JUMP top",
+        ".end",
+        ".end",
+    ]);
+    let mut memory = make_memory(&program, STACK_CAP);
+    let mut machine = Program::new(&program, memory.as_mut_slice()).unwrap();
+    machine.set_step_limit(Some(100));
+    let result = machine.call(0, 0);
+    assert!(matches!(result, Err(MachineError::StepLimitExceeded(100))));
+}
+
+#[test]
+fn const_expressions_in_operands() -> Result<(), MachineError> {
+    let program = assemble_program(&[
+        ".const BASE 10",
+        ".const STEP (BASE + 2) * 3",
+        ".const MASK 0xF0 | 0x0F",
+        ".machine main locals 0 functions 1",
+        ".func main index 0",
+        "PUSH STEP",
+        "PUSH MASK & (1 << 4)",
+        "PUSH BASE / 3 - 1",
+        "EXIT",
+        ".end",
+        ".end",
+    ]);
+    let mut globals = [0u32; 1];
+    let mut stack: Vec<StackWord, STACK_CAP> = Vec::new();
+    run_single(&program, &mut globals, &mut stack)?;
+    assert_eq!(stack.as_slice(), &[36, 16, 2]);
+    Ok(())
+}
+
+#[test]
+fn label_offsets_in_operands() -> Result<(), MachineError> {
+    let program = assemble_program(&[
+        ".machine main locals 0 functions 1",
+        ".func main index 0",
+        "JUMP skip + 2",
+        "PUSH 99",
+        "skip:",
+        "PUSH 1",
+        "PUSH 2",
+        "EXIT",
+        ".end",
+        ".end",
+    ]);
+    let mut globals = [0u32; 1];
+    let mut stack: Vec<StackWord, STACK_CAP> = Vec::new();
+    run_single(&program, &mut globals, &mut stack)?;
+    assert_eq!(stack.as_slice(), &[2]);
+    Ok(())
+}
+
+#[test]
+fn const_expressions_in_data() -> Result<(), MachineError> {
+    let program = assemble_program(&[
+        ".const LED_COUNT 8",
+        ".machine main locals 0 functions 1",
+        ".data table",
+        "first:",
+        ".word LED_COUNT * 2",
+        "LED_COUNT - 1",
+        ".end",
+        ".func main index 0",
+        "LOAD_STATIC first + 1",
+        "LOAD_STATIC first",
+        "EXIT",
+        ".end",
+        ".end",
+    ]);
+    let mut globals = [0u32; 1];
+    let mut stack: Vec<StackWord, STACK_CAP> = Vec::new();
+    run_single(&program, &mut globals, &mut stack)?;
+    assert_eq!(stack.as_slice(), &[7, 16]);
+    Ok(())
+}
+
+#[test]
+fn shared_function_resolves_forward_labels() -> Result<(), MachineError> {
+    let program = assemble_program_with_shared(
+        &[
+            ".shared_func helper index 0",
+            "JUMP done",
+            "PUSH 99",
+            "done:",
+            "PUSH 1",
+            "RET 1",
+            ".end",
+            ".machine main locals 0 functions 1",
+            ".func main index 0",
+            "PUSH 0",
+            "CALL_SHARED helper",
+            "EXIT",
+            ".end",
+            ".end",
+        ],
+        1,
+        1,
+    );
+    let mut globals = [0u32; 1];
+    let mut stack: Vec<StackWord, STACK_CAP> = Vec::new();
+    run_single(&program, &mut globals, &mut stack)?;
+    assert_eq!(stack.as_slice(), &[1]);
+    Ok(())
+}
+
+#[test]
+fn macros_expand_with_local_labels() -> Result<(), MachineError> {
+    let program = assemble_program(&[
+        ".macro push_max a, b",
+        "PUSH a",
+        "PUSH b",
+        "BRLT b_bigger ; both values are popped",
+        "PUSH a",
+        "JUMP done",
+        "b_bigger:",
+        "PUSH b",
+        "done:",
+        ".endm",
+        ".macro push_both_max x y",
+        "push_max x, y",
+        "push_max y + 1, x",
+        ".endm",
+        ".machine main locals 0 functions 1",
+        ".func main index 0",
+        "push_max 3, 9",
+        "push_max 2 + 5, 4",
+        "push_both_max 1 , 6",
+        "EXIT",
+        ".end",
+        ".end",
+    ]);
+    let mut globals = [0u32; 1];
+    let mut stack: Vec<StackWord, STACK_CAP> = Vec::new();
+    run_single(&program, &mut globals, &mut stack)?;
+    assert_eq!(stack.as_slice(), &[9, 7, 6, 7]);
+    Ok(())
+}
+
+#[test]
+fn structured_if_else_lowers_to_branches() -> Result<(), MachineError> {
+    let program = assemble_program(&[
+        ".machine main locals 0 functions 1",
+        ".func main index 0",
+        "PUSH 3",
+        ".if lt 5",
+        "PUSH 1",
+        ".else",
+        "PUSH 2",
+        ".endif",
+        "PUSH 5",
+        "PUSH 5",
+        ".if ne",
+        "PUSH 3",
+        ".endif",
+        "PUSH 7",
+        ".if eq 7",
+        "PUSH 4",
+        "DUP",
+        ".if gte 9",
+        "PUSH 5",
+        ".else",
+        "PUSH 6",
+        ".endif",
+        ".endif",
+        "EXIT",
+        ".end",
+        ".end",
+    ]);
+    let mut globals = [0u32; 1];
+    let mut stack: Vec<StackWord, STACK_CAP> = Vec::new();
+    run_single(&program, &mut globals, &mut stack)?;
+    assert_eq!(stack.as_slice(), &[1, 4, 6]);
+    Ok(())
+}
+
+#[test]
+fn structured_loops_lower_to_branches() -> Result<(), MachineError> {
+    let program = assemble_program(&[
+        ".machine main locals 2 functions 1",
+        ".local total 0",
+        ".local i 1",
+        ".func main index 0",
+        ".for i 0, 4",
+        "LLOAD total",
+        "LLOAD i",
+        "ADD",
+        "LSTORE total",
+        ".endfor",
+        ".while",
+        "LLOAD total",
+        ".do gt 0",
+        "LLOAD total",
+        "PUSH 3",
+        "SUB",
+        "LSTORE total",
+        ".endwhile",
+        "LLOAD i",
+        "EXIT",
+        ".end",
+        ".end",
+    ]);
+    let mut globals = [0u32; 2];
+    let mut stack: Vec<StackWord, STACK_CAP> = Vec::new();
+    run_single(&program, &mut globals, &mut stack)?;
+    assert_eq!(stack.as_slice(), &[4]);
+    Ok(())
+}
+
+#[test]
+fn fault_pc_maps_back_to_source() {
+    let mut buffer = [0u16; 256];
+    let mut map_buffer = [0u8; 64];
+    let builder = ProgramBuilder::<ASM_MACHINE_MAX, ASM_FUNCTION_MAX>::new(&mut buffer, 1, 1, 0).unwrap();
+    let mut asm: Assembler<ASM_MACHINE_MAX, ASM_FUNCTION_MAX, ASM_LABEL_CAP, ASM_DATA_CAP> =
+        Assembler::new(builder).with_source_map(&mut map_buffer);
+    for line in [
+        ".machine main locals 0 functions 1",
+        ".func main index 0",
+        "PUSH 1",
+        "POP",
+        "POP",
+        "EXIT",
+        ".end",
+        ".end",
+    ] {
+        asm.add_line(line).unwrap();
+    }
+    let (descriptor, map_len) = asm.finish_with_source_map().unwrap();
+    let program = &buffer[..descriptor.length];
+    let mut memory = make_memory(program, STACK_CAP);
+    let mut machine = Program::new(program, memory.as_mut_slice()).unwrap();
+    let result = machine.call(0, 0);
+    assert!(matches!(result, Err(MachineError::PopOnEmptyStack)));
+
+    let map = crate::assembler::source_map::SourceMap::new(&map_buffer[..map_len]).unwrap();
+    let source = map.lookup(machine.last_pc()).unwrap();
+    assert_eq!(source.line, 5);
+    assert_eq!(source.function, Some("main"));
+    assert_eq!(source.machine, Some("main"));
+}
+
+#[test]
+fn instances_start_with_their_own_locals() -> Result<(), MachineError> {
+    let mut buffer = [0u16; 256];
+    let builder = ProgramBuilder::<3, ASM_FUNCTION_MAX>::new(&mut buffer, 3, 1, 0).unwrap();
+    let mut asm: Assembler<3, ASM_FUNCTION_MAX, ASM_LABEL_CAP, ASM_DATA_CAP> = Assembler::new(builder);
+    for line in [
+        ".machine comet locals 3 functions 3",
+        ".local red 0",
+        ".local green 1",
+        ".local blue 2",
+        ".func init index 0",
+        "EXIT",
+        ".end",
+        ".func start_frame index 1",
+        "POP",
+        "EXIT",
+        ".end",
+        ".func get_color index 2",
+        "LLOAD red",
+        "LLOAD green",
+        "LLOAD blue",
+        "EXIT",
+        ".end",
+        ".end",
+        ".instance green_comet of comet green = 0xFF",
+        ".instance purple_comet of comet red = 0x80, blue = 0x80",
+    ] {
+        asm.add_line(line).unwrap();
+    }
+    let descriptor = asm.finish().unwrap();
+    assert_eq!(descriptor.instances.len(), 3);
+    let program = &buffer[..descriptor.length];
+    let mut memory = make_memory(program, STACK_CAP);
+    let mut machine = Program::new(program, memory.as_mut_slice())?;
+    let mut colors = std::vec::Vec::new();
+    for index in 0..3 {
+        machine.init_machine(index)?;
+        for _ in 0..3 {
+            machine.stack_mut().push(0)?;
+        }
+        colors.push(machine.get_led_color(index, 0)?);
+        machine.stack_mut().clear();
+    }
+    assert_eq!(colors, [(0, 0, 0), (0, 0xFF, 0), (0x80, 0, 0x80)]);
+    Ok(())
+}
@@ -0,0 +1,286 @@
+//! An async client for devices that speak the pliot protocol.
+//!
+//! `pliot::protocol::Controler` only builds messages. `Client` also sends
+//! them over a [`Transport`], waits for the reply carrying the same request
+//! id and turns it into a typed result. Replies to requests that have
+//! already timed out are skipped, so a slow device cannot answer the wrong
+//! request.
+//!
+//! Requests go one at a time, as the device serves them. The sizes default
+//! to flight-deck's, which the firmware is built with.
+
+use std::time::Duration;
+
+use light_machine::{ProgramWord, StackWord};
+use pliot::protocol::{Controler, ErrorLocation, ErrorType, FunctionId, Protocol, RequestId};
+use postcard::{from_bytes_cobs, to_allocvec_cobs};
+use thiserror_no_std::Error;
+
+pub mod transport;
+
+pub use transport::Transport;
+
+#[cfg(test)]
+mod test;
+
+/// How long a request waits for its reply unless told otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("transport: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("message: {0}")]
+    Postcard(#[from] postcard::Error),
+    #[error("no reply in time")]
+    Timeout,
+    #[error("the device answered {error_type:?} at {location:?}")]
+    Device {
+        error_type: ErrorType,
+        location: Option<ErrorLocation>,
+    },
+    #[error("{0} arguments are more than a call can carry")]
+    TooManyArgs(usize),
+    #[error("the device answered with the wrong kind of message")]
+    UnexpectedReply,
+    /// The device did not report an error, but it does not hold the program
+    /// either. The device drops errors in later blocks without replying.
+    #[error("the device does not hold the loaded program")]
+    LoadNotConfirmed,
+}
+
+/// How far a `load_program` has got, after each message sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadProgress {
+    pub sent: usize,
+    pub total: usize,
+}
+
+pub struct Client<
+    T,
+    const MAX_ARGS: usize = 10,
+    const MAX_RESULT: usize = 3,
+    const PROGRAM_BLOCK_SIZE: usize = 64,
+    const UI_BLOCK_SIZE: usize = 128,
+> {
+    transport: T,
+    controler: Controler<MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE>,
+    timeout: Duration,
+    retries: u32,
+}
+
+impl<
+    T: Transport,
+    const MAX_ARGS: usize,
+    const MAX_RESULT: usize,
+    const PROGRAM_BLOCK_SIZE: usize,
+    const UI_BLOCK_SIZE: usize,
+> Client<T, MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE>
+{
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            controler: Controler::new(),
+            timeout: DEFAULT_TIMEOUT,
+            retries: 0,
+        }
+    }
+
+    /// How long each request waits for its reply.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// How many more times a request that is safe to repeat is sent, with a
+    /// fresh request id, after it times out: the reads, `get_i2c_devices`
+    /// and `call_static`. None by default.
+    pub fn set_retries(&mut self, retries: u32) {
+        self.retries = retries;
+    }
+
+    pub fn into_transport(self) -> T {
+        self.transport
+    }
+
+    /// Calls function `function_index` of machine `machine_index`.
+    pub async fn call(
+        &mut self,
+        machine_index: ProgramWord,
+        function_index: u32,
+        args: &[StackWord],
+    ) -> Result<Vec<StackWord>, Error> {
+        let args = heapless::Vec::from_slice(args).map_err(|_| Error::TooManyArgs(args.len()))?;
+        let function = FunctionId {
+            machine_index,
+            function_index,
+        };
+        let request = self.controler.call(function, args);
+        match self.request(&request).await? {
+            Protocol::Return { result, .. } => Ok(result.to_vec()),
+            _ => Err(Error::UnexpectedReply),
+        }
+    }
+
+    /// Calls shared function `function_id`.
+    pub async fn call_static(&mut self, function_id: u32, args: &[StackWord]) -> Result<Vec<StackWord>, Error> {
+        let args = heapless::Vec::from_slice(args).map_err(|_| Error::TooManyArgs(args.len()))?;
+        let reply = self
+            .retrying_request(|controler| controler.call_static(function_id, args.clone()))
+            .await?;
+        match reply {
+            Protocol::StaticFunctionResult { error: Some(error_type), .. } => Err(Error::Device {
+                error_type,
+                location: None,
+            }),
+            Protocol::StaticFunctionResult { result, .. } => Ok(result.to_vec()),
+            _ => Err(Error::UnexpectedReply),
+        }
+    }
+
+    /// The addresses of the I2C devices the board found, a page at a time.
+    pub async fn get_i2c_devices(&mut self) -> Result<Vec<u8>, Error> {
+        let mut found = Vec::new();
+        loop {
+            let offset = found.len() as u32;
+            let Protocol::I2cDevices {
+                total_count, devices, ..
+            } = self.retrying_request(|controler| controler.get_i2c_devices(offset)).await?
+            else {
+                return Err(Error::UnexpectedReply);
+            };
+            found.extend_from_slice(&devices);
+            if devices.is_empty() || found.len() >= total_count as usize {
+                return Ok(found);
+            }
+        }
+    }
+
+    /// The UI state stored with the program, block by block.
+    pub async fn read_ui_state(&mut self) -> Result<Vec<u8>, Error> {
+        let mut state = Vec::new();
+        for block_number in 0.. {
+            let Protocol::UiStateBlock { total_size, block, .. } =
+                self.retrying_request(|controler| controler.read_ui_state(block_number)).await?
+            else {
+                return Err(Error::UnexpectedReply);
+            };
+            state.extend_from_slice(&block);
+            if block.is_empty() || state.len() >= total_size as usize {
+                break;
+            }
+        }
+        Ok(state)
+    }
+
+    /// The image of the loaded program, block by block.
+    pub async fn read_program(&mut self) -> Result<Vec<ProgramWord>, Error> {
+        let mut program = Vec::new();
+        for block_number in 0.. {
+            let Protocol::ProgramData { total_size, block, .. } =
+                self.retrying_request(|controler| controler.read_program(block_number)).await?
+            else {
+                return Err(Error::UnexpectedReply);
+            };
+            program.extend_from_slice(&block);
+            if block.is_empty() || program.len() >= total_size as usize {
+                break;
+            }
+        }
+        Ok(program)
+    }
+
+    /// Loads `program` and `ui_state`, calling `progress` after each block.
+    ///
+    /// The device only answers a load when it fails, and not always then, so
+    /// once the load is finished the first block is read back to confirm it.
+    pub async fn load_program(
+        &mut self,
+        program: &[ProgramWord],
+        ui_state: &[u8],
+        mut progress: impl FnMut(LoadProgress),
+    ) -> Result<(), Error> {
+        let total = program
+            .len()
+            .div_ceil(PROGRAM_BLOCK_SIZE)
+            .saturating_add(ui_state.len().div_ceil(UI_BLOCK_SIZE))
+            .saturating_add(1);
+        let mut load_id = None;
+        for (sent, message) in (1..).zip(self.controler.get_program_loader(program, ui_state)) {
+            load_id = message.get_request_id();
+            self.send(&message).await?;
+            progress(LoadProgress { sent, total });
+        }
+
+        let check = self.controler.read_program(0);
+        self.send(&check).await?;
+        let ids = [check.get_request_id(), load_id];
+        let Protocol::ProgramData { total_size, block, .. } = self.reply(&ids).await? else {
+            return Err(Error::UnexpectedReply);
+        };
+        let first_block = &program[..program.len().min(PROGRAM_BLOCK_SIZE)];
+        if total_size as usize != program.len() || block.as_slice() != first_block {
+            return Err(Error::LoadNotConfirmed);
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, message: &Protocol<MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE>) -> Result<(), Error> {
+        self.transport.send(&to_allocvec_cobs(message)?).await?;
+        Ok(())
+    }
+
+    async fn request(
+        &mut self,
+        message: &Protocol<MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE>,
+    ) -> Result<Protocol<MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE>, Error> {
+        self.send(message).await?;
+        self.reply(&[message.get_request_id()]).await
+    }
+
+    /// Sends the message `build` makes, and a fresh one each time the last
+    /// times out, up to the retry count.
+    async fn retrying_request(
+        &mut self,
+        mut build: impl FnMut(
+            &mut Controler<MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE>,
+        ) -> Protocol<MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE>,
+    ) -> Result<Protocol<MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE>, Error> {
+        let mut retries = self.retries;
+        loop {
+            let message = build(&mut self.controler);
+            match self.request(&message).await {
+                Err(Error::Timeout) if retries > 0 => retries -= 1,
+                reply => return reply,
+            }
+        }
+    }
+
+    /// The next reply to any of `ids`, with an `Error` reply returned as
+    /// one. Anything else that arrives meanwhile is dropped.
+    async fn reply(
+        &mut self,
+        ids: &[Option<RequestId>],
+    ) -> Result<Protocol<MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE>, Error> {
+        let wait = async {
+            loop {
+                let mut frame = self.transport.receive().await?;
+                // A frame cut short by a reset or a reconnect is not ours.
+                let Ok(reply) = from_bytes_cobs::<Protocol<MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE>>(&mut frame) else {
+                    continue;
+                };
+                let id = reply.get_request_id();
+                if id.is_none() || !ids.contains(&id) {
+                    continue;
+                }
+                if let Protocol::Error {
+                    error_type, location, ..
+                } = reply
+                {
+                    return Err(Error::Device { error_type, location });
+                }
+                return Ok(reply);
+            }
+        };
+        tokio::time::timeout(self.timeout, wait).await.map_err(|_| Error::Timeout)?
+    }
+}
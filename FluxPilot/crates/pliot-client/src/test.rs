@@ -0,0 +1,215 @@
+use std::time::Duration;
+
+use light_machine::assembler::Assembler;
+use light_machine::builder::{FunctionIndex, Op, ProgramBuilder};
+use light_machine::{ProgramWord, StackWord};
+use pliot::Pliot;
+use pliot::meme_storage::MemStorage;
+use pliot::protocol::{ErrorType, MessageType, Protocol, RequestId};
+use postcard::{from_bytes_cobs, to_allocvec_cobs};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+use crate::transport::{Framed, memory};
+use crate::{Client, Error, LoadProgress, Transport};
+
+type Device<'a, 'b> = Pliot<'a, 'b, 10, 3, 64, 128, MemStorage<'a>>;
+type ProtocolType = Protocol<10, 3, 64, 128>;
+
+/// A machine whose function 1 adds one to its argument, and a shared
+/// function 0 that returns 11 and 22.
+fn program() -> Vec<ProgramWord> {
+    let mut buffer = vec![0u16; 256];
+    let builder = ProgramBuilder::<'_, 1, 2>::new(&mut buffer, 1, 1, 1).unwrap();
+    let mut shared_function = builder.new_shared_function_at_index(FunctionIndex::new(0)).unwrap();
+    shared_function.add_op(Op::Push(11)).unwrap();
+    shared_function.add_op(Op::Push(22)).unwrap();
+    shared_function.add_op(Op::Exit).unwrap();
+    let (_index, builder) = shared_function.finish().unwrap();
+
+    let mut asm: Assembler<1, 2, 16, 16> = Assembler::new(builder);
+    let lines = [
+        ".machine main locals 0 functions 2",
+        "    .func init index 0",
+        "      EXIT",
+        "    .end",
+        "    .func add_one index 1",
+        "      PUSH 1",
+        "      ADD",
+        "      EXIT",
+        "    .end",
+        ".end",
+    ];
+    for line in lines {
+        asm.add_line(line).unwrap();
+    }
+    let length = asm.finish().unwrap().length;
+    buffer.truncate(length);
+    buffer
+}
+
+/// Serves `device` on `stream` the way the firmware's USB loop does, until
+/// the client hangs up.
+async fn serve(device: &mut Device<'_, '_>, stream: DuplexStream) {
+    serve_dropping(device, stream, 0).await;
+}
+
+/// `serve`, but the first `dropped` frames are lost on the way.
+async fn serve_dropping(device: &mut Device<'_, '_>, mut stream: DuplexStream, mut dropped: usize) {
+    let mut frame = Vec::new();
+    let mut chunk = [0u8; 256];
+    let mut reply = [0u8; 1024];
+    loop {
+        let read = stream.read(&mut chunk).await.unwrap();
+        if read == 0 {
+            return;
+        }
+        for &byte in &chunk[..read] {
+            frame.push(byte);
+            if byte != 0 {
+                continue;
+            }
+            if dropped > 0 {
+                dropped -= 1;
+                frame.clear();
+                continue;
+            }
+            let wrote = device.process_message(&mut frame, &mut reply).unwrap_or_default();
+            frame.clear();
+            stream.write_all(&reply[..wrote]).await.unwrap();
+        }
+    }
+}
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap()
+}
+
+#[test]
+fn client_loads_and_talks_to_a_device() {
+    let program = program();
+    let ui_state: Vec<u8> = (0..200).collect();
+    let mut storage_buffer = vec![0u16; 1024];
+    let mut ui_state_buffer = vec![0u8; 512];
+    let mut storage = MemStorage::new(&mut storage_buffer, &mut ui_state_buffer);
+    let mut memory_words = vec![0 as StackWord; 256];
+    let mut device = Device::new(&mut storage, &mut memory_words);
+    device.set_i2c_devices(&[0x20, 0x21, 0x40, 0x48, 0x50]);
+    let (transport, device_end) = memory(4096);
+
+    let session = async {
+        let mut client: Client<_> = Client::new(transport);
+        let mut progress = Vec::new();
+        client
+            .load_program(&program, &ui_state, |step| progress.push(step))
+            .await
+            .unwrap();
+        // One program block, two UI state blocks and the finish.
+        assert_eq!(progress.len(), 4);
+        assert_eq!(progress.last(), Some(&LoadProgress { sent: 4, total: 4 }));
+
+        assert_eq!(client.read_program().await.unwrap(), program);
+        assert_eq!(client.read_ui_state().await.unwrap(), ui_state);
+        assert_eq!(client.call(0, 1, &[41]).await.unwrap(), vec![42]);
+        assert_eq!(client.call_static(0, &[]).await.unwrap(), vec![11, 22]);
+        assert_eq!(client.get_i2c_devices().await.unwrap(), vec![0x20, 0x21, 0x40, 0x48, 0x50]);
+
+        let Err(Error::TooManyArgs(11)) = client.call(0, 1, &[0; 11]).await else {
+            panic!("eleven arguments should not fit");
+        };
+        // A failed call gets no reply, and the next request is not confused
+        // by it.
+        client.set_timeout(Duration::from_millis(50));
+        let Err(Error::Timeout) = client.call(7, 0, &[]).await else {
+            panic!("a call to a missing machine should time out");
+        };
+        assert_eq!(client.call(0, 1, &[1]).await.unwrap(), vec![2]);
+    };
+    runtime().block_on(async {
+        tokio::join!(session, serve(&mut device, device_end));
+    });
+}
+
+#[test]
+fn client_reports_a_failed_load() {
+    let program = program();
+    // Too small to hold the program.
+    let mut storage_buffer = vec![0u16; 16];
+    let mut ui_state_buffer = [0u8; 0];
+    let mut storage = MemStorage::new(&mut storage_buffer, &mut ui_state_buffer);
+    let mut memory_words = vec![0 as StackWord; 256];
+    let mut device = Device::new(&mut storage, &mut memory_words);
+    let (transport, device_end) = memory(4096);
+
+    let session = async {
+        let mut client: Client<_> = Client::new(transport);
+        let result = client.load_program(&program, &[], |_| {}).await;
+        // The device starts no load and so refuses the finish; the error
+        // carries the load's request id.
+        let Err(Error::Device {
+            error_type: ErrorType::UnexpectedMessageType(MessageType::FinishProgram),
+            ..
+        }) = result
+        else {
+            panic!("the load should fail, got {result:?}");
+        };
+    };
+    runtime().block_on(async {
+        tokio::join!(session, serve(&mut device, device_end));
+    });
+}
+
+#[test]
+fn client_retries_reads_the_device_lost() {
+    let program = program();
+    // The program is already in storage, so the first frames are reads.
+    let mut storage_buffer = vec![0u16; 1024];
+    storage_buffer[..program.len()].copy_from_slice(&program);
+    let mut ui_state_buffer = [0u8; 0];
+    let mut storage = MemStorage::with_program(&mut storage_buffer, &mut ui_state_buffer, program.len());
+    let mut memory_words = vec![0 as StackWord; 256];
+    let mut device = Device::new(&mut storage, &mut memory_words);
+    let (transport, device_end) = memory(4096);
+
+    let session = async {
+        let mut client: Client<_> = Client::new(transport);
+        client.set_timeout(Duration::from_millis(50));
+        let Err(Error::Timeout) = client.read_program().await else {
+            panic!("a lost read should time out without retries");
+        };
+        client.set_retries(1);
+        assert_eq!(client.read_program().await.unwrap(), program);
+        assert_eq!(client.call_static(0, &[]).await.unwrap(), vec![11, 22]);
+    };
+    runtime().block_on(async {
+        tokio::join!(session, serve_dropping(&mut device, device_end, 2));
+    });
+}
+
+#[test]
+fn client_skips_frames_for_other_requests() {
+    let (transport, mut device_end) = memory(4096);
+    let device = async move {
+        let mut frame = Framed::new(&mut device_end).receive().await.unwrap();
+        let request: ProtocolType = from_bytes_cobs(&mut frame).unwrap();
+        let request_id = request.get_request_id().unwrap();
+        let stale = ProtocolType::Return {
+            request_id: RequestId::new(request_id.value() + 100),
+            result: heapless::Vec::from_slice(&[1]).unwrap(),
+        };
+        let answer = ProtocolType::Return {
+            request_id,
+            result: heapless::Vec::from_slice(&[2]).unwrap(),
+        };
+        device_end.write_all(&to_allocvec_cobs(&stale).unwrap()).await.unwrap();
+        // Half a frame from a reset, then noise that is not a message.
+        device_end.write_all(&[0x05, 0x01, 0x02, 0]).await.unwrap();
+        device_end.write_all(&to_allocvec_cobs(&answer).unwrap()).await.unwrap();
+    };
+    let session = async {
+        let mut client: Client<_> = Client::new(transport);
+        assert_eq!(client.call(0, 0, &[]).await.unwrap(), vec![2]);
+    };
+    runtime().block_on(async {
+        tokio::join!(session, device);
+    });
+}
@@ -0,0 +1,95 @@
+//! Ways to reach a device. Every transport carries the same frames: a
+//! postcard message, COBS encoded and ended by a zero byte, as
+//! `usb_io::io_loop` reads them.
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+
+/// The longest frame kept while waiting for its zero byte. The device drops
+/// longer frames too, so nothing this long can be a reply.
+const FRAME_CAP: usize = 1024;
+
+/// Sends and receives whole frames.
+pub trait Transport {
+    /// Sends `frame`, zero byte included.
+    fn send(&mut self, frame: &[u8]) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// The next frame, zero byte included. A timeout may drop this future
+    /// part way through, so it must not lose bytes when it is.
+    fn receive(&mut self) -> impl Future<Output = io::Result<Vec<u8>>> + Send;
+}
+
+/// Frames over any byte stream: a socket, a serial port or a pipe.
+pub struct Framed<S> {
+    stream: S,
+    received: Vec<u8>,
+}
+
+impl<S> Framed<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            received: Vec::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Transport for Framed<S> {
+    async fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.stream.write_all(frame).await?;
+        self.stream.flush().await
+    }
+
+    async fn receive(&mut self) -> io::Result<Vec<u8>> {
+        let mut chunk = [0u8; 256];
+        loop {
+            if let Some(end) = self.received.iter().position(|&byte| byte == 0) {
+                return Ok(self.received.drain(..=end).collect());
+            }
+            if self.received.len() > FRAME_CAP {
+                self.received.clear();
+            }
+            // Only a completed read changes `received`, so dropping this
+            // future between reads loses nothing.
+            let read = self.stream.read(&mut chunk).await?;
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.received.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
+
+/// Connects to a device over TCP, such as `fluxpilot device`.
+pub async fn connect_tcp(address: impl tokio::net::ToSocketAddrs) -> io::Result<Framed<tokio::net::TcpStream>> {
+    let stream = tokio::net::TcpStream::connect(address).await?;
+    stream.set_nodelay(true)?;
+    Ok(Framed::new(stream))
+}
+
+/// Connects to a device on a Unix socket.
+#[cfg(unix)]
+pub async fn connect_unix(path: impl AsRef<std::path::Path>) -> io::Result<Framed<tokio::net::UnixStream>> {
+    tokio::net::UnixStream::connect(path).await.map(Framed::new)
+}
+
+/// Opens a board's USB serial port, such as `/dev/ttyACM0`.
+#[cfg(feature = "serial")]
+pub fn open_serial(path: &str, baud_rate: u32) -> io::Result<Framed<tokio_serial::SerialStream>> {
+    use tokio_serial::SerialPortBuilderExt;
+
+    let port = tokio_serial::new(path, baud_rate).open_native_async()?;
+    Ok(Framed::new(port))
+}
+
+/// A transport with nothing behind it, and the stream a test's stand-in for
+/// the device reads the frames from and answers on.
+pub fn memory(buffer_bytes: usize) -> (Framed<DuplexStream>, DuplexStream) {
+    let (client, device) = tokio::io::duplex(buffer_bytes);
+    (Framed::new(client), device)
+}
@@ -18,6 +18,9 @@
     )
 )]
 #![cfg_attr(not(test), warn(clippy::missing_panics_doc))]
+// `StorageError` carries its `ErrorLocation` inline; there is no allocator to
+// box it, so large `Err` variants are expected throughout this crate.
+#![allow(clippy::result_large_err)]
 
 pub mod meme_storage;
 pub mod protocol;
@@ -166,6 +169,8 @@ pub trait Storage {
         program_number: ProgramNumber,
         memory: &'b mut [StackWord],
     ) -> Result<Program<'a, 'b>, StorageError>;
+    /// Words in the loaded program image.
+    fn get_program_len(&mut self, program_number: ProgramNumber) -> Result<u32, StorageError>;
     fn get_ui_state_len(&mut self, program_number: ProgramNumber) -> Result<u32, StorageError>;
     fn read_ui_state_block(
         &mut self,
@@ -202,6 +207,7 @@ pub struct Pliot<
     memory: &'b mut [StackWord],
     loader: Option<CurrentLoader<S>>,
     i2c_devices: Vec<u8, I2C_DEVICE_LIST_CAP>,
+    step_limit: Option<u32>,
 }
 
 impl<
@@ -220,12 +226,20 @@ impl<
             memory,
             loader: None,
             i2c_devices: Vec::new(),
+            step_limit: None,
         }
     }
 
+    /// Limit how many instructions any single VM entry point may run before
+    /// it fails with `MachineError::StepLimitExceeded`.
+    pub fn set_step_limit(&mut self, limit: Option<u32>) {
+        self.step_limit = limit;
+    }
+
     pub fn init(&mut self) -> Result<(), PliotError> {
         let progroam_unmber = ProgramNumber(0);
         let mut program = self.storage.get_program(progroam_unmber, self.memory)?;
+        program.set_step_limit(self.step_limit);
         let machine_count = program.machine_count()?;
         if machine_count == 0 {
             return Err(PliotError::MachineError(
@@ -504,9 +518,12 @@ impl<
                             offset,
                             temp.as_mut_slice(),
                         )?;
+                        let read_bytes = temp
+                            .get(..read)
+                            .ok_or(StorageError::new(StorageErrorKind::UiStateReadOutOfBounds))?;
                         let mut block: Vec<u8, UI_BLOCK_SIZE> = Vec::new();
                         block
-                            .extend_from_slice(&temp[..read])
+                            .extend_from_slice(read_bytes)
                             .map_err(|_| StorageError::new(StorageErrorKind::UiStateTooLarge))?;
                         let response =
                             Protocol::<MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE>::UiStateBlock {
@@ -524,20 +541,59 @@ impl<
                     Ok(len) => len,
                     Err(error) => {
                         let (error_type, location) =
-                            Self::error_type_for_read_ui_state(error, block_number);
-                        match Self::write_error(
-                            Some(request_id),
-                            error_type,
-                            location,
-                            out_buff,
-                        ) {
-                            Ok(len) => len,
-                            Err(_) => 0,
-                        }
+                            Self::error_type_for_read(error, block_number);
+                        Self::write_error(Some(request_id), error_type, location, out_buff)
+                            .unwrap_or_default()
                     }
                 }
             }
 
+            Protocol::ReadProgram {
+                request_id,
+                block_number,
+            } => {
+                let result: Result<usize, PliotError> = (|| {
+                    let program_number = ProgramNumber(0);
+                    let total_size = self.storage.get_program_len(program_number)?;
+                    let program = self.storage.get_program(program_number, self.memory)?;
+                    // Blocks past the end come back empty, as for UI state.
+                    let start = usize::try_from(block_number)
+                        .ok()
+                        .and_then(|block| block.checked_mul(PROGRAM_BLOCK_SIZE))
+                        .unwrap_or(usize::MAX);
+                    let end = start
+                        .saturating_add(PROGRAM_BLOCK_SIZE)
+                        .min(total_size as usize);
+                    let words = program.static_data().get(start..end).unwrap_or_default();
+                    let mut block: Vec<ProgramWord, PROGRAM_BLOCK_SIZE> = Vec::new();
+                    block
+                        .extend_from_slice(words)
+                        .map_err(|_| StorageError::new(StorageErrorKind::ProgramTooLarge))?;
+                    let response =
+                        Protocol::<MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE>::ProgramData {
+                            request_id,
+                            total_size,
+                            block_number,
+                            block,
+                        };
+                    let wrote = postcard::to_slice_cobs(&response, out_buff)?;
+                    Ok(wrote.len())
+                })();
+
+                match result {
+                    Ok(len) => len,
+                    Err(error) => {
+                        let (error_type, location) = Self::error_type_for_read(error, block_number);
+                        Self::write_error(Some(request_id), error_type, location, out_buff)
+                            .unwrap_or_default()
+                    }
+                }
+            }
+
+            Protocol::ProgramData { request_id, .. } => {
+                Self::write_unexpected_message_type(Some(request_id), MessageType::ProgramData, out_buff)?
+            }
+
             Protocol::FinishProgram { request_id } => {
                 let current = self.loader.take();
                 match current {
@@ -574,6 +630,7 @@ impl<
         };
         let progroam_unmber = ProgramNumber(0);
         let mut program = self.storage.get_program(progroam_unmber, self.memory)?;
+        program.set_step_limit(self.step_limit);
 
         {
             let stack = program.stack_mut();
@@ -606,6 +663,7 @@ impl<
         };
         let progroam_unmber = ProgramNumber(0);
         let mut program = self.storage.get_program(progroam_unmber, self.memory)?;
+        program.set_step_limit(self.step_limit);
         let machine_count = program.machine_count()?;
         if machine_count == 0 {
             return Err(PliotError::MachineError(
@@ -641,6 +699,7 @@ impl<
     ) -> Result<(), PliotError> {
         let progroam_unmber = ProgramNumber(0);
         let mut program = self.storage.get_program(progroam_unmber, self.memory)?;
+        program.set_step_limit(self.step_limit);
         program.stack_mut().clear();
         program.start_frame(machine_number, tick)?;
         Ok(())
@@ -654,6 +713,7 @@ impl<
     ) -> Result<(u8, u8, u8), PliotError> {
         let progroam_unmber = ProgramNumber(0);
         let mut program = self.storage.get_program(progroam_unmber, self.memory)?;
+        program.set_step_limit(self.step_limit);
         {
             let stack = program.stack_mut();
             stack.clear();
@@ -691,7 +751,7 @@ impl<
         Ok(wrote.len())
     }
 
-    fn error_type_for_read_ui_state(
+    fn error_type_for_read(
         error: PliotError,
         block_number: u32,
     ) -> (ErrorType, Option<ErrorLocation>) {
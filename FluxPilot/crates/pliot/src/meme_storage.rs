@@ -4,6 +4,9 @@ use light_machine::StackWord;
 pub struct MemStorage<'a> {
     programs: [&'a mut [ProgramWord]; 2],
     active_index: usize,
+    // `None` when neither a load nor `with_program` gave the image's length;
+    // the whole buffer holds the image.
+    program_len: Option<usize>,
     ui_state: &'a mut [u8],
     ui_state_len: usize,
 }
@@ -16,10 +19,25 @@ impl<'a> MemStorage<'a> {
         Self {
             programs: [program_a, program_b],
             active_index: 0,
+            program_len: None,
             ui_state,
             ui_state_len: 0,
         }
     }
+
+    /// Storage whose first `program_len` words already hold an image, such as
+    /// the one `default_program` builds in place, so reads report that image
+    /// rather than the whole half.
+    pub fn with_program(
+        program: &'a mut [ProgramWord],
+        ui_state: &'a mut [u8],
+        program_len: usize,
+    ) -> Self {
+        let mut storage = Self::new(program, ui_state);
+        let half = storage.programs.first().map_or(0, |program| program.len());
+        storage.program_len = Some(program_len.min(half));
+        storage
+    }
 }
 
 impl<'a> Storage for MemStorage<'a> {
@@ -79,8 +97,10 @@ impl<'a> Storage for MemStorage<'a> {
 
     fn finish_load(&mut self, loader: Self::L) -> Result<ProgramNumber, StorageError> {
         let target_index = loader.target_index;
+        let program_len = loader.program_end;
         let ui_state_len = loader.finish_load()?;
         self.active_index = target_index;
+        self.program_len = Some(program_len);
         self.ui_state_len = ui_state_len;
         Ok(ProgramNumber(0))
     }
@@ -104,6 +124,17 @@ impl<'a> Storage for MemStorage<'a> {
         }
     }
 
+    fn get_program_len(&mut self, program_number: ProgramNumber) -> Result<u32, StorageError> {
+        if program_number.0 != 0 {
+            return Err(StorageError::new(StorageErrorKind::UnknownProgram));
+        }
+        let program = self
+            .programs
+            .get(self.active_index)
+            .ok_or(StorageError::new(StorageErrorKind::UnknownProgram))?;
+        Ok(self.program_len.unwrap_or(program.len()) as u32)
+    }
+
     fn get_ui_state_len(&mut self, program_number: ProgramNumber) -> Result<u32, StorageError> {
         if program_number.0 != 0 {
             return Err(StorageError::new(StorageErrorKind::UnknownProgram));
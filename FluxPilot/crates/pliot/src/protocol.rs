@@ -41,6 +41,8 @@ pub enum MessageType {
     UiStateBlock,
     ReadUiState,
     FinishProgram,
+    ReadProgram,
+    ProgramData,
 }
 
 pub const ERROR_LOCATION_FILE_MAX: usize = 96;
@@ -175,6 +177,18 @@ pub enum Protocol<
     },
     /// Finish the new program load
     FinishProgram { request_id: RequestId },
+    /// Read a block of the loaded program image
+    ReadProgram {
+        request_id: RequestId,
+        block_number: u32,
+    },
+    /// A block of the loaded program image; `total_size` is in words
+    ProgramData {
+        request_id: RequestId,
+        total_size: u32,
+        block_number: u32,
+        block: Vec<ProgramWord, PROGRAM_BLOCK_SIZE>,
+    },
 }
 
 impl<
@@ -199,6 +213,8 @@ impl<
             Protocol::UiStateBlock { request_id, .. } => Some(*request_id),
             Protocol::ReadUiState { request_id, .. } => Some(*request_id),
             Protocol::FinishProgram { request_id, .. } => Some(*request_id),
+            Protocol::ReadProgram { request_id, .. } => Some(*request_id),
+            Protocol::ProgramData { request_id, .. } => Some(*request_id),
         }
     }
 }
@@ -280,6 +296,17 @@ impl<
         }
     }
 
+    pub fn read_program(
+        &mut self,
+        block_number: u32,
+    ) -> Protocol<MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE> {
+        let request_id = self.get_request_id();
+        Protocol::ReadProgram {
+            request_id,
+            block_number,
+        }
+    }
+
     fn get_request_id(&mut self) -> RequestId {
         self.next_request = self.next_request.wrapping_add(1);
         RequestId(self.next_request)
@@ -241,7 +241,7 @@ fn test_pilot_get_color() -> Result<(), MachineError> {
 
     let program_builder = machine.finish().expect("Could not finish program");
 
-    let descriptor = program_builder.finish_program();
+    let descriptor = program_builder.finish_program().expect("Could not finish program");
 
     let program = &buffer[0..descriptor.length];
 
@@ -490,7 +490,7 @@ fn test_call_static_function() -> Result<(), PliotError> {
     let (_index, machine) = function.finish().expect("could not finish function");
     let program_builder = machine.finish().expect("could not finish machine");
 
-    let descriptor = program_builder.finish_program();
+    let descriptor = program_builder.finish_program().expect("could not finish program");
     let program = &buffer[0..descriptor.length];
 
     let mut storage_buffer = [0u16; 256];
@@ -636,6 +636,155 @@ fn test_read_ui_state_blocks() -> Result<(), PliotError> {
     Ok(())
 }
 
+#[test]
+fn test_read_program_blocks() -> Result<(), PliotError> {
+    const MACHINE_COUNT: usize = 1;
+    const FUNCTION_COUNT: usize = 1;
+    const LABEL_CAP: usize = 16;
+    const DATA_CAP: usize = 160;
+
+    let mut buffer = [0u16; 512];
+    let builder = ProgramBuilder::<MACHINE_COUNT, FUNCTION_COUNT>::new(
+        &mut buffer,
+        MACHINE_COUNT as ProgramWord,
+        MACHINE_COUNT as ProgramWord,
+        SHARED_FUNCTION_COUNT,
+    )
+    .unwrap();
+    let builder = add_shared_stubs(builder);
+    let mut asm: Assembler<MACHINE_COUNT, FUNCTION_COUNT, LABEL_CAP, DATA_CAP> =
+        Assembler::new(builder);
+
+    // Enough static data that the image takes two blocks.
+    let mut lines: StdVec<String> = vec![
+        ".machine main locals 0 functions 1".to_string(),
+        "    .data table".to_string(),
+    ];
+    lines.extend((0..150).map(|value| format!("    .word {value}")));
+    lines.extend(
+        ["    .end", "    .func init index 0", "      EXIT", "    .end", ".end"]
+            .iter()
+            .map(|line| line.to_string()),
+    );
+    for line in lines.iter() {
+        asm.add_line(line).unwrap();
+    }
+    let descriptor = asm.finish().unwrap();
+    let program = &buffer[..descriptor.length];
+    assert!(program.len() > PROGRAM_BLOCK_SIZE);
+
+    let mut storage_buffer = [0u16; 1024];
+    let mut ui_state_mem = [0u8; 16];
+    let mut storage = MemStorage::new(storage_buffer.as_mut_slice(), ui_state_mem.as_mut_slice());
+    let mut controler: Controler<MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE> =
+        Controler::new();
+
+    let mut memory = [0u32; 128];
+    let memory = memory.as_mut_slice();
+    let mut pliot =
+        Pliot::<MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE, MemStorage>::new(
+            &mut storage,
+            memory,
+        );
+
+    let mut out_buf = vec![0u8; 1024];
+    for message in controler.get_program_loader(program, &[]) {
+        let mut in_buf = to_vec_cobs::<ProtocolType, 2048>(&message).unwrap();
+        pliot.process_message(&mut in_buf[..], out_buf.as_mut_slice())?;
+    }
+
+    let mut image: StdVec<ProgramWord> = StdVec::new();
+    for expected_block in 0..3 {
+        let read_block = controler.read_program(expected_block);
+        let mut in_buf = to_vec_cobs::<ProtocolType, 256>(&read_block).unwrap();
+        let wrote = pliot.process_message(&mut in_buf[..], out_buf.as_mut_slice())?;
+        let response: ProtocolType =
+            from_bytes_cobs(&mut out_buf[..wrote]).expect("could not read response");
+        match response {
+            Protocol::ProgramData {
+                total_size,
+                block_number,
+                block,
+                ..
+            } => {
+                assert_eq!(total_size, program.len() as u32);
+                assert_eq!(block_number, expected_block);
+                // The third block is past the end.
+                assert_eq!(block.is_empty(), expected_block == 2);
+                image.extend_from_slice(block.as_slice());
+            }
+            _ => panic!("response was not ProgramData"),
+        }
+    }
+    assert_eq!(image.as_slice(), program);
+
+    Ok(())
+}
+
+#[test]
+fn test_read_program_built_in_place() -> Result<(), PliotError> {
+    const MACHINE_COUNT: usize = 1;
+    const FUNCTION_COUNT: usize = 1;
+
+    // The image goes straight into storage, as `default_program` writes it,
+    // with no load to record its length.
+    let mut storage_buffer = [0u16; 1024];
+    let builder = ProgramBuilder::<MACHINE_COUNT, FUNCTION_COUNT>::new(
+        &mut storage_buffer,
+        MACHINE_COUNT as ProgramWord,
+        MACHINE_COUNT as ProgramWord,
+        SHARED_FUNCTION_COUNT,
+    )
+    .unwrap();
+    let builder = add_shared_stubs(builder);
+    let mut asm: Assembler<MACHINE_COUNT, FUNCTION_COUNT, 16, 16> = Assembler::new(builder);
+    for line in [
+        ".machine main locals 0 functions 1",
+        "    .func init index 0",
+        "      EXIT",
+        "    .end",
+        ".end",
+    ] {
+        asm.add_line(line).unwrap();
+    }
+    let length = asm.finish().unwrap().length;
+    let program: StdVec<ProgramWord> = storage_buffer[..length].to_vec();
+
+    let mut ui_state_mem = [0u8; 16];
+    let mut storage = MemStorage::with_program(
+        storage_buffer.as_mut_slice(),
+        ui_state_mem.as_mut_slice(),
+        length,
+    );
+    assert_eq!(storage.get_program_len(ProgramNumber(0)).ok(), Some(length as u32));
+
+    let mut controler: Controler<MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE> =
+        Controler::new();
+    let mut memory = [0u32; 128];
+    let memory = memory.as_mut_slice();
+    let mut pliot =
+        Pliot::<MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE, MemStorage>::new(
+            &mut storage,
+            memory,
+        );
+    let mut out_buf = vec![0u8; 1024];
+    let mut in_buf = to_vec_cobs::<ProtocolType, 256>(&controler.read_program(0)).unwrap();
+    let wrote = pliot.process_message(&mut in_buf[..], out_buf.as_mut_slice())?;
+    let response: ProtocolType =
+        from_bytes_cobs(&mut out_buf[..wrote]).expect("could not read response");
+    match response {
+        Protocol::ProgramData {
+            total_size, block, ..
+        } => {
+            assert_eq!(total_size, length as u32);
+            assert_eq!(block.as_slice(), program.as_slice());
+        }
+        _ => panic!("response was not ProgramData"),
+    }
+
+    Ok(())
+}
+
 #[test]
 fn test_get_i2c_devices_message() -> Result<(), PliotError> {
     let mut storage_buffer = [0u16; 128];
@@ -0,0 +1,51 @@
+#![no_main]
+
+//! Feeds arbitrary source to the firmware (heapless) assembler. The first two
+//! bytes pick the machine and shared function counts handed to the builder;
+//! the rest is treated as assembly text. A small include library, including
+//! a file that includes itself, is available to `.include`.
+
+use libfuzzer_sys::fuzz_target;
+use light_machine::assembler::Assembler;
+use light_machine::assembler::include::StaticResolver;
+use light_machine::builder::ProgramBuilder;
+use light_machine::ProgramWord;
+
+const MACHINE_MAX: usize = 4;
+const FUNCTION_MAX: usize = 16;
+const LABEL_CAP: usize = 32;
+const DATA_CAP: usize = 64;
+
+const LIBRARY: StaticResolver = StaticResolver::new(&[
+    ("helper", ".shared_func helper\nPUSH 1\nRET 1\n.end\n"),
+    ("consts", ".const LEDS 30\n.const LAST LEDS - 1\n"),
+    ("self", ".include \"self\"\n"),
+    ("outer", ".include \"consts\"\n.include \"helper\"\n"),
+]);
+
+fuzz_target!(|data: &[u8]| {
+    let [machines, shared, source @ ..] = data else {
+        return;
+    };
+    let Ok(source) = core::str::from_utf8(source) else {
+        return;
+    };
+    let machine_count = ProgramWord::from(machines % MACHINE_MAX as u8 + 1);
+    let shared_function_count = ProgramWord::from(shared % 8);
+
+    let mut buffer = [0 as ProgramWord; 1024];
+    let Ok(builder) = ProgramBuilder::<MACHINE_MAX, FUNCTION_MAX>::new(
+        &mut buffer,
+        machine_count,
+        machine_count,
+        shared_function_count,
+    ) else {
+        return;
+    };
+    let mut asm: Assembler<MACHINE_MAX, FUNCTION_MAX, LABEL_CAP, DATA_CAP> =
+        Assembler::new(builder);
+    if asm.add_source(source, &LIBRARY).is_err() {
+        return;
+    }
+    let _ = asm.finish();
+});
@@ -0,0 +1,43 @@
+#![no_main]
+
+//! Feeds arbitrary source to the flight-deck graph assembler and emits the
+//! resulting graph into a program image. The first byte picks the shared
+//! function count; the rest is treated as assembly text.
+
+use flight_deck::graph_assembler::GraphAssembler;
+use libfuzzer_sys::fuzz_target;
+use light_machine::builder::ProgramBuilder;
+use light_machine::ProgramWord;
+
+const MACHINE_MAX: usize = 16;
+const FUNCTION_MAX: usize = 16;
+
+fuzz_target!(|data: &[u8]| {
+    let [shared, source @ ..] = data else {
+        return;
+    };
+    let Ok(source) = core::str::from_utf8(source) else {
+        return;
+    };
+
+    let mut assembler = GraphAssembler::new(ProgramWord::from(*shared % 8));
+    for line in source.lines() {
+        if assembler.add_line(line).is_err() {
+            return;
+        }
+    }
+    let Ok(graph) = assembler.finish() else {
+        return;
+    };
+
+    let mut buffer = vec![0 as ProgramWord; 4096];
+    let Ok(builder) = ProgramBuilder::<MACHINE_MAX, FUNCTION_MAX>::new(
+        &mut buffer,
+        graph.instance_count(),
+        graph.type_count(),
+        graph.shared_function_count(),
+    ) else {
+        return;
+    };
+    let _ = graph.emit_into(builder);
+});
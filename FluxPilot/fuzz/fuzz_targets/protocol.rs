@@ -0,0 +1,41 @@
+#![no_main]
+
+//! Drives `Pliot::process_message` with arbitrary COBS frames, exactly as
+//! `usb_io::io_loop` hands them over: the input is split on the zero
+//! terminator and each frame is processed in turn. Between frames the LED
+//! loop entry points are exercised so programs loaded over the wire run too.
+
+use libfuzzer_sys::fuzz_target;
+use light_machine::{ProgramWord, StackWord};
+use pliot::meme_storage::MemStorage;
+use pliot::Pliot;
+
+// Match the device configuration so seeds mirror real traffic.
+const MAX_ARGS: usize = 3;
+const MAX_RESULT: usize = 3;
+const PROGRAM_BLOCK_SIZE: usize = 64;
+const UI_BLOCK_SIZE: usize = 128;
+const STEP_LIMIT: u32 = 10_000;
+const MACHINE_LIMIT: ProgramWord = 4;
+
+fuzz_target!(|data: &[u8]| {
+    let mut program_buffer = [0 as ProgramWord; 1024];
+    let mut ui_state = [0u8; 512];
+    let mut memory = [0 as StackWord; 256];
+    let mut storage = MemStorage::new(&mut program_buffer, &mut ui_state);
+    let mut pliot: Pliot<'_, '_, MAX_ARGS, MAX_RESULT, PROGRAM_BLOCK_SIZE, UI_BLOCK_SIZE, _> =
+        Pliot::new(&mut storage, &mut memory);
+    pliot.set_step_limit(Some(STEP_LIMIT));
+
+    let mut out_buff = [0u8; 512];
+    for frame in data.split_inclusive(|byte| *byte == 0) {
+        let mut frame = frame.to_vec();
+        let _ = pliot.process_message(&mut frame, &mut out_buff);
+
+        let machine_count = pliot.machine_count().unwrap_or(0);
+        for machine in 0..machine_count.min(MACHINE_LIMIT) {
+            let _ = pliot.start_frame(machine, 0);
+            let _ = pliot.get_led_color(machine, 0, (0, 0, 0));
+        }
+    }
+});
@@ -0,0 +1,44 @@
+#![no_main]
+
+//! Runs arbitrary program images through the same init / start_frame /
+//! get_color cycle the firmware LED loop uses. Every failure must come back
+//! as a `MachineError`; a panic or hang is a bug.
+
+use libfuzzer_sys::fuzz_target;
+use light_machine::{Program, ProgramWord, StackWord};
+
+const MEMORY_WORDS: usize = 512;
+const STEP_LIMIT: u32 = 10_000;
+const MACHINE_LIMIT: ProgramWord = 8;
+const LED_COUNT: u16 = 4;
+
+fuzz_target!(|data: &[u8]| {
+    let image: Vec<ProgramWord> = data
+        .chunks_exact(2)
+        .map(|pair| ProgramWord::from_le_bytes([pair[0], pair[1]]))
+        .collect();
+    let mut memory = [0 as StackWord; MEMORY_WORDS];
+    let Ok(mut program) = Program::new(&image, &mut memory) else {
+        return;
+    };
+    program.set_step_limit(Some(STEP_LIMIT));
+
+    let _ = program.call_shared(0);
+    let Ok(machine_count) = program.machine_count() else {
+        return;
+    };
+    for machine in 0..machine_count.min(MACHINE_LIMIT) {
+        program.stack_mut().clear();
+        let _ = program.init_machine(machine);
+        program.stack_mut().clear();
+        let _ = program.start_frame(machine, 0);
+        for index in 0..LED_COUNT {
+            let stack = program.stack_mut();
+            stack.clear();
+            for seed in [0, 0, 0] {
+                let _ = stack.push(seed);
+            }
+            let _ = program.get_led_color(machine, index);
+        }
+    }
+});
@@ -229,14 +229,18 @@ async fn main(spawner: Spawner) -> () {
     #[cfg(feature = "storage-mem")]
     let storage = {
         let program_buffer = PROGRAM_BUFFER.init([0u16; PROGRAM_BUFFER_SIZE]);
-        if default_program(program_buffer).is_err() {
-            // BUG: we should log here.
-            return;
-        }
+        let program_len = match default_program(program_buffer) {
+            Ok(length) => length,
+            Err(_) => {
+                // BUG: we should log here.
+                return;
+            }
+        };
         let ui_state_buffer = UI_STATE_BUFFER.init([0u8; UI_STATE_BUFFER_SIZE]);
-        MEM_STORAGE.init(MemStorage::new(
+        MEM_STORAGE.init(MemStorage::with_program(
             program_buffer.as_mut_slice(),
             ui_state_buffer.as_mut_slice(),
+            program_len,
         ))
     };
     #[cfg(feature = "storage-flash")]